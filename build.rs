@@ -0,0 +1,380 @@
+//! Generates a JSON Schema of the configuration file from the source of the configuration
+//! types, served at `/config/schema` so editors can validate and complete configuration
+//! files. The types are read from their declarations, with their doc comments as
+//! descriptions and their serde attributes for names, tags and defaults.
+
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+use serde_json::{json, Map, Value};
+
+/// Type the configuration file deserializes to.
+const ROOT: &str = "Config";
+
+/// A struct field or enum variant.
+#[derive(Default)]
+struct Member {
+    name: String,
+    /// The type of a field or newtype variant.
+    ty: Option<String>,
+    /// The fields of a struct variant.
+    fields: Vec<Member>,
+    doc: Vec<String>,
+    serde: Vec<String>,
+}
+
+struct Item {
+    is_enum: bool,
+    doc: Vec<String>,
+    serde: Vec<String>,
+    members: Vec<Member>,
+}
+
+/// The arguments of the `#[serde(...)]` attribute on `line`, if it is one.
+fn serde_args(line: &str) -> Option<Vec<String>> {
+    let args = line.strip_prefix("#[serde(")?.strip_suffix(")]")?;
+    Some(args.split(", ").map(str::to_string).collect())
+}
+
+/// The value of `key = "value"` in serde arguments.
+fn serde_value<'a>(serde: &'a [String], key: &str) -> Option<&'a str> {
+    serde.iter().find_map(|arg| {
+        arg.strip_prefix(key)?
+            .trim_start()
+            .strip_prefix('=')?
+            .trim()
+            .strip_prefix('"')?
+            .strip_suffix('"')
+    })
+}
+
+fn rename(name: &str, rule: Option<&str>) -> String {
+    match rule {
+        Some("lowercase") => name.to_lowercase(),
+        Some("snake_case") => {
+            let mut snake = String::new();
+            for (i, c) in name.chars().enumerate() {
+                if c.is_uppercase() && i > 0 {
+                    snake.push('_');
+                }
+                snake.extend(c.to_lowercase());
+            }
+            snake
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// Parse the members of a struct or enum, from the line after its opening brace up to its
+/// closing brace. Returns the members and the number of lines read.
+fn parse_members(lines: &[&str]) -> (Vec<Member>, usize) {
+    let mut members = Vec::new();
+    let mut doc = Vec::new();
+    let mut serde = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i].trim();
+        i += 1;
+        if line.starts_with('}') {
+            break;
+        }
+        if let Some(text) = line.strip_prefix("///") {
+            doc.push(text.trim().to_string());
+            continue;
+        }
+        if let Some(args) = serde_args(line) {
+            serde.extend(args);
+            continue;
+        }
+        if line.is_empty() || line.starts_with("#[") || line.starts_with("//") {
+            continue;
+        }
+        let line = line
+            .trim_start_matches("pub(crate) ")
+            .trim_start_matches("pub ");
+        let mut member = Member {
+            doc: std::mem::take(&mut doc),
+            serde: std::mem::take(&mut serde),
+            ..Member::default()
+        };
+        if let Some((name, ty)) = line.split_once(": ") {
+            member.name = name.to_string();
+            member.ty = Some(ty.trim_end_matches(',').to_string());
+        } else if let Some(name) = line.strip_suffix(" {") {
+            member.name = name.to_string();
+            let (fields, read) = parse_members(&lines[i..]);
+            member.fields = fields;
+            i += read;
+        } else if let Some((name, ty)) = line.split_once('(') {
+            member.name = name.to_string();
+            member.ty = Some(ty.trim_end_matches(',').trim_end_matches(')').to_string());
+        } else {
+            member.name = line.trim_end_matches(',').to_string();
+        }
+        members.push(member);
+    }
+    (members, i)
+}
+
+/// The deserializable structs and enums declared in `source`.
+fn parse(source: &str, items: &mut BTreeMap<String, Item>) {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut doc = Vec::new();
+    let mut attrs: Vec<&str> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        if let Some(text) = line.strip_prefix("///") {
+            doc.push(text.trim().to_string());
+            continue;
+        }
+        if line.starts_with("#[") {
+            attrs.push(line);
+            continue;
+        }
+        let declaration = line
+            .strip_prefix("pub struct ")
+            .map(|rest| (false, rest))
+            .or_else(|| line.strip_prefix("pub enum ").map(|rest| (true, rest)));
+        let doc = std::mem::take(&mut doc);
+        let attrs = std::mem::take(&mut attrs);
+        let Some((is_enum, rest)) = declaration else {
+            continue;
+        };
+        let deserialize = attrs
+            .iter()
+            .any(|attr| attr.starts_with("#[derive(") && attr.contains("Deserialize"));
+        let name: String = rest
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .collect();
+        if !deserialize || items.contains_key(&name) {
+            continue;
+        }
+        let members = if rest.ends_with('{') {
+            let (members, read) = parse_members(&lines[i..]);
+            i += read;
+            members
+        } else {
+            Vec::new()
+        };
+        let serde = attrs.iter().filter_map(|attr| serde_args(attr)).flatten();
+        items.insert(
+            name,
+            Item {
+                is_enum,
+                doc,
+                serde: serde.collect(),
+                members,
+            },
+        );
+    }
+}
+
+fn describe(mut schema: Value, doc: &[String]) -> Value {
+    let description = doc.join(" ");
+    if !description.is_empty() {
+        if let Some(object) = schema.as_object_mut() {
+            object.insert(String::from("description"), Value::String(description));
+        }
+    }
+    schema
+}
+
+/// The schema of the Rust type `ty`, noting the types it refers to in `used`.
+fn type_schema(ty: &str, items: &BTreeMap<String, Item>, used: &mut Vec<String>) -> Value {
+    let ty = ty.trim();
+    let generic = |outer: &str| {
+        ty.strip_prefix(outer)
+            .and_then(|rest| rest.strip_prefix('<'))
+            .and_then(|rest| rest.strip_suffix('>'))
+    };
+    if let Some(inner) = generic("Option") {
+        return json!({"anyOf": [type_schema(inner, items, used), {"type": "null"}]});
+    }
+    if let Some(inner) = generic("Vec").or_else(|| generic("VecDeque")) {
+        return json!({"type": "array", "items": type_schema(inner, items, used)});
+    }
+    if let Some((_, value)) = generic("BTreeMap")
+        .or_else(|| generic("HashMap"))
+        .and_then(|inner| inner.split_once(", "))
+    {
+        return json!({"type": "object", "additionalProperties": type_schema(value, items, used)});
+    }
+    if let Some((inner, length)) = ty
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .and_then(|inner| inner.split_once("; "))
+    {
+        let length: u64 = length.parse().unwrap_or_default();
+        return json!({
+            "type": "array",
+            "items": type_schema(inner, items, used),
+            "minItems": length,
+            "maxItems": length,
+        });
+    }
+    match ty {
+        // Deserialized by hand, to fill in the meter before the settings.
+        "SinkTemplate" => type_schema("SinkConfig", items, used),
+        "String" | "SocketAddr" | "IpAddr" => json!({"type": "string"}),
+        "NaiveDate" => json!({"type": "string", "format": "date"}),
+        "bool" => json!({"type": "boolean"}),
+        "f32" | "f64" => json!({"type": "number"}),
+        "u8" | "u16" | "u32" | "u64" | "usize" => json!({"type": "integer", "minimum": 0}),
+        "i8" | "i16" | "i32" | "i64" | "isize" => json!({"type": "integer"}),
+        _ if items.contains_key(ty) => {
+            if !used.iter().any(|name| name == ty) {
+                used.push(ty.to_string());
+            }
+            json!({"$ref": format!("#/$defs/{}", ty)})
+        }
+        // Types with a deserializer of their own.
+        _ => json!({}),
+    }
+}
+
+/// The properties and required fields of an object with `fields`, and the schemas of
+/// flattened fields.
+fn object_schema(
+    fields: &[Member],
+    all_default: bool,
+    items: &BTreeMap<String, Item>,
+    used: &mut Vec<String>,
+) -> Map<String, Value> {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    let mut flattened = Vec::new();
+    for field in fields {
+        let ty = field.ty.as_deref().unwrap_or_default();
+        if field.serde.iter().any(|arg| arg == "flatten") {
+            flattened.push(type_schema(ty, items, used));
+            continue;
+        }
+        let name = serde_value(&field.serde, "rename").map_or(field.name.clone(), String::from);
+        let schema = if serde_value(&field.serde, "deserialize_with").is_some() {
+            json!({"type": "string"})
+        } else {
+            type_schema(ty, items, used)
+        };
+        let default = field.serde.iter().any(|arg| arg.starts_with("default"));
+        if !all_default && !default && !ty.starts_with("Option<") {
+            required.push(Value::String(name.clone()));
+        }
+        properties.insert(name, describe(schema, &field.doc));
+    }
+    let mut object = Map::new();
+    object.insert(String::from("type"), json!("object"));
+    object.insert(String::from("properties"), Value::Object(properties));
+    if !required.is_empty() {
+        object.insert(String::from("required"), Value::Array(required));
+    }
+    if !flattened.is_empty() {
+        object.insert(String::from("allOf"), Value::Array(flattened));
+    }
+    object
+}
+
+fn item_schema(item: &Item, items: &BTreeMap<String, Item>, used: &mut Vec<String>) -> Value {
+    if serde_value(&item.serde, "try_from") == Some("String") {
+        return describe(json!({"type": "string"}), &item.doc);
+    }
+    let all_default = item.serde.iter().any(|arg| arg == "default");
+    if !item.is_enum {
+        return describe(
+            Value::Object(object_schema(&item.members, all_default, items, used)),
+            &item.doc,
+        );
+    }
+
+    let rule = serde_value(&item.serde, "rename_all");
+    let tag = serde_value(&item.serde, "tag");
+    let variants: Vec<Value> = item
+        .members
+        .iter()
+        .map(|variant| {
+            let name = serde_value(&variant.serde, "rename")
+                .map_or_else(|| rename(&variant.name, rule), String::from);
+            let content = match &variant.ty {
+                Some(ty) => Some(type_schema(ty, items, used)),
+                None if !variant.fields.is_empty() => Some(Value::Object(object_schema(
+                    &variant.fields,
+                    false,
+                    items,
+                    used,
+                ))),
+                None => None,
+            };
+            let schema = match (tag, content) {
+                (Some(tag), content) => {
+                    let mut object = json!({
+                        "type": "object",
+                        "properties": {tag: {"const": name}},
+                        "required": [tag],
+                    });
+                    if let Some(content) = content {
+                        object["allOf"] = json!([content]);
+                    }
+                    object
+                }
+                (None, Some(content)) => json!({
+                    "type": "object",
+                    "properties": {name.clone(): content},
+                    "required": [name],
+                }),
+                (None, None) => json!({"const": name}),
+            };
+            describe(schema, &variant.doc)
+        })
+        .collect();
+    describe(json!({"oneOf": variants}), &item.doc)
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    let mut items = BTreeMap::new();
+    // The configuration types come first, in case other modules use the same names.
+    let mut paths = vec![Path::new("src/config.rs").to_path_buf()];
+    let mut directories = vec![Path::new("src").to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let Ok(entries) = fs::read_dir(&directory) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                directories.push(path);
+            } else if path.extension().is_some_and(|extension| extension == "rs") {
+                paths.push(path);
+            }
+        }
+    }
+    for path in paths {
+        if let Ok(source) = fs::read_to_string(&path) {
+            parse(&source, &mut items);
+        }
+    }
+
+    let mut used = vec![ROOT.to_string()];
+    let mut definitions = Map::new();
+    let mut next = 0;
+    while next < used.len() {
+        let name = used[next].clone();
+        next += 1;
+        if let Some(item) = items.get(&name) {
+            let schema = item_schema(item, &items, &mut used);
+            definitions.insert(name, schema);
+        }
+    }
+    let schema = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "dsmrd configuration",
+        "$ref": format!("#/$defs/{}", ROOT),
+        "$defs": definitions,
+    });
+
+    let out = env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    let schema = serde_json::to_string_pretty(&schema).expect("the schema serializes");
+    fs::write(Path::new(&out).join("config_schema.json"), schema)
+        .expect("the schema can be written");
+}
@@ -0,0 +1,100 @@
+//! Local actions on alerts, for simple load shedding such as switching off the boiler
+//! while the main fuse is about to trip. Every action in `actions` names an alert: it is
+//! taken when the alert fires and undone when it resolves. A GPIO pin is set active while
+//! the alert fires, a command is run with `fire` or `resolve`.
+//!
+//! Actions are taken whether alerts are silenced or not, silences only hold back
+//! notifications. When the daemon starts, the pins are set to match the alerts firing.
+
+use std::{process::Command, thread};
+
+use log::{error, info};
+
+#[cfg(feature = "gpio")]
+use crate::lock::RecoverLock;
+use crate::{
+    appdata::AppData,
+    config::{Action, ActionConfig},
+};
+
+/// Take the actions of the alert `name` as it fires, or undo them as it resolves.
+pub fn run(appdata: &AppData, name: &str, firing: bool, summary: &str, description: &str) {
+    for action in actions(appdata, name) {
+        match &action.action {
+            #[cfg(feature = "gpio")]
+            Action::Gpio { pin, active_low } => set_pin(*pin, firing != *active_low),
+            Action::Command { command } => run_command(command, name, firing, summary, description),
+        }
+    }
+}
+
+/// Set the pins of every action to match whether its alert fires.
+pub fn restore(appdata: &AppData) {
+    for action in &appdata.config().actions {
+        match action.action {
+            #[cfg(feature = "gpio")]
+            Action::Gpio { pin, active_low } => {
+                let firing = appdata.alerts.read_recover().is_firing(&action.alert);
+                set_pin(pin, firing != active_low);
+            }
+            // Commands ran as the alert fired or resolved.
+            Action::Command { .. } => {}
+        }
+    }
+}
+
+fn actions<'a>(appdata: &'a AppData, name: &'a str) -> impl Iterator<Item = &'a ActionConfig> {
+    appdata
+        .config()
+        .actions
+        .iter()
+        .filter(move |action| action.alert == name)
+}
+
+/// Run the command of an action in a thread of its own, so a slow script doesn't hold up
+/// the alert.
+fn run_command(command: &[String], name: &str, firing: bool, summary: &str, description: &str) {
+    let Some((program, args)) = command.split_first() else {
+        return;
+    };
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .arg(if firing { "fire" } else { "resolve" })
+        .env("DSMRD_ALERT", name)
+        .env("DSMRD_SUMMARY", summary)
+        .env("DSMRD_DESCRIPTION", description);
+    let (program, name) = (program.clone(), name.to_string());
+    let result = thread::Builder::new().name(String::from("action")).spawn({
+        let (program, name) = (program.clone(), name.clone());
+        move || match command.status() {
+            Ok(status) if status.success() => info!("Ran {} for alert {}.", program, name),
+            Ok(status) => error!("{} for alert {} failed: {}", program, name, status),
+            Err(e) => error!("Unable to run {} for alert {}: {}", program, name, e),
+        }
+    });
+    if let Err(e) = result {
+        error!("Unable to run {} for alert {}: {}", program, name, e);
+    }
+}
+
+/// Export the pin through sysfs if needed, make it an output and set it.
+#[cfg(feature = "gpio")]
+fn set_pin(pin: u32, high: bool) {
+    let result = (|| {
+        let path = format!("/sys/class/gpio/gpio{}", pin);
+        if !std::path::Path::new(&path).exists() {
+            std::fs::write("/sys/class/gpio/export", pin.to_string())?;
+        }
+        std::fs::write(format!("{}/direction", path), "out")?;
+        std::fs::write(format!("{}/value", path), if high { "1" } else { "0" })
+    })();
+    match result {
+        Ok(_) => info!(
+            "Set GPIO pin {} {}.",
+            pin,
+            if high { "high" } else { "low" }
+        ),
+        Err(e) => error!("Unable to set GPIO pin {}: {}", pin, e),
+    }
+}
@@ -0,0 +1,220 @@
+//! Aggregator mode, for a hub collecting the meters of several buildings. Instead of
+//! reading a serial port, the daemon accepts the state pushed by other dsmrd instances,
+//! the collectors, and serves them together.
+//!
+//! A collector pushes with a collector sink, which posts to `/push/<meter id>` on the
+//! aggregator with the token of that meter in an `Authorization: Bearer` header. The
+//! aggregator serves the states of all meters at `/meters` and of a single one at
+//! `/meters/<id>`. API tokens scoped to a meter id give access to that meter only.
+//!
+//! Redundant collectors may push the same meter. States pushed before are dropped, see
+//! `dedup`.
+//!
+//! With a shared token configured, collectors of any meter may push, to `/push/<meter id>`
+//! or to `/push`, which takes the meter id from the equipment id in the state. Every meter
+//! gets a pipeline of the configured sinks when its first state comes in, see
+//! `sink::spawn_pipeline`.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Method, Request, Response, StatusCode,
+};
+use log::{debug, error, info};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    appdata::AppData,
+    auth,
+    dedup::Recent,
+    history::now_millis,
+    lock::RecoverLock,
+    sink::{self, Pipeline},
+};
+
+/// Largest state accepted from a collector.
+const MAX_BODY: usize = 64 * 1024;
+
+/// The last state pushed by a collector.
+#[derive(Clone, Debug, Serialize)]
+pub struct RemoteMeter {
+    /// Time the state was received in milliseconds since the unix epoch.
+    pub received_at: u64,
+    /// Number of states taken since the aggregator started, duplicates aside.
+    pub pushes: u64,
+    /// Number of states dropped as duplicates.
+    pub duplicates: u64,
+    pub state: Value,
+    /// The sinks fed the states of the meter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sinks: Option<Pipeline>,
+    #[serde(skip)]
+    recent: Recent<Value>,
+}
+
+/// States of the remote meters by id.
+pub type RemoteMeters = Arc<RwLock<BTreeMap<String, RemoteMeter>>>;
+
+/// The meter id in a `/meters/<id>` path.
+pub fn meter_in_path(path: &str) -> Option<&str> {
+    path.strip_prefix("/meters/").filter(|id| !id.is_empty())
+}
+
+/// Whether `req` is a collector pushing, which is checked against the token of the meter
+/// rather than the API tokens.
+pub fn is_push(req: &Request<Body>) -> bool {
+    let path = req.uri().path();
+    path == "/push" || path.starts_with("/push/")
+}
+
+fn respond(status: StatusCode, message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+}
+
+/// Handler for `POST /push` and `POST /push/<id>`.
+pub async fn push_handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let Some(config) = &appdata.config().aggregator else {
+        return respond(StatusCode::NOT_FOUND, "Error: not an aggregator.");
+    };
+    if req.method() != Method::POST {
+        return respond(StatusCode::METHOD_NOT_ALLOWED, "Error: method not allowed.");
+    }
+    let id = req
+        .uri()
+        .path()
+        .strip_prefix("/push/")
+        .filter(|id| !id.is_empty())
+        .map(str::to_string);
+    let configured = id
+        .as_ref()
+        .and_then(|id| config.meters.iter().find(|meter| &meter.id == id));
+    if id.is_some() && configured.is_none() && config.token.is_none() {
+        return respond(StatusCode::NOT_FOUND, "Error: unknown meter.");
+    }
+    let presented = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let authorized = presented.is_some_and(|presented| {
+        configured.is_some_and(|meter| auth::same(&meter.token, presented))
+            || config
+                .token
+                .as_ref()
+                .is_some_and(|token| auth::same(token, presented))
+    });
+    if !authorized {
+        return respond(
+            StatusCode::UNAUTHORIZED,
+            "Error: a valid token for the meter is required.",
+        );
+    }
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) if body.len() > MAX_BODY => {
+            return respond(StatusCode::PAYLOAD_TOO_LARGE, "Error: state is too large.")
+        }
+        Ok(body) => body,
+        Err(e) => {
+            return respond(
+                StatusCode::BAD_REQUEST,
+                &format!("Error: unable to read request body: {}", e),
+            )
+        }
+    };
+    let state = match serde_json::from_slice::<Value>(&body) {
+        Ok(state @ Value::Object(_)) => state,
+        Ok(_) => return respond(StatusCode::BAD_REQUEST, "Error: expected a JSON object."),
+        Err(e) => return respond(StatusCode::BAD_REQUEST, &format!("Error: {}", e)),
+    };
+    let Some(id) = id.or_else(|| state["equipment_id"].as_str().map(str::to_string)) else {
+        return respond(
+            StatusCode::BAD_REQUEST,
+            "Error: the state has no equipment id to route it by.",
+        );
+    };
+
+    debug!("Received the state of meter {}", id);
+    let mut meters = appdata.remote_meters.write_recover();
+    if !meters.contains_key(&id) {
+        let unconfigured = meters
+            .keys()
+            .filter(|id| !config.meters.iter().any(|meter| &meter.id == *id))
+            .count();
+        if unconfigured >= config.max_meters && !config.meters.iter().any(|meter| meter.id == id) {
+            return respond(
+                StatusCode::TOO_MANY_REQUESTS,
+                &format!("Error: at most {} meters can be pushed.", config.max_meters),
+            );
+        }
+        info!("First state of meter {} received.", id);
+    }
+    let meter = meters.entry(id.clone()).or_insert_with(|| RemoteMeter {
+        received_at: 0,
+        pushes: 0,
+        duplicates: 0,
+        state: Value::Null,
+        sinks: None,
+        recent: Recent::default(),
+    });
+    if let Some(datetime) = state["datetime"].as_str() {
+        if meter.recent.is_duplicate(state.clone()) {
+            debug!("Dropped a duplicate state of {}", datetime);
+            meter.duplicates += 1;
+            return Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty());
+        }
+    }
+    meter.received_at = now_millis();
+    meter.pushes += 1;
+    if meter.sinks.is_none() && !config.sinks.is_empty() {
+        meter.sinks = sink::spawn_pipeline(&appdata, &id)
+            .map_err(|e| error!("Unable to set up the sinks of meter {}: {}", id, e))
+            .ok();
+    }
+    if let Some(sinks) = &meter.sinks {
+        sinks.feed(state.clone());
+    }
+    meter.state = state;
+    drop(meters);
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+}
+
+/// Handler for `/meters` and `/meters/<id>`.
+pub async fn meters_handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    if appdata.config().aggregator.is_none() {
+        return respond(StatusCode::NOT_FOUND, "Error: not an aggregator.");
+    }
+    let meters = appdata.remote_meters.read_recover();
+    let json = match meter_in_path(req.uri().path()) {
+        Some(id) => match meters.get(id) {
+            Some(meter) => serde_json::to_string(meter),
+            None => return respond(StatusCode::NOT_FOUND, "Error: no state of this meter."),
+        },
+        None => serde_json::to_string(&*meters),
+    };
+    match json {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, &format!("Error: {}", e)),
+    }
+}
@@ -0,0 +1,286 @@
+//! Alerts that stay on until the condition behind them clears, such as a jump of the
+//! baseload. An alert firing for the first time is recorded as an `alert` event, a
+//! `reminder` event follows every reminder interval while it keeps firing, and a
+//! `recovery` event once it clears. Notification channels set up for reminders only serve
+//! as escalation. The firing alerts are kept in the configured state file, so a restart
+//! neither repeats nor forgets them. Besides the alerts of their own of some modules,
+//! `alerts.rules` fires alerts on thresholds, see `rules`, and alerts can switch relays or
+//! run scripts, see `actions`.
+//!
+//! `POST /alerts/<name>/test` sends a made up alert through the notification channels and
+//! webhook of an alert and reports how each delivery went, to check their configuration.
+
+use std::{collections::BTreeMap, fs, sync::Arc, thread, thread::JoinHandle, time::Duration};
+
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actions,
+    appdata::AppData,
+    baseload,
+    config::AlertConfig,
+    events::{Event, EventKind},
+    fuse,
+    history::now_millis,
+    lock::RecoverLock,
+    notify, supervisor,
+};
+
+/// Time between checks for reminders that are due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A firing alert.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Firing {
+    summary: String,
+    description: String,
+    /// Time the alert started firing in milliseconds since the unix epoch.
+    since: u64,
+    /// Time of the last notice, the first alert or a reminder.
+    notified_at: u64,
+    reminders: u32,
+}
+
+/// The firing alerts by name, kept in the configured state file.
+#[derive(Debug)]
+pub struct Alerts {
+    state_file: Option<String>,
+    firing: BTreeMap<String, Firing>,
+}
+
+impl Alerts {
+    pub fn new(config: &AlertConfig) -> Self {
+        let firing = config
+            .state_file
+            .as_ref()
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(state) => serde_json::from_str(&state)
+                    .map_err(|e| error!("Unable to parse alert state {}: {}", path, e))
+                    .ok(),
+                Err(e) => {
+                    info!("No alert state read from {}: {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            state_file: config.state_file.clone(),
+            firing,
+        }
+    }
+
+    pub fn is_firing(&self, name: &str) -> bool {
+        self.firing.contains_key(name)
+    }
+
+    pub fn save(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let result = serde_json::to_string(&self.firing)
+            .map_err(|e| e.to_string())
+            .and_then(|state| fs::write(path, state).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Unable to write alert state {}: {}", path, e);
+        }
+    }
+}
+
+/// Fire the alert `name`. Returns whether it wasn't firing yet, in which case the alert is
+/// recorded as an event.
+pub fn fire(appdata: &AppData, name: &str, summary: String, description: String) -> bool {
+    raise(appdata, name, EventKind::Alert, summary, description)
+}
+
+/// Fire the alert `name` like `fire`, recorded as an urgent alert.
+pub fn fire_urgent(appdata: &AppData, name: &str, summary: String, description: String) -> bool {
+    raise(appdata, name, EventKind::UrgentAlert, summary, description)
+}
+
+fn raise(
+    appdata: &AppData,
+    name: &str,
+    kind: EventKind,
+    summary: String,
+    description: String,
+) -> bool {
+    let mut alerts = appdata.alerts.write_recover();
+    if let Some(firing) = alerts.firing.get_mut(name) {
+        firing.description = description;
+        alerts.save();
+        return false;
+    }
+    warn!("Alert {}: {}", summary, description);
+    let now = now_millis();
+    alerts.firing.insert(
+        name.to_string(),
+        Firing {
+            summary: summary.clone(),
+            description: description.clone(),
+            since: now,
+            notified_at: now,
+            reminders: 0,
+        },
+    );
+    alerts.save();
+    drop(alerts);
+    actions::run(appdata, name, true, &summary, &description);
+    appdata
+        .events
+        .write_recover()
+        .record(kind, summary, description);
+    true
+}
+
+/// Clear the alert `name`, recording the recovery if it was firing.
+pub fn resolve(appdata: &AppData, name: &str, description: String) {
+    let mut alerts = appdata.alerts.write_recover();
+    let Some(firing) = alerts.firing.remove(name) else {
+        return;
+    };
+    alerts.save();
+    drop(alerts);
+    info!("Alert {} resolved: {}", firing.summary, description);
+    actions::run(appdata, name, false, &firing.summary, &description);
+    appdata.events.write_recover().record(
+        EventKind::Recovery,
+        format!("Resolved: {}", firing.summary),
+        description,
+    );
+}
+
+/// Spawn a thread that records a reminder for every alert still firing after the
+/// reminder interval.
+pub fn spawn_alert_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("alerts", appdata, |appdata| {
+        let interval = appdata
+            .config()
+            .alerts
+            .reminder_interval
+            .saturating_mul(1000);
+        if interval == 0 {
+            return;
+        }
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+            let now = now_millis();
+            let mut alerts = appdata.alerts.write_recover();
+            let mut due = Vec::new();
+            for firing in alerts.firing.values_mut() {
+                if now.saturating_sub(firing.notified_at) < interval {
+                    continue;
+                }
+                firing.notified_at = now;
+                firing.reminders += 1;
+                due.push(firing.clone());
+            }
+            if due.is_empty() {
+                continue;
+            }
+            alerts.save();
+            drop(alerts);
+
+            let mut events = appdata.events.write_recover();
+            for firing in due {
+                let hours = now.saturating_sub(firing.since) / 3_600_000;
+                events.record(
+                    EventKind::Reminder,
+                    format!("Still firing: {}", firing.summary),
+                    format!(
+                        "{} Firing for {} hours, reminder {}.",
+                        firing.description, hours, firing.reminders
+                    ),
+                );
+            }
+        }
+    })
+}
+
+/// How the delivery of a test alert went.
+#[derive(Serialize)]
+struct Delivery {
+    destination: &'static str,
+    error: Option<String>,
+}
+
+fn respond(status: StatusCode, message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+}
+
+/// Send a test of the alert `name` through its notification channels and webhook.
+fn test(appdata: &AppData, name: &str) -> Vec<Delivery> {
+    let event = Event {
+        timestamp: now_millis(),
+        kind: if name == fuse::ALERT {
+            EventKind::UrgentAlert
+        } else {
+            EventKind::Alert
+        },
+        summary: format!("Test: {}", name),
+        description: String::from("A test of the alert, no action needed."),
+    };
+    let mut results = notify::send_now(appdata, &event);
+    if name == baseload::ALERT {
+        results.extend(baseload::test_alert(appdata).map(|result| ("webhook", result)));
+    }
+    results
+        .into_iter()
+        .map(|(destination, result)| Delivery {
+            destination,
+            error: result.err(),
+        })
+        .collect()
+}
+
+/// Handler for `POST /alerts/<name>/test`.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let path = req.uri().path().trim_end_matches('/');
+    let Some(name) = path
+        .strip_prefix("/alerts/")
+        .and_then(|rest| rest.strip_suffix("/test"))
+        .filter(|name| {
+            [baseload::ALERT, fuse::ALERT].contains(name)
+                || appdata
+                    .config()
+                    .alerts
+                    .rules
+                    .iter()
+                    .any(|rule| rule.name == *name)
+        })
+        .map(str::to_string)
+    else {
+        return respond(StatusCode::NOT_FOUND, "Error: unknown alert.");
+    };
+    if req.method() != Method::POST {
+        return respond(StatusCode::METHOD_NOT_ALLOWED, "Error: method not allowed.");
+    }
+
+    let deliveries = tokio::task::spawn_blocking(move || test(&appdata, &name))
+        .await
+        .unwrap_or_default();
+    if deliveries.is_empty() {
+        return respond(
+            StatusCode::CONFLICT,
+            "Error: no notification channel or webhook takes this alert.",
+        );
+    }
+    let status = if deliveries.iter().all(|delivery| delivery.error.is_none()) {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_GATEWAY
+    };
+    match serde_json::to_string(&deliveries) {
+        Ok(json) => Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, &format!("Error: {}", e)),
+    }
+}
@@ -0,0 +1,118 @@
+//! Networks the daemon may send to on behalf of others. Registered UDP clients and webhooks
+//! outside these networks are refused, so the daemon can't be used to flood arbitrary hosts.
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+/// A network in CIDR notation, e.g. `192.168.0.0/16`. A bare address is a network of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid network {}", cidr);
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack socket show up as IPv4-mapped IPv6 addresses.
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask(u32::from(net).into(), u32::from(ip).into(), self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => mask(net.into(), ip.into(), self.prefix, 128),
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` of `bits` bits of both addresses are the same.
+fn mask(net: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    net >> shift == ip >> shift
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(cidr: String) -> Result<Self, Self::Error> {
+        Cidr::parse(&cidr)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{}/{}", self.addr, self.prefix))
+    }
+}
+
+/// Private (RFC 1918 and unique local) and loopback networks.
+pub fn default_networks() -> Vec<Cidr> {
+    [
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "127.0.0.0/8",
+        "fc00::/7",
+        "::1",
+    ]
+    .into_iter()
+    .filter_map(|cidr| Cidr::parse(cidr).ok())
+    .collect()
+}
+
+pub fn is_allowed(networks: &[Cidr], ip: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(ip))
+}
+
+/// Check that every address the host of `url` resolves to is allowed. The addresses may
+/// change by the time they're connected to, so connections have to be made by a dialer
+/// `allowing` the networks as well.
+pub fn check_url(networks: &[Cidr], url: &str) -> Result<(), String> {
+    let url = url::Url::parse(url).map_err(|e| format!("Invalid url {}: {}", url, e))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("No host in url {}", url))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Unable to resolve {}: {}", host, e))?
+        .collect();
+    check_addrs(networks, host, &addrs)
+}
+
+/// Check that every address `host` resolved to is allowed.
+pub fn check_addrs(networks: &[Cidr], host: &str, addrs: &[SocketAddr]) -> Result<(), String> {
+    match addrs.iter().find(|addr| !is_allowed(networks, addr.ip())) {
+        Some(addr) => Err(format!(
+            "{} resolves to {}, which is not in the outbound allowlist",
+            host,
+            addr.ip()
+        )),
+        None => Ok(()),
+    }
+}
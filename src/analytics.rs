@@ -0,0 +1,388 @@
+//! Usage analytics over whole days, computed from the history store.
+//!
+//! `/analytics/gas` normalizes gas usage for the weather using degree days: the number of
+//! degrees the mean outdoor temperature of a day is below the base temperature. Gas used
+//! per degree day shows how efficiently the house is heated, whatever the weather.
+//!
+//! `/analytics/compare?period=week` compares the usage of every day of this week with the
+//! same day of the week before, or of this month and the month before with `period=month`.
+//! Every figure is a list with a value per day, in the order of the labels, so it can be
+//! handed to a charting library as is.
+//!
+//! `/analytics/saldering` reprices every month for the end of net metering, see `netting`.
+//!
+//! `/analytics/baseload` serves the baseload of every night with the trend it is compared
+//! with, see `baseload`.
+
+use std::sync::Arc;
+
+use chrono::{Datelike, Days, Local, Months, NaiveDate};
+use hyper::{header::CONTENT_TYPE, Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    appdata::AppData,
+    baseload,
+    history::{day_range, History, Metric},
+    lock::RecoverLock,
+    netting, query,
+    weather::Temperatures,
+};
+
+/// Number of days reported unless the client asks for a range.
+const DEFAULT_DAYS: u64 = 7;
+/// Upper bound on the number of days in a single response.
+const MAX_DAYS: u64 = 366;
+
+#[derive(Serialize)]
+struct GasAnalytics {
+    base_temperature: f64,
+    days: Vec<GasDay>,
+    total: GasTotal,
+}
+
+#[derive(Serialize)]
+struct GasDay {
+    date: NaiveDate,
+    /// Gas used in m³.
+    gas: Option<f64>,
+    /// Mean outdoor temperature in °C.
+    temperature: Option<f64>,
+    degree_days: Option<f64>,
+    /// m³ per degree day. `null` on days without degree days, when the heating is off.
+    gas_per_degree_day: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct GasTotal {
+    gas: f64,
+    degree_days: f64,
+    gas_per_degree_day: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct BaseloadAnalytics {
+    night_start: u32,
+    night_end: u32,
+    percentile: f64,
+    days: Vec<BaseloadDay>,
+}
+
+#[derive(Serialize)]
+struct BaseloadDay {
+    /// The day the night ends on.
+    date: NaiveDate,
+    /// Baseload in kW.
+    baseload: Option<f64>,
+    /// Median baseload of the nights before in kW.
+    trend: Option<f64>,
+    /// Whether the baseload rose more than the configured jump above the trend.
+    jump: bool,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Period {
+    #[default]
+    Week,
+    Month,
+}
+
+#[derive(Deserialize)]
+struct CompareParams {
+    #[serde(default)]
+    period: Period,
+}
+
+#[derive(Serialize)]
+struct Comparison {
+    period: Period,
+    /// Day of the week or of the month of every value.
+    labels: Vec<String>,
+    current: Series,
+    previous: Series,
+}
+
+/// Usage per day of a period, `null` for days without data or still to come.
+#[derive(Serialize)]
+struct Series {
+    from: NaiveDate,
+    to: NaiveDate,
+    /// Electricity delivered to the client per tariff in kWh.
+    delivered_tariff1: Vec<Option<f64>>,
+    delivered_tariff2: Vec<Option<f64>>,
+    /// Electricity delivered by the client per tariff in kWh.
+    received_tariff1: Vec<Option<f64>>,
+    received_tariff2: Vec<Option<f64>>,
+    /// Gas in m³.
+    gas: Vec<Option<f64>>,
+}
+
+impl Series {
+    /// Usage on the `days` days from `from`, of which those up to `to` are in the period.
+    fn compute(history: &History, from: NaiveDate, to: NaiveDate, days: usize) -> Self {
+        let today = Local::now().date_naive();
+        let usage = |metric| -> Vec<Option<f64>> {
+            from.iter_days()
+                .take(days)
+                .map(|date| {
+                    let (start, end) = day_range(date);
+                    (date <= to && date <= today)
+                        .then(|| history.usage(metric, start, end))
+                        .flatten()
+                })
+                .collect()
+        };
+        Self {
+            from,
+            to,
+            delivered_tariff1: usage(Metric::EnergyDeliveredTariff1),
+            delivered_tariff2: usage(Metric::EnergyDeliveredTariff2),
+            received_tariff1: usage(Metric::EnergyReceivedTariff1),
+            received_tariff2: usage(Metric::EnergyReceivedTariff2),
+            gas: usage(Metric::GasDelivered),
+        }
+    }
+}
+
+/// Handler for `/analytics/...`.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    match req.uri().path().trim_end_matches('/') {
+        "/analytics/gas" => gas(req, appdata),
+        "/analytics/baseload" => baseload(req, appdata),
+        "/analytics/compare" => compare(req, appdata),
+        "/analytics/saldering" => netting::handler(appdata),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Error: unknown analytics endpoint.")),
+    }
+}
+
+/// Gas usage per day from `from` up to and including `to`, both dates in local time.
+/// Defaults to the last seven full days.
+fn gas(req: Request<Body>, appdata: Arc<AppData>) -> Result<Response<Body>, hyper::http::Error> {
+    let (from, to) = match parse_range(&req) {
+        Ok(range) => range,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Error: {}", e)))
+        }
+    };
+    let base_temperature = appdata
+        .config()
+        .weather
+        .as_ref()
+        .map(|weather| weather.base_temperature)
+        .unwrap_or(18.0);
+
+    let history = appdata.history.read_recover();
+    let temperatures = appdata.temperatures.read_recover();
+    let days: Vec<GasDay> = from
+        .iter_days()
+        .take_while(|date| *date <= to)
+        .map(|date| gas_day(date, &history, &temperatures, base_temperature))
+        .collect();
+    drop(history);
+    drop(temperatures);
+
+    // Only days with both figures count, or missing data would skew the ratio.
+    let (gas, degree_days) = days
+        .iter()
+        .filter_map(|day| Some((day.gas?, day.degree_days?)))
+        .fold((0.0, 0.0), |(gas, degree_days), day| {
+            (gas + day.0, degree_days + day.1)
+        });
+    let analytics = GasAnalytics {
+        base_temperature,
+        days,
+        total: GasTotal {
+            gas,
+            degree_days,
+            gas_per_degree_day: (degree_days > 0.0).then(|| gas / degree_days),
+        },
+    };
+    match serde_json::to_string(&analytics) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+/// Baseload of the nights ending from `from` up to and including `to`. Nights the job
+/// didn't estimate yet, like the last one, are estimated from the history store.
+fn baseload(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let (from, to) = match parse_range(&req) {
+        Ok(range) => range,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Error: {}", e)))
+        }
+    };
+    let config = &appdata.config().baseload;
+    let baseloads = appdata.baseloads.read_recover();
+    let history = appdata.history.read_recover();
+    let days = from
+        .iter_days()
+        .take_while(|date| *date <= to)
+        .map(|date| {
+            let baseload = baseloads
+                .get(date)
+                .or_else(|| baseload::estimate(&history, date, config));
+            let trend = baseloads.trend(date, config.trend_days);
+            BaseloadDay {
+                date,
+                baseload,
+                trend,
+                jump: baseload
+                    .zip(trend)
+                    .is_some_and(|(baseload, trend)| baseload - trend > config.jump),
+            }
+        })
+        .collect();
+    drop(history);
+    drop(baseloads);
+
+    let analytics = BaseloadAnalytics {
+        night_start: config.night_start,
+        night_end: config.night_end,
+        percentile: config.percentile,
+        days,
+    };
+    match serde_json::to_string(&analytics) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+/// Usage per day of the current week or month and of the one before.
+fn compare(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let params: CompareParams = match query::parse(&req) {
+        Ok(params) => params,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Error: {}", e)))
+        }
+    };
+    let today = Local::now().date_naive();
+    let day = |date: NaiveDate, days: u32| date.checked_sub_days(Days::new(u64::from(days)));
+    let periods = match params.period {
+        Period::Week => day(today, today.weekday().num_days_from_monday()).and_then(|start| {
+            let previous = day(start, 7)?;
+            Some((
+                (start, start + Days::new(6)),
+                (previous, start - Days::new(1)),
+            ))
+        }),
+        Period::Month => day(today, today.day0()).and_then(|start| {
+            let previous = start.checked_sub_months(Months::new(1))?;
+            let end = start.checked_add_months(Months::new(1))? - Days::new(1);
+            Some(((start, end), (previous, start - Days::new(1))))
+        }),
+    };
+    let Some(((from, to), (previous_from, previous_to))) = periods else {
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from("Error: unable to determine the periods."));
+    };
+
+    let days = (to - from)
+        .num_days()
+        .max((previous_to - previous_from).num_days()) as usize
+        + 1;
+    let labels = from
+        .iter_days()
+        .take(days)
+        .map(|date| match params.period {
+            Period::Week => date.format("%a").to_string(),
+            Period::Month => (date.signed_duration_since(from).num_days() + 1).to_string(),
+        })
+        .collect();
+    let history = appdata.history.read_recover();
+    let comparison = Comparison {
+        period: params.period,
+        labels,
+        current: Series::compute(&history, from, to, days),
+        previous: Series::compute(&history, previous_from, previous_to, days),
+    };
+    drop(history);
+    match serde_json::to_string(&comparison) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+fn gas_day(
+    date: NaiveDate,
+    history: &History,
+    temperatures: &Temperatures,
+    base_temperature: f64,
+) -> GasDay {
+    let (from, to) = day_range(date);
+    let gas = history.usage(Metric::GasDelivered, from, to);
+    let temperature = temperatures.mean(from, to);
+    let degree_days = temperature.map(|t| (base_temperature - t).max(0.0));
+    GasDay {
+        date,
+        gas,
+        temperature,
+        degree_days,
+        gas_per_degree_day: gas
+            .zip(degree_days)
+            .filter(|(_, degree_days)| *degree_days > 0.0)
+            .map(|(gas, degree_days)| gas / degree_days),
+    }
+}
+
+/// The `from` and `to` dates of the request.
+fn parse_range(req: &Request<Body>) -> Result<(NaiveDate, NaiveDate), String> {
+    let mut from = None;
+    let mut to = None;
+    for (key, value) in url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes()) {
+        let date = || {
+            NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+                .map_err(|_| format!("invalid date {}, expected YYYY-MM-DD.", value))
+        };
+        match key.as_ref() {
+            "from" => from = Some(date()?),
+            "to" => to = Some(date()?),
+            _ => {}
+        }
+    }
+
+    let yesterday = Local::now().date_naive() - Days::new(1);
+    let to = to.unwrap_or(yesterday);
+    let from = from.unwrap_or(to - Days::new(DEFAULT_DAYS - 1));
+    if from > to {
+        return Err(String::from("from is after to."));
+    }
+    if from + Days::new(MAX_DAYS) <= to {
+        return Err(format!("at most {} days can be requested.", MAX_DAYS));
+    }
+    Ok((from, to))
+}
@@ -0,0 +1,307 @@
+//! Annual figures as found on the yearly energy statement (jaaropgave): electricity
+//! delivered and fed back per tariff, and gas, over contract years starting on a
+//! configurable date.
+//!
+//! The history store doesn't go back a year, so the meter totals are recorded at the
+//! start of every contract year instead. These are kept in a state file if one is
+//! configured, so the figures survive restarts.
+
+use std::{fs, sync::Arc};
+
+use chrono::{Datelike, Local, NaiveDate, TimeZone};
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use log::{error, info};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    appdata::AppData,
+    config::AnnualConfig,
+    history::{now_millis, Metric, Sample},
+    lock::RecoverLock,
+    status::LastError,
+};
+
+/// Number of contract years kept.
+const MAX_PERIODS: usize = 10;
+
+/// A day of the year, e.g. `07-01` for the first of July.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ContractDate {
+    month: u32,
+    day: u32,
+}
+
+impl ContractDate {
+    pub const NEW_YEAR: ContractDate = ContractDate { month: 1, day: 1 };
+
+    /// The start of the contract year `date` falls in.
+    fn period_start(&self, date: NaiveDate) -> NaiveDate {
+        let start = |year| NaiveDate::from_ymd_opt(year, self.month, self.day);
+        match start(date.year()) {
+            Some(start) if start <= date => start,
+            _ => start(date.year() - 1).unwrap_or(date),
+        }
+    }
+
+    /// The start of the contract year after the one starting at `start`.
+    fn next_start(&self, start: NaiveDate) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(start.year() + 1, self.month, self.day)
+    }
+}
+
+impl TryFrom<String> for ContractDate {
+    type Error = String;
+
+    fn try_from(date: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid contract date {}, expected MM-DD", date);
+        let (month, day) = date.split_once('-').ok_or_else(invalid)?;
+        let (month, day) = (
+            month.parse().map_err(|_| invalid())?,
+            day.parse().map_err(|_| invalid())?,
+        );
+        // Every year has to have the date, so the 29th of February is out.
+        NaiveDate::from_ymd_opt(2001, month, day).ok_or_else(invalid)?;
+        Ok(Self { month, day })
+    }
+}
+
+impl Serialize for ContractDate {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{:02}-{:02}", self.month, self.day))
+    }
+}
+
+/// Meter totals at a point in time.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Readings {
+    timestamp: u64,
+    delivered: [Option<f64>; 2],
+    received: [Option<f64>; 2],
+    gas: Option<f64>,
+}
+
+impl Readings {
+    fn from_sample(sample: &Sample) -> Self {
+        Self {
+            timestamp: sample.timestamp,
+            delivered: [
+                sample.get(Metric::EnergyDeliveredTariff1),
+                sample.get(Metric::EnergyDeliveredTariff2),
+            ],
+            received: [
+                sample.get(Metric::EnergyReceivedTariff1),
+                sample.get(Metric::EnergyReceivedTariff2),
+            ],
+            gas: sample.get(Metric::GasDelivered),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Period {
+    start: NaiveDate,
+    /// Whether the first readings were taken on the start date. If not, usage between the
+    /// start date and the first readings is counted in the year before.
+    exact_start: bool,
+    first: Readings,
+    last: Readings,
+}
+
+/// Figures of a contract year as served by `/annual`.
+#[derive(Serialize)]
+struct Figures {
+    start: NaiveDate,
+    /// Last day of the contract year.
+    end: NaiveDate,
+    exact_start: bool,
+    /// Time of the first and last readings used, in milliseconds since the unix epoch.
+    from: u64,
+    to: u64,
+    /// Electricity delivered to the client in kWh, per tariff and in total.
+    delivered: [Option<f64>; 2],
+    delivered_total: Option<f64>,
+    /// Electricity fed back by the client in kWh.
+    received: [Option<f64>; 2],
+    received_total: Option<f64>,
+    /// Gas delivered in m³.
+    gas: Option<f64>,
+}
+
+/// The meter totals at the start of every contract year.
+#[derive(Debug)]
+pub struct Annual {
+    contract_date: ContractDate,
+    state_file: Option<String>,
+    periods: Vec<Period>,
+    /// Time the state file was last written in milliseconds since the unix epoch.
+    last_saved: Option<u64>,
+    last_error: Option<LastError>,
+}
+
+impl Annual {
+    pub fn new(config: &AnnualConfig) -> Self {
+        let periods = config
+            .state_file
+            .as_ref()
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(state) => serde_json::from_str(&state)
+                    .map_err(|e| error!("Unable to parse annual state {}: {}", path, e))
+                    .ok(),
+                Err(e) => {
+                    info!("No annual state read from {}: {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            contract_date: config.contract_date,
+            state_file: config.state_file.clone(),
+            periods,
+            last_saved: None,
+            last_error: None,
+        }
+    }
+
+    pub fn observe(&mut self, sample: &Sample) {
+        let readings = Readings::from_sample(sample);
+        if readings.delivered.iter().all(Option::is_none) && readings.gas.is_none() {
+            return;
+        }
+        let Some(date) = Local
+            .timestamp_millis_opt(sample.timestamp as i64)
+            .single()
+            .map(|time| time.date_naive())
+        else {
+            return;
+        };
+        let start = self.contract_date.period_start(date);
+
+        match self.periods.last_mut() {
+            Some(period) if period.start == start => period.last = readings,
+            _ => {
+                // The year before ends where this one starts.
+                if let Some(period) = self.periods.last_mut() {
+                    period.last = readings;
+                }
+                info!("Starting contract year {}", start);
+                self.periods.push(Period {
+                    start,
+                    exact_start: date == start,
+                    first: readings,
+                    last: readings,
+                });
+                if self.periods.len() > MAX_PERIODS {
+                    self.periods.remove(0);
+                }
+                self.save();
+            }
+        }
+    }
+
+    /// Record the contract years of `samples`, oldest first, that aren't recorded yet.
+    /// Returns the number of years added.
+    pub fn import(&mut self, samples: &[Sample]) -> usize {
+        let mut imported = Annual {
+            contract_date: self.contract_date,
+            state_file: None,
+            periods: Vec::new(),
+            last_saved: None,
+            last_error: None,
+        };
+        for sample in samples {
+            imported.observe(sample);
+        }
+        let count = self.periods.len();
+        for period in imported.periods {
+            if !self.periods.iter().any(|known| known.start == period.start) {
+                self.periods.push(period);
+            }
+        }
+        let added = self.periods.len() - count;
+        self.periods.sort_by_key(|period| period.start);
+        while self.periods.len() > MAX_PERIODS {
+            self.periods.remove(0);
+        }
+        self.save();
+        added
+    }
+
+    pub fn save(&mut self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let result = serde_json::to_string(&self.periods)
+            .map_err(|e| e.to_string())
+            .and_then(|state| fs::write(path, state).map_err(|e| e.to_string()));
+        match result {
+            Ok(_) => self.last_saved = Some(now_millis()),
+            Err(e) => {
+                error!("Unable to write annual state {}: {}", path, e);
+                self.last_error = Some(LastError::now(e));
+            }
+        }
+    }
+
+    pub fn state_file(&self) -> Option<&str> {
+        self.state_file.as_deref()
+    }
+
+    pub fn last_saved(&self) -> Option<u64> {
+        self.last_saved
+    }
+
+    pub fn last_error(&self) -> Option<&LastError> {
+        self.last_error.as_ref()
+    }
+
+    fn figures(&self) -> Vec<Figures> {
+        let usage = |first: Option<f64>, last: Option<f64>| Some(last? - first?);
+        let total = |values: [Option<f64>; 2]| Some(values[0]? + values[1]?);
+        self.periods
+            .iter()
+            .map(|period| {
+                let (first, last) = (&period.first, &period.last);
+                let delivered = [
+                    usage(first.delivered[0], last.delivered[0]),
+                    usage(first.delivered[1], last.delivered[1]),
+                ];
+                let received = [
+                    usage(first.received[0], last.received[0]),
+                    usage(first.received[1], last.received[1]),
+                ];
+                let end = self
+                    .contract_date
+                    .next_start(period.start)
+                    .and_then(|next| next.pred_opt())
+                    .unwrap_or(period.start);
+                Figures {
+                    start: period.start,
+                    end,
+                    exact_start: period.exact_start,
+                    from: first.timestamp,
+                    to: last.timestamp,
+                    delivered,
+                    delivered_total: total(delivered),
+                    received,
+                    received_total: total(received),
+                    gas: usage(first.gas, last.gas),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Handler for `/annual`, listing the figures of every contract year, most recent last.
+pub async fn handler(appdata: Arc<AppData>) -> Result<Response<Body>, hyper::http::Error> {
+    let figures = appdata.annual.read_recover().figures();
+    match serde_json::to_string(&figures) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
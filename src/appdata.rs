@@ -1,31 +1,245 @@
 use std::{
-    net::SocketAddr,
-    sync::{Arc, RwLock},
-    usize,
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex, OnceLock, PoisonError, RwLock},
+    time::{Duration, Instant},
 };
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
 use event_listener::{Event, EventListener};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    aggregator::RemoteMeters,
+    alerts::Alerts,
+    allowlist,
+    annual::Annual,
+    baseload::Baseloads,
+    config::Config,
+    encryption::{self, Key},
+    events::Events,
+    ha::HaState,
+    history::{History, Sample},
+    lock::RecoverLock,
+    metrics::Counters,
+    netting::Months,
+    prices::PriceTable,
+    readings::Readings,
+    session::Sessions,
+    silence::Silences,
+    sink::SinkHandle,
+    snapshot::Snapshots,
+    swap::Swaps,
+    traffic::Traffic,
+    weather::Temperatures,
+};
+
+#[cfg(feature = "mqtt")]
+use crate::mqtt::Mqtt;
+
+/// Upper bound on the number of unused tokens, so requesting tokens can't eat up memory.
+const MAX_PENDING_TOKENS: usize = 64;
+
+/// A token that can be used once to register a UDP client.
+#[derive(Debug)]
+struct PendingToken {
+    /// Only the host that asked for the token may use it.
+    ip: IpAddr,
+    interval: Option<Duration>,
+    expires: Instant,
+}
+
+/// A client receiving the state over UDP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Client {
+    pub addr: SocketAddr,
+    /// Minimum time between two packets sent to the client.
+    pub interval: Duration,
+    /// Key to encrypt packets to the client with.
+    pub key: Option<Key>,
+}
+
+/// A client as kept in `udp.register_file`. Keys aren't stored, they're looked up again
+/// when the clients are read back.
+#[derive(Deserialize, Serialize)]
+struct StoredClient {
+    addr: SocketAddr,
+    /// Minimum time between two packets in milliseconds.
+    interval: u64,
+}
+
+/// Reasons a UDP client can't be registered.
+#[derive(Debug)]
+pub enum RegisterError {
+    AlreadyRegistered,
+    /// The register holds the maximum number of clients.
+    TooManyClients(usize),
+    /// The client asked for packets more often than allowed.
+    IntervalTooShort(Duration),
+    TooManyTokens,
+    InvalidToken(&'static str),
+    NotAllowed,
+    /// Encryption is required and there's no key for the client.
+    NoKey,
+    Internal(String),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::AlreadyRegistered => write!(f, "Client already registered!"),
+            RegisterError::TooManyClients(max) => {
+                write!(f, "Maximum of {} registered clients reached!", max)
+            }
+            RegisterError::IntervalTooShort(min) => write!(
+                f,
+                "Interval is too short, the minimum is {} ms!",
+                min.as_millis()
+            ),
+            RegisterError::TooManyTokens => {
+                write!(f, "Too many pending tokens, try again later.")
+            }
+            RegisterError::InvalidToken(reason) => write!(f, "{}", reason),
+            RegisterError::NotAllowed => write!(f, "Client is not in the outbound allowlist!"),
+            RegisterError::NoKey => write!(f, "No encryption key configured for this client!"),
+            RegisterError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct AppData {
     local_addr: SocketAddr,
-    pub client_register: Arc<RwLock<Vec<SocketAddr>>>,
+    config: Arc<Config>,
+    pub client_register: Arc<RwLock<Vec<Client>>>,
+    /// Bytes sent to UDP clients by address, kept after a client unregisters so
+    /// registering again doesn't reset its quota.
+    pub udp_traffic: Arc<Mutex<HashMap<SocketAddr, Traffic>>>,
+    /// Tokens handed out by `/subscribe` that have not been used yet.
+    tokens: Arc<Mutex<HashMap<String, PendingToken>>>,
+    /// Socket the UDP sender sends from and receives hello datagrams on.
+    udp_socket: Arc<OnceLock<UdpSocket>>,
     event_listener: Arc<Event>,
+    /// Notified on every change of the reader status.
+    status_event: Arc<Event>,
+    pub history: Arc<RwLock<History>>,
+    /// Resets of the meter totals, for `/metrics`.
+    pub counters: Arc<RwLock<Counters>>,
+    /// Sinks as set up at startup.
+    pub sinks: Arc<RwLock<Vec<Arc<SinkHandle>>>>,
+    /// Meter totals at the start of every contract year.
+    pub annual: Arc<RwLock<Annual>>,
+    /// Day-ahead electricity prices.
+    pub prices: Arc<RwLock<PriceTable>>,
+    /// Outdoor temperatures from the weather API or MQTT topic.
+    pub temperatures: Arc<RwLock<Temperatures>>,
+    /// States pushed by collectors, in aggregator mode.
+    pub remote_meters: RemoteMeters,
+    /// Snapshots taken through `/snapshot`.
+    pub snapshots: Arc<RwLock<Snapshots>>,
+    /// Meter readings taken for the energy supplier.
+    pub readings: Arc<RwLock<Readings>>,
+    /// Measurement sessions opened through `/sessions`.
+    pub sessions: Arc<RwLock<Sessions>>,
+    /// Baseload of every night estimated so far.
+    pub baseloads: Arc<RwLock<Baseloads>>,
+    /// Electricity meter totals at the start and end of every month.
+    pub months: Arc<RwLock<Months>>,
+    /// Events served at `/events.ics`.
+    pub events: Arc<RwLock<Events>>,
+    /// Maintenance windows and silences set through `/alerts/silence`.
+    pub silences: Arc<RwLock<Silences>>,
+    /// Alerts that are firing.
+    pub alerts: Arc<RwLock<Alerts>>,
+    /// Meter swaps and the offsets continuing the totals across them.
+    pub swaps: Arc<RwLock<Swaps>>,
+    /// Whether this daemon is the active one of a pair.
+    pub ha: Arc<RwLock<HaState>>,
+    /// Connection to the MQTT broker, once it's set up.
+    #[cfg(feature = "mqtt")]
+    mqtt: Arc<OnceLock<Mqtt>>,
 }
 
 impl AppData {
-    pub fn new(local_addr: SocketAddr) -> Self {
+    pub fn new(local_addr: SocketAddr, config: Config) -> Self {
+        let history = History::open(&config.history);
+        let annual = Annual::new(&config.annual);
+        let snapshots = Snapshots::new(&config.snapshots);
+        let readings = Readings::new(&config.readings);
+        let baseloads = Baseloads::new(&config.baseload);
+        let months = Months::new(&config.netting);
+        let events = Events::new(&config.events);
+        let silences = Silences::new(&config.alerts.maintenance);
+        let alerts = Alerts::new(&config.alerts);
+        let swaps = Swaps::new(&config.swap);
+        let ha = HaState::new(config.ha.is_some());
+        let clients = load_clients(&config);
         Self {
             local_addr,
-            client_register: Arc::new(RwLock::new(Vec::new())),
+            config: Arc::new(config),
+            client_register: Arc::new(RwLock::new(clients)),
+            udp_traffic: Arc::new(Mutex::new(HashMap::new())),
+            tokens: Arc::new(Mutex::new(HashMap::new())),
+            udp_socket: Arc::new(OnceLock::new()),
             event_listener: Arc::new(Event::new()),
+            status_event: Arc::new(Event::new()),
+            history: Arc::new(RwLock::new(history)),
+            counters: Arc::new(RwLock::new(Counters::default())),
+            sinks: Arc::new(RwLock::new(Vec::new())),
+            annual: Arc::new(RwLock::new(annual)),
+            prices: Arc::new(RwLock::new(PriceTable::default())),
+            temperatures: Arc::new(RwLock::new(Temperatures::default())),
+            remote_meters: Arc::new(RwLock::new(BTreeMap::new())),
+            snapshots: Arc::new(RwLock::new(snapshots)),
+            readings: Arc::new(RwLock::new(readings)),
+            sessions: Arc::new(RwLock::new(Sessions::default())),
+            baseloads: Arc::new(RwLock::new(baseloads)),
+            months: Arc::new(RwLock::new(months)),
+            events: Arc::new(RwLock::new(events)),
+            silences: Arc::new(RwLock::new(silences)),
+            alerts: Arc::new(RwLock::new(alerts)),
+            swaps: Arc::new(RwLock::new(swaps)),
+            ha: Arc::new(RwLock::new(ha)),
+            #[cfg(feature = "mqtt")]
+            mqtt: Arc::new(OnceLock::new()),
         }
     }
 
+    /// Clear the poison a panic left on the locks, so other threads can go on using them.
+    pub fn clear_poison(&self) {
+        self.client_register.clear_poison();
+        self.tokens.clear_poison();
+        self.history.clear_poison();
+        self.counters.clear_poison();
+        self.sinks.clear_poison();
+        self.annual.clear_poison();
+        self.prices.clear_poison();
+        self.temperatures.clear_poison();
+        self.remote_meters.clear_poison();
+        self.snapshots.clear_poison();
+        self.readings.clear_poison();
+        self.udp_traffic.clear_poison();
+        self.sessions.clear_poison();
+        self.baseloads.clear_poison();
+        self.months.clear_poison();
+        self.events.clear_poison();
+        self.silences.clear_poison();
+        self.alerts.clear_poison();
+        self.swaps.clear_poison();
+        self.ha.clear_poison();
+    }
+
     pub fn local_addr(&self) -> &SocketAddr {
         &self.local_addr
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
     pub fn emit_event(&self) {
         self.event_listener.notify(usize::MAX);
     }
@@ -34,34 +248,259 @@ impl AppData {
         self.event_listener.listen()
     }
 
-    pub fn register_client(&self, client_addr: SocketAddr) -> Result<(), String> {
-        if let Ok(mut register) = self.client_register.write() {
-            if register.contains(&client_addr) {
-                return Err(String::from("Client already registered!"));
-            };
-            register.push(client_addr);
-            Ok(())
-        } else {
-            Err(String::from("Unable to register client!"))
+    pub fn emit_status_event(&self) {
+        self.status_event.notify(usize::MAX);
+    }
+
+    pub fn status_listener(&self) -> EventListener {
+        self.status_event.listen()
+    }
+
+    pub fn record_sample(&self, sample: Sample) {
+        self.counters.write_recover().observe(&sample);
+        self.annual.write_recover().observe(&sample);
+        self.months.write_recover().observe(&sample);
+        self.history.write_recover().append(sample);
+    }
+
+    /// Register a client, to be sent packets at most once every `interval`. Without an
+    /// interval, the client gets every telegram the configuration allows.
+    pub fn register_client(
+        &self,
+        client_addr: SocketAddr,
+        interval: Option<Duration>,
+    ) -> Result<(), RegisterError> {
+        let interval = self.check_interval(interval)?;
+        let mut register = self.client_register.write_recover();
+        if register.iter().any(|client| client.addr == client_addr) {
+            return Err(RegisterError::AlreadyRegistered);
+        };
+        if !allowlist::is_allowed(&self.config.outbound.allow, client_addr.ip()) {
+            return Err(RegisterError::NotAllowed);
+        }
+        if register.len() >= self.config.udp.max_clients {
+            return Err(RegisterError::TooManyClients(self.config.udp.max_clients));
+        }
+        let key = encryption::key_for(&self.config.udp.keys, client_addr.ip());
+        if key.is_none() && self.config.udp.require_encryption {
+            return Err(RegisterError::NoKey);
+        }
+        if key.is_some() && !encryption::available() {
+            // Better no packets than plain ones to a client that expects them encrypted.
+            return Err(RegisterError::Internal(String::from(
+                "Unable to encrypt packets, built without the udp-encryption feature!",
+            )));
+        }
+        register.push(Client {
+            addr: client_addr,
+            interval,
+            key,
+        });
+        self.write_clients(&register);
+        Ok(())
+    }
+
+    /// Write the registered clients to `udp.register_file`, if configured.
+    pub fn save_clients(&self) {
+        self.write_clients(&self.client_register.read_recover());
+    }
+
+    fn write_clients(&self, register: &[Client]) {
+        let Some(path) = &self.config.udp.register_file else {
+            return;
+        };
+        let clients: Vec<StoredClient> = register
+            .iter()
+            .map(|client| StoredClient {
+                addr: client.addr,
+                interval: client.interval.as_millis() as u64,
+            })
+            .collect();
+        let result = serde_json::to_string(&clients)
+            .map_err(|e| e.to_string())
+            .and_then(|clients| fs::write(path, clients).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Unable to write client register {}: {}", path, e);
+        }
+    }
+
+    /// The interval to use for a client asking for `interval`.
+    fn check_interval(&self, interval: Option<Duration>) -> Result<Duration, RegisterError> {
+        let min = Duration::from_secs(self.config.udp.min_interval);
+        match interval {
+            Some(interval) if interval < min => Err(RegisterError::IntervalTooShort(min)),
+            Some(interval) => Ok(interval),
+            None => Ok(min),
         }
     }
 
     pub fn unregister_client(&self, client_addr: SocketAddr) -> Result<(), String> {
-        if let Ok(mut register) = self.client_register.write() {
-            register.retain(|&addr| addr != client_addr);
-            Ok(())
-        } else {
-            Err(String::from("Unable to unregister client!"))
+        let mut register = self.client_register.write_recover();
+        register.retain(|client| client.addr != client_addr);
+        self.write_clients(&register);
+        Ok(())
+    }
+
+    /// Hand out a token for the host at `ip` to register with.
+    pub fn issue_token(
+        &self,
+        ip: IpAddr,
+        interval: Option<Duration>,
+    ) -> Result<String, RegisterError> {
+        self.check_interval(interval)?;
+        let max_clients = self.config.udp.max_clients;
+        if self.client_register.read_recover().len() >= max_clients {
+            return Err(RegisterError::TooManyClients(max_clients));
         }
+
+        let mut bytes = [0; 16];
+        getrandom::getrandom(&mut bytes)
+            .map_err(|e| RegisterError::Internal(format!("Unable to create token: {}", e)))?;
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+
+        let mut tokens = self
+            .tokens
+            .lock()
+            .map_err(|_| RegisterError::Internal(String::from("Unable to store token!")))?;
+        let now = Instant::now();
+        tokens.retain(|_, pending| pending.expires > now);
+        if tokens.len() >= MAX_PENDING_TOKENS {
+            return Err(RegisterError::TooManyTokens);
+        }
+        let expires = now + Duration::from_secs(self.config.udp.token_ttl);
+        tokens.insert(
+            token.clone(),
+            PendingToken {
+                ip,
+                interval,
+                expires,
+            },
+        );
+        Ok(token)
+    }
+
+    /// Register the client at `client_addr` if it has a valid token. Tokens can only be
+    /// used once.
+    pub fn redeem_token(&self, token: &str, client_addr: SocketAddr) -> Result<(), RegisterError> {
+        let pending = self
+            .tokens
+            .lock()
+            .map_err(|_| RegisterError::Internal(String::from("Unable to read tokens!")))?
+            .remove(token)
+            .ok_or(RegisterError::InvalidToken("Unknown token"))?;
+        if pending.expires <= Instant::now() {
+            return Err(RegisterError::InvalidToken("Token expired"));
+        }
+        if pending.ip != client_addr.ip() {
+            return Err(RegisterError::InvalidToken(
+                "Token was issued to another host",
+            ));
+        }
+        self.register_client(client_addr, pending.interval)
+    }
+
+    pub fn udp_socket(&self) -> Option<&UdpSocket> {
+        self.udp_socket.get()
+    }
+
+    pub fn udp_port(&self) -> Option<u16> {
+        self.udp_socket()
+            .and_then(|sock| sock.local_addr().ok())
+            .map(|addr| addr.port())
+    }
+
+    pub fn set_udp_socket(&self, sock: UdpSocket) {
+        let _ = self.udp_socket.set(sock);
+    }
+
+    #[cfg(feature = "mqtt")]
+    pub fn mqtt(&self) -> Option<&Mqtt> {
+        self.mqtt.get()
+    }
+
+    #[cfg(feature = "mqtt")]
+    pub fn set_mqtt(&self, mqtt: Mqtt) {
+        let _ = self.mqtt.set(mqtt);
     }
 
     pub fn list_clients(&self) -> Result<Vec<String>, String> {
-        match self.client_register.read() {
-            Ok(register) => {
-                let result: Vec<String> = register.iter().map(|f| f.to_string()).collect();
-                Ok(result)
+        let traffic = self
+            .udp_traffic
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let register = self.client_register.read_recover();
+        let result: Vec<String> = register
+            .iter()
+            .map(|client| {
+                let sent = traffic.get(&client.addr).copied().unwrap_or_default();
+                format!(
+                    "{} {} bytes sent, {} today{}\n",
+                    client.addr,
+                    sent.total(),
+                    sent.today(),
+                    if client.key.is_some() {
+                        ", encrypted"
+                    } else {
+                        ""
+                    }
+                )
+            })
+            .collect();
+        Ok(result)
+    }
+
+    /// Count `bytes` sent to `client_addr`. Returns false without counting them if they
+    /// would take the client over its daily quota.
+    pub fn count_udp_traffic(&self, client_addr: SocketAddr, bytes: u64) -> bool {
+        let mut traffic = self
+            .udp_traffic
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let sent = traffic.entry(client_addr).or_default();
+        if let Some(quota) = self.config.udp.daily_quota {
+            if sent.today() + bytes > quota {
+                return false;
             }
-            Err(e) => Err(format!("Error reading register: {}", e)),
         }
+        sent.add(bytes);
+        true
     }
 }
+
+/// Read the clients back from `udp.register_file`. Clients the configuration no longer
+/// accepts are dropped.
+fn load_clients(config: &Config) -> Vec<Client> {
+    let Some(path) = &config.udp.register_file else {
+        return Vec::new();
+    };
+    let stored: Vec<StoredClient> = match fs::read_to_string(path) {
+        Ok(clients) => match serde_json::from_str(&clients) {
+            Ok(clients) => clients,
+            Err(e) => {
+                error!("Unable to parse client register {}: {}", path, e);
+                return Vec::new();
+            }
+        },
+        Err(e) => {
+            info!("No clients read from {}: {}", path, e);
+            return Vec::new();
+        }
+    };
+    stored
+        .into_iter()
+        .filter(|client| allowlist::is_allowed(&config.outbound.allow, client.addr.ip()))
+        .filter_map(|client| {
+            let key = encryption::key_for(&config.udp.keys, client.addr.ip());
+            if key.is_none() && config.udp.require_encryption {
+                return None;
+            }
+            Some(Client {
+                addr: client.addr,
+                interval: Duration::from_millis(client.interval),
+                key,
+            })
+        })
+        .take(config.udp.max_clients)
+        .collect()
+}
@@ -0,0 +1,109 @@
+//! API tokens. Once any are configured, every request has to carry one in an
+//! `Authorization: Bearer` header.
+//!
+//! A token can be scoped to meter ids, giving read access to those meters only. Every
+//! daemon reads a single meter, so in a deployment with a daemon per meter a landlord can
+//! hand each tenant a token that only works on the daemon of their own meter.
+
+use hyper::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    Body, Method, Request, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+/// Endpoints that control the daemon or its clients, off limits to scoped tokens.
+const ADMIN_PATHS: [&str; 15] = [
+    "/start",
+    "/stop",
+    "/reader",
+    "/logs",
+    "/register",
+    "/unregister",
+    "/subscribe",
+    "/list",
+    "/sinks",
+    "/rpc",
+    "/graphql",
+    "/admin",
+    "/config",
+    "/ha",
+    "/alerts",
+];
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiToken {
+    pub token: String,
+    /// Meter ids the token gives read access to. Without meters, the token gives full
+    /// access.
+    #[serde(default)]
+    pub meters: Vec<String>,
+}
+
+/// Check the token of the request against the configured tokens. `meter_id` is the id of
+/// the meter this daemon reads, if known. Returns the status and message to refuse the
+/// request with.
+pub fn authorize(
+    req: &Request<Body>,
+    tokens: &[ApiToken],
+    meter_id: Option<&str>,
+) -> Result<(), (StatusCode, &'static str)> {
+    if tokens.is_empty() {
+        return Ok(());
+    }
+    let presented = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let Some(token) =
+        presented.and_then(|presented| tokens.iter().find(|t| same(&t.token, presented)))
+    else {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Error: a valid API token is required.",
+        ));
+    };
+    if token.meters.is_empty() {
+        return Ok(());
+    }
+
+    let read = matches!(*req.method(), Method::GET | Method::HEAD)
+        && !ADMIN_PATHS
+            .iter()
+            .any(|path| req.uri().path().starts_with(path));
+    if !read {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Error: the API token only gives read access.",
+        ));
+    }
+    match meter_id {
+        Some(id) if token.meters.iter().any(|meter| meter == id) => Ok(()),
+        _ => Err((
+            StatusCode::FORBIDDEN,
+            "Error: the API token gives no access to this meter.",
+        )),
+    }
+}
+
+/// The response to a request refused by `authorize`.
+pub fn refuse(
+    status: StatusCode,
+    message: &'static str,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let mut response = Response::builder().status(status);
+    if status == StatusCode::UNAUTHORIZED {
+        response = response.header(WWW_AUTHENTICATE, "Bearer");
+    }
+    response.body(Body::from(message))
+}
+
+/// Compare tokens in time independent of where they differ.
+pub fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
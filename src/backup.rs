@@ -0,0 +1,265 @@
+//! Backups of everything the daemon keeps on disk, to move it to a new SD card or host:
+//! the configuration file and the state files it names, such as the monthly and contract
+//! year totals, the baseload estimates, events, snapshots, firing alerts and the client
+//! register.
+//!
+//! `dsmrd backup [FILE] [--history]` writes a gzipped JSON archive, to stdout without a
+//! file, and `GET /admin/backup` serves one from a running daemon. The history journal,
+//! `history.file`, can run into tens of megabytes, so it's only included with `--history`
+//! or `?history=true`. `dsmrd restore FILE` writes the configuration back to
+//! `DSMRD_CONFIG`, or to `--config PATH`, and the state files back to where the
+//! configuration has them. Files that exist are only overwritten with `--force`.
+//!
+//! The spools of sinks aren't part of a backup, they only hold what a sink hasn't
+//! delivered yet.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use chrono::Local;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use hyper::{
+    header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    Body, Request, Response, StatusCode,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    appdata::AppData,
+    config::{Config, CONFIG_ENV},
+    history::now_millis,
+    install,
+    lock::RecoverLock,
+    query,
+    storage::state_files,
+};
+
+/// Version of the archive layout, raised when it changes in a way older daemons can't
+/// restore.
+const VERSION: u32 = 1;
+
+const BACKUP_USAGE: &str = "Usage: dsmrd backup [FILE] [--history]";
+const RESTORE_USAGE: &str = "Usage: dsmrd restore FILE [--config PATH] [--force]";
+
+#[derive(Serialize, Deserialize)]
+struct Archive {
+    version: u32,
+    /// Time the backup was taken in milliseconds since the unix epoch.
+    created_at: u64,
+    /// The configuration file as is, secrets included. Without one the daemon runs on
+    /// the defaults.
+    config: Option<String>,
+    /// The state files by the setting naming them, e.g. `netting.file`.
+    files: BTreeMap<String, StateFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    path: String,
+    contents: String,
+}
+
+/// Query parameters of `/admin/backup`.
+#[derive(Deserialize)]
+struct BackupParams {
+    #[serde(default)]
+    history: bool,
+}
+
+/// A gzipped archive of the configuration file and the state files of `config`, which is
+/// the configuration in that file, along with the history journal if `history` is set.
+/// State files that don't exist yet are left out.
+fn take(config: &Config, history: bool) -> Result<Vec<u8>, String> {
+    let config_file = match env::var(CONFIG_ENV) {
+        Ok(path) => {
+            Some(fs::read_to_string(&path).map_err(|e| format!("Unable to read {}: {}", path, e))?)
+        }
+        Err(_) => None,
+    };
+    let mut files = BTreeMap::new();
+    let journal = config
+        .history
+        .file
+        .as_deref()
+        .filter(|_| history)
+        .map(|path| ("history.file", path));
+    for (setting, path) in state_files(config).into_iter().chain(journal) {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Unable to read {}: {}", path, e)),
+        };
+        let path = path.to_string();
+        files.insert(setting.to_string(), StateFile { path, contents });
+    }
+    let archive = Archive {
+        version: VERSION,
+        created_at: now_millis(),
+        config: config_file,
+        files,
+    };
+
+    let json = serde_json::to_vec(&archive).map_err(|e| e.to_string())?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&json).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+/// Run `dsmrd backup` with the arguments following `backup`.
+pub fn backup(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut file = None;
+    let mut history = false;
+    for arg in args {
+        match arg.as_str() {
+            "--history" => history = true,
+            _ if arg.starts_with("--") || file.is_some() => return Err(String::from(BACKUP_USAGE)),
+            _ => file = Some(arg),
+        }
+    }
+    let file = file.filter(|file| file != "-");
+    let config = Config::load()?;
+    let archive = take(&config, history)?;
+    match &file {
+        Some(file) => {
+            fs::write(file, archive).map_err(|e| format!("Unable to write {}: {}", file, e))?;
+            eprintln!("Backed up to {}", file);
+        }
+        None => io::stdout()
+            .write_all(&archive)
+            .map_err(|e| format!("Unable to write the backup: {}", e))?,
+    }
+    Ok(())
+}
+
+fn restore_file(path: &Path, contents: &str) -> Result<(), String> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Unable to create {}: {}", parent.display(), e))?;
+    }
+    fs::write(path, contents).map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+    println!("Restored {}", path.display());
+    Ok(())
+}
+
+/// Run `dsmrd restore` with the arguments following `restore`.
+pub fn restore(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut file = None;
+    let mut config_path = None;
+    let mut force = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = Some(args.next().ok_or(RESTORE_USAGE)?),
+            "--force" => force = true,
+            _ if arg.starts_with("--") || file.is_some() => {
+                return Err(String::from(RESTORE_USAGE))
+            }
+            _ => file = Some(arg),
+        }
+    }
+    let file = file.ok_or(RESTORE_USAGE)?;
+
+    let compressed = fs::read(&file).map_err(|e| format!("Unable to read {}: {}", file, e))?;
+    let mut json = String::new();
+    GzDecoder::new(compressed.as_slice())
+        .read_to_string(&mut json)
+        .map_err(|e| format!("{} is not a dsmrd backup: {}", file, e))?;
+    let archive: Archive = serde_json::from_str(&json)
+        .map_err(|e| format!("{} is not a dsmrd backup: {}", file, e))?;
+    if archive.version > VERSION {
+        return Err(format!(
+            "{} was made by a newer dsmrd, with archive version {}.",
+            file, archive.version
+        ));
+    }
+
+    // Check everything before writing anything, so a restore doesn't stop halfway.
+    if let Some(config) = &archive.config {
+        serde_json::from_str::<Config>(config)
+            .map_err(|e| format!("The configuration in {} doesn't parse: {}", file, e))?;
+    }
+    let config_path = config_path
+        .or_else(|| env::var(CONFIG_ENV).ok())
+        .unwrap_or_else(|| install::CONFIG_PATH.to_string());
+    let mut writes: Vec<(&Path, &str)> = Vec::new();
+    if let Some(config) = &archive.config {
+        writes.push((Path::new(&config_path), config));
+    }
+    for state in archive.files.values() {
+        writes.push((Path::new(&state.path), &state.contents));
+    }
+    if !force {
+        if let Some((path, _)) = writes.iter().find(|(path, _)| path.exists()) {
+            return Err(format!(
+                "{} exists, restore with --force to overwrite it.",
+                path.display()
+            ));
+        }
+    }
+    for (path, contents) in writes {
+        restore_file(path, contents)?;
+    }
+    if archive.config.is_some() && env::var(CONFIG_ENV).ok().as_ref() != Some(&config_path) {
+        println!("Start dsmrd with {}={} to use it.", CONFIG_ENV, config_path);
+    }
+    Ok(())
+}
+
+/// Handler for `GET /admin/backup`.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    // The archive holds the secrets of the configuration.
+    if appdata.config().http.tokens.is_empty() {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from(
+                "Error: backups are only served once API tokens are configured.",
+            ));
+    }
+    let history = match query::parse::<BackupParams>(&req) {
+        Ok(params) => params.history,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Error: {}", e)))
+        }
+    };
+    let archive = tokio::task::spawn_blocking(move || {
+        // Write the samples still waiting, so the journal is up to date.
+        if history {
+            let _ = appdata.history.write_recover().flush();
+        }
+        take(appdata.config(), history)
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    match archive {
+        Ok(archive) => {
+            let name = format!("dsmrd-backup-{}.json.gz", Local::now().format("%Y%m%d"));
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/gzip")
+                .header(
+                    CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}\"", name),
+                )
+                .body(Body::from(archive))
+        }
+        Err(e) => {
+            error!("Unable to take a backup: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(format!("Error: {}", e)))
+        }
+    }
+}
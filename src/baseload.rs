@@ -0,0 +1,268 @@
+//! Daily estimate of the baseload: the power the house draws when nobody uses anything,
+//! from the fridge to devices on standby. It is taken as a low percentile of the power
+//! delivered during the night, so the short runs of a fridge don't count but a device
+//! left on all night does.
+//!
+//! A job estimates the baseload of every night once it is over and compares it with the
+//! median of the nights before. A baseload rising more than the configured jump above
+//! that trend usually means a forgotten device, and fires an alert that is also posted to
+//! the alert webhook. The alert clears on the first night back under the jump. The
+//! estimates are served at `/analytics/baseload`.
+
+use std::{collections::BTreeMap, fs, sync::Arc, thread::JoinHandle};
+
+use chrono::{Days, Local, NaiveDate, TimeZone};
+use log::{debug, error, info};
+use serde_json::json;
+
+use crate::{
+    alerts, allowlist,
+    appdata::AppData,
+    config::BaseloadConfig,
+    dial::Dialer,
+    events::EventKind,
+    history::{now_millis, History, Metric},
+    http_client::HttpClient,
+    lock::RecoverLock,
+    schedule::Schedule,
+    supervisor,
+};
+
+/// Name of the alert for a jump of the baseload.
+pub const ALERT: &str = "baseload";
+/// Number of daily estimates kept.
+const MAX_DAYS: usize = 400;
+
+/// The night ending on `date`, in milliseconds since the unix epoch.
+pub fn night(date: NaiveDate, config: &BaseloadConfig) -> Option<(u64, u64)> {
+    let at = |date: NaiveDate, hour: u32| {
+        let time = date.and_hms_opt(hour.min(23), 0, 0)?;
+        let time = Local.from_local_datetime(&time).earliest()?;
+        u64::try_from(time.timestamp_millis()).ok()
+    };
+    // A night starting before midnight starts the day before.
+    let start_date = match config.night_start > config.night_end {
+        true => date.checked_sub_days(Days::new(1))?,
+        false => date,
+    };
+    Some((
+        at(start_date, config.night_start)?,
+        at(date, config.night_end)?,
+    ))
+}
+
+/// The baseload in kW during the night ending on `date`, from the samples in `history`.
+pub fn estimate(history: &History, date: NaiveDate, config: &BaseloadConfig) -> Option<f64> {
+    let (from, to) = night(date, config)?;
+    let mut powers: Vec<f64> = history
+        .query_range(from, to)
+        .filter_map(|sample| sample.get(Metric::PowerDelivered))
+        .collect();
+    if powers.is_empty() {
+        return None;
+    }
+    powers.sort_by(f64::total_cmp);
+    let rank = (config.percentile.clamp(0.0, 100.0) / 100.0 * powers.len() as f64).ceil();
+    Some(powers[(rank as usize).saturating_sub(1).min(powers.len() - 1)])
+}
+
+/// The baseload of every night estimated so far, kept in the configured file.
+#[derive(Debug)]
+pub struct Baseloads {
+    file: Option<String>,
+    days: BTreeMap<NaiveDate, f64>,
+}
+
+impl Baseloads {
+    pub fn new(config: &BaseloadConfig) -> Self {
+        let days = config
+            .file
+            .as_ref()
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(days) => serde_json::from_str(&days)
+                    .map_err(|e| error!("Unable to parse baseloads {}: {}", path, e))
+                    .ok(),
+                Err(e) => {
+                    info!("No baseloads read from {}: {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            file: config.file.clone(),
+            days,
+        }
+    }
+
+    pub fn get(&self, date: NaiveDate) -> Option<f64> {
+        self.days.get(&date).copied()
+    }
+
+    fn contains(&self, date: NaiveDate) -> bool {
+        self.days.contains_key(&date)
+    }
+
+    fn insert(&mut self, date: NaiveDate, baseload: f64) {
+        self.days.insert(date, baseload);
+        while self.days.len() > MAX_DAYS {
+            self.days.pop_first();
+        }
+        self.save();
+    }
+
+    /// Median baseload of the `days` nights before `date`, from at least half of them.
+    pub fn trend(&self, date: NaiveDate, days: usize) -> Option<f64> {
+        let first = date.checked_sub_days(Days::new(days as u64))?;
+        let mut baseloads: Vec<f64> = self.days.range(first..date).map(|(_, b)| *b).collect();
+        if baseloads.is_empty() || baseloads.len() * 2 < days {
+            return None;
+        }
+        baseloads.sort_by(f64::total_cmp);
+        let middle = baseloads.len() / 2;
+        Some(match baseloads.len() % 2 {
+            0 => (baseloads[middle - 1] + baseloads[middle]) / 2.0,
+            _ => baseloads[middle],
+        })
+    }
+
+    pub fn save(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let result = serde_json::to_string(&self.days)
+            .map_err(|e| e.to_string())
+            .and_then(|days| fs::write(path, days).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Unable to write baseloads {}: {}", path, e);
+        }
+    }
+}
+
+/// Spawn a thread that estimates the baseload of every night once it is over.
+pub fn spawn_baseload_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("baseload", appdata, |appdata| {
+        let config = &appdata.config().baseload;
+        let hourly = Schedule::parse("0 * * * *").expect("valid schedule");
+        loop {
+            estimate_nights(appdata, config);
+            hourly.wait();
+        }
+    })
+}
+
+/// Estimate the nights in the history store that are over and not estimated yet.
+fn estimate_nights(appdata: &AppData, config: &BaseloadConfig) {
+    let Some(first) = appdata
+        .history
+        .read_recover()
+        .oldest()
+        .map(|sample| sample.timestamp)
+    else {
+        return;
+    };
+    let Some(oldest) = Local.timestamp_millis_opt(first as i64).single() else {
+        return;
+    };
+    let today = Local::now().date_naive();
+    let now = now_millis();
+
+    for date in oldest
+        .date_naive()
+        .iter_days()
+        .take_while(|date| *date <= today)
+    {
+        if appdata.baseloads.read_recover().contains(date) {
+            continue;
+        }
+        // Only whole nights count.
+        match night(date, config) {
+            Some((from, to)) if from >= first && to <= now => {}
+            _ => continue,
+        }
+        let Some(baseload) = estimate(&appdata.history.read_recover(), date, config) else {
+            continue;
+        };
+        debug!("Baseload on {} was {:.3} kW", date, baseload);
+        let mut baseloads = appdata.baseloads.write_recover();
+        baseloads.insert(date, baseload);
+        let trend = baseloads.trend(date, config.trend_days);
+        drop(baseloads);
+
+        // Nights estimated late, after a restart, are old news.
+        let Some(trend) = trend.filter(|_| date == today) else {
+            continue;
+        };
+        if baseload - trend <= config.jump {
+            alerts::resolve(
+                appdata,
+                ALERT,
+                format!("The baseload is back at {:.0} W.", baseload * 1000.0),
+            );
+            continue;
+        }
+        let fired = alerts::fire(
+            appdata,
+            ALERT,
+            String::from("Baseload jump"),
+            format!(
+                "The baseload rose to {:.0} W, up from {:.0} W. Is a device left on?",
+                baseload * 1000.0,
+                trend * 1000.0
+            ),
+        );
+        if fired {
+            alert(appdata, config, date, baseload, trend);
+        }
+    }
+}
+
+/// Post a jump of the baseload to the alert webhook, unless alerts are silenced.
+fn alert(appdata: &AppData, config: &BaseloadConfig, date: NaiveDate, baseload: f64, trend: f64) {
+    let Some(webhook) = &config.webhook else {
+        return;
+    };
+    if appdata
+        .silences
+        .read_recover()
+        .is_silenced(EventKind::Alert, now_millis())
+    {
+        info!(
+            "Not posting baseload alert for {}, alerts are silenced.",
+            date
+        );
+        return;
+    }
+    let body = json!({
+        "alert": "baseload_jump",
+        "date": date,
+        "baseload": baseload,
+        "trend": trend,
+    });
+    match post(appdata, &webhook.url, body) {
+        Ok(_) => info!("Posted baseload alert for {} to webhook.", date),
+        Err(e) => error!("Failed to post baseload alert to webhook: {}", e),
+    }
+}
+
+/// Post a made up jump of the baseload to the alert webhook, silenced or not. `None`
+/// without a webhook.
+pub fn test_alert(appdata: &AppData) -> Option<Result<(), String>> {
+    let webhook = appdata.config().baseload.webhook.as_ref()?;
+    let body = json!({
+        "alert": "baseload_jump",
+        "date": Local::now().date_naive(),
+        "baseload": 0.3,
+        "trend": 0.1,
+        "test": true,
+    });
+    Some(post(appdata, &webhook.url, body))
+}
+
+fn post(appdata: &AppData, url: &str, body: serde_json::Value) -> Result<(), String> {
+    let outbound = &appdata.config().outbound;
+    allowlist::check_url(&outbound.allow, url)
+        .and_then(|_| {
+            HttpClient::new(Dialer::with_proxy(outbound.proxy.as_ref()).allowing(&outbound.allow))
+        })
+        .and_then(|client| client.post(url, "application/json", &[], body.to_string().into_bytes()))
+}
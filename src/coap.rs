@@ -0,0 +1,659 @@
+//! A small CoAP server (RFC 7252) serving the latest state, with Observe (RFC 7641) support
+//! so constrained devices get new values pushed without keeping a connection open.
+//! Only clients in the outbound allowlist can observe. Every `confirm_interval` seconds an
+//! observer gets a confirmable notification, and observers that don't acknowledge it are
+//! dropped (RFC 7641 section 4.5).
+//!
+//! Resources:
+//! - `/state`: the meter state as served on `/` over HTTP.
+//! - `/power`: just the current power, for devices that can't handle the full state.
+//! - `/.well-known/core`: resource discovery.
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU16, AtomicU32, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use event_listener::Listener;
+use log::{debug, error, info};
+use serde_json::json;
+
+use crate::{
+    allowlist, appdata::AppData, history::Metric, lock::RecoverLock, output, reader::ReaderData,
+    supervisor,
+};
+
+const VERSION: u8 = 1;
+
+const TYPE_CON: u8 = 0;
+const TYPE_NON: u8 = 1;
+const TYPE_ACK: u8 = 2;
+const TYPE_RST: u8 = 3;
+
+const CODE_EMPTY: u8 = 0x00;
+const CODE_GET: u8 = 0x01;
+const CODE_CONTENT: u8 = 0x45;
+const CODE_BAD_REQUEST: u8 = 0x80;
+const CODE_NOT_FOUND: u8 = 0x84;
+const CODE_METHOD_NOT_ALLOWED: u8 = 0x85;
+const CODE_SERVICE_UNAVAILABLE: u8 = 0xa3;
+
+const OPTION_OBSERVE: u16 = 6;
+const OPTION_URI_PATH: u16 = 11;
+const OPTION_CONTENT_FORMAT: u16 = 12;
+
+const FORMAT_LINK: u16 = 40;
+const FORMAT_JSON: u16 = 50;
+
+/// Largest datagram we accept.
+const MAX_MESSAGE_SIZE: usize = 1152;
+
+/// Time to wait for the acknowledgement of a confirmable message, doubled on every
+/// retransmission, and the number of retransmissions (RFC 7252 section 4.8).
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Resource {
+    State,
+    Power,
+    Core,
+}
+
+impl Resource {
+    fn from_path(path: &[String]) -> Option<Self> {
+        match path {
+            [state] if state == "state" => Some(Resource::State),
+            [power] if power == "power" => Some(Resource::Power),
+            [well_known, core] if well_known == ".well-known" && core == "core" => {
+                Some(Resource::Core)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A client observing a resource.
+struct Observer {
+    addr: SocketAddr,
+    token: Vec<u8>,
+    resource: Resource,
+    /// When the client last acknowledged a notification, or registered.
+    confirmed: Instant,
+    /// The confirmable notification waiting for an acknowledgement.
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    id: u16,
+    sent: Instant,
+    retransmits: u32,
+}
+
+/// The parts of a CoAP message we care about.
+#[derive(Debug, Default, PartialEq)]
+struct Message {
+    kind: u8,
+    code: u8,
+    id: u16,
+    token: Vec<u8>,
+    observe: Option<u32>,
+    path: Vec<String>,
+    content_format: Option<u16>,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let [header, code, id_high, id_low, rest @ ..] = data else {
+            return None;
+        };
+        if header >> 6 != VERSION {
+            return None;
+        }
+        let token_length = (header & 0x0f) as usize;
+        if token_length > 8 || rest.len() < token_length {
+            return None;
+        }
+        let mut message = Message {
+            kind: (header >> 4) & 0x03,
+            code: *code,
+            id: u16::from_be_bytes([*id_high, *id_low]),
+            token: rest[..token_length].to_vec(),
+            ..Default::default()
+        };
+
+        let mut data = &rest[token_length..];
+        let mut number = 0u16;
+        while let [byte, tail @ ..] = data {
+            if *byte == 0xff {
+                message.payload = tail.to_vec();
+                break;
+            }
+            data = tail;
+            let delta = read_option_value(byte >> 4, &mut data)?;
+            let length = read_option_value(byte & 0x0f, &mut data)? as usize;
+            if data.len() < length {
+                return None;
+            }
+            let (value, tail) = data.split_at(length);
+            data = tail;
+            number = number.checked_add(delta)?;
+            match number {
+                OPTION_OBSERVE => message.observe = Some(read_uint(value)),
+                OPTION_URI_PATH => message
+                    .path
+                    .push(String::from_utf8_lossy(value).into_owned()),
+                OPTION_CONTENT_FORMAT => message.content_format = Some(read_uint(value) as u16),
+                _ => {}
+            }
+        }
+        Some(message)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = vec![
+            VERSION << 6 | self.kind << 4 | self.token.len() as u8,
+            self.code,
+        ];
+        data.extend_from_slice(&self.id.to_be_bytes());
+        data.extend_from_slice(&self.token);
+
+        // Options have to be written in order of their number.
+        let mut number = 0;
+        if let Some(observe) = self.observe {
+            write_option(&mut data, &mut number, OPTION_OBSERVE, &uint_bytes(observe));
+        }
+        if let Some(format) = self.content_format {
+            write_option(
+                &mut data,
+                &mut number,
+                OPTION_CONTENT_FORMAT,
+                &uint_bytes(format as u32),
+            );
+        }
+        if !self.payload.is_empty() {
+            data.push(0xff);
+            data.extend_from_slice(&self.payload);
+        }
+        data
+    }
+}
+
+/// Read an option delta or length, which may be extended by one or two bytes.
+fn read_option_value(nibble: u8, data: &mut &[u8]) -> Option<u16> {
+    match nibble {
+        13 => {
+            let (&extra, tail) = data.split_first()?;
+            *data = tail;
+            Some(extra as u16 + 13)
+        }
+        14 => {
+            let [high, low, tail @ ..] = *data else {
+                return None;
+            };
+            *data = tail;
+            u16::from_be_bytes([*high, *low]).checked_add(269)
+        }
+        15 => None,
+        n => Some(n as u16),
+    }
+}
+
+fn write_option(data: &mut Vec<u8>, number: &mut u16, option: u16, value: &[u8]) {
+    let nibble = |n: usize| match n {
+        0..=12 => (n as u8, Vec::new()),
+        13..=268 => (13, vec![(n - 13) as u8]),
+        _ => (14, ((n - 269) as u16).to_be_bytes().to_vec()),
+    };
+    let (delta, delta_extra) = nibble((option - *number) as usize);
+    let (length, length_extra) = nibble(value.len());
+    data.push(delta << 4 | length);
+    data.extend_from_slice(&delta_extra);
+    data.extend_from_slice(&length_extra);
+    data.extend_from_slice(value);
+    *number = option;
+}
+
+fn read_uint(value: &[u8]) -> u32 {
+    value.iter().fold(0, |n, b| n << 8 | *b as u32)
+}
+
+/// Minimal big endian encoding of an unsigned option value.
+fn uint_bytes(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(4);
+    bytes[start..].to_vec()
+}
+
+struct Server {
+    socket: UdpSocket,
+    appdata: Arc<AppData>,
+    reader_data: Arc<RwLock<ReaderData>>,
+    observers: Mutex<Vec<Observer>>,
+    message_id: AtomicU16,
+    /// Observe sequence number, only the lower 24 bits are sent.
+    sequence: AtomicU32,
+}
+
+impl Server {
+    fn next_id(&self) -> u16 {
+        self.message_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Content of a resource, or `None` when we have nothing to serve yet.
+    fn render(&self, resource: Resource) -> Option<(u16, Vec<u8>)> {
+        match resource {
+            Resource::Core => Some((
+                FORMAT_LINK,
+                b"</state>;obs;ct=50,</power>;obs;ct=50".to_vec(),
+            )),
+            Resource::State => {
+                let output = &self.appdata.config().output;
+                let data = self.reader_data.read_recover();
+                let state =
+                    output::render_state(&data, output.missing_values, output.format).ok()?;
+                Some((FORMAT_JSON, serde_json::to_vec(&state).ok()?))
+            }
+            Resource::Power => {
+                let history = self.appdata.history.read_recover();
+                let sample = history.latest()?;
+                let power = json!({
+                    "timestamp": sample.timestamp,
+                    "delivered": sample.get(Metric::PowerDelivered),
+                    "received": sample.get(Metric::PowerReceived),
+                });
+                Some((FORMAT_JSON, serde_json::to_vec(&power).ok()?))
+            }
+        }
+    }
+
+    fn send(&self, message: &Message, addr: SocketAddr) -> bool {
+        match self.socket.send_to(&message.encode(), addr) {
+            Ok(_) => true,
+            Err(e) => {
+                debug!("Unable to send CoAP message to {}: {}", addr, e);
+                false
+            }
+        }
+    }
+
+    /// Answer requests until the socket fails.
+    fn serve(&self) {
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        loop {
+            let (length, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("CoAP server stopped: {}", e);
+                    return;
+                }
+            };
+            let Some(request) = Message::parse(&buffer[..length]) else {
+                debug!("Ignoring invalid CoAP message from {}", addr);
+                continue;
+            };
+            self.handle(request, addr);
+        }
+    }
+
+    fn handle(&self, request: Message, addr: SocketAddr) {
+        // A reset means the client is no longer interested in what we sent it.
+        if request.kind == TYPE_RST {
+            self.forget(addr, None);
+            return;
+        }
+        if request.kind == TYPE_ACK {
+            self.confirm(addr, request.id);
+            return;
+        }
+        // An empty confirmable message is a ping, which is answered with a reset.
+        if request.code == CODE_EMPTY {
+            if request.kind == TYPE_CON {
+                let reset = Message {
+                    kind: TYPE_RST,
+                    id: request.id,
+                    ..Default::default()
+                };
+                self.send(&reset, addr);
+            }
+            return;
+        }
+
+        // Confirmable requests get a piggybacked response, others a response of their own.
+        let mut response = Message {
+            kind: if request.kind == TYPE_CON {
+                TYPE_ACK
+            } else {
+                TYPE_NON
+            },
+            id: if request.kind == TYPE_CON {
+                request.id
+            } else {
+                self.next_id()
+            },
+            token: request.token.clone(),
+            ..Default::default()
+        };
+
+        if request.code != CODE_GET {
+            response.code = CODE_METHOD_NOT_ALLOWED;
+        } else if let Some(resource) = Resource::from_path(&request.path) {
+            match self.render(resource) {
+                Some((format, payload)) => {
+                    response.code = CODE_CONTENT;
+                    response.content_format = Some(format);
+                    response.payload = payload;
+                    if resource != Resource::Core {
+                        response.observe = self.observe(&request, addr, resource);
+                    }
+                }
+                None => response.code = CODE_SERVICE_UNAVAILABLE,
+            }
+        } else if request.path.is_empty() {
+            response.code = CODE_BAD_REQUEST;
+        } else {
+            response.code = CODE_NOT_FOUND;
+        }
+        self.send(&response, addr);
+    }
+
+    /// Register or deregister an observer as asked for in the request. Returns the
+    /// sequence number to include in the response if the client is now observing.
+    fn observe(&self, request: &Message, addr: SocketAddr, resource: Resource) -> Option<u32> {
+        match request.observe {
+            Some(0) => {
+                // Notifications are bigger than the request, don't send them to spoofed
+                // addresses.
+                if !allowlist::is_allowed(&self.appdata.config().outbound.allow, addr.ip()) {
+                    debug!(
+                        "Not registering CoAP observer {} outside the allowlist",
+                        addr
+                    );
+                    return None;
+                }
+                let Ok(mut observers) = self.observers.lock() else {
+                    return None;
+                };
+                observers.retain(|o| o.addr != addr || o.token != request.token);
+                if observers.len() >= self.appdata.config().coap.as_ref()?.max_observers {
+                    // Not an error, the client just gets a single response.
+                    debug!("Too many CoAP observers, not registering {}", addr);
+                    return None;
+                }
+                debug!("{} observes {:?}", addr, resource);
+                observers.push(Observer {
+                    addr,
+                    token: request.token.clone(),
+                    resource,
+                    confirmed: Instant::now(),
+                    pending: None,
+                });
+                Some(self.sequence.load(Ordering::Relaxed) & 0xff_ffff)
+            }
+            Some(1) => {
+                self.forget(addr, Some(&request.token));
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Remove the observations of a client, optionally only the one with the given token.
+    fn forget(&self, addr: SocketAddr, token: Option<&[u8]>) {
+        if let Ok(mut observers) = self.observers.lock() {
+            observers.retain(|o| o.addr != addr || token.is_some_and(|t| t != o.token));
+        }
+    }
+
+    /// Note the acknowledgement of a confirmable notification.
+    fn confirm(&self, addr: SocketAddr, id: u16) {
+        if let Ok(mut observers) = self.observers.lock() {
+            for observer in observers.iter_mut() {
+                if observer.addr == addr && observer.pending.as_ref().is_some_and(|p| p.id == id) {
+                    observer.confirmed = Instant::now();
+                    observer.pending = None;
+                }
+            }
+        }
+    }
+
+    /// Send every observer the new value of its resource. Observers are sent a confirmable
+    /// notification every `confirm_interval` seconds. While it isn't acknowledged, newer
+    /// values take the place of retransmissions, and the observer is dropped once they
+    /// run out.
+    fn notify(&self) {
+        let Some(config) = self.appdata.config().coap.as_ref() else {
+            return;
+        };
+        let interval = Duration::from_secs(config.confirm_interval);
+        let sequence = self
+            .sequence
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1);
+        let Ok(mut observers) = self.observers.lock() else {
+            return;
+        };
+        let state = self.render(Resource::State);
+        let power = self.render(Resource::Power);
+        observers.retain_mut(|observer| {
+            let content = match observer.resource {
+                Resource::State => &state,
+                Resource::Power => &power,
+                Resource::Core => return false,
+            };
+            let Some((format, payload)) = content else {
+                return true;
+            };
+            let now = Instant::now();
+            let retransmits = match &observer.pending {
+                Some(pending) => {
+                    if now < pending.sent + ACK_TIMEOUT * (1 << pending.retransmits) {
+                        return true;
+                    }
+                    if pending.retransmits >= MAX_RETRANSMIT {
+                        debug!("CoAP observer {} stopped responding", observer.addr);
+                        return false;
+                    }
+                    Some(pending.retransmits + 1)
+                }
+                None if now >= observer.confirmed + interval => Some(0),
+                None => None,
+            };
+            let notification = Message {
+                kind: if retransmits.is_some() {
+                    TYPE_CON
+                } else {
+                    TYPE_NON
+                },
+                code: CODE_CONTENT,
+                id: self.next_id(),
+                token: observer.token.clone(),
+                observe: Some(sequence & 0xff_ffff),
+                content_format: Some(*format),
+                payload: payload.clone(),
+                ..Default::default()
+            };
+            if let Some(retransmits) = retransmits {
+                observer.pending = Some(Pending {
+                    id: notification.id,
+                    sent: now,
+                    retransmits,
+                });
+            }
+            self.send(&notification, observer.addr)
+        });
+    }
+}
+
+/// Spawn the CoAP server and a thread notifying its observers of new data.
+pub fn spawn_coap_server(
+    appdata: Arc<AppData>,
+    reader_data: Arc<RwLock<ReaderData>>,
+) -> Result<JoinHandle<()>, std::io::Error> {
+    let mut addr = *appdata.local_addr();
+    if let Some(config) = appdata.config().coap.as_ref() {
+        addr.set_port(config.port);
+    }
+    let socket = UdpSocket::bind(addr)?;
+    info!("CoAP server listening on {}", addr);
+
+    let server = Arc::new(Server {
+        socket,
+        appdata,
+        reader_data,
+        observers: Mutex::new(Vec::new()),
+        message_id: AtomicU16::new(0),
+        sequence: AtomicU32::new(0),
+    });
+
+    let notifier = server.clone();
+    supervisor::spawn("coap-notifier", server.appdata.clone(), move |appdata| {
+        loop {
+            let listener = appdata.event_listener();
+            listener.wait();
+            notifier.notify();
+        }
+    })?;
+    supervisor::spawn("coap", server.appdata.clone(), move |_| server.serve())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header of a confirmable GET with message id 1 and the given token.
+    fn get(token: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x40 | token.len() as u8, CODE_GET, 0x00, 0x01];
+        data.extend_from_slice(token);
+        data
+    }
+
+    #[test]
+    fn extended_deltas_and_lengths() {
+        let segment = "s".repeat(20);
+        let long_segment = "l".repeat(270);
+        let mut data = get(b"ab");
+        // Observe, then a path segment with a one byte extended length.
+        data.extend_from_slice(&[0x61, 0x00, 0x5d, 20 - 13]);
+        data.extend_from_slice(segment.as_bytes());
+        // A path segment with a two byte extended length.
+        data.extend_from_slice(&[0x0e, 0x00, 1]);
+        data.extend_from_slice(long_segment.as_bytes());
+        // Option 11 + 13 + 20 with a one byte extended delta, and option 44 + 269 + 31
+        // with a two byte one. Both are unknown, and skipped.
+        data.extend_from_slice(&[0xd1, 20, 0xff, 0xe0, 0x00, 31]);
+
+        let message = Message::parse(&data).unwrap();
+        assert_eq!(message.kind, TYPE_CON);
+        assert_eq!(message.code, CODE_GET);
+        assert_eq!(message.id, 1);
+        assert_eq!(message.token, b"ab");
+        assert_eq!(message.observe, Some(0));
+        assert_eq!(message.path, [segment, long_segment]);
+        assert!(message.payload.is_empty());
+    }
+
+    #[test]
+    fn reserved_nibble() {
+        let mut data = get(b"");
+        data.push(0xf1);
+        assert_eq!(Message::parse(&data), None);
+
+        let mut data = get(b"");
+        data.push(0x1f);
+        assert_eq!(Message::parse(&data), None);
+    }
+
+    #[test]
+    fn truncated() {
+        // Token length 4, with 2 bytes of token.
+        let mut data = get(b"ab");
+        data[0] = 0x44;
+        assert_eq!(Message::parse(&data), None);
+        // Token length 9 is reserved.
+        assert_eq!(
+            Message::parse(&[0x49, CODE_GET, 0, 1, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            None
+        );
+        // Too short for a header.
+        assert_eq!(Message::parse(&[0x40, CODE_GET, 0]), None);
+
+        let truncated_options: [&[u8]; 4] =
+            [&[0xb5, b'p', b'o', b'w'], &[0xd0], &[0xe0, 0x00], &[0xbd]];
+        for options in truncated_options {
+            let mut data = get(b"ab");
+            data.extend_from_slice(options);
+            assert_eq!(Message::parse(&data), None, "{:02x?}", options);
+        }
+    }
+
+    #[test]
+    fn payload_marker() {
+        let mut data = get(b"t");
+        data.extend_from_slice(&[0xb5]);
+        data.extend_from_slice(b"state");
+        data.push(0xff);
+        data.extend_from_slice(&[0xff, 0xb5, 0x00]);
+        let message = Message::parse(&data).unwrap();
+        assert_eq!(message.path, ["state"]);
+        assert_eq!(message.payload, [0xff, 0xb5, 0x00]);
+
+        // Without a marker there is no payload.
+        let message = Message::parse(&get(b"t")).unwrap();
+        assert!(message.payload.is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = Message {
+            kind: TYPE_NON,
+            code: CODE_CONTENT,
+            id: 0xbeef,
+            token: b"12345678".to_vec(),
+            observe: Some(0x01_0203),
+            content_format: Some(FORMAT_JSON),
+            payload: br#"{"power":1.5}"#.to_vec(),
+            ..Default::default()
+        };
+        let data = message.encode();
+        assert_eq!(
+            &data[..16],
+            [
+                0x58, 0x45, 0xbe, 0xef, b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', 0x63, 0x01,
+                0x02, 0x03
+            ]
+        );
+        assert_eq!(Message::parse(&data), Some(message));
+
+        // An observe sequence of zero is sent as an empty option.
+        let message = Message {
+            kind: TYPE_ACK,
+            code: CODE_CONTENT,
+            observe: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(message.encode(), [0x60, 0x45, 0x00, 0x00, 0x60]);
+        assert_eq!(Message::parse(&message.encode()), Some(message));
+    }
+
+    #[test]
+    fn write_extended_options() {
+        let mut data = get(b"");
+        let mut number = 0;
+        write_option(&mut data, &mut number, OPTION_URI_PATH, &[b'a'; 20]);
+        write_option(&mut data, &mut number, OPTION_URI_PATH, &[b'b'; 300]);
+        write_option(&mut data, &mut number, 400, &[1]);
+        assert_eq!(data[4..6], [0xbd, 20 - 13]);
+        assert_eq!(data[26..29], [0x0e, 0x00, 31]);
+        assert_eq!(data[329..], [0xe1, 0x00, 120, 1]);
+
+        let message = Message::parse(&data).unwrap();
+        assert_eq!(message.path, ["a".repeat(20), "b".repeat(300)]);
+    }
+}
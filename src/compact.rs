@@ -0,0 +1,102 @@
+//! A fixed binary layout of the most useful values, served at `/compact`, for displays
+//! built around a microcontroller. They can copy the response into a struct as is, rather
+//! than parse JSON. All values are little-endian, missing values are NaN.
+//!
+//! | Offset | Type | Value                                                   |
+//! |--------|------|---------------------------------------------------------|
+//! | 0      | u8   | Layout version, 1                                       |
+//! | 1      | u8   | Tariff in effect, 1 or 2, 0 if unknown                  |
+//! | 2      | u16  | Flags, bit 0 is set when the state is outdated          |
+//! | 4      | u32  | Time the telegram was received, seconds since the epoch |
+//! | 8      | f64  | Energy delivered to the client, tariff 1, kWh           |
+//! | 16     | f64  | Energy delivered to the client, tariff 2, kWh           |
+//! | 24     | f64  | Energy delivered by the client, tariff 1, kWh           |
+//! | 32     | f64  | Energy delivered by the client, tariff 2, kWh           |
+//! | 40     | f64  | Gas delivered, m³                                       |
+//! | 48     | f32  | Power delivered to the client, kW                       |
+//! | 52     | f32  | Power delivered by the client, kW                       |
+//! | 56     | f32  | Voltage of phase 1, V                                   |
+//! | 60     | f32  | Current of phase 1, A                                   |
+//!
+//! In C that is
+//!
+//! ```c
+//! struct dsmrd_compact {
+//!     uint8_t version;
+//!     uint8_t tariff;
+//!     uint16_t flags;
+//!     uint32_t received_at;
+//!     double energy_delivered[2];
+//!     double energy_received[2];
+//!     double gas;
+//!     float power_delivered;
+//!     float power_received;
+//!     float voltage_l1;
+//!     float current_l1;
+//! };
+//! ```
+//!
+//! New values are only ever added at the end, with a new layout version.
+
+use std::sync::{Arc, RwLock};
+
+use hyper::{
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+
+use crate::{
+    appdata::AppData,
+    lock::RecoverLock,
+    reader::{self, ReaderData},
+};
+
+const VERSION: u8 = 1;
+/// Size of the layout in bytes.
+const SIZE: usize = 64;
+/// Set in the flags when no telegram was received for a while.
+const OUTDATED: u16 = 1;
+
+/// Encode the state in the compact layout.
+fn encode(data: &ReaderData, outdated: bool) -> Vec<u8> {
+    let state = &data.dsmr_state;
+    let f64_or_nan = |value: Option<f64>| value.unwrap_or(f64::NAN).to_le_bytes();
+    let f32_or_nan =
+        |value: Option<f64>| value.map_or(f32::NAN, |value| value as f32).to_le_bytes();
+
+    let mut buffer = Vec::with_capacity(SIZE);
+    buffer.push(VERSION);
+    buffer.push(
+        state
+            .tariff
+            .and_then(|tariff| u8::try_from(tariff).ok())
+            .unwrap_or(0),
+    );
+    buffer.extend(if outdated { OUTDATED } else { 0 }.to_le_bytes());
+    let received_at = data.received_at.map_or(0, |received_at| received_at / 1000);
+    buffer.extend(u32::try_from(received_at).unwrap_or(u32::MAX).to_le_bytes());
+    for value in state.energy_delivered.iter().chain(&state.energy_received) {
+        buffer.extend(f64_or_nan(*value));
+    }
+    buffer.extend(f64_or_nan(state.gas().map(|gas| gas.value)));
+    buffer.extend(f32_or_nan(state.power_delivered));
+    buffer.extend(f32_or_nan(state.power_received));
+    buffer.extend(f32_or_nan(state.phases[0].voltage));
+    buffer.extend(f32_or_nan(state.phases[0].current));
+    buffer
+}
+
+/// Handler for `/compact`.
+pub async fn handler(
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    reader::wake(&appdata, &data).await;
+    let data = data.read_recover();
+    let outdated = data.is_outdated(data.stale_after(appdata.config().output.stale_after));
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(CACHE_CONTROL, "no-store")
+        .body(Body::from(encode(&data, outdated)))
+}
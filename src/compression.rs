@@ -0,0 +1,127 @@
+//! Compression of JSON responses for clients that accept it.
+
+use std::io::Write;
+
+use flate2::{
+    write::{DeflateEncoder, GzEncoder},
+    Compression,
+};
+use hyper::{
+    header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY},
+    Body, Response, StatusCode,
+};
+
+/// Responses smaller than this are not worth compressing.
+const MIN_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Pick an encoding from an `Accept-Encoding` header, preferring gzip.
+    pub fn from_header(header: Option<&HeaderValue>) -> Option<Self> {
+        let header = header?.to_str().ok()?;
+        let accepted: Vec<&str> = header
+            .split(',')
+            .filter_map(|part| {
+                let mut params = part.split(';').map(str::trim);
+                let name = params.next()?;
+                // An encoding with q=0 is explicitly not acceptable.
+                let refused = params.any(|p| {
+                    p.strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                });
+                (!refused).then_some(name)
+            })
+            .collect();
+
+        if accepted.iter().any(|e| *e == "gzip" || *e == "*") {
+            Some(Encoding::Gzip)
+        } else if accepted.contains(&"deflate") {
+            Some(Encoding::Deflate)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compress a JSON response with the given encoding. Other responses are returned as is.
+pub async fn compress(
+    response: Response<Body>,
+    encoding: Option<Encoding>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .is_some_and(|t| t.starts_with("application/json"));
+    if !is_json || response.status() != StatusCode::OK {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+    let Some(encoding) = encoding else {
+        return Ok(Response::from_parts(parts, body));
+    };
+
+    let data = match hyper::body::to_bytes(body).await {
+        Ok(data) => data,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(format!("Error: failed to read response: {}", e)))
+        }
+    };
+    if data.len() < MIN_SIZE {
+        return Ok(Response::from_parts(parts, Body::from(data)));
+    }
+
+    match encoding.encode(&data) {
+        Ok(compressed) => {
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            parts.headers.remove(CONTENT_LENGTH);
+            // The compressed body is a different representation, so the ETag can only
+            // be a weak one.
+            if let Some(etag) = parts.headers.get(ETAG).and_then(|e| e.to_str().ok()) {
+                if !etag.starts_with("W/") {
+                    if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                        parts.headers.insert(ETAG, weak);
+                    }
+                }
+            }
+            Ok(Response::from_parts(parts, Body::from(compressed)))
+        }
+        Err(_) => Ok(Response::from_parts(parts, Body::from(data))),
+    }
+}
@@ -0,0 +1,34 @@
+//! Deduplication of telegrams by the time the meter stamped them with, for setups where
+//! the same telegram arrives more than once: a splitter echoing, a reader picking up a
+//! telegram again after a reconnect, or two collectors pushing the same meter to an
+//! aggregator. Duplicates are dropped before they are stored or passed on, so nothing is
+//! counted twice.
+//!
+//! A duplicate has the timestamp and the values of a recent telegram, so a meter with a
+//! stuck clock is still read. Telegrams without a timestamp are always taken.
+
+use std::collections::VecDeque;
+
+/// Number of recent telegrams remembered, enough to catch a duplicate arriving a little
+/// late.
+const RECENT: usize = 16;
+
+/// The most recent telegrams seen.
+#[derive(Clone, Debug, Default)]
+pub struct Recent<T> {
+    seen: VecDeque<T>,
+}
+
+impl<T: PartialEq> Recent<T> {
+    /// Note `telegram`, returning whether it was seen recently.
+    pub fn is_duplicate(&mut self, telegram: T) -> bool {
+        if self.seen.contains(&telegram) {
+            return true;
+        }
+        if self.seen.len() >= RECENT {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(telegram);
+        false
+    }
+}
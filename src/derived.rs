@@ -0,0 +1,121 @@
+//! Values that the meter does not report itself, but that can be derived from what it does report.
+
+use serde::Serialize;
+
+use crate::history::{History, Metric, Sample};
+
+/// Window over which the average power factor is computed.
+pub const AVERAGE_WINDOW_MS: u64 = 5 * 60 * 1000;
+
+const PHASE_METRICS: [(Metric, Metric, Metric, Metric); 3] = [
+    (
+        Metric::VoltageL1,
+        Metric::CurrentL1,
+        Metric::PowerDeliveredL1,
+        Metric::PowerReceivedL1,
+    ),
+    (
+        Metric::VoltageL2,
+        Metric::CurrentL2,
+        Metric::PowerDeliveredL2,
+        Metric::PowerReceivedL2,
+    ),
+    (
+        Metric::VoltageL3,
+        Metric::CurrentL3,
+        Metric::PowerDeliveredL3,
+        Metric::PowerReceivedL3,
+    ),
+];
+
+/// Derived values for a single phase.
+#[derive(Debug, Default, Serialize)]
+#[cfg_attr(
+    feature = "graphql",
+    derive(async_graphql::SimpleObject),
+    graphql(name = "DerivedPhase")
+)]
+pub struct Phase {
+    /// Apparent power in kVA.
+    pub apparent_power: Option<f64>,
+    /// Estimated power factor of the latest telegram.
+    pub power_factor: Option<f64>,
+    /// Estimated power factor over the last `AVERAGE_WINDOW_MS`.
+    pub average_power_factor: Option<f64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct Derived {
+    /// Net reactive power in kvar, for meters that report it.
+    pub reactive_power: Option<f64>,
+    /// Apparent power in kVA from the active and reactive power.
+    pub apparent_power: Option<f64>,
+    /// Power factor from the active and reactive power, unlike the estimates per phase.
+    pub power_factor: Option<f64>,
+    pub phases: [Phase; 3],
+}
+
+impl Derived {
+    /// Compute derived values from the latest sample and the samples stored in history.
+    pub fn compute(latest: &Sample, history: &History) -> Self {
+        let mut derived = Derived::default();
+        let from = latest.timestamp.saturating_sub(AVERAGE_WINDOW_MS);
+
+        if let Some((active, reactive)) = total_power(latest) {
+            let apparent = active.hypot(reactive);
+            derived.reactive_power = Some(reactive);
+            derived.apparent_power = Some(apparent);
+            derived.power_factor = power_factor(active.abs(), apparent);
+        }
+
+        for (phase, &metrics) in derived.phases.iter_mut().zip(PHASE_METRICS.iter()) {
+            if let Some((active, apparent)) = phase_power(latest, metrics) {
+                phase.apparent_power = Some(apparent);
+                phase.power_factor = power_factor(active, apparent);
+            }
+
+            // Averaging the powers instead of the factors weighs each telegram by its load.
+            let (active, apparent) = history
+                .query_range(from, latest.timestamp)
+                .filter_map(|s| phase_power(&s, metrics))
+                .fold((0.0, 0.0), |(p, s), (active, apparent)| {
+                    (p + active, s + apparent)
+                });
+            phase.average_power_factor = power_factor(active, apparent);
+        }
+
+        derived
+    }
+}
+
+/// Net active (kW) and reactive (kvar) power, if the meter reports reactive power.
+fn total_power(sample: &Sample) -> Option<(f64, f64)> {
+    let reactive = match (
+        sample.get(Metric::ReactivePowerDelivered),
+        sample.get(Metric::ReactivePowerReceived),
+    ) {
+        (None, None) => return None,
+        (delivered, received) => delivered.unwrap_or(0.0) - received.unwrap_or(0.0),
+    };
+    let active =
+        sample.get(Metric::PowerDelivered)? - sample.get(Metric::PowerReceived).unwrap_or(0.0);
+    Some((active, reactive))
+}
+
+/// Active (kW) and apparent (kVA) power of a phase, if the meter reports enough to compute both.
+fn phase_power(sample: &Sample, metrics: (Metric, Metric, Metric, Metric)) -> Option<(f64, f64)> {
+    let (voltage, current, delivered, received) = metrics;
+    let apparent = sample.get(voltage)? * sample.get(current)? / 1000.0;
+    let active = (sample.get(delivered)? - sample.get(received).unwrap_or(0.0)).abs();
+    Some((active, apparent))
+}
+
+/// Estimate the power factor. The meter reports current in whole amperes, so this is only an
+/// estimate and is clamped to the range a power factor can have.
+fn power_factor(active: f64, apparent: f64) -> Option<f64> {
+    if apparent <= 0.0 {
+        return None;
+    }
+    Some((active / apparent).clamp(0.0, 1.0))
+}
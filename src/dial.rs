@@ -0,0 +1,178 @@
+//! Outbound TCP connections of sinks and jobs. These go through the proxy if one is
+//! configured. Otherwise they go to the pinned or resolved addresses of the host, raced
+//! happy eyeballs style (RFC 8305) so a broken IPv6 route doesn't stall every connect.
+//!
+//! Connections on behalf of others, such as to webhooks, are held to the outbound allowlist
+//! with `allowing`. The addresses are checked as they're connected to, so a host can't
+//! resolve to an allowed address for the check and to another one for the connection.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    allowlist::{self, Cidr},
+    config::ResolveConfig,
+    tunnel::Proxy,
+};
+
+/// Head start of a connection attempt before the next address is tried as well.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// Time after which a connection attempt is given up on.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolved addresses per host and port, along with the time they were resolved.
+type Cache = HashMap<(String, u16), (Instant, Vec<SocketAddr>)>;
+
+#[derive(Clone, Debug)]
+pub struct Dialer {
+    proxy: Option<Proxy>,
+    addresses: Vec<IpAddr>,
+    interval: Duration,
+    happy_eyeballs: bool,
+    cache: Arc<Mutex<Cache>>,
+    /// Networks connections may go to, if limited.
+    allow: Option<Vec<Cidr>>,
+}
+
+impl Dialer {
+    pub fn new(proxy: Option<&Proxy>, config: &ResolveConfig) -> Self {
+        Self {
+            proxy: proxy.cloned(),
+            addresses: config.addresses.clone(),
+            interval: Duration::from_secs(config.interval),
+            happy_eyeballs: config.happy_eyeballs,
+            cache: Arc::default(),
+            allow: None,
+        }
+    }
+
+    /// Only connect to addresses in `networks`.
+    pub fn allowing(mut self, networks: &[Cidr]) -> Self {
+        self.allow = Some(networks.to_vec());
+        self
+    }
+
+    /// A dialer with the default resolution settings.
+    pub fn with_proxy(proxy: Option<&Proxy>) -> Self {
+        Self::new(proxy, &ResolveConfig::default())
+    }
+
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    /// Connect to `host` and `port`.
+    pub fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        if let Some(proxy) = &self.proxy {
+            return proxy.tunnel(host, port);
+        }
+        let addrs = self.resolve(host, port)?;
+        if let Some(allow) = &self.allow {
+            allowlist::check_addrs(allow, host, &addrs)
+                .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        }
+        let result = match self.happy_eyeballs {
+            true => race(&addrs),
+            false => sequential(&addrs),
+        };
+        // The host may have moved, so look it up again next time.
+        if result.is_err() {
+            if let Ok(mut cache) = self.cache.lock() {
+                cache.remove(&(host.to_string(), port));
+            }
+        }
+        result
+    }
+
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if !self.addresses.is_empty() {
+            let addrs = self.addresses.iter().map(|ip| SocketAddr::new(*ip, port));
+            return Ok(interleave(addrs.collect()));
+        }
+        let key = (host.to_string(), port);
+        let mut cache = self
+            .cache
+            .lock()
+            .map_err(|_| io::Error::other("address cache poisoned"))?;
+        if let Some((resolved, addrs)) = cache.get(&key) {
+            if resolved.elapsed() < self.interval {
+                return Ok(addrs.clone());
+            }
+        }
+        let addrs = interleave((host, port).to_socket_addrs()?.collect());
+        if addrs.is_empty() {
+            return Err(io::Error::other(format!("no addresses found for {}", host)));
+        }
+        cache.insert(key, (Instant::now(), addrs.clone()));
+        Ok(addrs)
+    }
+}
+
+/// Alternate between address families, starting with the family of the first address.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut other = other.into_iter();
+    let mut result = Vec::new();
+    for addr in preferred {
+        result.push(addr);
+        result.extend(other.next());
+    }
+    result.extend(other);
+    result
+}
+
+fn sequential(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::other("no addresses to connect to")))
+}
+
+/// Start connecting to the next address whenever an attempt fails or hasn't succeeded
+/// within `ATTEMPT_DELAY`, and take the first connection made.
+fn race(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    let (sender, receiver) = mpsc::channel();
+    let mut next = addrs.iter().copied().peekable();
+    let mut pending = 0;
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = next.next() {
+            let sender = sender.clone();
+            thread::Builder::new().spawn(move || {
+                // Connections made after another one won are dropped along with the send.
+                let _ = sender.send(TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT));
+            })?;
+            pending += 1;
+        } else if pending == 0 {
+            return Err(
+                last_error.unwrap_or_else(|| io::Error::other("no addresses to connect to"))
+            );
+        }
+
+        let wait = match next.peek() {
+            Some(_) => ATTEMPT_DELAY,
+            None => CONNECT_TIMEOUT,
+        };
+        match receiver.recv_timeout(wait) {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => {
+                pending -= 1;
+                last_error = Some(e);
+            }
+            Err(_) => {}
+        }
+    }
+}
@@ -0,0 +1,502 @@
+//! Decoder for meters pushing DLMS/COSEM data-notifications wrapped in HDLC frames.
+//!
+//! The decoded values are stored in the same state as DSMR telegrams, so everything
+//! downstream of the reader works the same for both. Vendor specific layouts live in `han`.
+
+use chrono::{DateTime, FixedOffset};
+use log::debug;
+
+use crate::{
+    config::MeterFormat,
+    han,
+    model::{meter_time, MeterState},
+};
+
+const HDLC_FLAG: u8 = 0x7e;
+const DATA_NOTIFICATION: u8 = 0x0f;
+
+/// A blocking iterator yielding the state of every valid frame read from the byte stream.
+/// Frames that are invalid or that carry no data-notification are skipped.
+pub struct Reader<T: Iterator<Item = u8>> {
+    stream: T,
+    format: MeterFormat,
+}
+
+impl<T: Iterator<Item = u8>> Reader<T> {
+    pub fn new(stream: T, format: MeterFormat) -> Self {
+        Self { stream, format }
+    }
+
+    /// Read the next HDLC frame, without its flags.
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            // Wait for a flag. Consecutive frames may share a flag, so skip any repeats.
+            while self.stream.next()? != HDLC_FLAG {}
+            let mut first = self.stream.next()?;
+            while first == HDLC_FLAG {
+                first = self.stream.next()?;
+            }
+
+            // Frame format type 3 carries the frame length in the lower 11 bits.
+            if first & 0xf0 != 0xa0 {
+                continue;
+            }
+            let second = self.stream.next()?;
+            let length = (usize::from(first & 0x07) << 8) | usize::from(second);
+            if length < 8 {
+                continue;
+            }
+
+            let mut frame = Vec::with_capacity(length);
+            frame.push(first);
+            frame.push(second);
+            for _ in 2..length {
+                frame.push(self.stream.next()?);
+            }
+            return Some(frame);
+        }
+    }
+}
+
+impl<T: Iterator<Item = u8>> Iterator for Reader<T> {
+    type Item = Result<MeterState, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = self.next_frame()?;
+            match decode_frame(&frame, self.format) {
+                Ok(state) => return Some(Ok(state)),
+                Err(e) => debug!("Skipping HAN frame: {}", e),
+            }
+        }
+    }
+}
+
+/// A single value from a COSEM data structure.
+#[derive(Debug, PartialEq)]
+pub enum Data {
+    Null,
+    Array(Vec<Data>),
+    Structure(Vec<Data>),
+    OctetString(Vec<u8>),
+    Integer(i64),
+    Unsigned(u64),
+    Float(f64),
+    Enum,
+}
+
+impl Data {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Data::Integer(i) => Some(*i as f64),
+            Data::Unsigned(u) => Some(*u as f64),
+            Data::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    /// Collect all values that are not arrays or structures, depth first.
+    fn flatten(self, into: &mut Vec<Data>) {
+        match self {
+            Data::Array(items) | Data::Structure(items) => {
+                items.into_iter().for_each(|item| item.flatten(into))
+            }
+            item => into.push(item),
+        }
+    }
+}
+
+/// Cursor over the bytes of an APDU.
+struct Parser<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> Parser<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.buffer.len() < n {
+            return Err(String::from("Unexpected end of frame"));
+        }
+        let (taken, rest) = self.buffer.split_at(n);
+        self.buffer = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// A-XDR encoded length: a single byte, or 0x8n followed by n length bytes.
+    fn length(&mut self) -> Result<usize, String> {
+        let first = self.byte()?;
+        if first & 0x80 == 0 {
+            return Ok(usize::from(first));
+        }
+        self.take(usize::from(first & 0x7f))?
+            .iter()
+            .try_fold(0usize, |length, b| {
+                length
+                    .checked_mul(256)
+                    .map(|l| l + usize::from(*b))
+                    .ok_or_else(|| String::from("Invalid length"))
+            })
+    }
+
+    fn unsigned(&mut self, n: usize) -> Result<u64, String> {
+        Ok(self
+            .take(n)?
+            .iter()
+            .fold(0u64, |value, b| (value << 8) | u64::from(*b)))
+    }
+
+    fn signed(&mut self, n: usize) -> Result<i64, String> {
+        let shift = 64 - 8 * n as u32;
+        Ok(((self.unsigned(n)? << shift) as i64) >> shift)
+    }
+
+    fn data(&mut self) -> Result<Data, String> {
+        let tag = self.byte()?;
+        Ok(match tag {
+            0x00 => Data::Null,
+            0x01 | 0x02 => {
+                let length = self.length()?;
+                let items = (0..length)
+                    .map(|_| self.data())
+                    .collect::<Result<Vec<_>, _>>()?;
+                if tag == 0x01 {
+                    Data::Array(items)
+                } else {
+                    Data::Structure(items)
+                }
+            }
+            0x03 => Data::Unsigned(self.unsigned(1)?),
+            0x05 => Data::Integer(self.signed(4)?),
+            0x06 => Data::Unsigned(self.unsigned(4)?),
+            0x09 | 0x0a | 0x0c => {
+                let length = self.length()?;
+                Data::OctetString(self.take(length)?.to_vec())
+            }
+            0x0f => Data::Integer(self.signed(1)?),
+            0x10 => Data::Integer(self.signed(2)?),
+            0x11 => Data::Unsigned(self.unsigned(1)?),
+            0x12 => Data::Unsigned(self.unsigned(2)?),
+            0x14 => Data::Integer(self.signed(8)?),
+            0x15 => Data::Unsigned(self.unsigned(8)?),
+            0x16 => {
+                self.byte()?;
+                Data::Enum
+            }
+            0x17 => Data::Float(f64::from(f32::from_bits(self.unsigned(4)? as u32))),
+            0x18 => Data::Float(f64::from_bits(self.unsigned(8)?)),
+            0x19 => Data::OctetString(self.take(12)?.to_vec()),
+            tag => return Err(format!("Unsupported data type {:#04x}", tag)),
+        })
+    }
+}
+
+/// Decode a complete HDLC frame (without flags) into a meter state.
+fn decode_frame(frame: &[u8], format: MeterFormat) -> Result<MeterState, String> {
+    let (content, fcs) = frame.split_at(frame.len() - 2);
+    if crc16_x25(content) != u16::from_le_bytes([fcs[0], fcs[1]]) {
+        return Err(String::from("Invalid frame checksum"));
+    }
+    if frame[0] & 0x08 != 0 {
+        return Err(String::from("Segmented frames are not supported"));
+    }
+
+    // Skip the frame format, the variable length addresses (the last byte of an address
+    // has its lowest bit set), the control field and the header checksum.
+    let mut parser = Parser {
+        buffer: &content[2..],
+    };
+    while parser.byte()? & 0x01 == 0 {}
+    while parser.byte()? & 0x01 == 0 {}
+    parser.take(3)?;
+
+    // LLC header, followed by the APDU.
+    if parser.take(3)? != [0xe6, 0xe7, 0x00] {
+        return Err(String::from("Invalid LLC header"));
+    }
+    if parser.byte()? != DATA_NOTIFICATION {
+        return Err(String::from("Not a data-notification"));
+    }
+    // Long invoke id and priority.
+    parser.take(4)?;
+
+    // Optional date-time of the notification. Some meters tag it as an octet string.
+    let mut datetime = None;
+    let mut length = parser.byte()?;
+    if length == 0x09 {
+        length = parser.byte()?;
+    }
+    if length > 0 {
+        datetime = parse_datetime(parser.take(usize::from(length))?);
+    }
+
+    let mut values = Vec::new();
+    parser.data()?.flatten(&mut values);
+
+    let mut state = match format {
+        MeterFormat::Kaifa => han::decode_kaifa(&values),
+        MeterFormat::Kamstrup => decode_obis(&values, han::kamstrup_scaler),
+        _ => decode_obis(&values, |_, _| 0),
+    };
+    state.datetime = state.datetime.or(datetime);
+    Ok(state)
+}
+
+/// Decode notifications listing OBIS codes followed by their values. Most meters follow
+/// each value by its scaler and unit. For meters that don't, `default_scaler` gives the
+/// scaler for the C and D groups of the OBIS code.
+fn decode_obis(values: &[Data], default_scaler: fn(u8, u8) -> i32) -> MeterState {
+    let mut state = MeterState::default();
+    let mut items = values.iter().peekable();
+
+    while let Some(item) = items.next() {
+        let Data::OctetString(code) = item else {
+            continue;
+        };
+        if code.len() != 6 {
+            continue;
+        }
+        let Some(value) = items.next() else {
+            break;
+        };
+        // Clock objects carry their value as an octet string.
+        if let Data::OctetString(datetime) = value {
+            if (code[2], code[3], code[4]) == (1, 0, 0) {
+                state.datetime = parse_datetime(datetime);
+            }
+            continue;
+        }
+        let Some(value) = value.as_f64() else {
+            continue;
+        };
+
+        let scaler = match items.peek() {
+            Some(Data::Integer(scaler)) => {
+                items.next();
+                if let Some(Data::Enum) = items.peek() {
+                    items.next();
+                }
+                *scaler as i32
+            }
+            _ => default_scaler(code[2], code[3]),
+        };
+        apply_obis(
+            &mut state,
+            code[2],
+            code[3],
+            code[4],
+            value * 10f64.powi(scaler),
+        );
+    }
+    state
+}
+
+/// Store a value given in base units (W, Wh, var, varh, V, A) in the state, using kW, kvar,
+/// kWh and kvarh for power and energy.
+/// Meters without tariffs only send totals, which we store as the first tariff.
+fn apply_obis(state: &mut MeterState, c: u8, d: u8, e: u8, value: f64) {
+    match (c, d) {
+        (1, 7) => state.power_delivered = Some(value / 1000.0),
+        (2, 7) => state.power_received = Some(value / 1000.0),
+        (1, 8) => state.energy_delivered[usize::from(e == 2)] = Some(value / 1000.0),
+        (2, 8) => state.energy_received[usize::from(e == 2)] = Some(value / 1000.0),
+        (3, 7) => state.reactive_power_delivered = Some(value / 1000.0),
+        (4, 7) => state.reactive_power_received = Some(value / 1000.0),
+        (3, 8) => state.reactive_energy_delivered[usize::from(e == 2)] = Some(value / 1000.0),
+        (4, 8) => state.reactive_energy_received[usize::from(e == 2)] = Some(value / 1000.0),
+        (21, 7) => state.phases[0].power_delivered = Some(value / 1000.0),
+        (41, 7) => state.phases[1].power_delivered = Some(value / 1000.0),
+        (61, 7) => state.phases[2].power_delivered = Some(value / 1000.0),
+        (22, 7) => state.phases[0].power_received = Some(value / 1000.0),
+        (42, 7) => state.phases[1].power_received = Some(value / 1000.0),
+        (62, 7) => state.phases[2].power_received = Some(value / 1000.0),
+        (31, 7) => state.phases[0].current = Some(value),
+        (51, 7) => state.phases[1].current = Some(value),
+        (71, 7) => state.phases[2].current = Some(value),
+        (32, 7) => state.phases[0].voltage = Some(value),
+        (52, 7) => state.phases[1].voltage = Some(value),
+        (72, 7) => state.phases[2].voltage = Some(value),
+        _ => {}
+    }
+}
+
+/// Parse a COSEM date-time as sent by the meter.
+pub fn parse_datetime(bytes: &[u8]) -> Option<DateTime<FixedOffset>> {
+    if bytes.len() != 12 {
+        return None;
+    }
+    meter_time(
+        i32::from(u16::from_be_bytes([bytes[0], bytes[1]])),
+        bytes[2],
+        bytes[3],
+        bytes[5],
+        bytes[6],
+        bytes[7],
+        // The highest bit of the clock status signals daylight saving time.
+        bytes[11] & 0x80 != 0,
+    )
+}
+
+/// CRC-16/X-25 as used for the HDLC frame check sequence.
+fn crc16_x25(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clock of 1 March 2024 12:00:00, without daylight saving time.
+    const CLOCK: [u8; 12] = [0x07, 0xe8, 3, 1, 0xff, 12, 0, 0, 0xff, 0x80, 0x00, 0x00];
+
+    /// An HDLC frame, without flags, carrying a data-notification with `body` as its
+    /// notification body.
+    fn frame(body: &[u8]) -> Vec<u8> {
+        // Frame format, a one byte destination and a two byte source address, the control
+        // field and the header checksum, which isn't checked.
+        let mut frame = vec![0xa0, 0x00, 0x41, 0x08, 0x83, 0x13, 0x00, 0x00];
+        // LLC header, data-notification, long invoke id and priority, no date-time.
+        frame.extend([
+            0xe6,
+            0xe7,
+            0x00,
+            DATA_NOTIFICATION,
+            0x40,
+            0x00,
+            0x00,
+            0x01,
+            0x00,
+        ]);
+        frame.extend(body);
+        let length = frame.len() + 2;
+        frame[0] |= (length >> 8) as u8 & 0x07;
+        frame[1] = length as u8;
+        let fcs = crc16_x25(&frame);
+        frame.extend(fcs.to_le_bytes());
+        frame
+    }
+
+    /// The frame between flags, as the meter sends it.
+    fn stream(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut stream = vec![HDLC_FLAG];
+        for frame in frames {
+            stream.extend(frame);
+            stream.push(HDLC_FLAG);
+        }
+        stream
+    }
+
+    /// A structure of an OBIS code, a 32 bit unsigned value and its scaler and unit.
+    fn register(code: [u8; 6], value: u32, scaler: i8) -> Vec<u8> {
+        let mut register = vec![0x02, 0x03, 0x09, 0x06];
+        register.extend(code);
+        register.push(0x06);
+        register.extend(value.to_be_bytes());
+        register.extend([0x02, 0x02, 0x0f, scaler as u8, 0x16, 0x1b]);
+        register
+    }
+
+    fn list(items: &[Vec<u8>]) -> Vec<u8> {
+        let mut list = vec![0x02, items.len() as u8];
+        for item in items {
+            list.extend(item);
+        }
+        list
+    }
+
+    fn clock() -> Vec<u8> {
+        let mut clock = vec![0x02, 0x02, 0x09, 0x06, 0, 0, 1, 0, 0, 0xff, 0x09, 0x0c];
+        clock.extend(CLOCK);
+        clock
+    }
+
+    #[test]
+    fn crc16_x25_check() {
+        assert_eq!(crc16_x25(b"123456789"), 0x906e);
+    }
+
+    #[test]
+    fn valid_frame() {
+        let body = list(&[
+            clock(),
+            register([1, 0, 1, 7, 0, 0xff], 1500, 0),
+            register([1, 0, 1, 8, 0, 0xff], 123_456, 0),
+            register([1, 0, 32, 7, 0, 0xff], 2301, -1),
+            register([1, 0, 31, 7, 0, 0xff], 640, -2),
+        ]);
+        let mut reader = Reader::new(stream(&[frame(&body)]).into_iter(), MeterFormat::Dlms);
+        let state = reader.next().unwrap().unwrap();
+        assert_eq!(state.datetime, meter_time(2024, 3, 1, 12, 0, 0, false));
+        assert_eq!(state.power_delivered, Some(1.5));
+        assert_eq!(state.energy_delivered, [Some(123.456), None]);
+        // Negative scalers don't scale exactly.
+        assert!((state.phases[0].voltage.unwrap() - 230.1).abs() < 1e-9);
+        assert!((state.phases[0].current.unwrap() - 6.4).abs() < 1e-9);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let body = list(&[register([1, 0, 1, 7, 0, 0xff], 1500, 0)]);
+        let mut bad = frame(&body);
+        let last = bad.len() - 1;
+        bad[last] ^= 0x01;
+        assert_eq!(
+            decode_frame(&bad, MeterFormat::Dlms),
+            Err(String::from("Invalid frame checksum"))
+        );
+
+        // The reader skips the frame and goes on with the next.
+        let good = frame(&list(&[register([1, 0, 2, 7, 0, 0xff], 800, 0)]));
+        let mut reader = Reader::new(stream(&[bad, good]).into_iter(), MeterFormat::Dlms);
+        let state = reader.next().unwrap().unwrap();
+        assert_eq!(state.power_delivered, None);
+        assert_eq!(state.power_received, Some(0.8));
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let body = list(&[register([1, 0, 1, 7, 0, 0xff], 1500, 0)]);
+
+        // The stream ends halfway through the frame.
+        let mut cut = stream(&[frame(&body)]);
+        cut.truncate(cut.len() / 2);
+        assert!(Reader::new(cut.into_iter(), MeterFormat::Dlms)
+            .next()
+            .is_none());
+
+        // The frame is complete, but the data in it isn't.
+        let short = frame(&body[..body.len() - 3]);
+        assert_eq!(
+            decode_frame(&short, MeterFormat::Dlms),
+            Err(String::from("Unexpected end of frame"))
+        );
+    }
+
+    #[test]
+    fn unknown_obis_code() {
+        let body = list(&[
+            register([1, 0, 99, 7, 0, 0xff], 1500, 0),
+            register([1, 0, 2, 7, 0, 0xff], 800, 0),
+        ]);
+        let state = decode_frame(&frame(&body), MeterFormat::Dlms).unwrap();
+        let expected = MeterState {
+            power_received: Some(0.8),
+            ..MeterState::default()
+        };
+        assert_eq!(state, expected);
+    }
+}
@@ -0,0 +1,187 @@
+//! Encryption of UDP packets with keys shared with each client, for networks shared with
+//! others like the LAN of an apartment building. Packets are sealed with
+//! ChaCha20-Poly1305 (RFC 8439) and sent as a random 12 byte nonce, followed by the
+//! ciphertext and the 16 byte tag, without associated data. With random nonces a key is
+//! good for billions of packets, which takes well over a century at one packet a second.
+
+use std::fmt;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::config::{UdpKey, REDACTED};
+
+/// A 256 bit key, configured in base64, e.g. from `openssl rand -base64 32`.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Key([u8; 32]);
+
+impl TryFrom<String> for Key {
+    type Error = String;
+
+    fn try_from(key: String) -> Result<Self, Self::Error> {
+        let bytes = STANDARD
+            .decode(key.trim())
+            .map_err(|e| format!("Invalid key: {}", e))?;
+        bytes
+            .try_into()
+            .map(Key)
+            .map_err(|bytes: Vec<u8>| format!("Invalid key of {} bytes, need 32", bytes.len()))
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep keys out of logs.
+        f.write_str("Key(..)")
+    }
+}
+
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(REDACTED)
+    }
+}
+
+/// The key for a client at `addr`, from the first entry covering it.
+pub fn key_for(keys: &[UdpKey], addr: std::net::IpAddr) -> Option<Key> {
+    keys.iter()
+        .find(|key| key.clients.contains(addr))
+        .map(|key| key.key)
+}
+
+/// Whether this build can encrypt packets.
+pub fn available() -> bool {
+    cfg!(feature = "udp-encryption")
+}
+
+/// `payload` sealed with `key`.
+#[cfg(feature = "udp-encryption")]
+pub fn encrypt(key: &Key, payload: &[u8]) -> Result<Vec<u8>, String> {
+    use openssl::symm::{encrypt_aead, Cipher};
+
+    let mut nonce = [0; 12];
+    getrandom::getrandom(&mut nonce).map_err(|e| e.to_string())?;
+    let mut tag = [0; 16];
+    let ciphertext = encrypt_aead(
+        Cipher::chacha20_poly1305(),
+        &key.0,
+        Some(&nonce),
+        &[],
+        payload,
+        &mut tag,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mut packet = Vec::with_capacity(nonce.len() + ciphertext.len() + tag.len());
+    packet.extend_from_slice(&nonce);
+    packet.extend_from_slice(&ciphertext);
+    packet.extend_from_slice(&tag);
+    Ok(packet)
+}
+
+#[cfg(not(feature = "udp-encryption"))]
+pub fn encrypt(_key: &Key, _payload: &[u8]) -> Result<Vec<u8>, String> {
+    Err(String::from("Built without the udp-encryption feature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allowlist::Cidr;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn key() -> Key {
+        Key::try_from(String::from(KEY)).unwrap()
+    }
+
+    #[test]
+    fn parse_key() {
+        let expected: Vec<u8> = (0..32).collect();
+        assert_eq!(key().0[..], expected);
+        // Surrounding whitespace, as left by copying the output of openssl, is ignored.
+        assert_eq!(Key::try_from(format!(" {}\n", KEY)), Ok(key()));
+    }
+
+    #[test]
+    fn invalid_keys() {
+        let error = |key: &str| Key::try_from(String::from(key)).unwrap_err();
+        assert!(error("not base64!").starts_with("Invalid key: "));
+        assert!(error(&KEY[1..]).starts_with("Invalid key: "));
+        assert_eq!(
+            error("AAECAwQFBgcICQoLDA0ODw=="),
+            "Invalid key of 16 bytes, need 32"
+        );
+        assert_eq!(error(""), "Invalid key of 0 bytes, need 32");
+    }
+
+    #[test]
+    fn keys_by_client() {
+        let other = Key([0xff; 32]);
+        let keys = [
+            UdpKey {
+                clients: Cidr::parse("192.168.1.10").unwrap(),
+                key: other,
+            },
+            UdpKey {
+                clients: Cidr::parse("192.168.1.0/24").unwrap(),
+                key: key(),
+            },
+        ];
+        assert_eq!(key_for(&keys, [192, 168, 1, 10].into()), Some(other));
+        assert_eq!(key_for(&keys, [192, 168, 1, 11].into()), Some(key()));
+        assert_eq!(key_for(&keys, [192, 168, 2, 10].into()), None);
+    }
+
+    #[cfg(feature = "udp-encryption")]
+    fn decrypt(key: &Key, packet: &[u8]) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+        use openssl::symm::{decrypt_aead, Cipher};
+
+        let (nonce, rest) = packet.split_at(12);
+        let (ciphertext, tag) = rest.split_at(rest.len() - 16);
+        decrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &key.0,
+            Some(nonce),
+            &[],
+            ciphertext,
+            tag,
+        )
+    }
+
+    #[cfg(feature = "udp-encryption")]
+    #[test]
+    fn round_trip() {
+        let payload = br#"{"power_delivered":1.5}"#;
+        let packet = encrypt(&key(), payload).unwrap();
+        assert_eq!(packet.len(), 12 + payload.len() + 16);
+        assert_ne!(&packet[12..12 + payload.len()], payload);
+        assert_eq!(decrypt(&key(), &packet).unwrap(), payload);
+
+        // Every packet gets a nonce of its own.
+        let again = encrypt(&key(), payload).unwrap();
+        assert_ne!(packet[..12], again[..12]);
+        assert_eq!(decrypt(&key(), &again).unwrap(), payload);
+    }
+
+    #[cfg(feature = "udp-encryption")]
+    #[test]
+    fn tampered_packets() {
+        let packet = encrypt(&key(), b"payload").unwrap();
+        let last = packet.len() - 1;
+        for index in [0, 12, last] {
+            let mut tampered = packet.clone();
+            tampered[index] ^= 0x01;
+            assert!(decrypt(&key(), &tampered).is_err(), "byte {}", index);
+        }
+        assert!(decrypt(&Key([0; 32]), &packet).is_err());
+    }
+
+    #[cfg(not(feature = "udp-encryption"))]
+    #[test]
+    fn unavailable() {
+        assert!(!available());
+        assert!(encrypt(&key(), b"payload").is_err());
+    }
+}
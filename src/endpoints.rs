@@ -1,38 +1,335 @@
+#[cfg(feature = "graphql")]
+use crate::graphql;
 use crate::{
-    appdata::AppData,
-    reader::{spawn_dsmr_thread, ReaderData, ThreadStatus},
+    aggregator, alerts, analytics, annual,
+    appdata::{AppData, RegisterError},
+    auth, backup, compact,
+    compression::{compress, Encoding},
+    config::{self, StaleData, StateFormat},
+    derived::Derived,
+    events, feed, grafana, ha, health,
+    history::{Aggregation, Sample},
+    install::{self, InstallPaths},
+    lock::RecoverLock,
+    logs, metrics,
+    obis::Lang,
+    output, plain, prices, proxy, query,
+    reader::{self, start_reader, stop_reader, ReaderData},
+    readiness, readings, rpc, session, silence,
+    sink::{self, SinkStatus},
+    snapshot, storage, swap, system, udp_sender, validate,
+};
+use hyper::{
+    header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+    Body, Method, Request, Response, StatusCode,
 };
-use hyper::{Body, Request, Response, StatusCode};
 use log::debug;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Map, Value};
 use std::{
-    error::Error,
-    net::SocketAddr,
+    env,
+    net::{IpAddr, SocketAddr},
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
+/// Number of samples returned by `/history` unless the client asks for another limit.
+pub const DEFAULT_HISTORY_LIMIT: usize = 1000;
+/// Upper bound on the number of samples returned by `/history` in a single response.
+pub const MAX_HISTORY_LIMIT: usize = 10_000;
+
+/// A page of samples, along with the cursor to fetch the next page with.
+#[derive(Serialize)]
+struct HistoryPage {
+    samples: Vec<Sample>,
+    next: Option<String>,
+}
+
+/// Description of the fields of the state.
+#[derive(Serialize)]
+struct Schema {
+    lang: Lang,
+    fields: Map<String, Value>,
+}
+
+/// The running build and where `dsmrd install` puts its files.
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+    features: Vec<&'static str>,
+    install: InstallPaths,
+}
+
+/// Parameters of a `/history` request.
+struct HistoryQuery {
+    from: u64,
+    to: u64,
+    /// Only return samples stored after the sample with this id.
+    after: u64,
+    limit: usize,
+    /// Size of the buckets to aggregate samples in, in milliseconds.
+    resolution: Option<u64>,
+    aggregation: Aggregation,
+}
+
+/// Query parameters of `/history`.
+#[derive(Deserialize)]
+struct HistoryParams {
+    #[serde(default, deserialize_with = "query::time")]
+    from: Option<u64>,
+    #[serde(default, deserialize_with = "query::time")]
+    to: Option<u64>,
+    cursor: Option<u64>,
+    limit: Option<usize>,
+    #[serde(default, deserialize_with = "query::duration_millis")]
+    resolution: Option<u64>,
+    #[serde(default, deserialize_with = "aggregation")]
+    agg: Option<Aggregation>,
+}
+
+impl HistoryQuery {
+    fn parse(req: &Request<Body>) -> Result<Self, String> {
+        let params: HistoryParams = query::parse(req)?;
+        Ok(Self {
+            from: params.from.unwrap_or(0),
+            to: params.to.unwrap_or(u64::MAX),
+            after: params.cursor.unwrap_or(0),
+            limit: params
+                .limit
+                .unwrap_or(DEFAULT_HISTORY_LIMIT)
+                .clamp(1, MAX_HISTORY_LIMIT),
+            resolution: params.resolution.filter(|resolution| *resolution > 0),
+            aggregation: params.agg.unwrap_or(Aggregation::Avg),
+        })
+    }
+}
+
+fn aggregation<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Aggregation>, D::Error> {
+    query::with(d, Aggregation::from_name, "avg, min, max or last")
+}
+
+/// Query parameters of the endpoints that describe the state.
+#[derive(Deserialize)]
+struct LangParams {
+    #[serde(default, deserialize_with = "lang")]
+    lang: Option<Lang>,
+    #[serde(default)]
+    verbose: bool,
+}
+
+fn lang<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Lang>, D::Error> {
+    query::with(d, Lang::from_name, "en or nl")
+}
+
+/// Query parameters of `/register` and `/unregister`.
+#[derive(Deserialize)]
+struct ClientParams {
+    /// Address to send packets to. Defaults to the address of the client making the request.
+    ip: Option<IpAddr>,
+    port: u16,
+    #[serde(default, deserialize_with = "query::duration")]
+    interval: Option<Duration>,
+}
+
+impl ClientParams {
+    fn parse(req: &Request<Body>) -> Result<(SocketAddr, Option<Duration>), String> {
+        let params: Self = query::parse(req)?;
+        let ip = match params.ip {
+            Some(ip) => ip,
+            None => req
+                .extensions()
+                .get::<SocketAddr>()
+                .map(SocketAddr::ip)
+                .ok_or("missing parameter ip, and the address of the request is unknown")?,
+        };
+        Ok((SocketAddr::new(ip, params.port), params.interval))
+    }
+}
+
+/// Query parameters of `/subscribe`.
+#[derive(Deserialize)]
+struct IntervalParams {
+    #[serde(default, deserialize_with = "query::duration")]
+    interval: Option<Duration>,
+}
+
+/// Level to leave the data request line at.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LineLevel {
+    On,
+    Off,
+}
+
+/// Query parameters of `/reader/request`.
+#[derive(Deserialize)]
+struct RequestParams {
+    level: Option<LineLevel>,
+}
+
 /// Handler for all incoming http requests
 pub async fn handler(
-    req: Request<Body>,
+    mut req: Request<Body>,
     data: Arc<RwLock<ReaderData>>,
     appdata: Arc<AppData>,
 ) -> Result<Response<Body>, hyper::http::Error> {
-    debug!("Received request: {:?}", req);
-    match req.uri().to_string() {
+    let http = &appdata.config().http;
+    if !proxy::strip_base_path(&mut req, &http.base_path) {
+        return not_found("Error: not found.");
+    }
+    let client = proxy::client_addr(&req, &http.trusted_proxies);
+    if let Some(client) = client {
+        req.extensions_mut().insert(client);
+    }
+    debug!("Received request from {:?}: {:?}", client, req);
+    // Collectors push with the token of their meter, and the meter of `/meters/<id>` is
+    // the one in the path.
+    let meter_id = match aggregator::meter_in_path(req.uri().path()) {
+        Some(id) => Some(id.to_string()),
+        None => meter_id(&appdata, &data),
+    };
+    if !aggregator::is_push(&req) {
+        if let Err((status, message)) = auth::authorize(&req, &http.tokens, meter_id.as_deref()) {
+            return auth::refuse(status, message);
+        }
+    }
+    let encoding = Encoding::from_header(req.headers().get(ACCEPT_ENCODING));
+    let path = req.uri().path().to_string();
+    let response = match req.uri().to_string() {
         u if u.starts_with("/status") => get_latest_data(data).await,
+        u if u.starts_with("/compact") => compact::handler(appdata, data).await,
+        u if u.starts_with("/plain") => plain::handler(appdata, data).await,
+        u if u.starts_with("/health/detail") => health::handler(appdata, data).await,
+        u if u.starts_with("/reader/request") => request_data(req, appdata, data).await,
+        u if u.starts_with("/readyz") => readiness::handler(appdata, data).await,
+        u if u.starts_with("/push") => aggregator::push_handler(req, appdata).await,
+        u if u.starts_with("/meters") => aggregator::meters_handler(req, appdata).await,
         u if u.starts_with("/start") => start_thread(appdata, data).await,
-        u if u.starts_with("/stop") => stop_thread(data).await,
+        u if u.starts_with("/stop") => stop_thread(appdata, data).await,
         u if u.starts_with("/register") => register_client(appdata, req).await,
         u if u.starts_with("/unregister") => unregister_client(appdata, req).await,
+        u if u.starts_with("/subscribe") => subscribe_client(appdata, req).await,
         u if u.starts_with("/list") => list_clients(appdata).await,
-        _ => get_state(data).await,
-    }
+        u if u.starts_with("/derived") => get_derived(req, appdata, data).await,
+        u if u.starts_with("/history") => get_history(req, appdata).await,
+        u if u.starts_with("/prices") => prices::handler(req, appdata).await,
+        u if u.starts_with("/annual") => annual::handler(appdata).await,
+        u if u.starts_with("/alerts/silence") => silence::handler(req, appdata).await,
+        u if u.starts_with("/alerts/") => alerts::handler(req, appdata).await,
+        u if u.starts_with("/analytics") => analytics::handler(req, appdata).await,
+        u if u.starts_with("/events.ics") => events::handler(appdata).await,
+        u if u.starts_with("/feed.atom") => feed::handler(appdata).await,
+        u if u.starts_with("/grafana") => grafana::handler(req, appdata).await,
+        u if u.starts_with("/ha") => ha::handler(appdata, data).await,
+        u if u.starts_with("/sessions") => session::handler(req, appdata).await,
+        u if u.starts_with("/sinks") => manage_sinks(req, appdata, data).await,
+        u if u.starts_with("/swaps") => swap::handler(appdata).await,
+        u if u.starts_with("/readings") => readings::handler(req, appdata).await,
+        u if u.starts_with("/snapshots") => snapshot::handler(req, appdata).await,
+        u if u.starts_with("/snapshot") => snapshot::take_handler(req, appdata, data).await,
+        u if u.starts_with("/metrics") => metrics::handler(appdata).await,
+        u if u.starts_with("/schema") => get_schema(req).await,
+        u if u.starts_with("/admin/backup") => backup::handler(req, appdata).await,
+        u if u.starts_with("/admin/db") => storage::handler(req, appdata).await,
+        u if u.starts_with("/config/schema") => get_config_schema().await,
+        u if u.starts_with("/config") => get_config(appdata).await,
+        u if u.starts_with("/rpc") => rpc::handler(req, appdata, data).await,
+        u if u.starts_with("/validate") => validate::handler(req).await,
+        u if u.starts_with("/version") => get_version().await,
+        u if u.starts_with("/system") => system::handler().await,
+        u if u.starts_with("/logs/level") => {
+            let duration = Duration::from_secs(appdata.config().logs.level_duration);
+            logs::level_handler(req, duration).await
+        }
+        u if u.starts_with("/logs") => logs::handler(req).await,
+        #[cfg(feature = "graphql")]
+        u if u.starts_with("/graphql") => graphql::handler(req, appdata, data).await,
+        _ => get_state(req, appdata, data).await,
+    };
+    health::record_response(&path, response.as_ref().map(Response::status));
+    compress(response?, encoding).await
+}
+
+/// Id of the meter this daemon reads, as configured or reported by the meter.
+fn meter_id(appdata: &AppData, data: &RwLock<ReaderData>) -> Option<String> {
+    appdata
+        .config()
+        .reader
+        .meter_id
+        .clone()
+        .or_else(|| data.read_recover().dsmr_state.equipment_id.clone())
 }
 
-async fn get_state(data: Arc<RwLock<ReaderData>>) -> Result<Response<Body>, hyper::http::Error> {
+async fn get_state(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    // With verbose=1 every value is annotated with its OBIS code, unit and description,
+    // in the language asked for with lang.
+    let params: LangParams = match query::parse(&req) {
+        Ok(params) => params,
+        Err(e) => return bad_request(&e),
+    };
+    let verbose = params.verbose.then_some(params.lang.unwrap_or_default());
+    reader::wake(&appdata, &data).await;
+
     // Get a lock on the mutex containing the DSMR data
-    let content = data.read().expect("Failed to read RwLock...");
+    let content = data.read_recover();
+
+    // The state is outdated when the meter hasn't sent a telegram for a while.
+    let config = &appdata.config().output;
+    let stale_after = content.stale_after(config.stale_after);
+    let outdated = content.is_outdated(stale_after);
+    if outdated {
+        match config.stale_data {
+            StaleData::Mark => {}
+            StaleData::Unavailable => {
+                return Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(CACHE_CONTROL, "no-store")
+                    .body(Body::from(format!(
+                        "Error: no telegram received in the last {} seconds.",
+                        stale_after.unwrap_or_default().as_secs()
+                    )))
+            }
+            StaleData::Empty => {
+                return Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .header(CACHE_CONTROL, "no-store")
+                    .body(Body::from("{}"))
+            }
+        }
+    }
+
+    let etag = match (verbose, outdated) {
+        (Some(lang), false) => format!("\"{}-verbose-{}\"", content.sequence, lang.name()),
+        (Some(lang), true) => format!("\"{}-verbose-{}-outdated\"", content.sequence, lang.name()),
+        (None, false) => format!("\"{}\"", content.sequence),
+        (None, true) => format!("\"{}-outdated\"", content.sequence),
+    };
+    if is_not_modified(&req, &etag) {
+        return cached_response(&appdata, &etag, StatusCode::NOT_MODIFIED, Body::empty());
+    }
+
     // Deserialize the data to a json string.
-    let json = serde_json::to_string(&content.dsmr_state);
+    // Annotations describe the fields of the native format.
+    let format = match verbose {
+        Some(_) => StateFormat::Native,
+        None => config.format,
+    };
+    let json =
+        output::render_state(&content, config.missing_values, format).and_then(|mut state| {
+            if let (true, Value::Object(fields)) = (outdated, &mut state) {
+                fields.insert("outdated".into(), Value::Bool(true));
+                fields.insert("received_at".into(), content.received_at.into());
+            }
+            if let Some(lang) = verbose {
+                output::annotate(&mut state, lang);
+            }
+            serde_json::to_string(&state)
+        });
 
     if let Ok(json) = json {
         // If we can get a json string, return that.
@@ -40,7 +337,7 @@ async fn get_state(data: Arc<RwLock<ReaderData>>) -> Result<Response<Body>, hype
         // the DSMR state returns a 'null-frame' containing no data
         // or the last frame that was succesfully stored
         // It is up to the client to make sure the data is useful/valid.
-        Ok(Response::new(Body::from(json)))
+        cached_response(&appdata, &etag, StatusCode::OK, Body::from(json))
     } else {
         // If not, return a HTTP error.
         Response::builder()
@@ -49,14 +346,321 @@ async fn get_state(data: Arc<RwLock<ReaderData>>) -> Result<Response<Body>, hype
     }
 }
 
+/// Describe the fields of the state, in the language asked for with `lang`.
+async fn get_schema(req: Request<Body>) -> Result<Response<Body>, hyper::http::Error> {
+    let lang = match query::parse::<LangParams>(&req) {
+        Ok(params) => params.lang.unwrap_or_default(),
+        Err(e) => return bad_request(&e),
+    };
+    let schema = Schema {
+        lang,
+        fields: output::schema(lang),
+    };
+    match serde_json::to_string(&schema) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+/// The configuration in effect, with the defaults and command line arguments filled in and
+/// secrets redacted.
+async fn get_config(appdata: Arc<AppData>) -> Result<Response<Body>, hyper::http::Error> {
+    let config = appdata
+        .config()
+        .redacted()
+        .map(|config| json!({"file": env::var(config::CONFIG_ENV).ok(), "config": config}));
+    match config {
+        Ok(config) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(config.to_string())),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!(
+                "Error: failed to serialize config: {}",
+                e
+            ))),
+    }
+}
+
+/// The JSON Schema of the configuration file, for editors to validate it with.
+async fn get_config_schema() -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/schema+json")
+        .body(Body::from(config::SCHEMA))
+}
+
+async fn get_derived(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    // Derived values only change when a new telegram arrives.
+    let etag = format!("\"derived-{}\"", data.read_recover().sequence);
+    if is_not_modified(&req, &etag) {
+        return cached_response(&appdata, &etag, StatusCode::NOT_MODIFIED, Body::empty());
+    }
+
+    let history = appdata.history.read_recover();
+    // Without any data we return a derived state without values, just like the DSMR state.
+    let derived = match history.latest() {
+        Some(latest) => Derived::compute(&latest, &history),
+        None => Derived::default(),
+    };
+
+    if let Ok(json) = serde_json::to_string(&derived) {
+        cached_response(&appdata, &etag, StatusCode::OK, Body::from(json))
+    } else {
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from("Failed to compute derived data."))
+    }
+}
+
+/// Return the stored samples in the requested time range, oldest first. Large ranges are
+/// split in pages: pass the `next` cursor of a response as `cursor` to get the next page.
+/// With `resolution` (e.g. `5m`), samples are aggregated per bucket using `agg`, which is
+/// one of `avg` (default), `min`, `max` or `last`.
+async fn get_history(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let query = match HistoryQuery::parse(&req) {
+        Ok(query) => query,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Error: {}", e)))
+        }
+    };
+
+    let history = appdata.history.read_recover();
+    let (samples, next) = match query.resolution {
+        Some(resolution) => history.downsample(
+            query.from,
+            query.to,
+            query.after,
+            resolution,
+            query.aggregation,
+            query.limit,
+        ),
+        None => history.page(query.from, query.to, query.after, query.limit),
+    };
+    let page = HistoryPage {
+        samples,
+        next: next.map(|id| id.to_string()),
+    };
+
+    match serde_json::to_string(&page) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!(
+                "Error: failed to serialize history: {}",
+                e
+            ))),
+    }
+}
+
+/// Information on a sink as returned by `/sinks`.
+#[derive(Serialize)]
+struct SinkInfo {
+    id: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    enabled: bool,
+    #[serde(flatten)]
+    status: SinkStatus,
+}
+
+/// `GET /sinks` lists the sinks and their status, `POST /sinks/{id}/enable`, `disable`
+/// or `restart` manages a single sink, and `POST /sinks/{id}/test` hands it a sample of
+/// the current state right away.
+async fn manage_sinks(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    if let Some(id) = path
+        .strip_prefix("/sinks/")
+        .and_then(|rest| rest.strip_suffix("/test"))
+    {
+        return test_sink(req, appdata, data, id.to_string()).await;
+    }
+    let sinks = appdata.sinks.read_recover();
+
+    if path == "/sinks" {
+        if req.method() != Method::GET {
+            return method_not_allowed();
+        }
+        let info: Vec<SinkInfo> = sinks
+            .iter()
+            .map(|sink| SinkInfo {
+                id: sink.id.clone(),
+                kind: sink.kind,
+                enabled: sink.is_enabled(),
+                status: sink.status(),
+            })
+            .collect();
+        return match serde_json::to_string(&info) {
+            Ok(json) => Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(json)),
+            Err(e) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(format!(
+                    "Error: failed to serialize sinks: {}",
+                    e
+                ))),
+        };
+    }
+
+    let Some((id, action)) = path
+        .strip_prefix("/sinks/")
+        .and_then(|rest| rest.rsplit_once('/'))
+    else {
+        return not_found("Error: unknown sink endpoint.");
+    };
+    let Some(sink) = sinks.iter().find(|sink| sink.id == id) else {
+        return not_found(&format!("Error: unknown sink {}.", id));
+    };
+    if req.method() != Method::POST {
+        return method_not_allowed();
+    }
+    let message = match action {
+        "enable" => {
+            sink.set_enabled(true);
+            "enabled"
+        }
+        "disable" => {
+            sink.set_enabled(false);
+            "disabled"
+        }
+        "restart" => {
+            sink.restart();
+            "restarting"
+        }
+        _ => return not_found(&format!("Error: unknown sink action {}.", action)),
+    };
+    debug!("Sink {} {}", id, message);
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::from(format!("Sink {} {}.", id, message)))
+}
+
+/// `POST /sinks/{id}/test`, reporting whether the sink took the test sample.
+async fn test_sink(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+    id: String,
+) -> Result<Response<Body>, hyper::http::Error> {
+    if !appdata
+        .sinks
+        .read_recover()
+        .iter()
+        .any(|sink| sink.id == id)
+    {
+        return not_found(&format!("Error: unknown sink {}.", id));
+    }
+    if req.method() != Method::POST {
+        return method_not_allowed();
+    }
+    let sink_id = id.clone();
+    let result = tokio::task::spawn_blocking(move || sink::test(&appdata, &data, &sink_id))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    match result {
+        Ok(bytes) => Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(format!(
+                "Sink {} delivered the test sample ({} bytes).",
+                id, bytes
+            ))),
+        Err(e) => Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::from(format!("Error: sink {} failed: {}", id, e))),
+    }
+}
+
+async fn get_version() -> Result<Response<Body>, hyper::http::Error> {
+    let features = [
+        ("dlms", cfg!(feature = "dlms")),
+        ("graphql", cfg!(feature = "graphql")),
+        ("tls", cfg!(feature = "tls")),
+        ("email", cfg!(feature = "email")),
+        ("remote-write", cfg!(feature = "remote-write")),
+        ("coap", cfg!(feature = "coap")),
+        ("udp-encryption", cfg!(feature = "udp-encryption")),
+        ("gpio", cfg!(feature = "gpio")),
+        ("sqlite", cfg!(feature = "sqlite")),
+        ("postgres", cfg!(feature = "postgres")),
+        ("mqtt", cfg!(feature = "mqtt")),
+        ("mdns", cfg!(feature = "mdns")),
+        ("parquet", cfg!(feature = "parquet")),
+    ];
+    let version = Version {
+        version: env!("CARGO_PKG_VERSION"),
+        features: features
+            .into_iter()
+            .filter_map(|(name, enabled)| enabled.then_some(name))
+            .collect(),
+        install: install::paths(),
+    };
+    match serde_json::to_string(&version) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+fn not_found(message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from(message.to_string()))
+}
+
+fn bad_request(message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(format!("Error: {}", message)))
+}
+
+fn method_not_allowed() -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .body(Body::from("Error: method not allowed."))
+}
+
 async fn get_latest_data(
     mutex: Arc<RwLock<ReaderData>>,
 ) -> Result<Response<Body>, hyper::http::Error> {
-    let data = mutex.read().expect("Failed to read RwLock...");
-    let json = serde_json::to_string(&data.thread_status);
+    let data = mutex.read_recover();
+    let json = serde_json::to_value(&data.status).and_then(|mut status| {
+        status["telegram_interval"] = serde_json::to_value(&data.interval)?;
+        serde_json::to_string(&status)
+    });
     if let Ok(json) = json {
         // If we can get a json string, return that.
-        Ok(Response::new(Body::from(json)))
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json))
     } else {
         Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -68,24 +672,7 @@ async fn start_thread(
     appdata: Arc<AppData>,
     rwlock: Arc<RwLock<ReaderData>>,
 ) -> Result<Response<Body>, hyper::http::Error> {
-    // Check if we already have a running thread.
-    // Do this in a separate scope so the mutex gets unlocked/released after.
-    {
-        if rwlock
-            .read()
-            .expect("Failed to read RwLock...")
-            .thread_handle
-            .is_some()
-        {
-            debug!("Found existing thread. Not creating new thread.");
-            return Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from("Error: existing DMSR reader thread found."));
-        };
-    }
-
-    // Spawn the dsmr thread and return a response.
-    match spawn_dsmr_thread(appdata, rwlock, String::from("/dev/ttyUSB0")) {
+    match start_reader(appdata, rwlock) {
         Ok(_) =>
         // Return Ok statuscode.
         {
@@ -95,25 +682,68 @@ async fn start_thread(
         }
         Err(e) => Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from(format!(
-                "Error: failed to start DSMR reader thread.\n{}",
-                e,
-            ))),
+            .body(Body::from(format!("Error: {}", e))),
     }
 }
 
 async fn stop_thread(
+    appdata: Arc<AppData>,
     rwlock: Arc<RwLock<ReaderData>>,
 ) -> Result<Response<Body>, hyper::http::Error> {
-    let ok_response = Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::from("DMSR reader thread stopped."));
+    match stop_reader(&appdata, &rwlock) {
+        Ok(_) => Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("DMSR reader thread stopped.")),
+        Err(e) => Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
 
-    // Get a lock on the mutex containing the DSMR data
-    let mut data = rwlock.write().expect("Unable to write to RwLock...");
-    data.thread_status = ThreadStatus::Stopping;
+/// Raise the data request line of the meter. With `level=on` or `level=off` the line is
+/// left at that level, otherwise it is raised for the configured pulse.
+async fn request_data(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+    rwlock: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    if req.method() != Method::POST {
+        return method_not_allowed();
+    }
+    let level = match query::parse::<RequestParams>(&req) {
+        Ok(params) => params.level.map(|level| matches!(level, LineLevel::On)),
+        Err(e) => return bad_request(&e),
+    };
+    let port = rwlock.read_recover().port.clone();
+    let Some(port) = port else {
+        return Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body(Body::from("Error: the serial port isn't open."));
+    };
 
-    ok_response
+    let config = &appdata.config().reader;
+    let (line, pulse) = (
+        config.request_line,
+        Duration::from_millis(config.request_pulse),
+    );
+    let result = tokio::task::spawn_blocking(move || match level {
+        Some(level) => port.set_request_line(line, level),
+        None => {
+            port.set_request_line(line, true)?;
+            std::thread::sleep(pulse);
+            port.set_request_line(line, false)
+        }
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    match result {
+        Ok(_) => Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(format!("Data request line {:?} set.", line))),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
 }
 
 async fn list_clients(appdata: Arc<AppData>) -> Result<Response<Body>, hyper::http::Error> {
@@ -140,29 +770,34 @@ async fn register_client(
     appdata: Arc<AppData>,
     req: Request<Body>,
 ) -> Result<Response<Body>, hyper::http::Error> {
-    let remote_addr = match parse_client_addr(req).await {
-        Ok(res) => res,
-        Err(e) => {
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::from(format!("Error: {}", e)))
-                .unwrap())
-        }
+    if appdata.config().udp.require_token {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from(
+                "Error: clients have to register with a token from /subscribe.",
+            ));
+    }
+
+    let (remote_addr, interval) = match ClientParams::parse(&req) {
+        Ok(params) => params,
+        Err(e) => return bad_request(&e),
     };
 
-    match appdata.register_client(remote_addr) {
-        Ok(_) =>
-        // Return Ok statuscode.
-        {
+    match appdata.register_client(remote_addr, interval) {
+        Ok(_) => {
+            let hello = match udp_sender::send_hello(&appdata, remote_addr) {
+                Ok(_) => String::from("sent a hello datagram"),
+                Err(e) => format!("unable to send a hello datagram: {}", e),
+            };
             Response::builder()
                 .status(StatusCode::OK)
                 .body(Body::from(format!(
-                    "Succesfully registered client {}",
-                    remote_addr
+                    "Succesfully registered client {}, {}",
+                    remote_addr, hello
                 )))
         }
         Err(e) => Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .status(register_error_status(&e))
             .body(Body::from(format!(
                 "Error: failed to register client {}: {}",
                 remote_addr, e,
@@ -170,19 +805,84 @@ async fn register_client(
     }
 }
 
-async fn unregister_client(
+fn register_error_status(error: &RegisterError) -> StatusCode {
+    match error {
+        RegisterError::AlreadyRegistered => StatusCode::CONFLICT,
+        RegisterError::TooManyClients(_)
+        | RegisterError::IntervalTooShort(_)
+        | RegisterError::TooManyTokens => StatusCode::TOO_MANY_REQUESTS,
+        RegisterError::InvalidToken(_) => StatusCode::BAD_REQUEST,
+        RegisterError::NotAllowed | RegisterError::NoKey => StatusCode::FORBIDDEN,
+        RegisterError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Response to `/subscribe`.
+#[derive(Serialize)]
+struct Subscription {
+    /// Send `hello <token>` to this port to complete the registration.
+    token: String,
+    port: u16,
+    /// Seconds the token remains valid.
+    expires_in: u64,
+}
+
+/// Hand out a token the client completes its registration with over UDP. This way only
+/// hosts that can receive on their address, and that asked themselves, get registered.
+async fn subscribe_client(
     appdata: Arc<AppData>,
     req: Request<Body>,
 ) -> Result<Response<Body>, hyper::http::Error> {
-    let remote_addr = match parse_client_addr(req).await {
-        Ok(res) => res,
+    if req.method() != Method::POST {
+        return method_not_allowed();
+    }
+    let (Some(remote_addr), Some(port)) = (
+        req.extensions().get::<SocketAddr>().copied(),
+        appdata.udp_port(),
+    ) else {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from("Error: UDP service is not available."));
+    };
+
+    let interval = match query::parse::<IntervalParams>(&req) {
+        Ok(params) => params.interval,
+        Err(e) => return bad_request(&e),
+    };
+    let token = match appdata.issue_token(remote_addr.ip(), interval) {
+        Ok(token) => token,
         Err(e) => {
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
+            return Response::builder()
+                .status(register_error_status(&e))
                 .body(Body::from(format!("Error: {}", e)))
-                .unwrap())
         }
     };
+    debug!("Issued UDP token to {}", remote_addr.ip());
+    let subscription = Subscription {
+        token,
+        port,
+        expires_in: appdata.config().udp.token_ttl,
+    };
+    match serde_json::to_string(&subscription) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CACHE_CONTROL, "no-store")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+async fn unregister_client(
+    appdata: Arc<AppData>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let remote_addr = match ClientParams::parse(&req) {
+        Ok((remote_addr, _)) => remote_addr,
+        Err(e) => return bad_request(&e),
+    };
 
     debug!("Unregistering client {}", remote_addr);
 
@@ -206,20 +906,33 @@ async fn unregister_client(
     }
 }
 
-/// Helper function to parse requests to (un)register into SocketAddr using ip and port.
-async fn parse_client_addr(req: Request<Body>) -> Result<SocketAddr, Box<dyn Error>> {
-    let query = req.uri().query().ok_or("No query string found")?;
-
-    let params: std::collections::HashMap<String, String> =
-        url::form_urlencoded::parse(query.as_bytes())
-            .into_owned()
-            .collect();
-
-    let ip = params.get("ip").ok_or("No IP parameter found")?;
-    let port = params.get("port").ok_or("No port parameter found")?;
-
-    let addr = format!("{}:{}", ip, port);
-    let socket_addr: SocketAddr = addr.parse()?;
+/// Check whether the client already has the version identified by `etag`.
+fn is_not_modified(req: &Request<Body>, etag: &str) -> bool {
+    let Some(header) = req.headers().get(IF_NONE_MATCH) else {
+        return false;
+    };
+    header.to_str().is_ok_and(|tags| {
+        tags.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag || tag == "*")
+    })
+}
 
-    Ok(socket_addr)
+/// Build a response carrying the ETag and caching policy of the state endpoints.
+fn cached_response(
+    appdata: &AppData,
+    etag: &str,
+    status: StatusCode,
+    body: Body,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let cache_control = match appdata.config().http.cache_max_age {
+        0 => String::from("no-cache"),
+        max_age => format!("max-age={}", max_age),
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header(ETAG, etag)
+        .header(CACHE_CONTROL, cache_control)
+        .body(body)
 }
@@ -0,0 +1,261 @@
+//! Notable events, served as an iCalendar feed at `/events.ics` so they can be laid over
+//! a calendar: power failures and tariff changes reported by the meter, alerts such as a
+//! jump of the baseload with their reminders and recoveries, and a summary of the usage of every month that is over.
+//!
+//! Events are kept in the configured file, the monthly summaries are made from the
+//! monthly totals when the feed is requested.
+
+use std::{collections::VecDeque, fmt::Write, fs, sync::Arc};
+
+use chrono::{Datelike, Days, Local, Months, TimeZone, Utc};
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    appdata::AppData, config::EventConfig, history::now_millis, lock::RecoverLock,
+    model::MeterState,
+};
+
+/// Longest line in the feed in bytes, longer lines are folded.
+const MAX_LINE: usize = 75;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    PowerFailure,
+    LongPowerFailure,
+    TariffChange,
+    Alert,
+    /// An alert still firing, see `alerts`.
+    Reminder,
+    /// An alert that cleared.
+    Recovery,
+    /// A replaced or reset meter, see `swap`.
+    MeterSwap,
+    /// An alert that needs action right away, such as the main fuse about to trip. It is
+    /// notified with high priority.
+    UrgentAlert,
+}
+
+impl EventKind {
+    fn name(&self) -> &'static str {
+        match self {
+            EventKind::PowerFailure => "power-failure",
+            EventKind::LongPowerFailure => "long-power-failure",
+            EventKind::TariffChange => "tariff-change",
+            EventKind::Alert => "alert",
+            EventKind::Reminder => "reminder",
+            EventKind::Recovery => "recovery",
+            EventKind::MeterSwap => "meter-swap",
+            EventKind::UrgentAlert => "urgent-alert",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Event {
+    /// Time of the event in milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub kind: EventKind,
+    pub summary: String,
+    pub description: String,
+}
+
+/// What the meter reported last, to notice changes.
+#[derive(Debug, Default)]
+struct Reported {
+    power_failures: Option<u64>,
+    long_power_failures: Option<u64>,
+    tariff: Option<u16>,
+}
+
+/// The events so far, oldest first, kept in the configured file.
+#[derive(Debug)]
+pub struct Events {
+    file: Option<String>,
+    max: usize,
+    events: VecDeque<Event>,
+    reported: Reported,
+}
+
+impl Events {
+    pub fn new(config: &EventConfig) -> Self {
+        let events = config
+            .file
+            .as_ref()
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(events) => serde_json::from_str(&events)
+                    .map_err(|e| error!("Unable to parse events {}: {}", path, e))
+                    .ok(),
+                Err(e) => {
+                    info!("No events read from {}: {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            file: config.file.clone(),
+            max: config.max.max(1),
+            events,
+            reported: Reported::default(),
+        }
+    }
+
+    /// The events so far, oldest first.
+    pub fn all(&self) -> &VecDeque<Event> {
+        &self.events
+    }
+
+    /// Record an event that happened just now.
+    pub fn record(&mut self, kind: EventKind, summary: String, description: String) {
+        self.events.push_back(Event {
+            timestamp: now_millis(),
+            kind,
+            summary,
+            description,
+        });
+        while self.events.len() > self.max {
+            self.events.pop_front();
+        }
+        self.save();
+    }
+
+    /// Record the power failures and tariff changes in a state from the meter. The first
+    /// state only sets what later ones are compared with.
+    pub fn observe(&mut self, state: &MeterState) {
+        let failures = |previous: Option<u64>, current: Option<u64>| match (previous, current) {
+            (Some(previous), Some(current)) if current > previous => Some(current - previous),
+            _ => None,
+        };
+        if let Some(count) = failures(self.reported.power_failures, state.power_failures) {
+            self.record(
+                EventKind::PowerFailure,
+                String::from("Power failure"),
+                format!("The meter counted {} power failure(s).", count),
+            );
+        }
+        if let Some(count) = failures(self.reported.long_power_failures, state.long_power_failures)
+        {
+            self.record(
+                EventKind::LongPowerFailure,
+                String::from("Long power failure"),
+                format!("The meter counted {} long power failure(s).", count),
+            );
+        }
+        if let (Some(previous), Some(tariff)) = (self.reported.tariff, state.tariff) {
+            if previous != tariff {
+                self.record(
+                    EventKind::TariffChange,
+                    format!("Tariff {}", tariff),
+                    format!("The tariff changed from {} to {}.", previous, tariff),
+                );
+            }
+        }
+        self.reported = Reported {
+            power_failures: state.power_failures.or(self.reported.power_failures),
+            long_power_failures: state
+                .long_power_failures
+                .or(self.reported.long_power_failures),
+            tariff: state.tariff.or(self.reported.tariff),
+        };
+    }
+
+    pub fn save(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let result = serde_json::to_string(&self.events)
+            .map_err(|e| e.to_string())
+            .and_then(|events| fs::write(path, events).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Unable to write events {}: {}", path, e);
+        }
+    }
+}
+
+/// `text` escaped for an iCalendar value.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Append `line` to `ics`, folded at `MAX_LINE` bytes.
+fn push_line(ics: &mut String, line: &str) {
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE {
+            ics.push_str("\r\n ");
+            length = 1;
+        }
+        ics.push(c);
+        length += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+fn push_event(ics: &mut String, uid: &str, start: &str, summary: &str, description: &str) {
+    let stamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+    push_line(ics, "BEGIN:VEVENT");
+    push_line(ics, &format!("UID:{}@dsmrd", uid));
+    push_line(ics, &format!("DTSTAMP:{}", stamp));
+    push_line(ics, &format!("DTSTART{}", start));
+    push_line(ics, &format!("SUMMARY:{}", escape(summary)));
+    push_line(ics, &format!("DESCRIPTION:{}", escape(description)));
+    push_line(ics, "END:VEVENT");
+}
+
+/// Handler for `/events.ics`.
+pub async fn handler(appdata: Arc<AppData>) -> Result<Response<Body>, hyper::http::Error> {
+    let mut ics = String::new();
+    push_line(&mut ics, "BEGIN:VCALENDAR");
+    push_line(&mut ics, "VERSION:2.0");
+    push_line(&mut ics, "PRODID:-//dsmrd//events//EN");
+    push_line(&mut ics, "X-WR-CALNAME:Energy meter");
+
+    for event in appdata.events.read_recover().events.iter() {
+        let Some(time) = Utc.timestamp_millis_opt(event.timestamp as i64).single() else {
+            continue;
+        };
+        push_event(
+            &mut ics,
+            &format!("{}-{}", event.kind.name(), event.timestamp),
+            &format!(":{}", time.format("%Y%m%dT%H%M%SZ")),
+            &event.summary,
+            &event.description,
+        );
+    }
+
+    // Every month that is over, as an all-day event on its last day.
+    let this_month = Local::now().date_naive().with_day(1);
+    for (start, delivered, received) in appdata.months.read_recover().usage() {
+        let last_day = start
+            .checked_add_months(Months::new(1))
+            .and_then(|next| next.checked_sub_days(Days::new(1)));
+        let Some(last_day) = last_day.filter(|_| Some(start) < this_month) else {
+            continue;
+        };
+        let mut description = String::new();
+        if let Some(delivered) = delivered {
+            let _ = writeln!(description, "Delivered: {:.1} kWh", delivered);
+        }
+        if let Some(received) = received {
+            let _ = writeln!(description, "Fed back: {:.1} kWh", received);
+        }
+        push_event(
+            &mut ics,
+            &format!("month-{}", start.format("%Y-%m")),
+            &format!(";VALUE=DATE:{}", last_day.format("%Y%m%d")),
+            &format!("Energy usage in {}", start.format("%B %Y")),
+            description.trim_end(),
+        );
+    }
+    push_line(&mut ics, "END:VCALENDAR");
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/calendar; charset=utf-8")
+        .body(Body::from(ics))
+}
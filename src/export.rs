@@ -0,0 +1,281 @@
+//! High-resolution export of every telegram, for appliance disaggregation (NILM) research
+//! tools that need the per-phase power at the rate the meter sends it rather than the
+//! sampled history. Records are appended to a file, a new one per day or hour when the
+//! path holds `strftime` fields, and streamed to the clients of a Unix socket.
+//!
+//! A stream starts with a 16 byte header: the magic `DSMRNILM`, the layout version as a
+//! u16, the size of a record as a u16 and 4 reserved bytes. A file gets the header when it
+//! is created, a socket client when it connects. Records of 64 bytes follow, one per
+//! telegram. All values are little-endian, missing values are NaN.
+//!
+//! | Offset | Type   | Value                                                    |
+//! |--------|--------|----------------------------------------------------------|
+//! | 0      | u64    | Time the telegram was received, ms since the epoch       |
+//! | 8      | f32    | Power delivered to the client, W                         |
+//! | 12     | f32    | Power delivered by the client, W                         |
+//! | 16     | f32[3] | Power delivered to the client per phase, W               |
+//! | 28     | f32[3] | Power delivered by the client per phase, W               |
+//! | 40     | f32[3] | Voltage per phase, V                                     |
+//! | 52     | f32[3] | Current per phase, A                                     |
+//!
+//! With numpy, a file reads as
+//! `np.fromfile(path, offset=16, dtype=[("t", "<u8"), ("p", "<f4", 2), ("pl", "<f4", 6), ("v", "<f4", 3), ("i", "<f4", 3)])`.
+//!
+//! With `export.format` set to `parquet` the file is written as Parquet instead, see
+//! `export::parquet`.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    sync::{Arc, Mutex, RwLock},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use chrono::Local;
+use event_listener::Listener;
+use log::{debug, error, info, warn};
+
+use crate::{
+    appdata::AppData,
+    config::{ExportConfig, ExportFormat},
+    history::{Metric, Sample},
+    lock::RecoverLock,
+    reader::ReaderData,
+    supervisor,
+};
+
+#[cfg(feature = "parquet")]
+mod parquet;
+
+const MAGIC: &[u8; 8] = b"DSMRNILM";
+const VERSION: u16 = 1;
+/// Size of a record in bytes.
+const SIZE: usize = 64;
+/// Time a socket client gets to take a record before it is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The header starting every stream.
+fn header() -> [u8; 16] {
+    let mut header = [0; 16];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..10].copy_from_slice(&VERSION.to_le_bytes());
+    header[10..12].copy_from_slice(&(SIZE as u16).to_le_bytes());
+    header
+}
+
+/// Number of values in a record.
+const VALUES: usize = 14;
+
+/// The values of a record, powers in W.
+fn values(sample: &Sample) -> [Option<f32>; VALUES] {
+    let kilo = [
+        Metric::PowerDelivered,
+        Metric::PowerReceived,
+        Metric::PowerDeliveredL1,
+        Metric::PowerDeliveredL2,
+        Metric::PowerDeliveredL3,
+        Metric::PowerReceivedL1,
+        Metric::PowerReceivedL2,
+        Metric::PowerReceivedL3,
+    ];
+    let unit = [
+        Metric::VoltageL1,
+        Metric::VoltageL2,
+        Metric::VoltageL3,
+        Metric::CurrentL1,
+        Metric::CurrentL2,
+        Metric::CurrentL3,
+    ];
+    let mut values = [None; VALUES];
+    let all = kilo
+        .iter()
+        .map(|metric| sample.get(*metric).map(|kw| kw * 1000.0))
+        .chain(unit.iter().map(|metric| sample.get(*metric)));
+    for (value, sampled) in values.iter_mut().zip(all) {
+        *value = sampled.map(|value| value as f32);
+    }
+    values
+}
+
+/// Encode a sample as a record.
+fn encode(sample: &Sample) -> [u8; SIZE] {
+    let mut record = [0; SIZE];
+    record[..8].copy_from_slice(&sample.timestamp.to_le_bytes());
+    for (bytes, value) in record[8..].chunks_exact_mut(4).zip(values(sample)) {
+        bytes.copy_from_slice(&value.unwrap_or(f32::NAN).to_le_bytes());
+    }
+    record
+}
+
+/// The path of the file to write now, following `pattern`.
+fn path(pattern: &str) -> Result<String, String> {
+    let mut path = String::new();
+    write!(path, "{}", Local::now().format(pattern))
+        .map_err(|_| format!("invalid export file {}", pattern))?;
+    Ok(path)
+}
+
+/// The file records are appended to, following the date in its path.
+struct BinaryFile {
+    pattern: String,
+    path: String,
+    file: Option<File>,
+}
+
+impl BinaryFile {
+    fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            path: String::new(),
+            file: None,
+        }
+    }
+
+    fn write(&mut self, record: &[u8]) -> Result<(), String> {
+        let path = path(&self.pattern)?;
+        if path != self.path {
+            self.file = None;
+            self.path = path;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .map_err(|e| format!("unable to open {}: {}", self.path, e))?;
+                if file.metadata().map_err(|e| e.to_string())?.len() == 0 {
+                    file.write_all(&header()).map_err(|e| e.to_string())?;
+                }
+                info!("Exporting telegrams to {}", self.path);
+                self.file.insert(file)
+            }
+        };
+        if let Err(e) = file.write_all(record) {
+            // Open the file again for the next record.
+            self.file = None;
+            return Err(format!("unable to write {}: {}", self.path, e));
+        }
+        Ok(())
+    }
+}
+
+/// The export file in the configured format.
+enum Output {
+    Binary(BinaryFile),
+    #[cfg(feature = "parquet")]
+    Parquet(Box<parquet::ParquetFile>),
+}
+
+impl Output {
+    fn new(pattern: &str, format: ExportFormat) -> Result<Self, String> {
+        match format {
+            ExportFormat::Binary => Ok(Self::Binary(BinaryFile::new(pattern))),
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => {
+                Ok(Self::Parquet(Box::new(parquet::ParquetFile::new(pattern))))
+            }
+            #[cfg(not(feature = "parquet"))]
+            ExportFormat::Parquet => Err(String::from("built without the parquet feature")),
+        }
+    }
+
+    fn write(&mut self, sample: &Sample) -> Result<(), String> {
+        match self {
+            Self::Binary(file) => file.write(&encode(sample)),
+            #[cfg(feature = "parquet")]
+            Self::Parquet(file) => file.write(sample),
+        }
+    }
+}
+
+type Clients = Arc<Mutex<Vec<UnixStream>>>;
+
+/// Listen on the socket at `path`, sending new clients the header.
+fn listen(path: &str, clients: Clients) -> Result<(), io::Error> {
+    // A socket left behind by an earlier run.
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    info!("Exporting telegrams on {}", path);
+    thread::Builder::new()
+        .name("export-socket".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Unable to accept an export client: {}", e);
+                        continue;
+                    }
+                };
+                let accepted = stream
+                    .set_write_timeout(Some(WRITE_TIMEOUT))
+                    .and_then(|_| stream.write_all(&header()));
+                match accepted {
+                    Ok(_) => {
+                        debug!("Export client connected");
+                        if let Ok(mut clients) = clients.lock() {
+                            clients.push(stream);
+                        }
+                    }
+                    Err(e) => warn!("Unable to set up an export client: {}", e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Spawns a thread writing a record for every telegram to the configured file and socket.
+pub fn spawn_export(
+    appdata: Arc<AppData>,
+    reader_data: Arc<RwLock<ReaderData>>,
+    config: &ExportConfig,
+) -> Result<JoinHandle<()>, io::Error> {
+    let clients: Clients = Arc::default();
+    if let Some(path) = &config.socket {
+        listen(path, clients.clone())?;
+    }
+    let file = config.file.clone();
+    let format = config.format;
+    supervisor::spawn("export", appdata, move |appdata| {
+        let mut output = file
+            .as_deref()
+            .and_then(|file| match Output::new(file, format) {
+                Ok(output) => Some(output),
+                Err(e) => {
+                    error!("Unable to export telegrams to {}: {}", file, e);
+                    None
+                }
+            });
+        let mut last_sequence = reader_data.read_recover().sequence;
+        loop {
+            let listener = appdata.event_listener();
+            listener.wait();
+
+            let sample = {
+                let data = reader_data.read_recover();
+                // Events are emitted for other reasons than a telegram as well.
+                let Some(received_at) = data.received_at.filter(|_| data.sequence != last_sequence)
+                else {
+                    continue;
+                };
+                last_sequence = data.sequence;
+                Sample::from_state(received_at, &data.dsmr_state)
+            };
+
+            if let Some(output) = &mut output {
+                if let Err(e) = output.write(&sample) {
+                    error!("Unable to export telegram: {}", e);
+                }
+            }
+            if let Ok(mut clients) = clients.lock() {
+                let record = encode(&sample);
+                clients.retain_mut(|client| client.write_all(&record).is_ok());
+            }
+        }
+    })
+}
@@ -0,0 +1,227 @@
+//! The export file as Parquet, with the values of a binary record as columns: `time`, the
+//! time the telegram was received as a UTC timestamp in ms, and a nullable float column
+//! per value, named like the metric it holds.
+//!
+//! Rows are written in row groups of an hour of telegrams, and the file is finished when
+//! its path changes. A file isn't readable before, and one the daemon stopped writing to
+//! never is. Parquet files can't be appended to, so a file that exists already is left
+//! alone and `-1`, `-2`, ... is added to the name of the new one.
+
+use std::{fs::File, path::Path, sync::Arc};
+
+use log::{error, info};
+use parquet::{
+    basic::Compression,
+    data_type::{FloatType, Int64Type},
+    errors::ParquetError,
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+
+use super::{path, values, VALUES};
+use crate::history::Sample;
+
+/// Names of the value columns, in the order of a record.
+const COLUMNS: [&str; VALUES] = [
+    "power_delivered",
+    "power_received",
+    "power_delivered_l1",
+    "power_delivered_l2",
+    "power_delivered_l3",
+    "power_received_l1",
+    "power_received_l2",
+    "power_received_l3",
+    "voltage_l1",
+    "voltage_l2",
+    "voltage_l3",
+    "current_l1",
+    "current_l2",
+    "current_l3",
+];
+
+/// Rows written at once, an hour of telegrams at one a second.
+const ROW_GROUP: usize = 3600;
+
+/// The Parquet file rows are written to, following the date in its path.
+pub struct ParquetFile {
+    pattern: String,
+    path: String,
+    writer: Option<SerializedFileWriter<File>>,
+    /// Rows waiting for a row group.
+    times: Vec<i64>,
+    rows: Vec<[Option<f32>; VALUES]>,
+}
+
+impl ParquetFile {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            path: String::new(),
+            writer: None,
+            times: Vec::new(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, sample: &Sample) -> Result<(), String> {
+        let path = path(&self.pattern)?;
+        if path != self.path {
+            if let Err(e) = self.finish() {
+                error!("Unable to export telegrams: {}", e);
+            }
+            self.path = path;
+        }
+        self.times.push(sample.timestamp as i64);
+        self.rows.push(values(sample));
+        if self.rows.len() >= ROW_GROUP {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    /// Write the waiting rows as a row group, creating the file first. On errors the file
+    /// is given up along with the rows.
+    fn write_row_group(&mut self) -> Result<(), String> {
+        let written = match &mut self.writer {
+            Some(writer) => write_rows(writer, &self.times, &self.rows),
+            None => create(&self.path).and_then(|(path, writer)| {
+                info!("Exporting telegrams to {}", path);
+                self.path = path;
+                write_rows(self.writer.insert(writer), &self.times, &self.rows)
+            }),
+        };
+        self.times.clear();
+        self.rows.clear();
+        written.map_err(|e| {
+            self.writer = None;
+            format!("unable to write {}: {}", self.path, e)
+        })
+    }
+
+    /// Write the waiting rows and the footer.
+    fn finish(&mut self) -> Result<(), String> {
+        if !self.rows.is_empty() {
+            self.write_row_group()?;
+        }
+        if let Some(writer) = self.writer.take() {
+            writer
+                .close()
+                .map_err(|e| format!("unable to finish {}: {}", self.path, e))?;
+            info!("Finished {}", self.path);
+        }
+        Ok(())
+    }
+}
+
+/// Create the file at `path`, or next to it when it exists. Returns the path of the file.
+fn create(path: &str) -> Result<(String, SerializedFileWriter<File>), ParquetError> {
+    let mut free = path.to_string();
+    let original = Path::new(path);
+    let stem = original.with_extension("");
+    let mut n = 0;
+    while Path::new(&free).exists() {
+        n += 1;
+        free = match original.extension() {
+            Some(extension) => format!("{}-{}.{}", stem.display(), n, extension.to_string_lossy()),
+            None => format!("{}-{}", path, n),
+        };
+    }
+    let columns: String = COLUMNS
+        .iter()
+        .map(|column| format!("optional float {};", column))
+        .collect();
+    let schema = parse_message_type(&format!(
+        "message export {{ required int64 time (TIMESTAMP(MILLIS, true)); {} }}",
+        columns
+    ))?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let file = File::create(&free)?;
+    let writer = SerializedFileWriter::new(file, Arc::new(schema), Arc::new(properties))?;
+    Ok((free, writer))
+}
+
+/// Write `rows` received at `times` as a row group.
+fn write_rows(
+    writer: &mut SerializedFileWriter<File>,
+    times: &[i64],
+    rows: &[[Option<f32>; VALUES]],
+) -> Result<(), ParquetError> {
+    let mut row_group = writer.next_row_group()?;
+    if let Some(mut column) = row_group.next_column()? {
+        column.typed::<Int64Type>().write_batch(times, None, None)?;
+        column.close()?;
+    }
+    for i in 0..VALUES {
+        let Some(mut column) = row_group.next_column()? else {
+            break;
+        };
+        // Missing values are left out of the values, with a level of 0.
+        let values: Vec<f32> = rows.iter().filter_map(|row| row[i]).collect();
+        let levels: Vec<i16> = rows.iter().map(|row| i16::from(row[i].is_some())).collect();
+        column
+            .typed::<FloatType>()
+            .write_batch(&values, Some(&levels), None)?;
+        column.close()?;
+    }
+    row_group.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::Field,
+    };
+
+    use super::*;
+    use crate::history::{Metric, METRIC_COUNT};
+
+    #[test]
+    fn rows() {
+        let dir = env::temp_dir().join(format!("dsmrd-export-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pattern = dir.join("export.parquet").to_string_lossy().into_owned();
+        // A file from before, which is left alone.
+        fs::write(&pattern, b"earlier").unwrap();
+
+        let mut file = ParquetFile::new(&pattern);
+        let mut sample = Sample::from_values(1000, [None; METRIC_COUNT]);
+        sample.set(Metric::PowerDelivered, Some(1.5));
+        sample.set(Metric::VoltageL1, Some(230.0));
+        file.write(&sample).unwrap();
+        file.write(&Sample::from_values(2000, [None; METRIC_COUNT]))
+            .unwrap();
+        file.finish().unwrap();
+        assert_eq!(fs::read(&pattern).unwrap(), b"earlier");
+
+        let written = dir.join("export-1.parquet");
+        let reader = SerializedFileReader::new(File::open(&written).unwrap()).unwrap();
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        let columns: Vec<_> = rows[0].get_column_iter().collect();
+        assert_eq!(columns.len(), VALUES + 1);
+        assert_eq!(columns[0].0, "time");
+        assert_eq!(columns[0].1, &Field::TimestampMillis(1000));
+        assert_eq!(
+            columns[1],
+            (&String::from("power_delivered"), &Field::Float(1500.0))
+        );
+        assert_eq!(columns[2].1, &Field::Null);
+        assert_eq!(
+            columns[9],
+            (&String::from("voltage_l1"), &Field::Float(230.0))
+        );
+        let missing = rows[1].get_column_iter().skip(1);
+        assert!(missing.into_iter().all(|(_, field)| *field == Field::Null));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
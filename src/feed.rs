@@ -0,0 +1,107 @@
+//! An Atom feed at `/feed.atom` with the alerts and power failures of `events` and a usage
+//! summary of each of the last days, so any feed reader can follow the meter.
+
+use std::{cmp::Reverse, fmt::Write, sync::Arc};
+
+use chrono::{DateTime, Days, Local, TimeZone, Utc};
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+
+use crate::{
+    appdata::AppData, events::EventKind, history::day_range, lock::RecoverLock,
+    report::DailySummary,
+};
+
+/// Number of daily summaries in the feed.
+const SUMMARY_DAYS: u64 = 7;
+/// Upper bound on the number of events in the feed.
+const MAX_EVENTS: usize = 50;
+
+struct Entry {
+    id: String,
+    title: String,
+    updated: DateTime<Utc>,
+    content: String,
+}
+
+/// `text` escaped for XML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn time(timestamp: u64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(timestamp as i64).single()
+}
+
+/// Handler for `/feed.atom`.
+pub async fn handler(appdata: Arc<AppData>) -> Result<Response<Body>, hyper::http::Error> {
+    let mut entries: Vec<Entry> = appdata
+        .events
+        .read_recover()
+        .all()
+        .iter()
+        .rev()
+        // The tariff changes twice a day, which is hardly news.
+        .filter(|event| event.kind != EventKind::TariffChange)
+        .take(MAX_EVENTS)
+        .filter_map(|event| {
+            Some(Entry {
+                id: format!("urn:dsmrd:event:{}", event.timestamp),
+                title: event.summary.clone(),
+                updated: time(event.timestamp)?,
+                content: event.description.clone(),
+            })
+        })
+        .collect();
+
+    let today = Local::now().date_naive();
+    let history = appdata.history.read_recover();
+    let prices = appdata.prices.read_recover();
+    for days in 1..=SUMMARY_DAYS {
+        let Some(date) = today.checked_sub_days(Days::new(days)) else {
+            continue;
+        };
+        let summary = DailySummary::compute(date, &history, appdata.config(), Some(&prices));
+        if summary.delivered.iter().all(Option::is_none) && summary.gas.is_none() {
+            continue;
+        }
+        let Some(updated) = time(day_range(date).1) else {
+            continue;
+        };
+        entries.push(Entry {
+            id: format!("urn:dsmrd:summary:{}", date),
+            title: format!("Energy usage on {}", date),
+            updated,
+            content: summary.to_text(),
+        });
+    }
+    drop(prices);
+    drop(history);
+    entries.sort_by_key(|entry| Reverse(entry.updated));
+
+    let updated = entries.first().map_or_else(Utc::now, |entry| entry.updated);
+    let mut atom = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    atom.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    atom.push_str("<title>Energy meter</title>\n<id>urn:dsmrd:feed</id>\n");
+    let _ = writeln!(atom, "<updated>{}</updated>", updated.to_rfc3339());
+    atom.push_str("<author><name>dsmrd</name></author>\n");
+    for entry in entries {
+        let _ = writeln!(
+            atom,
+            "<entry>\n<id>{}</id>\n<title>{}</title>\n<updated>{}</updated>\n\
+             <content type=\"text\">{}</content>\n</entry>",
+            entry.id,
+            escape(&entry.title),
+            entry.updated.to_rfc3339(),
+            escape(&entry.content)
+        );
+    }
+    atom.push_str("</feed>\n");
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/atom+xml; charset=utf-8")
+        .body(Body::from(atom))
+}
@@ -0,0 +1,146 @@
+//! Warning before the main fuse trips. Fuses and breakers trip on the heat a current builds
+//! up, so a short peak is fine while a current a little over the rating trips them once it
+//! lasts. The heat of every phase is modelled after the current the meter reports: it moves
+//! towards `(current / (trip_factor * rating))²` with `fuse.time_constant`, and the fuse
+//! trips when it reaches 1.
+//!
+//! When the heat of a phase would reach that within `fuse.warn_before` seconds if its
+//! current stays as it is, an urgent alert fires with the estimated time to trip. The alert
+//! resolves once no phase is on its way to trip. The model is a rough estimate, a fuse
+//! also heats up with the temperature of the meter cupboard.
+
+use std::{sync::Arc, thread, thread::JoinHandle, time::Duration};
+
+use crate::{
+    alerts,
+    appdata::AppData,
+    config::FuseConfig,
+    history::{now_millis, Metric, Sample},
+    lock::RecoverLock,
+    supervisor,
+};
+
+/// Name of the alert for a fuse about to trip.
+pub const ALERT: &str = "fuse";
+/// Time between checks of the new samples.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest time in milliseconds between telegrams the current of the first counts for.
+/// Across longer gaps the fuse only cools down.
+const MAX_GAP: u64 = 5 * 60 * 1000;
+
+const CURRENTS: [Metric; 3] = [Metric::CurrentL1, Metric::CurrentL2, Metric::CurrentL3];
+
+/// Heat of the fuse in every phase, 1 being the heat it trips at.
+#[derive(Debug, Default)]
+struct Model {
+    heat: [f64; 3],
+    /// Time and currents of the previous sample.
+    last: Option<(u64, [Option<f64>; 3])>,
+}
+
+impl Model {
+    /// Heat up or cool down the fuse over the time since the previous sample.
+    fn update(&mut self, config: &FuseConfig, sample: &Sample) {
+        let currents = CURRENTS.map(|metric| sample.get(metric));
+        if let Some((timestamp, last)) = self.last {
+            let elapsed = sample.timestamp.saturating_sub(timestamp);
+            let decay = (-(elapsed as f64) / (config.time_constant.max(1) * 1000) as f64).exp();
+            for (heat, current) in self.heat.iter_mut().zip(last) {
+                let target = match current.filter(|_| elapsed <= MAX_GAP) {
+                    Some(current) => steady_heat(config, current),
+                    None => 0.0,
+                };
+                *heat = target + (*heat - target) * decay;
+            }
+        }
+        self.last = Some((sample.timestamp, currents));
+    }
+
+    /// Seconds until the fuse of `phase` trips if its current stays as it is, if it will.
+    fn time_to_trip(&self, config: &FuseConfig, phase: usize) -> Option<f64> {
+        let current = self.last?.1[phase]?;
+        let target = steady_heat(config, current);
+        let heat = self.heat[phase];
+        if heat >= 1.0 {
+            return Some(0.0);
+        }
+        if target <= 1.0 {
+            return None;
+        }
+        Some(config.time_constant as f64 * ((target - heat) / (target - 1.0)).ln())
+    }
+}
+
+/// The heat a current holds the fuse at once it lasts.
+fn steady_heat(config: &FuseConfig, current: f64) -> f64 {
+    (current / (config.trip_factor * config.rating)).powi(2)
+}
+
+/// Spawn a thread that follows the heat of the fuse from the new samples, firing the alert
+/// when it is about to trip.
+pub fn spawn_fuse_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("fuse", appdata, |appdata| {
+        let Some(config) = appdata.config().fuse.as_ref() else {
+            return;
+        };
+        let mut model = Model::default();
+        let mut after = 0;
+        let mut alerted = None;
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+            for sample in appdata.history.read_recover().since(after) {
+                model.update(config, &sample);
+                after = sample.id;
+            }
+            // Old samples, read after a restart or while the meter is quiet, only heat up
+            // the model.
+            if model
+                .last
+                .is_none_or(|(timestamp, _)| now_millis().saturating_sub(timestamp) > MAX_GAP)
+            {
+                continue;
+            }
+            check(appdata, config, &model, &mut alerted);
+        }
+    })
+}
+
+/// Fire or resolve the alert. `alerted` is the description of the alert last fired, so the
+/// alert state is only written when it changes.
+fn check(appdata: &AppData, config: &FuseConfig, model: &Model, alerted: &mut Option<String>) {
+    let soonest = (0..3)
+        .filter_map(|phase| Some((phase, model.time_to_trip(config, phase)?)))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b));
+    let Some((phase, seconds)) = soonest else {
+        // Also resolves an alert left firing before a restart.
+        *alerted = None;
+        alerts::resolve(
+            appdata,
+            ALERT,
+            String::from("The current is back within what the main fuse holds."),
+        );
+        return;
+    };
+    if seconds > config.warn_before as f64 {
+        return;
+    }
+    let current = model.last.and_then(|(_, currents)| currents[phase]);
+    let description = format!(
+        "Phase L{} draws {:.0} A on a {} A fuse, which trips in about {} min if this goes \
+         on. Switch off a large load.",
+        phase + 1,
+        current.unwrap_or_default(),
+        config.rating,
+        (seconds / 60.0).ceil().max(1.0)
+    );
+    if alerted.as_ref() == Some(&description) {
+        return;
+    }
+    alerts::fire_urgent(
+        appdata,
+        ALERT,
+        String::from("Main fuse about to trip"),
+        description.clone(),
+    );
+    *alerted = Some(description);
+}
@@ -0,0 +1,183 @@
+//! Endpoints implementing the Grafana simple-json datasource API on top of the history store.
+//! The same endpoints can be used from the Infinity datasource.
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response, StatusCode};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    appdata::AppData,
+    history::{parse_time, Metric},
+    lock::RecoverLock,
+};
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SearchRequest {
+    target: String,
+}
+
+#[derive(Deserialize)]
+struct TimeRange {
+    from: String,
+    to: String,
+}
+
+#[derive(Deserialize)]
+struct Target {
+    target: String,
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueryRequest {
+    range: TimeRange,
+    #[serde(default)]
+    interval_ms: u64,
+    #[serde(default)]
+    targets: Vec<Target>,
+}
+
+#[derive(Serialize)]
+struct Column {
+    text: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum QueryResult {
+    TimeSerie {
+        target: String,
+        datapoints: Vec<(f64, u64)>,
+    },
+    Table {
+        columns: [Column; 2],
+        rows: Vec<(u64, f64)>,
+        #[serde(rename = "type")]
+        kind: &'static str,
+    },
+}
+
+/// Handler for all requests below `/grafana`.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    match path.as_str() {
+        // Used by grafana to test the datasource.
+        "/grafana" => Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("OK")),
+        "/grafana/search" => search(req).await,
+        "/grafana/query" => query(req, appdata).await,
+        // We have no annotations, but grafana expects this endpoint to exist.
+        "/grafana/annotations" => json_response("[]".to_string()),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Error: unknown grafana endpoint.")),
+    }
+}
+
+/// List the metrics available for graphing, optionally filtered by the requested target.
+async fn search(req: Request<Body>) -> Result<Response<Body>, hyper::http::Error> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return bad_request(format!("Error: unable to read request body: {}", e)),
+    };
+    // Grafana may send an empty body, which simply means 'everything'.
+    let search: SearchRequest = serde_json::from_slice(&body).unwrap_or_default();
+
+    let names: Vec<&str> = Metric::ALL
+        .iter()
+        .map(|m| m.name())
+        .filter(|name| name.contains(&search.target))
+        .collect();
+
+    match serde_json::to_string(&names) {
+        Ok(json) => json_response(json),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+/// Return the stored values for every requested target within the requested time range.
+async fn query(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return bad_request(format!("Error: unable to read request body: {}", e)),
+    };
+    let query: QueryRequest = match serde_json::from_slice(&body) {
+        Ok(query) => query,
+        Err(e) => return bad_request(format!("Error: invalid query: {}", e)),
+    };
+    debug!("Received grafana query for {} targets", query.targets.len());
+
+    let (from, to) = match (parse_time(&query.range.from), parse_time(&query.range.to)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return bad_request(String::from("Error: invalid time range.")),
+    };
+
+    let history = appdata.history.read_recover();
+
+    let mut results = Vec::new();
+    for target in query.targets {
+        let Some(metric) = Metric::from_name(&target.target) else {
+            return bad_request(format!("Error: unknown target {}", target.target));
+        };
+        let points = history.query(metric, from, to, query.interval_ms);
+
+        let result = if target.kind.as_deref() == Some("table") {
+            QueryResult::Table {
+                columns: [
+                    Column {
+                        text: "time",
+                        kind: "time",
+                    },
+                    Column {
+                        text: metric.name(),
+                        kind: "number",
+                    },
+                ],
+                rows: points.iter().map(|p| (p.timestamp, p.value)).collect(),
+                kind: "table",
+            }
+        } else {
+            QueryResult::TimeSerie {
+                target: target.target,
+                datapoints: points.iter().map(|p| (p.value, p.timestamp)).collect(),
+            }
+        };
+        results.push(result);
+    }
+
+    match serde_json::to_string(&results) {
+        Ok(json) => json_response(json),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+fn json_response(json: String) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json))
+}
+
+fn bad_request(message: String) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(message))
+}
@@ -0,0 +1,311 @@
+//! GraphQL API at `/graphql`, covering the meter state, derived values, history and the
+//! status of the daemon in a single schema.
+//!
+//! Queries are sent as POST, or as GET with the query in the `query` parameter.
+//! Subscriptions run over a WebSocket on the same path, using either the graphql-ws or the
+//! older subscriptions-transport-ws protocol.
+
+use std::{
+    str::FromStr,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use async_graphql::{
+    http::{parse_query_string, WebSocket, WebSocketProtocols, WsMessage},
+    BatchRequest, Context, Data, EmptyMutation, Error, Object, Result, Schema, SimpleObject,
+    Subscription,
+};
+use futures::{future, stream, SinkExt, Stream, StreamExt};
+use hyper::{
+    header::{CONTENT_TYPE, SEC_WEBSOCKET_PROTOCOL},
+    Body, Method, Request, Response, StatusCode,
+};
+use log::debug;
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+use crate::{
+    appdata::AppData,
+    derived::Derived,
+    endpoints::{DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT},
+    history::{parse_time, Metric, Sample},
+    lock::RecoverLock,
+    model::MeterState,
+    reader::ReaderData,
+    sink::SinkStatus,
+    status::ThreadStatus,
+    websocket::{self, Socket},
+};
+
+type DsmrSchema = Schema<Query, EmptyMutation, Subscription>;
+
+fn schema() -> &'static DsmrSchema {
+    static SCHEMA: OnceLock<DsmrSchema> = OnceLock::new();
+    SCHEMA.get_or_init(|| Schema::new(Query, EmptyMutation, Subscription))
+}
+
+/// Where the resolvers get their data, passed along with every request.
+#[derive(Clone)]
+struct Sources {
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+}
+
+impl Sources {
+    fn meter_state(&self) -> MeterState {
+        self.data.read_recover().dsmr_state.clone()
+    }
+}
+
+struct Query;
+
+#[Object]
+impl Query {
+    /// The state of the meter according to the latest telegram.
+    async fn state(&self, ctx: &Context<'_>) -> Result<MeterState> {
+        Ok(ctx.data::<Sources>()?.meter_state())
+    }
+
+    /// Values derived from the latest telegram and recent history.
+    async fn derived(&self, ctx: &Context<'_>) -> Result<Derived> {
+        let sources = ctx.data::<Sources>()?;
+        let history = sources.appdata.history.read_recover();
+        Ok(history
+            .latest()
+            .map(|latest| Derived::compute(&latest, &history))
+            .unwrap_or_default())
+    }
+
+    /// Samples recorded between `from` and `to`, oldest first. Times are given in
+    /// milliseconds since the unix epoch or as RFC 3339.
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<String>,
+        to: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Sample>> {
+        let parse = |time: Option<String>, default: u64| match time {
+            Some(time) => parse_time(&time).ok_or_else(|| Error::new("Invalid time")),
+            None => Ok(default),
+        };
+        let (from, to) = (parse(from, 0)?, parse(to, u64::MAX)?);
+        let sources = ctx.data::<Sources>()?;
+        let history = sources.appdata.history.read_recover();
+        Ok(history
+            .query_range(from, to)
+            .take(
+                limit
+                    .unwrap_or(DEFAULT_HISTORY_LIMIT)
+                    .clamp(1, MAX_HISTORY_LIMIT),
+            )
+            .collect())
+    }
+
+    /// What the daemon is up to.
+    async fn status(&self, ctx: &Context<'_>) -> Result<Status> {
+        let sources = ctx.data::<Sources>()?;
+        let (reader, telegrams) = {
+            let data = sources.data.read_recover();
+            (data.status.current(), data.sequence)
+        };
+        let sinks = sources
+            .appdata
+            .sinks
+            .read_recover()
+            .iter()
+            .map(|handle| SinkInfo {
+                id: handle.id.clone(),
+                kind: handle.kind,
+                enabled: handle.is_enabled(),
+                status: handle.status(),
+            })
+            .collect();
+        Ok(Status {
+            reader,
+            telegrams,
+            clients: sources.appdata.list_clients()?,
+            sinks,
+        })
+    }
+}
+
+#[derive(SimpleObject)]
+struct Status {
+    reader: ThreadStatus,
+    /// Number of telegrams received.
+    telegrams: u64,
+    /// Clients registered to receive telegrams over UDP.
+    clients: Vec<String>,
+    sinks: Vec<SinkInfo>,
+}
+
+#[derive(SimpleObject)]
+struct SinkInfo {
+    id: String,
+    #[graphql(name = "type")]
+    kind: &'static str,
+    enabled: bool,
+    status: SinkStatus,
+}
+
+#[derive(SimpleObject)]
+struct MetricValue {
+    metric: Metric,
+    value: f64,
+}
+
+/// A snapshot of the interesting values of a single telegram.
+#[Object]
+impl Sample {
+    /// Time of reception in milliseconds since the unix epoch.
+    async fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// The value of a single metric.
+    async fn value(&self, metric: Metric) -> Option<f64> {
+        self.get(metric)
+    }
+
+    /// All metrics with a value.
+    async fn values(&self) -> Vec<MetricValue> {
+        Metric::ALL
+            .into_iter()
+            .filter_map(|metric| {
+                Some(MetricValue {
+                    metric,
+                    value: self.get(metric)?,
+                })
+            })
+            .collect()
+    }
+}
+
+struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    /// The state of the meter, every time a telegram arrives.
+    async fn telegrams(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = MeterState>> {
+        let sources = ctx.data::<Sources>()?.clone();
+        Ok(stream::unfold(sources, |sources| async move {
+            sources.appdata.event_listener().await;
+            let state = sources.meter_state();
+            Some((state, sources))
+        }))
+    }
+
+    /// The latest sample, every time one is recorded.
+    async fn samples(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = Sample>> {
+        let sources = ctx.data::<Sources>()?.clone();
+        Ok(stream::unfold(sources, |sources| async move {
+            sources.appdata.event_listener().await;
+            let sample = sources.appdata.history.read_recover().latest()?.clone();
+            Some((sample, sources))
+        }))
+    }
+}
+
+/// Handler for `/graphql`.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let sources = Sources { appdata, data };
+    if websocket::is_upgrade(&req) {
+        return subscribe(req, sources);
+    }
+
+    let request = match *req.method() {
+        Method::GET => parse_query_string(req.uri().query().unwrap_or_default())
+            .map(BatchRequest::from)
+            .map_err(|e| e.to_string()),
+        Method::POST => match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => serde_json::from_slice::<BatchRequest>(&body).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        },
+        _ => {
+            return Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::from("Error: method not allowed."))
+        }
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Error: invalid GraphQL request: {}", e)))
+        }
+    };
+
+    let response = schema().execute_batch(request.data(sources)).await;
+    match serde_json::to_string(&response) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+/// Upgrade the connection to a WebSocket running subscriptions.
+fn subscribe(req: Request<Body>, sources: Sources) -> Result<Response<Body>, hyper::http::Error> {
+    let protocol = req
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|protocols| protocols.to_str().ok())
+        .and_then(|protocols| {
+            protocols
+                .split(',')
+                .find_map(|protocol| WebSocketProtocols::from_str(protocol.trim()).ok())
+        });
+    let Some(protocol) = protocol else {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(
+                "Error: expected the graphql-transport-ws or graphql-ws protocol.",
+            ));
+    };
+    websocket::upgrade(
+        req,
+        Some(protocol.sec_websocket_protocol()),
+        move |socket| run_subscriptions(socket, protocol, sources),
+    )
+}
+
+async fn run_subscriptions(socket: Socket, protocol: WebSocketProtocols, sources: Sources) {
+    let (mut sink, stream) = socket.split();
+    let input = stream
+        .take_while(|message| future::ready(matches!(message, Ok(m) if !m.is_close())))
+        .filter_map(|message| {
+            future::ready(match message {
+                Ok(Message::Text(text)) => Some(text.into_bytes()),
+                Ok(Message::Binary(data)) => Some(data),
+                _ => None,
+            })
+        });
+
+    let mut data = Data::default();
+    data.insert(sources);
+    let mut output = WebSocket::new(schema().clone(), input, protocol).connection_data(data);
+    while let Some(message) = output.next().await {
+        let message = match message {
+            WsMessage::Text(text) => Message::Text(text),
+            WsMessage::Close(code, reason) => Message::Close(Some(CloseFrame {
+                code: CloseCode::from(code),
+                reason: reason.into(),
+            })),
+        };
+        if sink.send(message).await.is_err() {
+            break;
+        }
+    }
+    debug!("GraphQL subscription connection closed");
+}
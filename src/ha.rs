@@ -0,0 +1,241 @@
+//! Active/standby pairs of daemons reading the same meter through two P1 splitters, so
+//! only one of them publishes to the sinks they share. Each polls the other at `/ha`. A
+//! daemon reading telegrams becomes active when the other isn't, or can't, and the
+//! priority decides which one when both could. When the active daemon stops answering or
+//! reading telegrams for the lease time, the standby takes over.
+//!
+//! Without a configured peer, the daemons of a pair find each other over mDNS.
+
+use std::{
+    sync::{Arc, RwLock},
+    thread,
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    allowlist, appdata::AppData, config::HaConfig, dial::Dialer, history::now_millis,
+    http_client::HttpClient, lock::RecoverLock, reader::ReaderData, supervisor,
+};
+
+#[cfg(feature = "mdns")]
+mod mdns;
+
+/// What a daemon of a pair reports about itself at `/ha`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub id: String,
+    pub priority: u32,
+    /// Whether it read a telegram within the lease time.
+    pub healthy: bool,
+    pub active: bool,
+}
+
+/// The state of a daemon of a pair as served at `/ha`.
+#[derive(Serialize)]
+struct Report<'a> {
+    #[serde(flatten)]
+    status: Status,
+    since: u64,
+    peer: Option<&'a Status>,
+    peer_seen: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct HaState {
+    pub active: bool,
+    /// Time of the last change between active and standby.
+    since: u64,
+    peer: Option<Status>,
+    /// Time the peer last answered.
+    peer_seen: Option<u64>,
+}
+
+impl HaState {
+    /// A daemon of a pair starts as standby, so after a restart it doesn't publish
+    /// alongside the active one.
+    pub fn new(paired: bool) -> Self {
+        Self {
+            active: !paired,
+            since: now_millis(),
+            peer: None,
+            peer_seen: None,
+        }
+    }
+}
+
+/// Whether a telegram was read within the lease time.
+fn healthy(reader_data: &RwLock<ReaderData>, config: &HaConfig) -> bool {
+    let received_at = reader_data.read_recover().received_at;
+    received_at.is_some_and(|at| now_millis().saturating_sub(at) <= config.lease * 1000)
+}
+
+/// Whether this daemon goes before `peer` when both could be active.
+fn wins(config: &HaConfig, peer: &Status) -> bool {
+    (config.priority, &peer.id) > (peer.priority, &config.id)
+}
+
+/// Spawn a thread that polls the peer and decides whether this daemon is active.
+pub fn spawn_ha_job(
+    appdata: Arc<AppData>,
+    reader_data: Arc<RwLock<ReaderData>>,
+) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("ha", appdata, move |appdata| {
+        let Some(config) = &appdata.config().ha else {
+            return;
+        };
+        let outbound = &appdata.config().outbound;
+        if let Some(peer) = &config.peer {
+            if let Err(e) = allowlist::check_url(&outbound.allow, peer) {
+                error!("Unable to poll peer {}: {}", peer, e);
+                return;
+            }
+        }
+        let client = match HttpClient::new(
+            Dialer::with_proxy(outbound.proxy.as_ref()).allowing(&outbound.allow),
+        ) {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Unable to poll the peer: {}", e);
+                return;
+            }
+        };
+        #[cfg(feature = "mdns")]
+        let mut discovery = match config.peer {
+            Some(_) => None,
+            None => match mdns::Discovery::start(config, appdata.local_addr()) {
+                Ok(discovery) => Some(discovery),
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            },
+        };
+        #[cfg(not(feature = "mdns"))]
+        if config.peer.is_none() {
+            error!("No peer to pair with: set one, or build with the mdns feature to find it.");
+            return;
+        }
+        let authorization = config
+            .token
+            .as_ref()
+            .map(|token| format!("Bearer {}", token));
+        let headers: Vec<(&str, &str)> = authorization
+            .iter()
+            .map(|authorization| ("Authorization", authorization.as_str()))
+            .collect();
+        match &config.peer {
+            Some(peer) => info!("Pairing with {} as {}.", peer, config.id),
+            None => info!("Looking for a peer over mDNS as {}.", config.id),
+        }
+
+        let mut reachable = true;
+        loop {
+            let peer = match &config.peer {
+                Some(peer) => Some(peer.clone()),
+                #[cfg(feature = "mdns")]
+                None => discovery
+                    .as_mut()
+                    .and_then(|discovery| discovery.peer())
+                    .map(str::to_string),
+                #[cfg(not(feature = "mdns"))]
+                None => None,
+            };
+            let name = peer.as_deref().unwrap_or("the peer");
+            // A peer found over mDNS is only polled when it's allowed.
+            let polled = peer.as_ref().map(|peer| {
+                allowlist::check_url(&outbound.allow, peer).and_then(|_| {
+                    let url = format!("{}/ha", peer.trim_end_matches('/'));
+                    client.get(&url, &headers).and_then(|body| {
+                        serde_json::from_slice::<Status>(&body).map_err(|e| e.to_string())
+                    })
+                })
+            });
+            let now = now_millis();
+            let healthy = healthy(&reader_data, config);
+            let mut state = appdata.ha.write_recover();
+            match polled {
+                Some(Ok(peer)) => {
+                    if !reachable {
+                        info!("Peer {} answers again.", name);
+                    }
+                    reachable = true;
+                    state.peer = Some(peer);
+                    state.peer_seen = Some(now);
+                }
+                Some(Err(e)) if reachable => {
+                    warn!("Unable to poll peer {}: {}", name, e);
+                    reachable = false;
+                }
+                Some(Err(e)) => debug!("Unable to poll peer {}: {}", name, e),
+                None => debug!("No peer found over mDNS yet."),
+            }
+
+            let peer = state
+                .peer
+                .as_ref()
+                .filter(|_| {
+                    state
+                        .peer_seen
+                        .is_some_and(|seen| now.saturating_sub(seen) <= config.lease * 1000)
+                })
+                .filter(|peer| peer.healthy);
+            let active = healthy
+                && match peer {
+                    None => true,
+                    // Both may have claimed the lease at the same time.
+                    Some(peer) if peer.active => state.active && wins(config, peer),
+                    Some(peer) => wins(config, peer) || state.active,
+                };
+            if active != state.active {
+                if active {
+                    info!("Active now, publishing to sinks.");
+                } else {
+                    info!("Standby now, leaving the sinks to {}.", name);
+                }
+                state.active = active;
+                state.since = now;
+            }
+            drop(state);
+            thread::sleep(Duration::from_secs(config.interval.max(1)));
+        }
+    })
+}
+
+/// Handler for `/ha`, reporting the state of this daemon to its peer.
+pub async fn handler(
+    appdata: Arc<AppData>,
+    reader_data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let Some(config) = &appdata.config().ha else {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Error: not paired with another daemon."));
+    };
+    let healthy = healthy(&reader_data, config);
+    let state = appdata.ha.read_recover();
+    let report = Report {
+        status: Status {
+            id: config.id.clone(),
+            priority: config.priority,
+            healthy,
+            active: state.active,
+        },
+        since: state.since,
+        peer: state.peer.as_ref(),
+        peer_seen: state.peer_seen,
+    };
+    match serde_json::to_string(&report) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
@@ -0,0 +1,108 @@
+//! Finding the other daemon of a pair over mDNS. Each daemon advertises its HTTP address
+//! as a `_dsmrd-ha._tcp` service named after its id, with its group in the TXT record, and
+//! takes the other one of the same group it finds as its peer.
+
+use std::net::SocketAddr;
+
+use log::info;
+use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::config::HaConfig;
+
+const SERVICE_TYPE: &str = "_dsmrd-ha._tcp.local.";
+
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    events: Receiver<ServiceEvent>,
+    /// Full name of the service of this daemon.
+    own: String,
+    group: String,
+    /// Full name and address of the other daemon, as last found.
+    peer: Option<(String, String)>,
+}
+
+impl Discovery {
+    /// Advertise this daemon listening at `addr`, on every interface when it listens on
+    /// all of them, and start looking for the other one.
+    pub fn start(config: &HaConfig, addr: &SocketAddr) -> Result<Self, String> {
+        let error = |e: mdns_sd::Error| format!("Unable to use mDNS: {}", e);
+        let daemon = ServiceDaemon::new().map_err(error)?;
+        let group = config.group.clone().unwrap_or_default();
+        let host = format!("{}.local.", config.id);
+        let properties = [("group", group.as_str())];
+        let service = if addr.ip().is_unspecified() {
+            ServiceInfo::new(
+                SERVICE_TYPE,
+                &config.id,
+                &host,
+                (),
+                addr.port(),
+                &properties[..],
+            )
+            .map(ServiceInfo::enable_addr_auto)
+        } else {
+            ServiceInfo::new(
+                SERVICE_TYPE,
+                &config.id,
+                &host,
+                addr.ip(),
+                addr.port(),
+                &properties[..],
+            )
+        }
+        .map_err(error)?;
+        let own = service.get_fullname().to_string();
+        daemon.register(service).map_err(error)?;
+        let events = daemon.browse(SERVICE_TYPE).map_err(error)?;
+        Ok(Self {
+            daemon,
+            events,
+            own,
+            group,
+            peer: None,
+        })
+    }
+
+    /// Address of the other daemon, e.g. `http://192.168.1.20:3000`, if it was found and
+    /// didn't leave since.
+    pub fn peer(&mut self) -> Option<&str> {
+        for event in self.events.try_iter() {
+            match event {
+                ServiceEvent::ServiceResolved(service) => {
+                    if service.fullname == self.own
+                        || service.get_property_val_str("group").unwrap_or_default() != self.group
+                    {
+                        continue;
+                    }
+                    let Some(ip) = service.get_addresses_v4().into_iter().min() else {
+                        continue;
+                    };
+                    let url = format!("http://{}:{}", ip, service.port);
+                    if self.peer.as_ref().map(|(_, found)| found) != Some(&url) {
+                        info!("Found peer {} at {} over mDNS.", service.fullname, url);
+                    }
+                    self.peer = Some((service.fullname, url));
+                }
+                ServiceEvent::ServiceRemoved(_, fullname)
+                    if self
+                        .peer
+                        .as_ref()
+                        .is_some_and(|(name, _)| *name == fullname) =>
+                {
+                    info!("Peer {} left.", fullname);
+                    self.peer = None;
+                }
+                _ => {}
+            }
+        }
+        self.peer.as_ref().map(|(_, url)| url.as_str())
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        // Tells the other daemon this one left.
+        let _ = self.daemon.unregister(&self.own);
+        let _ = self.daemon.shutdown();
+    }
+}
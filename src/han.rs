@@ -0,0 +1,54 @@
+//! Vendor specific layouts of the Nordic HAN port, as found on Aidon, Kamstrup and Kaifa
+//! meters. Aidon sends standard OBIS tagged lists which need nothing special.
+
+use crate::{
+    dlms::{parse_datetime, Data},
+    model::MeterState,
+};
+
+/// Kamstrup sends current in 0.01 A and energy in 10 Wh, everything else without scaling.
+pub fn kamstrup_scaler(c: u8, d: u8) -> i32 {
+    match (c, d) {
+        (31 | 51 | 71, 7) => -2,
+        (_, 8) => 1,
+        _ => 0,
+    }
+}
+
+/// Kaifa sends its lists without OBIS codes, so values are identified by their position.
+/// The length of the list tells us which list it is, and whether the meter has one or
+/// three phases.
+pub fn decode_kaifa(values: &[Data]) -> MeterState {
+    let mut state = MeterState::default();
+    let number = |i: usize| values.get(i).and_then(Data::as_f64);
+
+    // List 1 only holds the current power.
+    if values.len() == 1 {
+        state.power_delivered = number(0).map(|w| w / 1000.0);
+        return state;
+    }
+
+    state.power_delivered = number(3).map(|w| w / 1000.0);
+    state.power_received = number(4).map(|w| w / 1000.0);
+    state.reactive_power_delivered = number(5).map(|var| var / 1000.0);
+    state.reactive_power_received = number(6).map(|var| var / 1000.0);
+
+    let (phases, energy_offset) = match values.len() {
+        9 | 14 => (1, 10),
+        _ => (3, 14),
+    };
+    for phase in 0..phases {
+        state.phases[phase].current = number(7 + phase).map(|ma| ma / 1000.0);
+        state.phases[phase].voltage = number(7 + phases + phase).map(|dv| dv / 10.0);
+    }
+
+    // List 3 adds the clock and the energy registers.
+    if let Some(Data::OctetString(datetime)) = values.get(energy_offset - 1) {
+        state.datetime = parse_datetime(datetime);
+        state.energy_delivered[0] = number(energy_offset).map(|wh| wh / 1000.0);
+        state.energy_received[0] = number(energy_offset + 1).map(|wh| wh / 1000.0);
+        state.reactive_energy_delivered[0] = number(energy_offset + 2).map(|varh| varh / 1000.0);
+        state.reactive_energy_received[0] = number(energy_offset + 3).map(|varh| varh / 1000.0);
+    }
+    state
+}
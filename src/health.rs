@@ -0,0 +1,216 @@
+//! The health of every part of the daemon in one place, served at `/health/detail`, so
+//! dashboards and scripts can tell with a single request whether all is well. Answers 503
+//! when any part is down.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use serde::Serialize;
+
+use crate::{
+    appdata::AppData,
+    history::now_millis,
+    lock::RecoverLock,
+    reader::ReaderData,
+    sink::BreakerState,
+    status::{LastError, ThreadStatus},
+    supervisor,
+};
+
+/// Responses of the HTTP server, to report its health.
+static HTTP: Mutex<HttpState> = Mutex::new(HttpState {
+    last_success: None,
+    last_error: None,
+});
+
+struct HttpState {
+    last_success: Option<u64>,
+    last_error: Option<LastError>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum State {
+    Ok,
+    /// Working, but not as it should.
+    Degraded,
+    Down,
+    /// Turned off on purpose.
+    Disabled,
+}
+
+#[derive(Serialize)]
+struct Subsystem {
+    name: String,
+    state: State,
+    /// Time of the last successful operation in milliseconds since the unix epoch.
+    last_success: Option<u64>,
+    last_error: Option<LastError>,
+    restarts: u64,
+}
+
+#[derive(Serialize)]
+struct Health {
+    /// Whether no subsystem is down.
+    healthy: bool,
+    subsystems: Vec<Subsystem>,
+}
+
+/// Note the outcome of an HTTP request for `path`. Only internal errors count as errors,
+/// a 503 is how some endpoints report on something else being unavailable.
+pub fn record_response(path: &str, status: Result<StatusCode, &hyper::http::Error>) {
+    let Ok(mut http) = HTTP.lock() else {
+        return;
+    };
+    match status {
+        Ok(status) if status != StatusCode::INTERNAL_SERVER_ERROR => {
+            http.last_success = Some(now_millis())
+        }
+        Ok(status) => http.last_error = Some(LastError::now(format!("{} on {}", status, path))),
+        Err(e) => http.last_error = Some(LastError::now(format!("{} on {}", e, path))),
+    }
+}
+
+fn reader(appdata: &AppData, data: &RwLock<ReaderData>) -> Subsystem {
+    let data = data.read_recover();
+    let stale = data.is_outdated(data.stale_after(appdata.config().output.stale_after));
+    let state = match data.status.current() {
+        ThreadStatus::Running if stale => State::Degraded,
+        ThreadStatus::Running | ThreadStatus::Sleeping => State::Ok,
+        ThreadStatus::Starting | ThreadStatus::Disconnected => State::Degraded,
+        ThreadStatus::Stopping | ThreadStatus::Stopped => State::Disabled,
+        ThreadStatus::Failed => State::Down,
+    };
+    Subsystem {
+        name: String::from("reader"),
+        state,
+        last_success: data.received_at,
+        last_error: data.last_error.clone(),
+        restarts: data.reconnects,
+    }
+}
+
+fn sinks(appdata: &AppData) -> Vec<Subsystem> {
+    appdata
+        .sinks
+        .read_recover()
+        .iter()
+        .map(|sink| {
+            let status = sink.status();
+            let state = if !sink.is_enabled() {
+                State::Disabled
+            } else if status.breaker == BreakerState::Open || status.connected == Some(false) {
+                State::Down
+            } else if status.consecutive_errors > 0 {
+                State::Degraded
+            } else {
+                State::Ok
+            };
+            Subsystem {
+                name: format!("sink-{}", sink.id),
+                state,
+                last_success: status.last_publish,
+                last_error: status
+                    .last_error
+                    .zip(status.last_error_at)
+                    .map(|(message, at)| LastError { at, message }),
+                restarts: supervisor::restarts(&format!("sink-{}", sink.id)),
+            }
+        })
+        .collect()
+}
+
+/// The annual state file, the only state written to disk.
+fn storage(appdata: &AppData) -> Subsystem {
+    let annual = appdata.annual.read_recover();
+    let last_success = annual.last_saved();
+    let last_error = annual.last_error().cloned();
+    let state = match (&last_error, annual.state_file()) {
+        (_, None) => State::Disabled,
+        (Some(error), _) if last_success.is_none_or(|saved| saved < error.at) => State::Down,
+        _ => State::Ok,
+    };
+    Subsystem {
+        name: String::from("storage"),
+        state,
+        last_success,
+        last_error,
+        restarts: 0,
+    }
+}
+
+/// The connection to the MQTT broker, disabled without one.
+#[cfg(feature = "mqtt")]
+fn mqtt(appdata: &AppData) -> Subsystem {
+    let Some(mqtt) = appdata.mqtt() else {
+        return Subsystem {
+            name: String::from("mqtt"),
+            state: State::Disabled,
+            last_success: None,
+            last_error: None,
+            restarts: 0,
+        };
+    };
+    let status = mqtt.status();
+    let state = match (mqtt.connected(), &status.last_error) {
+        (true, _) => State::Ok,
+        // Still connecting.
+        (false, None) => State::Degraded,
+        (false, Some(_)) => State::Down,
+    };
+    Subsystem {
+        name: String::from("mqtt"),
+        state,
+        last_success: status.last_success,
+        last_error: status.last_error,
+        restarts: status.reconnects,
+    }
+}
+
+fn http() -> Subsystem {
+    let (last_success, last_error) = match HTTP.lock() {
+        Ok(http) => (http.last_success, http.last_error.clone()),
+        Err(_) => (None, None),
+    };
+    Subsystem {
+        name: String::from("http"),
+        // It answers this request.
+        state: State::Ok,
+        last_success,
+        last_error,
+        restarts: 0,
+    }
+}
+
+/// Handler for `/health/detail`.
+pub async fn handler(
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let mut subsystems = vec![reader(&appdata, &data)];
+    subsystems.extend(sinks(&appdata));
+    subsystems.push(storage(&appdata));
+    #[cfg(feature = "mqtt")]
+    subsystems.push(mqtt(&appdata));
+    subsystems.push(http());
+    let healthy = subsystems
+        .iter()
+        .all(|subsystem| subsystem.state != State::Down);
+
+    match serde_json::to_string(&Health {
+        healthy,
+        subsystems,
+    }) {
+        Ok(json) => Response::builder()
+            .status(if healthy {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            })
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
@@ -0,0 +1,703 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::ops::{Deref, DerefMut};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Days, Local, NaiveDate, TimeZone};
+use log::error;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use crate::sql;
+use crate::{
+    config::{HistoryConfig, HistoryEngine},
+    journal::JournalStore,
+    model::MeterState,
+};
+
+/// Number of samples kept in memory: one day of telegrams at the DSMR5 rate of one per second.
+pub const DEFAULT_CAPACITY: usize = 86_400;
+
+/// The values we keep track of in the history store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+pub enum Metric {
+    PowerDelivered,
+    PowerReceived,
+    EnergyDeliveredTariff1,
+    EnergyDeliveredTariff2,
+    EnergyReceivedTariff1,
+    EnergyReceivedTariff2,
+    GasDelivered,
+    VoltageL1,
+    VoltageL2,
+    VoltageL3,
+    CurrentL1,
+    CurrentL2,
+    CurrentL3,
+    PowerDeliveredL1,
+    PowerDeliveredL2,
+    PowerDeliveredL3,
+    PowerReceivedL1,
+    PowerReceivedL2,
+    PowerReceivedL3,
+    /// Estimated from the power of the phase, see `phase_energy`.
+    EnergyDeliveredL1,
+    EnergyDeliveredL2,
+    EnergyDeliveredL3,
+    EnergyReceivedL1,
+    EnergyReceivedL2,
+    EnergyReceivedL3,
+    /// Reported by some meters only, the energy summed over the tariffs.
+    ReactivePowerDelivered,
+    ReactivePowerReceived,
+    ReactiveEnergyDelivered,
+    ReactiveEnergyReceived,
+}
+
+pub const METRIC_COUNT: usize = 29;
+
+impl Metric {
+    pub const ALL: [Metric; METRIC_COUNT] = [
+        Metric::PowerDelivered,
+        Metric::PowerReceived,
+        Metric::EnergyDeliveredTariff1,
+        Metric::EnergyDeliveredTariff2,
+        Metric::EnergyReceivedTariff1,
+        Metric::EnergyReceivedTariff2,
+        Metric::GasDelivered,
+        Metric::VoltageL1,
+        Metric::VoltageL2,
+        Metric::VoltageL3,
+        Metric::CurrentL1,
+        Metric::CurrentL2,
+        Metric::CurrentL3,
+        Metric::PowerDeliveredL1,
+        Metric::PowerDeliveredL2,
+        Metric::PowerDeliveredL3,
+        Metric::PowerReceivedL1,
+        Metric::PowerReceivedL2,
+        Metric::PowerReceivedL3,
+        Metric::EnergyDeliveredL1,
+        Metric::EnergyDeliveredL2,
+        Metric::EnergyDeliveredL3,
+        Metric::EnergyReceivedL1,
+        Metric::EnergyReceivedL2,
+        Metric::EnergyReceivedL3,
+        Metric::ReactivePowerDelivered,
+        Metric::ReactivePowerReceived,
+        Metric::ReactiveEnergyDelivered,
+        Metric::ReactiveEnergyReceived,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::PowerDelivered => "power_delivered",
+            Metric::PowerReceived => "power_received",
+            Metric::EnergyDeliveredTariff1 => "energy_delivered_tariff1",
+            Metric::EnergyDeliveredTariff2 => "energy_delivered_tariff2",
+            Metric::EnergyReceivedTariff1 => "energy_received_tariff1",
+            Metric::EnergyReceivedTariff2 => "energy_received_tariff2",
+            Metric::GasDelivered => "gas_delivered",
+            Metric::VoltageL1 => "voltage_l1",
+            Metric::VoltageL2 => "voltage_l2",
+            Metric::VoltageL3 => "voltage_l3",
+            Metric::CurrentL1 => "current_l1",
+            Metric::CurrentL2 => "current_l2",
+            Metric::CurrentL3 => "current_l3",
+            Metric::PowerDeliveredL1 => "power_delivered_l1",
+            Metric::PowerDeliveredL2 => "power_delivered_l2",
+            Metric::PowerDeliveredL3 => "power_delivered_l3",
+            Metric::PowerReceivedL1 => "power_received_l1",
+            Metric::PowerReceivedL2 => "power_received_l2",
+            Metric::PowerReceivedL3 => "power_received_l3",
+            Metric::EnergyDeliveredL1 => "energy_delivered_l1",
+            Metric::EnergyDeliveredL2 => "energy_delivered_l2",
+            Metric::EnergyDeliveredL3 => "energy_delivered_l3",
+            Metric::EnergyReceivedL1 => "energy_received_l1",
+            Metric::EnergyReceivedL2 => "energy_received_l2",
+            Metric::EnergyReceivedL3 => "energy_received_l3",
+            Metric::ReactivePowerDelivered => "reactive_power_delivered",
+            Metric::ReactivePowerReceived => "reactive_power_received",
+            Metric::ReactiveEnergyDelivered => "reactive_energy_delivered",
+            Metric::ReactiveEnergyReceived => "reactive_energy_received",
+        }
+    }
+
+    /// Whether the metric is a meter total, which only goes up until the meter is reset.
+    pub fn is_cumulative(&self) -> bool {
+        matches!(
+            self,
+            Metric::EnergyDeliveredTariff1
+                | Metric::EnergyDeliveredTariff2
+                | Metric::EnergyReceivedTariff1
+                | Metric::EnergyReceivedTariff2
+                | Metric::GasDelivered
+                | Metric::EnergyDeliveredL1
+                | Metric::EnergyDeliveredL2
+                | Metric::EnergyDeliveredL3
+                | Metric::EnergyReceivedL1
+                | Metric::EnergyReceivedL2
+                | Metric::EnergyReceivedL3
+                | Metric::ReactiveEnergyDelivered
+                | Metric::ReactiveEnergyReceived
+        )
+    }
+
+    pub fn from_name(name: &str) -> Option<Metric> {
+        Metric::ALL.iter().find(|m| m.name() == name).copied()
+    }
+}
+
+/// A snapshot of the interesting values of a single telegram.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    /// Identifies the sample, assigned in increasing order when stored.
+    pub id: u64,
+    /// Time of reception in milliseconds since the unix epoch.
+    pub timestamp: u64,
+    values: [Option<f64>; METRIC_COUNT],
+}
+
+impl Sample {
+    pub fn from_state(timestamp: u64, state: &MeterState) -> Self {
+        let mut values = [None; METRIC_COUNT];
+        let mut set = |metric: Metric, value: Option<f64>| values[metric as usize] = value;
+
+        set(Metric::PowerDelivered, state.power_delivered);
+        set(Metric::PowerReceived, state.power_received);
+        set(Metric::EnergyDeliveredTariff1, state.energy_delivered[0]);
+        set(Metric::EnergyDeliveredTariff2, state.energy_delivered[1]);
+        set(Metric::EnergyReceivedTariff1, state.energy_received[0]);
+        set(Metric::EnergyReceivedTariff2, state.energy_received[1]);
+        set(Metric::GasDelivered, state.gas().map(|m| m.value));
+        set(
+            Metric::ReactivePowerDelivered,
+            state.reactive_power_delivered,
+        );
+        set(Metric::ReactivePowerReceived, state.reactive_power_received);
+        let total = |tariffs: [Option<f64>; 2]| tariffs.into_iter().flatten().reduce(|a, b| a + b);
+        set(
+            Metric::ReactiveEnergyDelivered,
+            total(state.reactive_energy_delivered),
+        );
+        set(
+            Metric::ReactiveEnergyReceived,
+            total(state.reactive_energy_received),
+        );
+
+        let lines = [
+            (
+                Metric::VoltageL1,
+                Metric::CurrentL1,
+                Metric::PowerDeliveredL1,
+                Metric::PowerReceivedL1,
+            ),
+            (
+                Metric::VoltageL2,
+                Metric::CurrentL2,
+                Metric::PowerDeliveredL2,
+                Metric::PowerReceivedL2,
+            ),
+            (
+                Metric::VoltageL3,
+                Metric::CurrentL3,
+                Metric::PowerDeliveredL3,
+                Metric::PowerReceivedL3,
+            ),
+        ];
+        for (phase, (voltage, current, delivered, received)) in state.phases.iter().zip(lines) {
+            set(voltage, phase.voltage);
+            set(current, phase.current);
+            set(delivered, phase.power_delivered);
+            set(received, phase.power_received);
+        }
+
+        Self::from_values(timestamp, values)
+    }
+
+    pub fn from_values(timestamp: u64, values: [Option<f64>; METRIC_COUNT]) -> Self {
+        Self {
+            id: 0,
+            timestamp,
+            values,
+        }
+    }
+
+    pub fn get(&self, metric: Metric) -> Option<f64> {
+        self.values[metric as usize]
+    }
+
+    pub fn set(&mut self, metric: Metric, value: Option<f64>) {
+        self.values[metric as usize] = value;
+    }
+}
+
+/// Samples are serialized as a flat object holding the timestamp and every metric.
+impl Serialize for Sample {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(METRIC_COUNT + 1))?;
+        map.serialize_entry("timestamp", &self.timestamp)?;
+        for metric in Metric::ALL {
+            map.serialize_entry(metric.name(), &self.get(metric))?;
+        }
+        map.end()
+    }
+}
+
+/// A single (timestamp, value) pair as returned by queries.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Point {
+    pub timestamp: u64,
+    pub value: f64,
+}
+
+/// A store of samples, oldest first. The engine is chosen with `history.engine`. An
+/// engine implements the methods without a default, the others are built on those.
+pub trait HistoryStore: Debug + Send + Sync {
+    /// Store a sample, assigning it the next id.
+    fn append(&mut self, sample: Sample);
+
+    /// All samples with a timestamp in `[from, to]`, oldest first.
+    fn query_range(&self, from: u64, to: u64) -> Box<dyn Iterator<Item = Sample> + '_>;
+
+    /// The samples stored after the sample with id `after`, oldest first.
+    fn since(&self, after: u64) -> Box<dyn Iterator<Item = Sample> + '_>;
+
+    fn latest(&self) -> Option<Sample>;
+
+    fn oldest(&self) -> Option<Sample>;
+
+    /// Number of samples stored.
+    fn count(&self) -> usize;
+
+    /// Number of samples kept, the oldest are dropped to make room.
+    fn capacity(&self) -> usize;
+
+    /// Space taken by the samples in bytes.
+    fn bytes(&self) -> usize;
+
+    /// Drop the samples with a timestamp before `before`. Returns the number dropped.
+    fn prune(&mut self, before: u64) -> usize;
+
+    /// Give back the space held beyond what the samples stored need.
+    fn vacuum(&mut self) {}
+
+    /// Write the samples waiting to be written, for engines that write behind.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// How far the writes are behind, for engines that write behind.
+    fn flush_stats(&self) -> Option<FlushStats> {
+        None
+    }
+
+    /// Increase of a cumulative metric such as an energy reading over `[from, to]`.
+    fn usage(&self, metric: Metric, from: u64, to: u64) -> Option<f64> {
+        let mut values = self.query_range(from, to).filter_map(|s| s.get(metric));
+        let first = values.next()?;
+        Some(values.last().unwrap_or(first) - first)
+    }
+
+    /// At most `limit` samples in `[from, to]` stored after the sample with id `after`,
+    /// oldest first. Also returns the id to continue from if there are more samples.
+    fn page(&self, from: u64, to: u64, after: u64, limit: usize) -> (Vec<Sample>, Option<u64>) {
+        let mut samples = match after {
+            0 => self.query_range(from, to),
+            _ => Box::new(
+                self.since(after)
+                    .filter(move |s| from <= s.timestamp && s.timestamp <= to),
+            ),
+        };
+        let page: Vec<Sample> = samples.by_ref().take(limit).collect();
+        let next = match samples.next() {
+            Some(_) => page.last().map(|s| s.id),
+            None => None,
+        };
+        (page, next)
+    }
+
+    /// Values of `metric` in `[from, to]`. When `step` is non-zero, values are averaged
+    /// over buckets of `step` milliseconds, each point stamped with the start of its bucket.
+    fn query(&self, metric: Metric, from: u64, to: u64, step: u64) -> Vec<Point> {
+        let to_point = |s: &Sample| {
+            s.get(metric).map(|value| Point {
+                timestamp: s.timestamp,
+                value,
+            })
+        };
+        if step == 0 {
+            return self
+                .query_range(from, to)
+                .filter_map(|s| to_point(&s))
+                .collect();
+        }
+        let (buckets, _) = self.downsample(from, to, 0, step, Aggregation::Avg, usize::MAX);
+        buckets.iter().filter_map(to_point).collect()
+    }
+
+    /// Aggregate the samples in `[from, to]` stored after the sample with id `after` into
+    /// at most `limit` buckets of `step` milliseconds. Each bucket is stamped with the time
+    /// it starts and the id of the last sample in it. Also returns the id to continue from
+    /// if there are more samples.
+    fn downsample(
+        &self,
+        from: u64,
+        to: u64,
+        after: u64,
+        step: u64,
+        aggregation: Aggregation,
+        limit: usize,
+    ) -> (Vec<Sample>, Option<u64>) {
+        let mut buckets = Vec::new();
+        let mut current: Option<Bucket> = None;
+
+        for sample in self.query_range(from, to).filter(|s| s.id > after) {
+            let start = sample.timestamp - sample.timestamp % step.max(1);
+            match current {
+                Some(ref mut bucket) if bucket.start == start => bucket.add(&sample),
+                _ => {
+                    if let Some(bucket) = current.take() {
+                        if buckets.len() == limit {
+                            let next = buckets.last().map(|b: &Sample| b.id);
+                            return (buckets, next);
+                        }
+                        buckets.push(bucket.finish(aggregation));
+                    }
+                    let mut bucket = Bucket::new(start);
+                    bucket.add(&sample);
+                    current = Some(bucket);
+                }
+            }
+        }
+
+        if let Some(bucket) = current {
+            if buckets.len() == limit {
+                let next = buckets.last().map(|b: &Sample| b.id);
+                return (buckets, next);
+            }
+            buckets.push(bucket.finish(aggregation));
+        }
+        (buckets, None)
+    }
+
+    /// A single sample summarizing the samples stored after the sample with id `after`:
+    /// the last value of cumulative metrics and the average of the others, at the time of
+    /// the last sample. `None` if there are no such samples.
+    fn summary(&self, after: u64) -> Option<Sample> {
+        let mut samples = self.since(after).peekable();
+        let mut bucket = Bucket::new(samples.peek()?.timestamp);
+        let mut timestamp = 0;
+        for sample in samples {
+            bucket.add(&sample);
+            timestamp = sample.timestamp;
+        }
+        let last = bucket.last;
+        let mut summary = bucket.finish(Aggregation::Avg);
+        for metric in Metric::ALL.into_iter().filter(Metric::is_cumulative) {
+            summary.values[metric as usize] = last[metric as usize];
+        }
+        summary.timestamp = timestamp;
+        Some(summary)
+    }
+}
+
+/// How far an engine that writes behind is.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct FlushStats {
+    /// Number of batches written.
+    pub flushes: u64,
+    /// Number of batches that couldn't be written, and are tried again.
+    pub errors: u64,
+    /// Number of samples waiting to be written.
+    pub pending: usize,
+    /// Time the oldest waiting sample has waited in milliseconds.
+    pub lag: u64,
+    /// Time of the last batch written in milliseconds since the unix epoch.
+    pub last_flush: Option<u64>,
+}
+
+/// The history store, whatever its engine.
+#[derive(Debug)]
+pub struct History(Box<dyn HistoryStore>, HistoryEngine);
+
+impl History {
+    /// Open the configured history store. When it can't be opened, the history is kept in
+    /// memory.
+    pub fn open(config: &HistoryConfig) -> Self {
+        let memory = || {
+            Self(
+                Box::new(MemoryStore::new(config.capacity)),
+                HistoryEngine::Memory,
+            )
+        };
+        let store: Result<Box<dyn HistoryStore>, String> = match config.engine {
+            HistoryEngine::Memory => return memory(),
+            HistoryEngine::File => match &config.file {
+                Some(path) => JournalStore::open(config, path)
+                    .map(|journal| Box::new(journal) as Box<dyn HistoryStore>),
+                None => Err(String::from("no history.file configured")),
+            },
+            #[cfg(feature = "sqlite")]
+            HistoryEngine::Sqlite => match &config.file {
+                Some(path) => sql::Sqlite::open(path)
+                    .and_then(|database| sql::SqlStore::new(config, database))
+                    .map(|store| Box::new(store) as Box<dyn HistoryStore>),
+                None => Err(String::from("no history.file configured")),
+            },
+            #[cfg(not(feature = "sqlite"))]
+            HistoryEngine::Sqlite => Err(String::from("built without the sqlite feature")),
+            #[cfg(feature = "postgres")]
+            HistoryEngine::Postgres => match &config.url {
+                Some(url) => sql::Postgres::open(url)
+                    .and_then(|database| sql::SqlStore::new(config, database))
+                    .map(|store| Box::new(store) as Box<dyn HistoryStore>),
+                None => Err(String::from("no history.url configured")),
+            },
+            #[cfg(not(feature = "postgres"))]
+            HistoryEngine::Postgres => Err(String::from("built without the postgres feature")),
+        };
+        match store {
+            Ok(store) => Self(store, config.engine),
+            Err(e) => {
+                error!("Unable to open the history, keeping it in memory: {}", e);
+                memory()
+            }
+        }
+    }
+
+    /// The engine in use, which is the memory engine if the configured one failed to open.
+    pub fn engine(&self) -> HistoryEngine {
+        self.1
+    }
+}
+
+impl Deref for History {
+    type Target = dyn HistoryStore;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl DerefMut for History {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut()
+    }
+}
+
+/// The in-memory engine, a ring buffer of the most recent samples.
+#[derive(Debug)]
+pub struct MemoryStore {
+    samples: VecDeque<Sample>,
+    capacity: usize,
+    next_id: u64,
+}
+
+impl MemoryStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            capacity,
+            next_id: 1,
+        }
+    }
+
+    /// Store a sample that was stored before, keeping its id.
+    pub fn restore(&mut self, sample: Sample) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.next_id = self.next_id.max(sample.id + 1);
+        while self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+}
+
+impl HistoryStore for MemoryStore {
+    fn append(&mut self, mut sample: Sample) {
+        if self.capacity == 0 {
+            return;
+        }
+        sample.id = self.next_id;
+        self.next_id += 1;
+        while self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    fn query_range(&self, from: u64, to: u64) -> Box<dyn Iterator<Item = Sample> + '_> {
+        // Samples are stored in order of arrival, so we can skip ahead with a binary search.
+        let start = self.samples.partition_point(|s| s.timestamp < from);
+        Box::new(
+            self.samples
+                .range(start..)
+                .take_while(move |s| s.timestamp <= to)
+                .cloned(),
+        )
+    }
+
+    fn since(&self, after: u64) -> Box<dyn Iterator<Item = Sample> + '_> {
+        let start = self.samples.partition_point(|s| s.id <= after);
+        Box::new(self.samples.range(start..).cloned())
+    }
+
+    fn latest(&self) -> Option<Sample> {
+        self.samples.back().cloned()
+    }
+
+    fn oldest(&self) -> Option<Sample> {
+        self.samples.front().cloned()
+    }
+
+    fn count(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn bytes(&self) -> usize {
+        self.samples.capacity() * std::mem::size_of::<Sample>()
+    }
+
+    fn prune(&mut self, before: u64) -> usize {
+        let count = self.samples.partition_point(|s| s.timestamp < before);
+        self.samples.drain(..count);
+        count
+    }
+
+    fn vacuum(&mut self) {
+        self.samples.shrink_to_fit();
+    }
+}
+
+/// Ways to combine the samples in a bucket into a single value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregation {
+    Avg,
+    Min,
+    Max,
+    Last,
+}
+
+impl Aggregation {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "avg" => Some(Aggregation::Avg),
+            "min" => Some(Aggregation::Min),
+            "max" => Some(Aggregation::Max),
+            "last" => Some(Aggregation::Last),
+            _ => None,
+        }
+    }
+}
+
+/// Running aggregates of the samples in a bucket.
+struct Bucket {
+    start: u64,
+    last_id: u64,
+    sum: [f64; METRIC_COUNT],
+    count: [u32; METRIC_COUNT],
+    min: [Option<f64>; METRIC_COUNT],
+    max: [Option<f64>; METRIC_COUNT],
+    last: [Option<f64>; METRIC_COUNT],
+}
+
+impl Bucket {
+    fn new(start: u64) -> Self {
+        Self {
+            start,
+            last_id: 0,
+            sum: [0.0; METRIC_COUNT],
+            count: [0; METRIC_COUNT],
+            min: [None; METRIC_COUNT],
+            max: [None; METRIC_COUNT],
+            last: [None; METRIC_COUNT],
+        }
+    }
+
+    fn add(&mut self, sample: &Sample) {
+        self.last_id = sample.id;
+        for (i, value) in sample.values.iter().enumerate() {
+            let Some(value) = *value else {
+                continue;
+            };
+            self.sum[i] += value;
+            self.count[i] += 1;
+            self.min[i] = Some(self.min[i].map_or(value, |min| min.min(value)));
+            self.max[i] = Some(self.max[i].map_or(value, |max| max.max(value)));
+            self.last[i] = Some(value);
+        }
+    }
+
+    fn finish(self, aggregation: Aggregation) -> Sample {
+        let values = match aggregation {
+            Aggregation::Avg => std::array::from_fn(|i| {
+                (self.count[i] > 0).then(|| self.sum[i] / f64::from(self.count[i]))
+            }),
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+            Aggregation::Last => self.last,
+        };
+        Sample {
+            id: self.last_id,
+            timestamp: self.start,
+            values,
+        }
+    }
+}
+
+/// Current time in milliseconds since the unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Start and end of a local day in milliseconds since the unix epoch.
+pub fn day_range(date: NaiveDate) -> (u64, u64) {
+    let start = |date: NaiveDate| {
+        date.and_hms_opt(0, 0, 0)
+            .and_then(|t| Local.from_local_datetime(&t).earliest())
+            .and_then(|t| u64::try_from(t.timestamp_millis()).ok())
+            .unwrap_or(0)
+    };
+    let next = date.checked_add_days(Days::new(1)).unwrap_or(date);
+    (start(date), start(next).saturating_sub(1))
+}
+
+/// Parse a time given either in milliseconds since the unix epoch or as RFC 3339.
+pub fn parse_time(time: &str) -> Option<u64> {
+    if let Ok(millis) = time.parse::<u64>() {
+        return Some(millis);
+    }
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .and_then(|t| u64::try_from(t.timestamp_millis()).ok())
+}
+
+/// Parse a duration such as `500ms`, `30s`, `5m`, `1h` or `1d` into milliseconds.
+/// A number without unit is taken as seconds.
+pub fn parse_duration(duration: &str) -> Option<u64> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let multiplier = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return None,
+    };
+    number.checked_mul(multiplier)
+}
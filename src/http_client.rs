@@ -0,0 +1,288 @@
+//! A small blocking HTTP(S) client for use in worker threads.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::{
+    client::connect::{Connected, Connection},
+    header::{HeaderValue, PROXY_AUTHORIZATION},
+    service::Service,
+    Body, Client, Method, Request, Uri,
+};
+#[cfg(feature = "tls")]
+use hyper_tls::HttpsConnector;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    runtime::{self, Runtime},
+};
+
+use crate::{
+    dial::Dialer,
+    tunnel::{Proxy, ProxyKind},
+};
+
+/// Without the `tls` feature, only plain HTTP urls can be requested.
+#[cfg(feature = "tls")]
+type Connector = HttpsConnector<ProxyConnector>;
+#[cfg(not(feature = "tls"))]
+type Connector = ProxyConnector;
+
+/// Time after which a request is given up on.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wraps the async hyper client in its own runtime, so worker threads can make requests
+/// without being async themselves.
+pub struct HttpClient {
+    runtime: Runtime,
+    client: Client<Connector>,
+    proxy: Option<Proxy>,
+}
+
+impl HttpClient {
+    /// Create a client making its connections with `dialer`.
+    pub fn new(dialer: Dialer) -> Result<Self, String> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| format!("Unable to create runtime: {}", e))?;
+        let proxy = dialer.proxy().cloned();
+        let connector = ProxyConnector { dialer };
+        #[cfg(feature = "tls")]
+        let connector = HttpsConnector::new_with_connector(connector);
+        // The connector needs a runtime to be created in.
+        let client = runtime.block_on(async { Client::builder().build(connector) });
+        Ok(Self {
+            runtime,
+            client,
+            proxy,
+        })
+    }
+
+    /// GET `url`, returning the body of the response. Fails if the server doesn't answer
+    /// with a success status.
+    pub fn get(&self, url: &str, headers: &[(&str, &str)]) -> Result<Vec<u8>, String> {
+        let mut request = Request::builder().method(Method::GET).uri(url);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request
+            .body(Body::empty())
+            .map_err(|e| format!("Invalid request to {}: {}", url, e))?;
+        self.send(url, request)
+    }
+
+    /// POST `body` to `url`, failing if the server doesn't answer with a success status.
+    pub fn post(
+        &self,
+        url: &str,
+        content_type: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<(), String> {
+        self.upload(Method::POST, url, content_type, headers, body)
+            .map(|_| ())
+    }
+
+    /// POST `body` to `url`, returning the body of the response. Fails if the server
+    /// doesn't answer with a success status.
+    pub fn exchange(
+        &self,
+        url: &str,
+        content_type: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        self.upload(Method::POST, url, content_type, headers, body)
+    }
+
+    /// PUT `body` to `url`, failing if the server doesn't answer with a success status.
+    pub fn put(
+        &self,
+        url: &str,
+        content_type: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<(), String> {
+        self.upload(Method::PUT, url, content_type, headers, body)
+            .map(|_| ())
+    }
+
+    fn upload(
+        &self,
+        method: Method,
+        url: &str,
+        content_type: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        let request = upload_request(method, url, content_type, headers, body)?;
+        self.send(url, request)
+    }
+
+    fn send(&self, url: &str, request: Request<Body>) -> Result<Vec<u8>, String> {
+        self.runtime.block_on(self.fetch(url, request, TIMEOUT))
+    }
+
+    /// Run `future` on the runtime of the client, to make requests concurrently with
+    /// `fetch`.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// Make a request, returning the body of the response. Fails if the server doesn't
+    /// answer with a success status within `timeout`.
+    pub async fn fetch(
+        &self,
+        url: &str,
+        mut request: Request<Body>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, String> {
+        // An HTTP proxy forwarding plain HTTP requests sees them as they are, so it needs
+        // its credentials in every request.
+        let authorization = self
+            .proxy
+            .as_ref()
+            .filter(|proxy| proxy.kind == ProxyKind::Http)
+            .filter(|_| request.uri().scheme_str() == Some("http"))
+            .and_then(Proxy::authorization)
+            .and_then(|authorization| HeaderValue::from_str(&authorization).ok());
+        if let Some(authorization) = authorization {
+            request
+                .headers_mut()
+                .insert(PROXY_AUTHORIZATION, authorization);
+        }
+        let response = tokio::time::timeout(timeout, self.client.request(request))
+            .await
+            .map_err(|_| format!("Request to {} timed out", url))?
+            .map_err(|e| format!("Request to {} failed: {}", url, e))?;
+        if !response.status().is_success() {
+            return Err(format!("Request to {} failed: {}", url, response.status()));
+        }
+        tokio::time::timeout(timeout, hyper::body::to_bytes(response.into_body()))
+            .await
+            .map_err(|_| format!("Reading the response of {} timed out", url))?
+            .map(|body| body.to_vec())
+            .map_err(|e| format!("Unable to read the response of {}: {}", url, e))
+    }
+}
+
+/// Build a request sending `body` to `url`.
+pub fn upload_request(
+    method: Method,
+    url: &str,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: Vec<u8>,
+) -> Result<Request<Body>, String> {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(url)
+        .header("Content-Type", content_type);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request
+        .body(Body::from(body))
+        .map_err(|e| format!("Invalid request to {}: {}", url, e))
+}
+
+/// Connects directly, or through the configured proxy. Plain HTTP requests are handed to
+/// an HTTP proxy as they are, everything else goes through a tunnel.
+#[derive(Clone)]
+pub struct ProxyConnector {
+    dialer: Dialer,
+}
+
+impl Service<Uri> for ProxyConnector {
+    type Response = ProxyStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<ProxyStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let dialer = self.dialer.clone();
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or_else(|| io::Error::other(format!("No host in {}", uri)))?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string();
+            let https = uri.scheme_str() == Some("https");
+            if https && !cfg!(feature = "tls") {
+                return Err(io::Error::other(format!("Unsupported url {}", uri)));
+            }
+            let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+            let forward_to = dialer
+                .proxy()
+                .filter(|proxy| !https && proxy.kind == ProxyKind::Http)
+                .cloned();
+            let forwarding = forward_to.is_some();
+            // Connecting blocks, so it's done off the runtime.
+            let stream = tokio::task::spawn_blocking(move || {
+                let stream = match forward_to {
+                    Some(proxy) => proxy.connect_direct(),
+                    None => dialer.connect(&host, port),
+                }?;
+                stream.set_nonblocking(true)?;
+                Ok::<_, io::Error>(stream)
+            })
+            .await
+            .map_err(io::Error::other)??;
+            Ok(ProxyStream {
+                stream: TcpStream::from_std(stream)?,
+                forwarding,
+            })
+        })
+    }
+}
+
+/// A connection made by `ProxyConnector`.
+pub struct ProxyStream {
+    stream: TcpStream,
+    /// Whether requests go to an HTTP proxy, which needs them in absolute form.
+    forwarding: bool,
+}
+
+impl Connection for ProxyStream {
+    fn connected(&self) -> Connected {
+        self.stream.connected().proxy(self.forwarding)
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
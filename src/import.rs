@@ -0,0 +1,340 @@
+//! `dsmrd import` reads the readings of a meter from before dsmrd into the monthly and
+//! contract year totals, so switching from another reader doesn't mean starting over. It
+//! takes DSMR-reader database dumps made by `pg_dump`, CSV exports with a column per value
+//! and logs of captured telegrams, gzipped or not.
+//!
+//! The samples served at `/history` are only kept in memory, so the import doesn't add to
+//! those. Stop the daemon while importing: the totals are written to the state files it
+//! reads at startup. Months and years already in those files are kept as they are.
+
+use std::{fs, io::Read};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use flate2::read::GzDecoder;
+
+use crate::{
+    annual::Annual,
+    config::Config,
+    history::{self, Metric, Sample, METRIC_COUNT},
+    netting::Months,
+    validate,
+};
+
+const USAGE: &str = "Usage: dsmrd import [--format dump|csv|telegrams] FILE...";
+
+/// DSMR-reader table of the gas readings, whose columns have names of their own.
+const GAS_TABLE: &str = "dsmr_consumption_gasconsumption";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// A DSMR-reader PostgreSQL dump, with the readings as `COPY` data.
+    Dump,
+    /// A header with the names of the columns and a line per reading.
+    Csv,
+    /// Raw telegrams as captured from the P1 port.
+    Telegrams,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dump" => Some(Format::Dump),
+            "csv" => Some(Format::Csv),
+            "telegrams" => Some(Format::Telegrams),
+            _ => None,
+        }
+    }
+
+    fn guess(text: &str) -> Self {
+        if text.starts_with("COPY ") || text.contains("\nCOPY ") {
+            Format::Dump
+        } else if text.contains("1-0:1.8.") {
+            Format::Telegrams
+        } else {
+            Format::Csv
+        }
+    }
+}
+
+/// The values of a single reading, by the time it was taken.
+type Reading = (u64, [Option<f64>; METRIC_COUNT]);
+
+/// Run `dsmrd import` with the arguments following `import`.
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut format = None;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                let name = args.next().ok_or(USAGE)?;
+                format = Some(Format::from_name(&name).ok_or(USAGE)?);
+            }
+            _ if arg.starts_with("--") => return Err(String::from(USAGE)),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        return Err(String::from(USAGE));
+    }
+
+    let config = Config::load()?;
+    if config.netting.file.is_none() && config.annual.state_file.is_none() {
+        return Err(String::from(
+            "Neither netting.file nor annual.state_file is configured, so there is nowhere \
+             to import to.",
+        ));
+    }
+
+    let mut readings = Vec::new();
+    for path in &paths {
+        let text = read(path)?;
+        let format = format.unwrap_or_else(|| Format::guess(&text));
+        let (mut read, skipped) = match format {
+            Format::Dump => dump(&text),
+            Format::Csv => csv(&text)?,
+            Format::Telegrams => telegrams(&text),
+        };
+        println!(
+            "Read {} readings from {} as {:?}, skipped {}.",
+            read.len(),
+            path,
+            format,
+            skipped
+        );
+        readings.append(&mut read);
+    }
+    if readings.is_empty() {
+        return Err(String::from("No readings found to import."));
+    }
+    let samples = samples(readings);
+
+    if let Some(file) = &config.netting.file {
+        let added = Months::new(&config.netting).import(&samples);
+        println!("Added the totals of {} months to {}.", added, file);
+    }
+    if let Some(file) = &config.annual.state_file {
+        let added = Annual::new(&config.annual).import(&samples);
+        println!("Added the totals of {} contract years to {}.", added, file);
+    }
+    Ok(())
+}
+
+/// The contents of the file at `path`, unpacked if it is gzipped.
+fn read(path: &str) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+    if !bytes.starts_with(&[0x1f, 0x8b]) {
+        return String::from_utf8(bytes).map_err(|e| format!("Unable to read {}: {}", path, e));
+    }
+    let mut text = String::new();
+    GzDecoder::new(bytes.as_slice())
+        .read_to_string(&mut text)
+        .map_err(|e| format!("Unable to unpack {}: {}", path, e))?;
+    Ok(text)
+}
+
+/// The samples of `readings` in order of time. Meter totals missing from a reading are
+/// taken from the one before, as the readings of gas and electricity may come apart.
+fn samples(mut readings: Vec<Reading>) -> Vec<Sample> {
+    readings.sort_by_key(|(timestamp, _)| *timestamp);
+    let mut totals = [None; METRIC_COUNT];
+    readings
+        .into_iter()
+        .map(|(timestamp, mut values)| {
+            for metric in Metric::ALL.into_iter().filter(Metric::is_cumulative) {
+                let i = metric as usize;
+                values[i] = values[i].or(totals[i]);
+                totals[i] = values[i];
+            }
+            Sample::from_values(timestamp, values)
+        })
+        .collect()
+}
+
+/// The metric in the column `column`, by its name in DSMR-reader or in dsmrd.
+fn metric(table: Option<&str>, column: &str) -> Option<Metric> {
+    if table == Some(GAS_TABLE) {
+        return (column == "delivered").then_some(Metric::GasDelivered);
+    }
+    let metric = match column.strip_prefix("electricity_").unwrap_or(column) {
+        "delivered_1" => Metric::EnergyDeliveredTariff1,
+        "delivered_2" => Metric::EnergyDeliveredTariff2,
+        "returned_1" => Metric::EnergyReceivedTariff1,
+        "returned_2" => Metric::EnergyReceivedTariff2,
+        "currently_delivered" => Metric::PowerDelivered,
+        "currently_returned" => Metric::PowerReceived,
+        "extra_device_delivered" => Metric::GasDelivered,
+        "phase_voltage_l1" => Metric::VoltageL1,
+        "phase_voltage_l2" => Metric::VoltageL2,
+        "phase_voltage_l3" => Metric::VoltageL3,
+        "phase_power_current_l1" => Metric::CurrentL1,
+        "phase_power_current_l2" => Metric::CurrentL2,
+        "phase_power_current_l3" => Metric::CurrentL3,
+        "phase_currently_delivered_l1" => Metric::PowerDeliveredL1,
+        "phase_currently_delivered_l2" => Metric::PowerDeliveredL2,
+        "phase_currently_delivered_l3" => Metric::PowerDeliveredL3,
+        "phase_currently_returned_l1" => Metric::PowerReceivedL1,
+        "phase_currently_returned_l2" => Metric::PowerReceivedL2,
+        "phase_currently_returned_l3" => Metric::PowerReceivedL3,
+        column => return Metric::from_name(column),
+    };
+    Some(metric)
+}
+
+/// Whether `column` holds the time of the readings.
+fn is_time(column: &str) -> bool {
+    matches!(column, "timestamp" | "read_at" | "time" | "datetime")
+}
+
+/// Parse a time as written by PostgreSQL, e.g. `2021-03-04 12:34:56+01`, or in any form
+/// `history::parse_time` takes. Times without an offset are local.
+fn parse_timestamp(time: &str) -> Option<u64> {
+    let time = time.trim();
+    history::parse_time(time).or_else(|| {
+        let millis = match DateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S%.f%#z") {
+            Ok(time) => time.timestamp_millis(),
+            Err(_) => ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"]
+                .iter()
+                .find_map(|format| NaiveDateTime::parse_from_str(time, format).ok())
+                .and_then(|time| Local.from_local_datetime(&time).earliest())?
+                .timestamp_millis(),
+        };
+        u64::try_from(millis).ok()
+    })
+}
+
+/// The readings in rows of `columns`. Returns them along with the number of rows skipped.
+fn rows<'a>(
+    table: Option<&str>,
+    columns: &[&str],
+    rows: impl Iterator<Item = Vec<&'a str>>,
+) -> (Vec<Reading>, usize) {
+    let Some(time) = columns.iter().position(|column| is_time(column)) else {
+        return (Vec::new(), 0);
+    };
+    let metrics: Vec<Option<Metric>> = columns.iter().map(|column| metric(table, column)).collect();
+    if metrics.iter().all(Option::is_none) {
+        return (Vec::new(), 0);
+    }
+    let mut readings = Vec::new();
+    let mut skipped = 0;
+    for row in rows {
+        let Some(timestamp) = row.get(time).and_then(|time| parse_timestamp(time)) else {
+            skipped += 1;
+            continue;
+        };
+        let mut values = [None; METRIC_COUNT];
+        for (metric, value) in metrics.iter().zip(&row) {
+            if let Some(metric) = metric {
+                values[*metric as usize] = value.trim().parse().ok();
+            }
+        }
+        readings.push((timestamp, values));
+    }
+    (readings, skipped)
+}
+
+/// The readings in the `COPY` data of a PostgreSQL dump, from every table with a time and
+/// values dsmrd keeps.
+fn dump(text: &str) -> (Vec<Reading>, usize) {
+    let mut readings = Vec::new();
+    let mut skipped = 0;
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        // COPY public.dsmr_datalogger_dsmrreading (id, timestamp, ...) FROM stdin;
+        let Some((table, columns)) = line
+            .strip_prefix("COPY ")
+            .and_then(|rest| rest.strip_suffix(") FROM stdin;"))
+            .and_then(|rest| rest.split_once(" ("))
+        else {
+            continue;
+        };
+        let table = table.rsplit('.').next().unwrap_or(table);
+        let columns: Vec<&str> = columns
+            .split(", ")
+            .map(|column| column.trim_matches('"'))
+            .collect();
+        let data = lines
+            .by_ref()
+            .take_while(|line| *line != "\\.")
+            .map(|line| line.split('\t').collect());
+        let (mut read, rows_skipped) = rows(Some(table), &columns, data);
+        readings.append(&mut read);
+        skipped += rows_skipped;
+    }
+    (readings, skipped)
+}
+
+/// The readings in a CSV export, separated by commas or semicolons. With semicolons,
+/// numbers may have a decimal comma.
+fn csv(text: &str) -> Result<(Vec<Reading>, usize), String> {
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let header = lines.next().ok_or("The CSV file is empty.")?;
+    let separator = match header.contains(';') {
+        true => ';',
+        false => ',',
+    };
+    let field = |field: &str| field.trim().trim_matches('"').to_string();
+    let columns: Vec<String> = header
+        .split(separator)
+        .map(|column| field(column).to_lowercase())
+        .collect();
+    let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+    if !columns.iter().any(|column| is_time(column)) {
+        return Err(String::from(
+            "The CSV file has no timestamp, read_at, time or datetime column.",
+        ));
+    }
+    let data: Vec<Vec<String>> = lines
+        .map(|line| {
+            line.split(separator)
+                .map(|value| match separator {
+                    ';' => field(value).replace(',', "."),
+                    _ => field(value),
+                })
+                .collect()
+        })
+        .collect();
+    let data = data
+        .iter()
+        .map(|row| row.iter().map(String::as_str).collect());
+    Ok(rows(None, &columns, data))
+}
+
+/// The readings in a log of captured telegrams. Lines ending in LF only are taken to
+/// have lost their CR in the capture.
+fn telegrams(text: &str) -> (Vec<Reading>, usize) {
+    let mut readings = Vec::new();
+    let mut skipped = 0;
+    let mut rest = text;
+    while let Some(start) = rest.find('/') {
+        let telegram = &rest[start..];
+        let Some(end) = telegram.find('!') else {
+            skipped += 1;
+            break;
+        };
+        // The checksum follows the end of the telegram.
+        let end = telegram[end + 1..]
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .map_or(telegram.len(), |length| end + 1 + length);
+        rest = &telegram[end..];
+        let mut telegram = telegram[..end].to_string();
+        if !telegram.contains("\r\n") {
+            telegram = telegram.replace('\n', "\r\n");
+        }
+        if telegram.len() > validate::READOUT_SIZE {
+            skipped += 1;
+            continue;
+        }
+        let timestamp = validate::convert(&telegram).ok().and_then(|state| {
+            let timestamp = u64::try_from(state.datetime?.timestamp_millis()).ok()?;
+            Some((timestamp, state))
+        });
+        let Some((timestamp, state)) = timestamp else {
+            skipped += 1;
+            continue;
+        };
+        let sample = Sample::from_state(timestamp, &state);
+        readings.push((timestamp, Metric::ALL.map(|metric| sample.get(metric))));
+    }
+    (readings, skipped)
+}
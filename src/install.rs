@@ -0,0 +1,157 @@
+//! `dsmrd install` sets the daemon up on a headless machine: it installs the binary, a
+//! systemd unit, a default configuration and a udev rule giving the P1 cable a stable name.
+//!
+//! With `--root DIR` the files are written below `DIR` instead of `/`, to build a package
+//! from a cross-compiled binary.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+pub const BINARY_PATH: &str = "/usr/local/bin/dsmrd";
+pub const UNIT_PATH: &str = "/etc/systemd/system/dsmrd.service";
+pub const CONFIG_PATH: &str = "/etc/dsmrd/config.json";
+pub const UDEV_RULE_PATH: &str = "/etc/udev/rules.d/99-dsmrd.rules";
+/// Name the udev rule gives the P1 cable.
+pub const DEVICE_PATH: &str = "/dev/p1";
+
+const USAGE: &str = "Usage: dsmrd install [--root DIR] [--listen ADDR]";
+
+/// Where the installed files are, as reported by `/version`.
+#[derive(Serialize)]
+pub struct InstallPaths {
+    pub binary: InstalledFile,
+    pub unit: InstalledFile,
+    pub config: InstalledFile,
+    pub udev_rule: InstalledFile,
+}
+
+#[derive(Serialize)]
+pub struct InstalledFile {
+    pub path: &'static str,
+    pub installed: bool,
+}
+
+impl InstalledFile {
+    fn new(path: &'static str) -> Self {
+        Self {
+            path,
+            installed: Path::new(path).exists(),
+        }
+    }
+}
+
+pub fn paths() -> InstallPaths {
+    InstallPaths {
+        binary: InstalledFile::new(BINARY_PATH),
+        unit: InstalledFile::new(UNIT_PATH),
+        config: InstalledFile::new(CONFIG_PATH),
+        udev_rule: InstalledFile::new(UDEV_RULE_PATH),
+    }
+}
+
+/// Run `dsmrd install` with the arguments following `install`.
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut root = PathBuf::from("/");
+    let mut listen = String::from("127.0.0.1:3000");
+    while let Some(arg) = args.next() {
+        let value = args.next();
+        match (arg.as_str(), value) {
+            ("--root", Some(value)) => root = PathBuf::from(value),
+            ("--listen", Some(value)) => listen = value,
+            _ => return Err(String::from(USAGE)),
+        }
+    }
+
+    let binary =
+        env::current_exe().map_err(|e| format!("Unable to find the dsmrd binary: {}", e))?;
+    let target = below(&root, BINARY_PATH);
+    // Copying a file onto itself would truncate it.
+    if fs::canonicalize(&binary).ok() != fs::canonicalize(&target).ok() {
+        create_parent(&target)?;
+        fs::copy(&binary, &target)
+            .map_err(|e| format!("Unable to copy {}: {}", binary.display(), e))?;
+        println!("Installed {}", target.display());
+    }
+
+    write(&below(&root, UNIT_PATH), &unit(&listen))?;
+    write(&below(&root, UDEV_RULE_PATH), UDEV_RULE)?;
+    // Never overwrite a configuration someone may have edited.
+    let config = below(&root, CONFIG_PATH);
+    if config.exists() {
+        println!("Keeping existing {}", config.display());
+    } else {
+        write(&config, DEFAULT_CONFIG)?;
+    }
+
+    if root == Path::new("/") {
+        println!();
+        println!("To start dsmrd, run:");
+        println!("  udevadm control --reload && udevadm trigger");
+        println!("  systemctl daemon-reload && systemctl enable --now dsmrd");
+    }
+    Ok(())
+}
+
+/// `path` moved below `root`.
+fn below(root: &Path, path: &str) -> PathBuf {
+    root.join(path.trim_start_matches('/'))
+}
+
+fn create_parent(path: &Path) -> Result<(), String> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent)
+            .map_err(|e| format!("Unable to create {}: {}", parent.display(), e)),
+        None => Ok(()),
+    }
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+    create_parent(path)?;
+    fs::write(path, contents).map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+    println!("Installed {}", path.display());
+    Ok(())
+}
+
+fn unit(listen: &str) -> String {
+    format!(
+        "[Unit]
+Description=DSMR P1 reader
+After=network.target
+
+[Service]
+Type=notify
+ExecStart={binary} {listen} {device}
+Environment=DSMRD_CONFIG={config}
+DynamicUser=yes
+SupplementaryGroups=dialout
+Restart=on-failure
+RestartSec=5
+
+[Install]
+WantedBy=multi-user.target
+",
+        binary = BINARY_PATH,
+        listen = listen,
+        device = DEVICE_PATH,
+        config = CONFIG_PATH,
+    )
+}
+
+/// Links the USB serial adapters P1 cables are commonly built around to `/dev/p1`.
+const UDEV_RULE: &str = r#"# P1 cables for dsmrd: FTDI FT232R, Prolific PL2303 and CH340 adapters.
+SUBSYSTEM=="tty", ATTRS{idVendor}=="0403", ATTRS{idProduct}=="6001", SYMLINK+="p1", GROUP="dialout", MODE="0660"
+SUBSYSTEM=="tty", ATTRS{idVendor}=="067b", ATTRS{idProduct}=="2303", SYMLINK+="p1", GROUP="dialout", MODE="0660"
+SUBSYSTEM=="tty", ATTRS{idVendor}=="1a86", ATTRS{idProduct}=="7523", SYMLINK+="p1", GROUP="dialout", MODE="0660"
+"#;
+
+const DEFAULT_CONFIG: &str = r#"{
+  "reader": { "format": "dsmr" },
+  "output": { "missing_values": "null" },
+  "history": { "capacity": 86400 },
+  "sampling": { "mode": "telegram" }
+}
+"#;
@@ -0,0 +1,238 @@
+//! The file engine of the history store: samples are kept in memory like with the memory
+//! engine, and journaled to `history.file` so they survive a restart. Writing a line per
+//! telegram wears out an SD card, so samples are written behind: they're buffered and
+//! appended in a batch once `history.flush_size` samples are waiting or the oldest has
+//! waited `history.flush_interval` seconds.
+//!
+//! Each batch is appended with a single write and synced before it counts as flushed, so a
+//! crash loses at most the samples still waiting. A line torn by a crash is skipped when
+//! the journal is read back, after which the journal is compacted: written anew to a
+//! temporary file that replaces it. The journal is compacted as well when it holds twice
+//! the samples kept, and when the stores are vacuumed.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    config::HistoryConfig,
+    history::{now_millis, FlushStats, HistoryStore, MemoryStore, Metric, Sample, METRIC_COUNT},
+};
+
+/// A sample as journaled: its id, timestamp and values. Values of metrics added later are
+/// missing from older lines.
+type Line = (u64, u64, Vec<Option<f64>>);
+
+#[derive(Debug)]
+pub struct JournalStore {
+    memory: MemoryStore,
+    path: String,
+    /// Samples waiting to be appended to the journal.
+    pending: Vec<Sample>,
+    /// Number of samples in the journal, some of which may have been dropped from memory.
+    journaled: usize,
+    flush_interval: u64,
+    flush_size: usize,
+    stats: FlushStats,
+}
+
+impl JournalStore {
+    /// Read back the journal at `path`, creating it if there is none yet.
+    pub fn open(config: &HistoryConfig, path: &str) -> Result<Self, String> {
+        let mut memory = MemoryStore::new(config.capacity);
+        let mut journaled = 0;
+        let mut torn = 0;
+        match fs::read_to_string(path) {
+            Ok(journal) => {
+                for line in journal.lines() {
+                    match serde_json::from_str::<Line>(line) {
+                        Ok((id, timestamp, line)) => {
+                            let mut values = [None; METRIC_COUNT];
+                            for (value, read) in values.iter_mut().zip(line) {
+                                *value = read;
+                            }
+                            let mut sample = Sample::from_values(timestamp, values);
+                            sample.id = id;
+                            memory.restore(sample);
+                            journaled += 1;
+                        }
+                        Err(_) => torn += 1,
+                    }
+                }
+                info!("Read {} samples from {}.", journaled, path);
+            }
+            Err(e) => info!("No samples read from {}: {}", path, e),
+        }
+        let mut store = Self {
+            memory,
+            path: path.to_string(),
+            pending: Vec::new(),
+            journaled,
+            flush_interval: config.flush_interval,
+            flush_size: config.flush_size.max(1),
+            stats: FlushStats::default(),
+        };
+        if torn > 0 {
+            warn!("Skipped {} unreadable lines in {}.", torn, path);
+            store.compact()?;
+        } else {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Unable to open {}: {}", path, e))?;
+        }
+        Ok(store)
+    }
+
+    fn line(sample: &Sample) -> Result<String, String> {
+        let values = Metric::ALL.map(|metric| sample.get(metric));
+        serde_json::to_string(&(sample.id, sample.timestamp, values)).map_err(|e| e.to_string())
+    }
+
+    /// Append the waiting samples to the journal.
+    fn append_pending(&mut self) -> Result<(), String> {
+        let mut batch = String::new();
+        for sample in &self.pending {
+            batch.push_str(&Self::line(sample)?);
+            batch.push('\n');
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| e.to_string())?;
+        file.write_all(batch.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| e.to_string())?;
+        self.journaled += self.pending.len();
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Write the journal anew with the samples kept, waiting ones included.
+    fn compact(&mut self) -> Result<(), String> {
+        let temporary = format!("{}.tmp", self.path);
+        let mut journal = String::new();
+        for sample in self.memory.since(0) {
+            journal.push_str(&Self::line(&sample)?);
+            journal.push('\n');
+        }
+        let written = File::create(&temporary)
+            .and_then(|mut file| {
+                file.write_all(journal.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&temporary, &self.path));
+        if let Err(e) = written {
+            return Err(format!("Unable to write {}: {}", self.path, e));
+        }
+        self.journaled = self.memory.count();
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn due(&self) -> bool {
+        let Some(oldest) = self.pending.first() else {
+            return false;
+        };
+        self.pending.len() >= self.flush_size
+            || now_millis().saturating_sub(oldest.timestamp) >= self.flush_interval * 1000
+    }
+
+    fn record(&mut self, result: Result<(), String>) -> Result<(), String> {
+        match &result {
+            Ok(_) => {
+                self.stats.flushes += 1;
+                self.stats.last_flush = Some(now_millis());
+            }
+            Err(e) => {
+                self.stats.errors += 1;
+                error!("Unable to write samples to {}: {}", self.path, e);
+            }
+        }
+        result
+    }
+}
+
+impl HistoryStore for JournalStore {
+    fn append(&mut self, sample: Sample) {
+        self.memory.append(sample);
+        if let Some(sample) = self.memory.latest() {
+            self.pending.push(sample);
+        }
+        // While the journal can't be written, the samples dropped from memory are given up.
+        if self.pending.len() > self.memory.capacity() {
+            self.pending.remove(0);
+        }
+        if self.due() {
+            let _ = self.flush();
+        }
+    }
+
+    fn query_range(&self, from: u64, to: u64) -> Box<dyn Iterator<Item = Sample> + '_> {
+        self.memory.query_range(from, to)
+    }
+
+    fn since(&self, after: u64) -> Box<dyn Iterator<Item = Sample> + '_> {
+        self.memory.since(after)
+    }
+
+    fn latest(&self) -> Option<Sample> {
+        self.memory.latest()
+    }
+
+    fn oldest(&self) -> Option<Sample> {
+        self.memory.oldest()
+    }
+
+    fn count(&self) -> usize {
+        self.memory.count()
+    }
+
+    fn capacity(&self) -> usize {
+        self.memory.capacity()
+    }
+
+    fn bytes(&self) -> usize {
+        self.memory.bytes()
+    }
+
+    fn prune(&mut self, before: u64) -> usize {
+        self.memory.prune(before)
+    }
+
+    /// Compact the journal, which also drops the pruned samples from it.
+    fn vacuum(&mut self) {
+        self.memory.vacuum();
+        let result = self.compact();
+        let _ = self.record(result);
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let result = if self.journaled + self.pending.len() >= 2 * self.memory.capacity() {
+            self.compact()
+        } else {
+            self.append_pending()
+        };
+        self.record(result)
+    }
+
+    fn flush_stats(&self) -> Option<FlushStats> {
+        let lag = self
+            .pending
+            .first()
+            .map_or(0, |oldest| now_millis().saturating_sub(oldest.timestamp));
+        Some(FlushStats {
+            pending: self.pending.len(),
+            lag,
+            ..self.stats
+        })
+    }
+}
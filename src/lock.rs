@@ -0,0 +1,22 @@
+//! Locks that stay usable after a thread panicked while holding them. The data behind our
+//! locks is replaced whole or updated a field at a time, so it's still fine to use after a
+//! panic, and refusing it would leave every later request failing as well.
+
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+pub trait RecoverLock<T> {
+    /// Lock for reading, ignoring poison.
+    fn read_recover(&self) -> RwLockReadGuard<'_, T>;
+    /// Lock for writing, ignoring poison.
+    fn write_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> RecoverLock<T> for RwLock<T> {
+    fn read_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,301 @@
+//! The most recent log lines, kept in memory and served at `/logs`, for diagnosing the
+//! daemon without a shell on the device it runs on. Lines of level info and up are kept
+//! whatever `RUST_LOG` says; what gets printed follows `RUST_LOG`, unless adjusted for a
+//! while through `/logs/level`.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{SecondsFormat, Utc};
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use log::{info, Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{history::now_millis, lock::RecoverLock, query};
+
+/// Least severe level kept.
+const KEPT_LEVEL: Level = Level::Info;
+/// Number of lines `/logs` returns unless asked for another number.
+const DEFAULT_TAIL: usize = 100;
+
+/// Number of lines kept, set from the configuration once it's loaded.
+static CAPACITY: AtomicUsize = AtomicUsize::new(1000);
+static LINES: Mutex<VecDeque<Line>> = Mutex::new(VecDeque::new());
+static LOGGER: OnceLock<RingLogger> = OnceLock::new();
+
+struct Line {
+    at: String,
+    level: Level,
+    target: String,
+    message: String,
+}
+
+/// Prints log records like `env_logger` does, and keeps them.
+struct RingLogger {
+    /// Logger as configured through `RUST_LOG`.
+    base: env_logger::Logger,
+    adjusted: RwLock<Option<Adjusted>>,
+}
+
+/// A log filter set through `/logs/level`, on top of `RUST_LOG`.
+struct Adjusted {
+    filter: String,
+    logger: env_logger::Logger,
+    until: Instant,
+    /// `until` in milliseconds since the unix epoch.
+    until_millis: u64,
+}
+
+impl RingLogger {
+    /// Run `f` with the logger currently in effect, dropping an adjusted filter once it
+    /// expired.
+    fn with_logger<T>(&self, f: impl FnOnce(&env_logger::Logger) -> T) -> T {
+        let expired = match self.adjusted.read_recover().as_ref() {
+            Some(adjusted) if Instant::now() < adjusted.until => return f(&adjusted.logger),
+            Some(_) => true,
+            None => false,
+        };
+        if expired && self.reset() {
+            info!("Log filter adjustment expired, back to RUST_LOG.");
+        }
+        f(&self.base)
+    }
+
+    /// Go back to the filter configured through `RUST_LOG`. Returns whether the filter was
+    /// adjusted.
+    fn reset(&self) -> bool {
+        let was_adjusted = self.adjusted.write_recover().take().is_some();
+        log::set_max_level(self.base.filter().max(KEPT_LEVEL.to_level_filter()));
+        was_adjusted
+    }
+
+    fn adjust(&self, filter: String, duration: Duration) -> Result<(), String> {
+        let logger = env_logger::Builder::from_default_env()
+            .parse_filters(&filter)
+            .build();
+        let max_level = logger.filter().max(KEPT_LEVEL.to_level_filter());
+        *self.adjusted.write_recover() = Some(Adjusted {
+            filter,
+            logger,
+            until: Instant::now() + duration,
+            until_millis: now_millis() + duration.as_millis() as u64,
+        });
+        log::set_max_level(max_level);
+        Ok(())
+    }
+
+    fn state(&self) -> LevelState {
+        match self.adjusted.read_recover().as_ref() {
+            Some(adjusted) if Instant::now() < adjusted.until => LevelState {
+                filter: Some(adjusted.filter.clone()),
+                until: Some(adjusted.until_millis),
+            },
+            _ => LevelState {
+                filter: None,
+                until: None,
+            },
+        }
+    }
+}
+
+impl Log for RingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.with_logger(|logger| logger.enabled(metadata)) || metadata.level() <= KEPT_LEVEL
+    }
+
+    fn log(&self, record: &Record) {
+        self.with_logger(|logger| {
+            if logger.matches(record) {
+                logger.log(record);
+            }
+        });
+        if record.level() > KEPT_LEVEL {
+            return;
+        }
+        let capacity = CAPACITY.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+        if let Ok(mut lines) = LINES.lock() {
+            while lines.len() >= capacity {
+                lines.pop_front();
+            }
+            lines.push_back(Line {
+                at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                level: record.level(),
+                target: record.target().to_string(),
+                message: record.args().to_string(),
+            });
+        }
+    }
+
+    fn flush(&self) {
+        self.base.flush();
+    }
+}
+
+/// Set up logging, configured through `RUST_LOG` like `env_logger`.
+pub fn init() {
+    let logger = LOGGER.get_or_init(|| RingLogger {
+        base: env_logger::Builder::from_default_env().build(),
+        adjusted: RwLock::new(None),
+    });
+    if log::set_logger(logger).is_ok() {
+        logger.reset();
+    }
+}
+
+/// Keep `lines` lines from now on, 0 to keep none.
+pub fn set_capacity(lines: usize) {
+    CAPACITY.store(lines, Ordering::Relaxed);
+    if let Ok(mut kept) = LINES.lock() {
+        while kept.len() > lines {
+            kept.pop_front();
+        }
+    }
+}
+
+/// Query parameters of `/logs`.
+#[derive(Deserialize)]
+struct LogsParams {
+    #[serde(default, deserialize_with = "level")]
+    level: Option<LevelFilter>,
+    tail: Option<usize>,
+}
+
+fn level<'de, D: Deserializer<'de>>(d: D) -> Result<Option<LevelFilter>, D::Error> {
+    query::with(
+        d,
+        |level| LevelFilter::from_str(level).ok(),
+        "error, warn, info, debug or trace",
+    )
+}
+
+/// Query parameters of `PUT /logs/level`.
+#[derive(Deserialize)]
+struct LevelParams {
+    #[serde(default, rename = "for", deserialize_with = "query::duration")]
+    duration: Option<Duration>,
+}
+
+/// Handler for `/logs`. Returns the last `tail` lines of at least level `level`, as plain
+/// text.
+pub async fn handler(req: Request<Body>) -> Result<Response<Body>, hyper::http::Error> {
+    let params: LogsParams = match query::parse(&req) {
+        Ok(params) => params,
+        Err(e) => return bad_request(&e),
+    };
+    let level = params.level.unwrap_or(LevelFilter::Trace);
+    let tail = params.tail.unwrap_or(DEFAULT_TAIL);
+
+    let mut body = String::new();
+    if let Ok(lines) = LINES.lock() {
+        let matching: Vec<&Line> = lines.iter().filter(|line| line.level <= level).collect();
+        for line in &matching[matching.len().saturating_sub(tail)..] {
+            let _ = writeln!(
+                body,
+                "[{} {:<5} {}] {}",
+                line.at, line.level, line.target, line.message
+            );
+        }
+    }
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body))
+}
+
+fn bad_request(message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(format!("Error: {}", message)))
+}
+
+/// The log filter adjustment in effect, as served by `/logs/level`.
+#[derive(Serialize)]
+struct LevelState {
+    /// Filter in `RUST_LOG` syntax, applied on top of `RUST_LOG`.
+    filter: Option<String>,
+    /// Time the filter expires in milliseconds since the unix epoch.
+    until: Option<u64>,
+}
+
+/// Handler for `/logs/level`. `PUT` a filter in `RUST_LOG` syntax, e.g.
+/// `dsmrd::reader=trace`, to apply it for `for` (e.g. `5m`) or `duration`, `DELETE` to go
+/// back to `RUST_LOG` right away.
+pub async fn level_handler(
+    req: Request<Body>,
+    duration: Duration,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let Some(logger) = LOGGER.get() else {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from("Error: logging isn't set up."));
+    };
+    match *req.method() {
+        Method::GET => {}
+        Method::PUT => {
+            let duration = match query::parse::<LevelParams>(&req) {
+                Ok(params) => params.duration.unwrap_or(duration),
+                Err(e) => return bad_request(&e),
+            };
+            let filter = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => String::from_utf8_lossy(&body).trim().to_string(),
+                Err(e) => return bad_request(&e.to_string()),
+            };
+            if let Err(e) = check_filter(&filter) {
+                return bad_request(&e);
+            }
+            if let Err(e) = logger.adjust(filter.clone(), duration) {
+                return bad_request(&e);
+            }
+            info!(
+                "Log filter adjusted to {} for {} seconds.",
+                filter,
+                duration.as_secs()
+            );
+        }
+        Method::DELETE => {
+            if logger.reset() {
+                info!("Log filter back to RUST_LOG.");
+            }
+        }
+        _ => {
+            return Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::from("Error: method not allowed."))
+        }
+    }
+    match serde_json::to_string(&logger.state()) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+/// Check a filter in `RUST_LOG` syntax, which `env_logger` would otherwise just warn about.
+fn check_filter(filter: &str) -> Result<(), String> {
+    if filter.is_empty() {
+        return Err(String::from("no filter given"));
+    }
+    for directive in filter.split(',') {
+        if let Some((module, level)) = directive.split_once('=') {
+            if module.trim().is_empty() || LevelFilter::from_str(level.trim()).is_err() {
+                return Err(format!("invalid filter directive {}", directive));
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,14 +1,31 @@
 use crate::{
     endpoints::handler,
     reader::{spawn_dsmr_thread, ReaderData},
+    readiness::spawn_readiness_job,
 };
+use alerts::spawn_alert_job;
 use appdata::AppData;
+use baseload::spawn_baseload_job;
+#[cfg(feature = "coap")]
+use coap::spawn_coap_server;
+use config::Config;
+use export::spawn_export;
+use fuse::spawn_fuse_job;
+use ha::spawn_ha_job;
 use hyper::{
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
-    Server,
+    Body, Request, Server,
 };
 use log::{debug, error, info};
+#[cfg(feature = "mqtt")]
+use mqtt::spawn_mqtt_job;
+use notify::spawn_notifier;
+use prices::spawn_price_job;
+use readings::spawn_readings_job;
+use report::spawn_report_job;
+use rules::spawn_rules_job;
+use sink::spawn_sinks;
 use std::{
     convert::Infallible,
     env,
@@ -16,70 +33,343 @@ use std::{
     str::FromStr,
     sync::{Arc, RwLock},
 };
+use storage::{spawn_flush_job, spawn_vacuum_job};
 use udp_sender::spawn_udp_sender;
+use weather::spawn_weather_job;
 
+mod actions;
+mod aggregator;
+mod alerts;
+mod allowlist;
+mod analytics;
+mod annual;
 mod appdata;
+mod auth;
+mod backup;
+mod baseload;
+#[cfg(feature = "coap")]
+mod coap;
+mod compact;
+mod compression;
+mod config;
+mod dedup;
+mod derived;
+mod dial;
+#[cfg(feature = "dlms")]
+mod dlms;
+mod encryption;
 mod endpoints;
+mod events;
+mod export;
+mod feed;
+mod fuse;
+mod grafana;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod ha;
+#[cfg(feature = "dlms")]
+mod han;
+mod health;
+mod history;
+mod http_client;
+mod import;
+mod install;
+mod journal;
+mod lock;
+mod logs;
+mod metrics;
+mod model;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod netting;
+mod notify;
+mod obis;
+mod output;
+mod phase_energy;
+mod plain;
+mod prices;
+mod proxy;
+mod query;
 mod reader;
+mod readiness;
+mod readings;
+mod report;
+mod rpc;
+mod rules;
+mod sampling;
+mod schedule;
+mod session;
+mod signature;
+mod silence;
+mod sink;
+mod snapshot;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+mod sql;
+mod status;
+mod storage;
+mod supervisor;
+mod swap;
+mod system;
+mod traffic;
+mod tunnel;
 mod udp_sender;
+mod validate;
+mod weather;
+mod websocket;
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-    let path = match env::args().nth(2) {
+    logs::init();
+    supervisor::install_panic_hook();
+    if env::args().nth(1).as_deref() == Some("install") {
+        if let Err(e) = install::run(env::args().skip(2)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let command: Option<fn(_) -> _> = match env::args().nth(1).as_deref() {
+        Some("import") => Some(import::run),
+        Some("backup") => Some(backup::backup),
+        Some("restore") => Some(backup::restore),
+        _ => None,
+    };
+    if let Some(command) = command {
+        if let Err(e) = command(env::args().skip(2)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => panic!("Error loading configuration: {}", e),
+    };
+    logs::set_capacity(config.logs.lines);
+    let path = match env::args().nth(2).or(config.reader.device.take()) {
         Some(path) => path.to_owned(),
         None => String::from("/dev/ttyUSB0"),
     };
     info!("Using DSMR-reader at {:?}", path);
+    // The configuration served at `/config` is the one in effect, arguments included.
+    config.reader.device = Some(path.clone());
 
     // Create a mutex inside an Arc to store the DSMR state.
     let dsmr_state = Arc::new(RwLock::new(ReaderData::default()));
 
-    // We'll bind to 127.0.0.1:3000 unless we find an ip in the env args
-    let mut addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    // We'll bind to the configured addresses and the one in the env args, or to
+    // 127.0.0.1:3000 if there are none.
+    let mut addrs = config.http.listen.clone();
     if let Some(given_addr) = env::args().nth(1) {
         if let Ok(parsed_addr) = SocketAddr::from_str(&given_addr) {
             debug!("Assigning {} to server.", parsed_addr);
-            addr = parsed_addr
+            if !addrs.contains(&parsed_addr) {
+                addrs.insert(0, parsed_addr);
+            }
         };
     };
+    if addrs.is_empty() {
+        addrs.push(SocketAddr::from(([127, 0, 0, 1], 3000)));
+    }
+    config.http.listen = addrs.clone();
 
-    let appdata = Arc::new(AppData::new(addr));
+    // The UDP sender and CoAP server use the first address.
+    let appdata = Arc::new(AppData::new(addrs[0], config));
+    // Relays follow the alerts left firing before a restart.
+    actions::restore(&appdata);
 
     // Spawn the thread running the DSMR reader. This continuously retrieves
     // data from the reader and stores it in an rwlock. Emits an event when new data is
     // stored.
-    match spawn_dsmr_thread(appdata.clone(), dsmr_state.clone(), path) {
-        Ok(_) => debug!("Spawned DSMR thread."),
-        Err(e) => panic!("Error spawning DSMR thread: {}", e),
+    // An aggregator serves the meters pushed to it instead.
+    if appdata.config().aggregator.is_some() {
+        info!("Running as an aggregator, not reading {:?}", path);
+    } else {
+        match spawn_dsmr_thread(appdata.clone(), dsmr_state.clone(), path) {
+            Ok(_) => debug!("Spawned DSMR thread."),
+            Err(e) => panic!("Error spawning DSMR thread: {}", e),
+        }
+    }
+
+    // Spawn the thread connecting to the MQTT broker, if one is configured.
+    #[cfg(feature = "mqtt")]
+    if appdata.config().mqtt.is_some() {
+        match spawn_mqtt_job(appdata.clone()) {
+            Ok(_) => debug!("Spawned MQTT thread."),
+            Err(e) => panic!("Error spawning MQTT thread: {}", e),
+        };
+    }
+    #[cfg(not(feature = "mqtt"))]
+    if appdata.config().mqtt.is_some() {
+        log::warn!("MQTT is configured, but dsmrd was built without the mqtt feature.");
+    }
+
+    // Spawn the thread waiting for the first telegram before reporting ready.
+    match spawn_readiness_job(appdata.clone(), dsmr_state.clone()) {
+        Ok(_) => debug!("Spawned readiness thread."),
+        Err(e) => panic!("Error spawning readiness thread: {}", e),
     }
 
     // Spawn the thread running the UDP sender. This continuously checks for new data by
     // listening to the event in appdata.
     match spawn_udp_sender(appdata.clone(), dsmr_state.clone()) {
         Ok(_) => debug!("Spawned UDP sender thread."),
-        Err(e) => panic!("Error spawning UDP sender thread"),
+        Err(e) => panic!("Error spawning UDP sender thread: {}", e),
+    };
+
+    // Spawn a thread for every configured sink. These listen to the same event as the
+    // UDP sender.
+    match spawn_sinks(appdata.clone(), dsmr_state.clone()) {
+        Ok(handles) => debug!("Spawned {} sink threads.", handles.len()),
+        Err(e) => panic!("Error spawning sink threads: {}", e),
+    };
+
+    // Spawn the CoAP server, if enabled.
+    #[cfg(feature = "coap")]
+    if appdata.config().coap.is_some() {
+        match spawn_coap_server(appdata.clone(), dsmr_state.clone()) {
+            Ok(_) => debug!("Spawned CoAP server thread."),
+            Err(e) => panic!("Error spawning CoAP server: {}", e),
+        };
+    }
+    #[cfg(not(feature = "coap"))]
+    if appdata.config().coap.is_some() {
+        log::warn!("CoAP is configured, but dsmrd was built without the coap feature.");
+    }
+
+    // Spawn the thread fetching day-ahead prices, if a provider is configured.
+    if appdata.config().dynamic_prices.is_some() {
+        match spawn_price_job(appdata.clone()) {
+            Ok(_) => debug!("Spawned price thread."),
+            Err(e) => panic!("Error spawning price thread: {}", e),
+        };
+    }
+
+    // Spawn the thread fetching the outdoor temperature, if a source is configured.
+    if appdata.config().weather.is_some() {
+        match spawn_weather_job(appdata.clone()) {
+            Ok(_) => debug!("Spawned weather thread."),
+            Err(e) => panic!("Error spawning weather thread: {}", e),
+        };
+    }
+
+    // Spawn the thread sending the daily report, if one is configured.
+    if appdata.config().report.is_some() {
+        match spawn_report_job(appdata.clone()) {
+            Ok(_) => debug!("Spawned report thread."),
+            Err(e) => panic!("Error spawning report thread: {}", e),
+        };
+    }
+
+    // Spawn the thread taking the meter readings for the energy supplier.
+    match spawn_readings_job(appdata.clone()) {
+        Ok(_) => debug!("Spawned readings thread."),
+        Err(e) => panic!("Error spawning readings thread: {}", e),
+    };
+
+    // Spawn the thread estimating the baseload of every night.
+    match spawn_baseload_job(appdata.clone()) {
+        Ok(_) => debug!("Spawned baseload thread."),
+        Err(e) => panic!("Error spawning baseload thread: {}", e),
+    };
+
+    // Spawn the thread warning before the main fuse trips, if its rating is configured.
+    if appdata.config().fuse.is_some() {
+        match spawn_fuse_job(appdata.clone()) {
+            Ok(_) => debug!("Spawned fuse thread."),
+            Err(e) => panic!("Error spawning fuse thread: {}", e),
+        };
+    }
+
+    // Spawn the thread checking the alert rules, if any are configured.
+    if !appdata.config().alerts.rules.is_empty() {
+        match spawn_rules_job(appdata.clone()) {
+            Ok(_) => debug!("Spawned rules thread."),
+            Err(e) => panic!("Error spawning rules thread: {}", e),
+        };
+    }
+
+    // Spawn the thread reminding of alerts that keep firing.
+    match spawn_alert_job(appdata.clone()) {
+        Ok(_) => debug!("Spawned alert thread."),
+        Err(e) => panic!("Error spawning alert thread: {}", e),
     };
 
-    let dsmr_service = make_service_fn(move |_con: &AddrStream| {
-        // Clone mutex to share it with each invocation of `make_service`.
+    // Spawn the thread vacuuming the stores.
+    match spawn_vacuum_job(appdata.clone()) {
+        Ok(_) => debug!("Spawned vacuum thread."),
+        Err(e) => panic!("Error spawning vacuum thread: {}", e),
+    };
+
+    // Spawn the thread writing the samples waiting for the history journal.
+    match spawn_flush_job(appdata.clone()) {
+        Ok(_) => debug!("Spawned history flush thread."),
+        Err(e) => panic!("Error spawning history flush thread: {}", e),
+    };
+
+    // Spawn the thread sending notifications, if channels are configured.
+    if !appdata.config().notifications.is_empty() {
+        match spawn_notifier(appdata.clone()) {
+            Ok(_) => debug!("Spawned notifier thread."),
+            Err(e) => panic!("Error spawning notifier thread: {}", e),
+        };
+    }
+
+    // Spawn the thread exporting every telegram, if an export is configured.
+    if let Some(export) = &appdata.config().export {
+        match spawn_export(appdata.clone(), dsmr_state.clone(), export) {
+            Ok(_) => debug!("Spawned export thread."),
+            Err(e) => panic!("Error spawning export thread: {}", e),
+        };
+    }
+
+    // Spawn the thread deciding whether this daemon publishes, if it is one of a pair.
+    if appdata.config().ha.is_some() {
+        match spawn_ha_job(appdata.clone(), dsmr_state.clone()) {
+            Ok(_) => debug!("Spawned ha thread."),
+            Err(e) => panic!("Error spawning ha thread: {}", e),
+        };
+    }
+
+    // Every address gets its own server, all sharing the same state.
+    let mut servers = Vec::new();
+    for addr in addrs {
         let dsmr_state = dsmr_state.clone();
         let appdata = appdata.clone();
+        let dsmr_service = make_service_fn(move |con: &AddrStream| {
+            // Clone mutex to share it with each invocation of `make_service`.
+            let dsmr_state = dsmr_state.clone();
+            let appdata = appdata.clone();
+            let remote_addr = con.remote_addr();
 
-        // Create a `Service` for responding to the request.
-        // Note: this is yet another context so we clone the mutex again!
-        let service = service_fn(move |req| handler(req, dsmr_state.clone(), appdata.clone()));
+            // Create a `Service` for responding to the request. The address of the client is
+            // passed along in the request extensions.
+            // Note: this is yet another context so we clone the mutex again!
+            let service = service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(remote_addr);
+                handler(req, dsmr_state.clone(), appdata.clone())
+            });
 
-        // Return the service to hyper.
-        async move { Ok::<_, Infallible>(service) }
-    });
+            // Return the service to hyper.
+            async move { Ok::<_, Infallible>(service) }
+        });
 
-    let server = Server::bind(&addr).serve(dsmr_service);
-
-    info!("Listening on http://{}", addr);
+        match Server::try_bind(&addr) {
+            Ok(builder) => {
+                info!("Listening on http://{}", addr);
+                servers.push(builder.serve(dsmr_service));
+            }
+            Err(e) => error!("Unable to listen on {}: {}", addr, e),
+        }
+    }
+    if servers.is_empty() {
+        panic!("Error starting HTTP server: no address to listen on.");
+    }
 
-    // Run this server for... forever!
-    if let Err(e) = server.await {
-        error!("server error: {}", e);
+    // Run these servers for... forever!
+    for result in futures::future::join_all(servers).await {
+        if let Err(e) = result {
+            error!("server error: {}", e);
+        }
     }
 }
@@ -0,0 +1,217 @@
+//! Latest values in the Prometheus text exposition format, served at `/metrics`.
+//!
+//! Meter totals are exported as counters, so `rate()` and `increase()` work on them. A total
+//! that goes down means the meter was reset or replaced, unless `swap.auto` continues the
+//! totals; Prometheus handles that like any counter reset, and we count these in
+//! `dsmr_counter_resets_total` so they can be told apart from restarts of dsmrd.
+
+use std::{fmt::Write, sync::Arc};
+
+use hyper::{Body, Response, StatusCode};
+use log::debug;
+
+use crate::{
+    appdata::AppData,
+    dedup::Recent,
+    history::{FlushStats, Metric, Sample},
+    lock::RecoverLock,
+    model::MeterState,
+    supervisor, system,
+};
+
+/// Name of a metric as exported to metric stores.
+pub fn metric_name(metric: Metric) -> String {
+    format!("dsmr_{}", metric.name())
+}
+
+/// Prometheus type of a metric.
+pub fn metric_type(metric: Metric) -> &'static str {
+    if metric.is_cumulative() {
+        "counter"
+    } else {
+        "gauge"
+    }
+}
+
+/// Reset bookkeeping of a single meter total.
+#[derive(Clone, Copy, Debug, Default)]
+struct Counter {
+    last: Option<f64>,
+    resets: u64,
+    /// Time of the last reset in milliseconds since the unix epoch.
+    last_reset: Option<u64>,
+}
+
+/// Keeps track of resets of the meter totals, and of telegrams the reader dropped.
+#[derive(Debug)]
+pub struct Counters {
+    counters: Vec<(Metric, Counter)>,
+    /// Number of partial or corrupt telegrams dropped by the reader.
+    resyncs: u64,
+    /// The last telegrams, kept across reconnects.
+    recent: Recent<MeterState>,
+    /// Number of telegrams dropped by the reader as duplicates.
+    duplicates: u64,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self {
+            counters: Metric::ALL
+                .into_iter()
+                .filter(Metric::is_cumulative)
+                .map(|metric| (metric, Counter::default()))
+                .collect(),
+            resyncs: 0,
+            recent: Recent::default(),
+            duplicates: 0,
+        }
+    }
+}
+
+impl Counters {
+    /// Look for resets between the previous sample and this one.
+    pub fn observe(&mut self, sample: &Sample) {
+        for (metric, counter) in &mut self.counters {
+            let Some(value) = sample.get(*metric) else {
+                continue;
+            };
+            if counter.last.is_some_and(|last| value < last) {
+                counter.resets += 1;
+                counter.last_reset = Some(sample.timestamp);
+            }
+            counter.last = Some(value);
+        }
+    }
+
+    /// Count a telegram the reader dropped to resynchronize on the next one.
+    pub fn resync(&mut self) {
+        self.resyncs += 1;
+    }
+
+    /// Whether the reader read `state` before, see `dedup`. Counts it if so.
+    pub fn is_duplicate(&mut self, state: &MeterState) -> bool {
+        let duplicate = state.datetime.is_some() && self.recent.is_duplicate(state.clone());
+        if duplicate {
+            self.duplicates += 1;
+        }
+        duplicate
+    }
+}
+
+/// Render the latest sample and the reset counts.
+pub fn render(sample: Option<&Sample>, counters: &Counters) -> String {
+    let mut body = String::new();
+    if let Some(sample) = sample {
+        for metric in Metric::ALL {
+            if let Some(value) = sample.get(metric) {
+                let name = metric_name(metric);
+                let _ = writeln!(body, "# TYPE {} {}", name, metric_type(metric));
+                let _ = writeln!(body, "{} {}", name, value);
+            }
+        }
+    }
+
+    let _ = writeln!(
+        body,
+        "# HELP dsmr_counter_resets_total Number of times a meter total went down."
+    );
+    let _ = writeln!(body, "# TYPE dsmr_counter_resets_total counter");
+    for (metric, counter) in &counters.counters {
+        let _ = writeln!(
+            body,
+            "dsmr_counter_resets_total{{metric=\"{}\"}} {}",
+            metric_name(*metric),
+            counter.resets
+        );
+    }
+    let _ = writeln!(
+        body,
+        "# HELP dsmr_counter_last_reset_timestamp_seconds Time of the last reset of a meter total."
+    );
+    let _ = writeln!(
+        body,
+        "# TYPE dsmr_counter_last_reset_timestamp_seconds gauge"
+    );
+    for (metric, counter) in &counters.counters {
+        if let Some(last_reset) = counter.last_reset {
+            let _ = writeln!(
+                body,
+                "dsmr_counter_last_reset_timestamp_seconds{{metric=\"{}\"}} {}",
+                metric_name(*metric),
+                last_reset as f64 / 1000.0
+            );
+        }
+    }
+    let _ = writeln!(
+        body,
+        "# HELP dsmr_reader_resyncs_total Number of partial or corrupt telegrams dropped."
+    );
+    let _ = writeln!(body, "# TYPE dsmr_reader_resyncs_total counter");
+    let _ = writeln!(body, "dsmr_reader_resyncs_total {}", counters.resyncs);
+    let _ = writeln!(
+        body,
+        "# HELP dsmr_reader_duplicates_total Number of telegrams dropped as duplicates."
+    );
+    let _ = writeln!(body, "# TYPE dsmr_reader_duplicates_total counter");
+    let _ = writeln!(body, "dsmr_reader_duplicates_total {}", counters.duplicates);
+    let _ = writeln!(
+        body,
+        "# HELP dsmr_panics_total Number of panics in the daemon, each followed by a restart of the job it happened in."
+    );
+    let _ = writeln!(body, "# TYPE dsmr_panics_total counter");
+    let _ = writeln!(body, "dsmr_panics_total {}", supervisor::panics());
+    body
+}
+
+/// Render how far the writes of the history store are behind.
+fn render_flush(stats: &FlushStats) -> String {
+    let mut body = String::new();
+    let _ = writeln!(
+        body,
+        "# HELP dsmr_history_flushes_total Number of batches of samples written to the history journal."
+    );
+    let _ = writeln!(body, "# TYPE dsmr_history_flushes_total counter");
+    let _ = writeln!(body, "dsmr_history_flushes_total {}", stats.flushes);
+    let _ = writeln!(
+        body,
+        "# HELP dsmr_history_flush_errors_total Number of batches of samples that couldn't be written."
+    );
+    let _ = writeln!(body, "# TYPE dsmr_history_flush_errors_total counter");
+    let _ = writeln!(body, "dsmr_history_flush_errors_total {}", stats.errors);
+    let _ = writeln!(
+        body,
+        "# HELP dsmr_history_pending_samples Number of samples waiting to be written."
+    );
+    let _ = writeln!(body, "# TYPE dsmr_history_pending_samples gauge");
+    let _ = writeln!(body, "dsmr_history_pending_samples {}", stats.pending);
+    let _ = writeln!(
+        body,
+        "# HELP dsmr_history_flush_lag_seconds Time the oldest waiting sample has waited."
+    );
+    let _ = writeln!(body, "# TYPE dsmr_history_flush_lag_seconds gauge");
+    let _ = writeln!(
+        body,
+        "dsmr_history_flush_lag_seconds {}",
+        stats.lag as f64 / 1000.0
+    );
+    body
+}
+
+/// Handler for `/metrics`.
+pub async fn handler(appdata: Arc<AppData>) -> Result<Response<Body>, hyper::http::Error> {
+    let history = appdata.history.read_recover();
+    let counters = appdata.counters.read_recover();
+    let mut body = render(history.latest().as_ref(), &counters);
+    if let Some(stats) = history.flush_stats() {
+        body.push_str(&render_flush(&stats));
+    }
+    match system::usage() {
+        Ok(usage) => body.push_str(&system::render(&usage)),
+        Err(e) => debug!("Unable to read resource usage: {}", e),
+    }
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(body))
+}
@@ -0,0 +1,111 @@
+//! The meter state as exposed by dsmrd, independent of the parser that produced it.
+//!
+//! Every reader backend converts its data into a `MeterState`, which is what gets stored,
+//! served and sent to clients. This keeps our output stable when a parser changes.
+
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+/// Device type of gas meters connected to the meter.
+pub const DEVICE_TYPE_GAS: u64 = 3;
+
+/// The state of the meter as reported by a single telegram.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct MeterState {
+    /// Time of the telegram according to the meter.
+    pub datetime: Option<DateTime<FixedOffset>>,
+    /// Version of the protocol used by the meter.
+    pub version: Option<String>,
+    pub equipment_id: Option<String>,
+    /// The tariff currently in effect, 1 or 2.
+    pub tariff: Option<u16>,
+    /// Energy delivered to the client per tariff in kWh.
+    pub energy_delivered: [Option<f64>; 2],
+    /// Energy delivered by the client per tariff in kWh.
+    pub energy_received: [Option<f64>; 2],
+    /// Power delivered to the client in kW.
+    pub power_delivered: Option<f64>,
+    /// Power delivered by the client in kW.
+    pub power_received: Option<f64>,
+    /// Reactive energy imported (Q+) and exported (Q-) per tariff in kvarh, for the meters
+    /// that report it. Meters without tariffs only send totals, stored as the first tariff.
+    pub reactive_energy_delivered: [Option<f64>; 2],
+    pub reactive_energy_received: [Option<f64>; 2],
+    /// Reactive energy per quadrant (QI to QIV) in kvarh, for four-quadrant meters.
+    pub reactive_energy_quadrants: [Option<f64>; 4],
+    /// Reactive power imported (Q+) and exported (Q-) in kvar.
+    pub reactive_power_delivered: Option<f64>,
+    pub reactive_power_received: Option<f64>,
+    pub power_failures: Option<u64>,
+    pub long_power_failures: Option<u64>,
+    pub phases: [Phase; 3],
+    /// Other meters (gas, water, heat) connected to the meter.
+    pub channels: [Channel; 4],
+}
+
+/// Values of one of the three phases.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct Phase {
+    pub voltage_sags: Option<u64>,
+    pub voltage_swells: Option<u64>,
+    /// Voltage in V.
+    pub voltage: Option<f64>,
+    /// Current in A.
+    pub current: Option<f64>,
+    /// Power delivered to the client in kW.
+    pub power_delivered: Option<f64>,
+    /// Power delivered by the client in kW.
+    pub power_received: Option<f64>,
+    /// Reactive power imported (Q+) and exported (Q-) in kvar.
+    pub reactive_power_delivered: Option<f64>,
+    pub reactive_power_received: Option<f64>,
+}
+
+/// A meter connected to the main meter.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct Channel {
+    pub device_type: Option<u64>,
+    pub equipment_id: Option<String>,
+    pub reading: Option<Measurement>,
+}
+
+/// A meter reading along with the time it was taken.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct Measurement {
+    pub datetime: Option<DateTime<FixedOffset>>,
+    pub value: f64,
+}
+
+impl MeterState {
+    /// The reading of the gas meter. Falls back to the first channel with a reading,
+    /// as not every meter reports the device type.
+    pub fn gas(&self) -> Option<&Measurement> {
+        self.channels
+            .iter()
+            .find(|c| c.device_type == Some(DEVICE_TYPE_GAS) && c.reading.is_some())
+            .or_else(|| self.channels.iter().find(|c| c.reading.is_some()))
+            .and_then(|c| c.reading.as_ref())
+    }
+}
+
+/// Build a timestamp from the local time as sent by the meter. Meters report in Central
+/// European Time and tell us whether daylight saving time is in effect.
+pub fn meter_time(
+    year: i32,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    dst: bool,
+) -> Option<DateTime<FixedOffset>> {
+    let offset = FixedOffset::east_opt(if dst { 7200 } else { 3600 })?;
+    NaiveDate::from_ymd_opt(year, month.into(), day.into())?
+        .and_hms_opt(hour.into(), minute.into(), second.into())?
+        .and_local_timezone(offset)
+        .single()
+}
@@ -0,0 +1,288 @@
+//! The connection to the MQTT broker in `mqtt`, shared by everything the daemon publishes.
+//! Topics are relative to `mqtt.prefix`, and messages are published with QoS 1, signed
+//! when `mqtt.hmac_key` is set.
+//!
+//! The connection is made again whenever it's lost. Messages published while there is
+//! none are dropped, except for retained ones: the last one of every retained topic is
+//! published again on every connection, so the broker holds the latest. Subscriptions
+//! are made again as well.
+//!
+//! The availability of the daemon is kept retained in `status`: `offline` until the first
+//! telegram is received, `online` from then on, and `offline` again as the last will when
+//! the connection is lost.
+
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use rumqttc::{Client, ConnectReturnCode, Event, LastWill, MqttOptions, Packet, QoS};
+use url::Url;
+
+use crate::{
+    allowlist, appdata::AppData, config::MqttConfig, history::now_millis, signature,
+    status::LastError, supervisor,
+};
+
+const DEFAULT_PORT: u16 = 1883;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Wait before connecting again after the connection failed.
+const RETRY: Duration = Duration::from_secs(5);
+/// Messages waiting to be sent before publishing fails.
+const CAPACITY: usize = 64;
+/// Topic of the availability of the daemon.
+pub const AVAILABILITY: &str = "status";
+
+/// How the connection to the broker fared, for `/health/detail`.
+#[derive(Clone, Debug, Default)]
+pub struct MqttStatus {
+    /// Time of the last connection or acknowledged message in milliseconds since the unix
+    /// epoch.
+    pub last_success: Option<u64>,
+    pub last_error: Option<LastError>,
+    /// Connections made after the first.
+    pub reconnects: u64,
+}
+
+pub struct Mqtt {
+    client: Client,
+    prefix: String,
+    hmac_key: Option<String>,
+    connected: AtomicBool,
+    status: Mutex<MqttStatus>,
+    /// The last message of every retained topic, by topic.
+    retained: Mutex<BTreeMap<String, Vec<u8>>>,
+    /// Topics subscribed to, with where their messages go.
+    subscriptions: Mutex<Vec<(String, Sender<Vec<u8>>)>>,
+}
+
+impl fmt::Debug for Mqtt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mqtt")
+            .field("prefix", &self.prefix)
+            .field("connected", &self.connected)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Mqtt {
+    /// Whether the daemon is connected to the broker.
+    pub fn connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> MqttStatus {
+        self.status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn update_status(&self, update: impl FnOnce(&mut MqttStatus)) {
+        update(&mut self.status.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    /// Publish `payload` to `topic` under the prefix. Returns the number of bytes sent,
+    /// 0 for a retained message waiting for the connection.
+    pub fn publish(&self, topic: &str, payload: &[u8], retain: bool) -> Result<u64, String> {
+        let signed;
+        let payload = match &self.hmac_key {
+            Some(key) if topic != AVAILABILITY => {
+                signed = signature::sign(key, payload)?;
+                &signed[..]
+            }
+            _ => payload,
+        };
+        let topic = self.topic(topic);
+        if retain {
+            self.retained
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(topic.clone(), payload.to_vec());
+        }
+        match self.connected() {
+            true => {}
+            false if retain => return Ok(0),
+            false => return Err(String::from("Not connected to the MQTT broker")),
+        }
+        self.client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload)
+            .map_err(|e| {
+                let message = format!("Unable to publish over MQTT: {}", e);
+                self.update_status(|status| status.last_error = Some(LastError::now(&message)));
+                message
+            })?;
+        Ok(payload.len() as u64)
+    }
+
+    /// The messages published to `topic` from now on. The topic is taken as is, without
+    /// the prefix, as it's usually one of another device.
+    pub fn subscribe(&self, topic: &str) -> Receiver<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscriptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((topic.to_string(), sender));
+        if self.connected() {
+            if let Err(e) = self.client.try_subscribe(topic, QoS::AtLeastOnce) {
+                warn!("Unable to subscribe to {} over MQTT: {}", topic, e);
+            }
+        }
+        receiver
+    }
+
+    /// Hand a message to the subscribers of its topic, forgetting the ones that left.
+    fn deliver(&self, topic: &str, payload: &[u8]) {
+        self.subscriptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(subscribed, sender)| {
+                subscribed != topic || sender.send(payload.to_vec()).is_ok()
+            });
+    }
+
+    /// Subscribe to the topics again, after connecting.
+    fn resubscribe(&self) {
+        let subscriptions = self
+            .subscriptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for (topic, _) in subscriptions.iter() {
+            if let Err(e) = self.client.try_subscribe(topic, QoS::AtLeastOnce) {
+                warn!("Unable to subscribe to {} over MQTT: {}", topic, e);
+            }
+        }
+    }
+
+    fn topic(&self, topic: &str) -> String {
+        prefixed(&self.prefix, topic)
+    }
+
+    /// Publish the retained messages again, after connecting.
+    fn republish(&self) {
+        let retained = self.retained.lock().unwrap_or_else(PoisonError::into_inner);
+        for (topic, payload) in retained.iter() {
+            if let Err(e) = self
+                .client
+                .try_publish(topic, QoS::AtLeastOnce, true, payload.clone())
+            {
+                warn!("Unable to publish {} over MQTT: {}", topic, e);
+            }
+        }
+    }
+}
+
+fn prefixed(prefix: &str, topic: &str) -> String {
+    match prefix.trim_end_matches('/') {
+        "" => topic.to_string(),
+        prefix => format!("{}/{}", prefix, topic),
+    }
+}
+
+fn options(config: &MqttConfig) -> Result<MqttOptions, String> {
+    let url =
+        Url::parse(&config.url).map_err(|e| format!("Invalid MQTT url {}: {}", config.url, e))?;
+    if url.scheme() != "mqtt" {
+        return Err(format!("Unsupported MQTT url {}", config.url));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("No host in MQTT url {}", config.url))?;
+    let mut options = MqttOptions::new(&config.client_id, host, url.port().unwrap_or(DEFAULT_PORT));
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_last_will(LastWill::new(
+        prefixed(&config.prefix, AVAILABILITY),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if !url.username().is_empty() {
+        options.set_credentials(url.username(), url.password().unwrap_or_default());
+    }
+    Ok(options)
+}
+
+/// Spawn a thread keeping the connection to the broker, which is available through
+/// `AppData::mqtt` from now on.
+pub fn spawn_mqtt_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, io::Error> {
+    let config = appdata
+        .config()
+        .mqtt
+        .as_ref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No MQTT broker"))?;
+    let options = options(config).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let (client, connection) = Client::new(options, CAPACITY);
+    let mqtt = Mqtt {
+        client,
+        prefix: config.prefix.clone(),
+        hmac_key: config.hmac_key.clone(),
+        connected: AtomicBool::new(false),
+        status: Mutex::new(MqttStatus::default()),
+        retained: Mutex::new(BTreeMap::new()),
+        subscriptions: Mutex::new(Vec::new()),
+    };
+    // Replaces an `online` left by an earlier run, until the first telegram.
+    let _ = mqtt.publish(AVAILABILITY, b"offline", true);
+    appdata.set_mqtt(mqtt);
+    let connection = Mutex::new(connection);
+    thread::Builder::new()
+        .name(String::from("mqtt"))
+        .spawn(move || {
+            let (Some(config), Some(mqtt)) = (&appdata.config().mqtt, appdata.mqtt()) else {
+                return;
+            };
+            if let Err(e) = allowlist::check_url(&appdata.config().outbound.allow, &config.url) {
+                error!("Unable to connect to the MQTT broker: {}", e);
+                return;
+            }
+            supervisor::run("mqtt", &appdata, |_| {
+                let mut connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut failing = false;
+                for event in connection.iter() {
+                    match event {
+                        Ok(Event::Incoming(Packet::ConnAck(ack)))
+                            if ack.code == ConnectReturnCode::Success =>
+                        {
+                            info!("Connected to MQTT broker {}", config.url);
+                            failing = false;
+                            mqtt.update_status(|status| {
+                                if status.last_success.is_some() {
+                                    status.reconnects += 1;
+                                }
+                                status.last_success = Some(now_millis());
+                            });
+                            mqtt.connected.store(true, Ordering::Relaxed);
+                            mqtt.resubscribe();
+                            mqtt.republish();
+                        }
+                        Ok(Event::Incoming(Packet::PubAck(_))) => {
+                            mqtt.update_status(|status| status.last_success = Some(now_millis()));
+                        }
+                        Ok(Event::Incoming(Packet::Publish(publish))) => {
+                            mqtt.deliver(&publish.topic, &publish.payload);
+                        }
+                        Ok(event) => debug!("MQTT: {:?}", event),
+                        Err(e) => {
+                            mqtt.connected.store(false, Ordering::Relaxed);
+                            mqtt.update_status(|status| {
+                                status.last_error = Some(LastError::now(e.to_string()))
+                            });
+                            if !failing {
+                                error!("Unable to reach MQTT broker {}: {}", config.url, e);
+                                failing = true;
+                            }
+                            thread::sleep(RETRY);
+                        }
+                    }
+                }
+            });
+        })
+}
@@ -0,0 +1,305 @@
+//! Scenarios for the end of net metering (saldering), for clients with solar panels. Under
+//! net metering the electricity fed back is deducted from the electricity delivered at the
+//! full price. Without it, the electricity fed back only earns the feed-in compensation,
+//! and some suppliers even charge for feeding back.
+//!
+//! The meter totals at the start and end of every month are recorded, and
+//! `/analytics/saldering` reprices every month under each configured scenario. Netting is
+//! settled per month here rather than per year, so months with a surplus count as such.
+//! Every month is priced at the contract in effect at its start, without standing charges.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fs,
+    sync::Arc,
+};
+
+use chrono::{Datelike, Local, NaiveDate, TimeZone};
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    appdata::AppData,
+    config::{NettingConfig, NettingScenario, PriceConfig},
+    history::{day_range, Metric, Sample},
+    lock::RecoverLock,
+    prices,
+};
+
+/// Number of months kept.
+const MAX_MONTHS: usize = 36;
+
+/// Electricity meter totals per tariff at a point in time.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Readings {
+    delivered: [Option<f64>; 2],
+    received: [Option<f64>; 2],
+}
+
+impl Readings {
+    fn from_sample(sample: &Sample) -> Self {
+        Self {
+            delivered: [
+                sample.get(Metric::EnergyDeliveredTariff1),
+                sample.get(Metric::EnergyDeliveredTariff2),
+            ],
+            received: [
+                sample.get(Metric::EnergyReceivedTariff1),
+                sample.get(Metric::EnergyReceivedTariff2),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Month {
+    first: Readings,
+    last: Readings,
+}
+
+/// The meter totals at the start and end of every month, by the first day of the month.
+#[derive(Debug)]
+pub struct Months {
+    file: Option<String>,
+    months: BTreeMap<NaiveDate, Month>,
+}
+
+impl Months {
+    pub fn new(config: &NettingConfig) -> Self {
+        let months = config
+            .file
+            .as_ref()
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(months) => serde_json::from_str(&months)
+                    .map_err(|e| error!("Unable to parse monthly totals {}: {}", path, e))
+                    .ok(),
+                Err(e) => {
+                    info!("No monthly totals read from {}: {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            file: config.file.clone(),
+            months,
+        }
+    }
+
+    pub fn observe(&mut self, sample: &Sample) {
+        let readings = Readings::from_sample(sample);
+        if readings.delivered.iter().all(Option::is_none) {
+            return;
+        }
+        let Some(start) = Local
+            .timestamp_millis_opt(sample.timestamp as i64)
+            .single()
+            .and_then(|time| time.date_naive().with_day(1))
+        else {
+            return;
+        };
+
+        if let Some(month) = self.months.get_mut(&start) {
+            month.last = readings;
+            return;
+        }
+        // The month before ends where this one starts.
+        if let Some((_, month)) = self.months.range_mut(..start).next_back() {
+            month.last = readings;
+        }
+        self.months.insert(
+            start,
+            Month {
+                first: readings,
+                last: readings,
+            },
+        );
+        while self.months.len() > MAX_MONTHS {
+            self.months.pop_first();
+        }
+        self.save();
+    }
+
+    /// Record the months of `samples`, oldest first, that aren't recorded yet. Returns the
+    /// number of months added.
+    pub fn import(&mut self, samples: &[Sample]) -> usize {
+        let mut imported = Months {
+            file: None,
+            months: BTreeMap::new(),
+        };
+        for sample in samples {
+            imported.observe(sample);
+        }
+        let mut added = 0;
+        for (start, month) in imported.months {
+            if let Entry::Vacant(entry) = self.months.entry(start) {
+                entry.insert(month);
+                added += 1;
+            }
+        }
+        while self.months.len() > MAX_MONTHS {
+            self.months.pop_first();
+        }
+        self.save();
+        added
+    }
+
+    /// Electricity delivered to and by the client in kWh in every month recorded, by the
+    /// first day of the month.
+    pub fn usage(&self) -> Vec<(NaiveDate, Option<f64>, Option<f64>)> {
+        let total = |first: [Option<f64>; 2], last: [Option<f64>; 2]| {
+            Some(last[0]? - first[0]? + last[1]? - first[1]?)
+        };
+        self.months
+            .iter()
+            .map(|(start, month)| {
+                (
+                    *start,
+                    total(month.first.delivered, month.last.delivered),
+                    total(month.first.received, month.last.received),
+                )
+            })
+            .collect()
+    }
+
+    pub fn save(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let result = serde_json::to_string(&self.months)
+            .map_err(|e| e.to_string())
+            .and_then(|months| fs::write(path, months).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Unable to write monthly totals {}: {}", path, e);
+        }
+    }
+}
+
+/// A month repriced under every scenario, as served by `/analytics/saldering`.
+#[derive(Serialize)]
+struct MonthFigures {
+    month: String,
+    /// Electricity delivered to and by the client per tariff in kWh.
+    delivered: [f64; 2],
+    received: [f64; 2],
+    scenarios: Vec<ScenarioCost>,
+}
+
+#[derive(Serialize)]
+struct ScenarioCost {
+    name: String,
+    /// Electricity fed back that is deducted from the electricity delivered in kWh.
+    netted: f64,
+    /// Cost of the electricity, negative when the client earns money.
+    cost: f64,
+}
+
+/// Cost of a month with `delivered` and `received` kWh per tariff under `scenario`.
+fn cost(
+    prices: &PriceConfig,
+    scenario: &NettingScenario,
+    delivered: [f64; 2],
+    received: [f64; 2],
+) -> ScenarioCost {
+    let delivered_total = delivered[0] + delivered[1];
+    let received_total = received[0] + received[1];
+    let gross = delivered[0] * prices.delivered[0] + delivered[1] * prices.delivered[1];
+    let netted = (received_total * scenario.netting.clamp(0.0, 1.0)).min(delivered_total);
+    // Netted electricity is worth the average price paid for the electricity delivered.
+    let netted_value = match delivered_total > 0.0 {
+        true => netted * gross / delivered_total,
+        false => 0.0,
+    };
+    // Whatever isn't netted earns the feed-in compensation, spread over the tariffs.
+    let rest = received_total - netted;
+    let compensation = match scenario.feed_in {
+        Some(feed_in) => rest * feed_in,
+        None if received_total > 0.0 => {
+            rest * (received[0] * prices.received[0] + received[1] * prices.received[1])
+                / received_total
+        }
+        None => 0.0,
+    };
+    ScenarioCost {
+        name: scenario.name.clone(),
+        netted,
+        cost: gross - netted_value - compensation + rest * scenario.feed_in_cost,
+    }
+}
+
+/// The scenarios compared when none are configured: full net metering and none at all,
+/// both with the configured compensation.
+fn default_scenarios() -> Vec<NettingScenario> {
+    vec![
+        NettingScenario {
+            name: String::from("saldering"),
+            netting: 1.0,
+            feed_in: None,
+            feed_in_cost: 0.0,
+        },
+        NettingScenario {
+            name: String::from("no saldering"),
+            netting: 0.0,
+            feed_in: None,
+            feed_in_cost: 0.0,
+        },
+    ]
+}
+
+fn respond(status: StatusCode, message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+}
+
+/// Handler for `/analytics/saldering`, listing every month recorded, most recent last.
+pub fn handler(appdata: Arc<AppData>) -> Result<Response<Body>, hyper::http::Error> {
+    if appdata.config().prices.is_none() && appdata.config().contracts.is_empty() {
+        return respond(
+            StatusCode::CONFLICT,
+            "Error: prices must be configured to compare scenarios.",
+        );
+    }
+    let config = &appdata.config().netting;
+    let scenarios = match config.scenarios.is_empty() {
+        true => default_scenarios(),
+        false => config.scenarios.clone(),
+    };
+    let usage = |first: Option<f64>, last: Option<f64>| Some(last? - first?);
+
+    let figures: Vec<MonthFigures> = appdata
+        .months
+        .read_recover()
+        .months
+        .iter()
+        .filter_map(|(start, month)| {
+            // Months are priced at the contract they start in.
+            let prices = prices::fixed_prices(appdata.config(), day_range(*start).0)?;
+            let (first, last) = (&month.first, &month.last);
+            let delivered = [
+                usage(first.delivered[0], last.delivered[0])?,
+                usage(first.delivered[1], last.delivered[1])?,
+            ];
+            let received = [
+                usage(first.received[0], last.received[0]).unwrap_or(0.0),
+                usage(first.received[1], last.received[1]).unwrap_or(0.0),
+            ];
+            Some(MonthFigures {
+                month: start.format("%Y-%m").to_string(),
+                delivered,
+                received,
+                scenarios: scenarios
+                    .iter()
+                    .map(|scenario| cost(prices, scenario, delivered, received))
+                    .collect(),
+            })
+        })
+        .collect();
+    match serde_json::to_string(&figures) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, &format!("Error: {}", e)),
+    }
+}
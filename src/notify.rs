@@ -0,0 +1,176 @@
+//! Push notifications of events to phones through Pushover, a Telegram bot or ntfy, so
+//! alerts arrive without a webhook receiver of one's own, or as JSON to an MQTT topic. Every configured channel gets
+//! the events of the kinds it is set up for, power failures and alerts with their
+//! reminders and recoveries by default. Events covered by a silence are not sent.
+
+use std::{sync::Arc, thread::JoinHandle};
+
+use event_listener::Listener;
+use log::{debug, error, info};
+use serde_json::json;
+
+use crate::{
+    appdata::AppData,
+    config::{NotificationChannel, NotificationConfig},
+    dial::Dialer,
+    events::{Event, EventKind},
+    http_client::HttpClient,
+    lock::RecoverLock,
+    supervisor,
+};
+
+const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+const TELEGRAM_URL: &str = "https://api.telegram.org";
+
+/// Send `event` through `channel`. Urgent alerts go out with high priority on the channels
+/// that have one.
+#[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+fn send(
+    appdata: &AppData,
+    client: &HttpClient,
+    channel: &NotificationChannel,
+    event: &Event,
+) -> Result<(), String> {
+    let urgent = event.kind == EventKind::UrgentAlert;
+    match channel {
+        NotificationChannel::Pushover { token, user } => {
+            let body = url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("token", token)
+                .append_pair("user", user)
+                .append_pair("title", &event.summary)
+                .append_pair("message", &event.description)
+                .append_pair("priority", if urgent { "1" } else { "0" })
+                .finish();
+            client.post(
+                PUSHOVER_URL,
+                "application/x-www-form-urlencoded",
+                &[],
+                body.into_bytes(),
+            )
+        }
+        NotificationChannel::Telegram { token, chat_id } => {
+            let body = json!({
+                "chat_id": chat_id,
+                "text": format!("{}\n{}", event.summary, event.description),
+            });
+            client.post(
+                &format!("{}/bot{}/sendMessage", TELEGRAM_URL, token),
+                "application/json",
+                &[],
+                body.to_string().into_bytes(),
+            )
+        }
+        NotificationChannel::Ntfy { url, topic, token } => {
+            let authorization = token.as_ref().map(|token| format!("Bearer {}", token));
+            let mut headers = vec![("Title", event.summary.as_str())];
+            if urgent {
+                headers.push(("Priority", "high"));
+            }
+            if let Some(authorization) = &authorization {
+                headers.push(("Authorization", authorization));
+            }
+            client.post(
+                &format!("{}/{}", url.trim_end_matches('/'), topic),
+                "text/plain; charset=utf-8",
+                &headers,
+                event.description.clone().into_bytes(),
+            )
+        }
+        #[cfg(feature = "mqtt")]
+        NotificationChannel::Mqtt { topic } => {
+            let mqtt = appdata.mqtt().ok_or("no MQTT broker is configured")?;
+            let payload = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+            mqtt.publish(topic, &payload, false).map(|_| ())
+        }
+    }
+}
+
+/// Send `event` through every channel set up for its kind right away, silenced or not.
+/// Returns the result for each of them.
+pub fn send_now(appdata: &AppData, event: &Event) -> Vec<(&'static str, Result<(), String>)> {
+    let client = HttpClient::new(Dialer::with_proxy(appdata.config().outbound.proxy.as_ref()));
+    appdata
+        .config()
+        .notifications
+        .iter()
+        .filter(|notification| notification.events.contains(&event.kind))
+        .map(|notification| {
+            let result = client
+                .as_ref()
+                .map_err(Clone::clone)
+                .and_then(|client| send(appdata, client, &notification.channel, event));
+            (notification.channel.name(), result)
+        })
+        .collect()
+}
+
+/// Spawn a thread that sends every new event to the configured channels.
+pub fn spawn_notifier(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("notifier", appdata, |appdata| {
+        let config: &[NotificationConfig] = &appdata.config().notifications;
+        let client =
+            match HttpClient::new(Dialer::with_proxy(appdata.config().outbound.proxy.as_ref())) {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("Unable to start notifier: {}", e);
+                    return;
+                }
+            };
+        info!("Notifier started for {} channels.", config.len());
+
+        // Events from before the start were notified already.
+        let mut last = appdata
+            .events
+            .read_recover()
+            .all()
+            .back()
+            .map_or(0, |event| event.timestamp);
+        loop {
+            // Events are checked on every telegram, and alerts recorded in between go
+            // out with the next one.
+            let listener = appdata.event_listener();
+            listener.wait();
+
+            let new: Vec<Event> = appdata
+                .events
+                .read_recover()
+                .all()
+                .iter()
+                .filter(|event| event.timestamp > last)
+                .cloned()
+                .collect();
+            let Some(newest) = new.last() else {
+                continue;
+            };
+            last = newest.timestamp;
+
+            for event in &new {
+                if appdata
+                    .silences
+                    .read_recover()
+                    .is_silenced(event.kind, event.timestamp)
+                {
+                    debug!("Not sending {}, alerts are silenced.", event.summary);
+                    continue;
+                }
+                for notification in config {
+                    if !notification.events.contains(&event.kind) {
+                        continue;
+                    }
+                    match send(appdata, &client, &notification.channel, event) {
+                        Ok(_) => debug!(
+                            "Sent {} through {}",
+                            event.summary,
+                            notification.channel.name()
+                        ),
+                        Err(e) => error!(
+                            "Unable to send notification through {}: {}",
+                            notification.channel.name(),
+                            e
+                        ),
+                    }
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,335 @@
+//! OBIS codes, units and descriptions of the fields of the meter state, following the
+//! DSMR 5.0.2 P1 companion standard. Used to annotate the state in verbose mode and to
+//! describe the fields at `/schema`. Descriptions are available in English and Dutch.
+
+use serde::Serialize;
+
+/// Language of the descriptions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    #[default]
+    En,
+    Nl,
+}
+
+impl Lang {
+    pub fn from_name(name: &str) -> Option<Lang> {
+        match name {
+            "en" => Some(Lang::En),
+            "nl" => Some(Lang::Nl),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Nl => "nl",
+        }
+    }
+
+    /// Pick the description in this language.
+    fn pick(self, en: impl Into<String>, nl: impl Into<String>) -> String {
+        match self {
+            Lang::En => en.into(),
+            Lang::Nl => nl.into(),
+        }
+    }
+}
+
+/// What a field of the meter state means.
+#[derive(Debug, Serialize)]
+pub struct FieldInfo {
+    pub obis: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<&'static str>,
+    pub description: String,
+}
+
+impl FieldInfo {
+    fn new(
+        obis: impl Into<String>,
+        unit: Option<&'static str>,
+        description: impl Into<String>,
+    ) -> Self {
+        Self {
+            obis: obis.into(),
+            unit,
+            description: description.into(),
+        }
+    }
+}
+
+/// Look up a field by its path in the serialized state, e.g. `["phases", "0", "voltage"]`.
+/// `device_type` is the device type of the channel the path points into, if any.
+pub fn describe(path: &[&str], device_type: Option<u64>, lang: Lang) -> Option<FieldInfo> {
+    let info = match path {
+        ["datetime"] => FieldInfo::new(
+            "0-0:1.0.0",
+            None,
+            lang.pick(
+                "Date and time of the telegram",
+                "Datum en tijd van het telegram",
+            ),
+        ),
+        ["version"] => FieldInfo::new(
+            "1-3:0.2.8",
+            None,
+            lang.pick("Version of the P1 output", "Versie van de P1-poort"),
+        ),
+        ["equipment_id"] => FieldInfo::new(
+            "0-0:96.1.1",
+            None,
+            lang.pick("Equipment identifier", "Identificatie van de meter"),
+        ),
+        ["tariff"] => FieldInfo::new(
+            "0-0:96.14.0",
+            None,
+            lang.pick(
+                "Tariff indicator electricity",
+                "Tariefindicator elektriciteit",
+            ),
+        ),
+        ["energy_delivered", tariff] => {
+            let tariff = tariff_number(tariff)?;
+            FieldInfo::new(
+                format!("1-0:1.8.{}", tariff),
+                Some("kWh"),
+                lang.pick(
+                    format!("Electricity delivered to client (tariff {})", tariff),
+                    format!("Elektriciteit geleverd aan klant (tarief {})", tariff),
+                ),
+            )
+        }
+        ["energy_received", tariff] => {
+            let tariff = tariff_number(tariff)?;
+            FieldInfo::new(
+                format!("1-0:2.8.{}", tariff),
+                Some("kWh"),
+                lang.pick(
+                    format!("Electricity delivered by client (tariff {})", tariff),
+                    format!("Elektriciteit geleverd door klant (tarief {})", tariff),
+                ),
+            )
+        }
+        ["power_delivered"] => FieldInfo::new(
+            "1-0:1.7.0",
+            Some("kW"),
+            lang.pick(
+                "Actual electricity power delivered (+P)",
+                "Actueel vermogen geleverd aan klant (+P)",
+            ),
+        ),
+        ["power_received"] => FieldInfo::new(
+            "1-0:2.7.0",
+            Some("kW"),
+            lang.pick(
+                "Actual electricity power received (-P)",
+                "Actueel vermogen geleverd door klant (-P)",
+            ),
+        ),
+        ["reactive_energy_delivered", tariff] => {
+            let tariff = tariff_number(tariff)?;
+            FieldInfo::new(
+                format!("1-0:3.8.{}", tariff),
+                Some("kvarh"),
+                lang.pick(
+                    format!("Reactive energy imported (tariff {})", tariff),
+                    format!("Blindenergie geïmporteerd (tarief {})", tariff),
+                ),
+            )
+        }
+        ["reactive_energy_received", tariff] => {
+            let tariff = tariff_number(tariff)?;
+            FieldInfo::new(
+                format!("1-0:4.8.{}", tariff),
+                Some("kvarh"),
+                lang.pick(
+                    format!("Reactive energy exported (tariff {})", tariff),
+                    format!("Blindenergie geëxporteerd (tarief {})", tariff),
+                ),
+            )
+        }
+        ["reactive_energy_quadrants", quadrant] => {
+            let quadrant = quadrant
+                .parse::<u8>()
+                .ok()
+                .filter(|quadrant| *quadrant < 4)?;
+            let numeral = ["I", "II", "III", "IV"][usize::from(quadrant)];
+            FieldInfo::new(
+                format!("1-0:{}.8.0", quadrant + 5),
+                Some("kvarh"),
+                lang.pick(
+                    format!("Reactive energy in quadrant {}", numeral),
+                    format!("Blindenergie in kwadrant {}", numeral),
+                ),
+            )
+        }
+        ["reactive_power_delivered"] => FieldInfo::new(
+            "1-0:3.7.0",
+            Some("kvar"),
+            lang.pick(
+                "Actual reactive power imported (+Q)",
+                "Actueel blindvermogen geïmporteerd (+Q)",
+            ),
+        ),
+        ["reactive_power_received"] => FieldInfo::new(
+            "1-0:4.7.0",
+            Some("kvar"),
+            lang.pick(
+                "Actual reactive power exported (-Q)",
+                "Actueel blindvermogen geëxporteerd (-Q)",
+            ),
+        ),
+        ["power_failures"] => FieldInfo::new(
+            "0-0:96.7.21",
+            None,
+            lang.pick(
+                "Number of power failures in any phase",
+                "Aantal stroomstoringen in een van de fasen",
+            ),
+        ),
+        ["long_power_failures"] => FieldInfo::new(
+            "0-0:96.7.9",
+            None,
+            lang.pick(
+                "Number of long power failures in any phase",
+                "Aantal lange stroomstoringen in een van de fasen",
+            ),
+        ),
+        ["phases", phase, field] => {
+            let phase: u8 = phase.parse().ok().filter(|phase| *phase < 3)?;
+            // The value groups of L2 and L3 are those of L1 plus 20 and 40.
+            let group = |l1: u8| l1 + 20 * phase;
+            let line = phase + 1;
+            match *field {
+                "voltage_sags" => FieldInfo::new(
+                    format!("1-0:{}.32.0", group(32)),
+                    None,
+                    lang.pick(
+                        format!("Number of voltage sags in phase L{}", line),
+                        format!("Aantal spanningsdips in fase L{}", line),
+                    ),
+                ),
+                "voltage_swells" => FieldInfo::new(
+                    format!("1-0:{}.36.0", group(32)),
+                    None,
+                    lang.pick(
+                        format!("Number of voltage swells in phase L{}", line),
+                        format!("Aantal spanningspieken in fase L{}", line),
+                    ),
+                ),
+                "voltage" => FieldInfo::new(
+                    format!("1-0:{}.7.0", group(32)),
+                    Some("V"),
+                    lang.pick(
+                        format!("Instantaneous voltage L{}", line),
+                        format!("Momentane spanning L{}", line),
+                    ),
+                ),
+                "current" => FieldInfo::new(
+                    format!("1-0:{}.7.0", group(31)),
+                    Some("A"),
+                    lang.pick(
+                        format!("Instantaneous current L{}", line),
+                        format!("Momentane stroom L{}", line),
+                    ),
+                ),
+                "power_delivered" => FieldInfo::new(
+                    format!("1-0:{}.7.0", group(21)),
+                    Some("kW"),
+                    lang.pick(
+                        format!("Instantaneous active power L{} (+P)", line),
+                        format!("Momentaan vermogen L{} (+P)", line),
+                    ),
+                ),
+                "power_received" => FieldInfo::new(
+                    format!("1-0:{}.7.0", group(22)),
+                    Some("kW"),
+                    lang.pick(
+                        format!("Instantaneous active power L{} (-P)", line),
+                        format!("Momentaan vermogen L{} (-P)", line),
+                    ),
+                ),
+                "reactive_power_delivered" => FieldInfo::new(
+                    format!("1-0:{}.7.0", group(23)),
+                    Some("kvar"),
+                    lang.pick(
+                        format!("Instantaneous reactive power L{} (+Q)", line),
+                        format!("Momentaan blindvermogen L{} (+Q)", line),
+                    ),
+                ),
+                "reactive_power_received" => FieldInfo::new(
+                    format!("1-0:{}.7.0", group(24)),
+                    Some("kvar"),
+                    lang.pick(
+                        format!("Instantaneous reactive power L{} (-Q)", line),
+                        format!("Momentaan blindvermogen L{} (-Q)", line),
+                    ),
+                ),
+                _ => return None,
+            }
+        }
+        ["channels", channel, rest @ ..] => {
+            let channel = channel.parse::<u8>().ok().filter(|channel| *channel < 4)? + 1;
+            match rest {
+                ["device_type"] => FieldInfo::new(
+                    format!("0-{}:24.1.0", channel),
+                    None,
+                    lang.pick(
+                        format!("Device type of channel {}", channel),
+                        format!("Apparaattype van kanaal {}", channel),
+                    ),
+                ),
+                ["equipment_id"] => FieldInfo::new(
+                    format!("0-{}:96.1.0", channel),
+                    None,
+                    lang.pick(
+                        format!("Equipment identifier of channel {}", channel),
+                        format!("Identificatie van de meter op kanaal {}", channel),
+                    ),
+                ),
+                ["reading", "datetime"] => FieldInfo::new(
+                    format!("0-{}:24.2.1", channel),
+                    None,
+                    lang.pick(
+                        format!("Time of the last reading of channel {}", channel),
+                        format!("Tijdstip van de laatste meterstand van kanaal {}", channel),
+                    ),
+                ),
+                ["reading", "value"] => FieldInfo::new(
+                    format!("0-{}:24.2.1", channel),
+                    device_unit(device_type),
+                    lang.pick(
+                        format!("Last reading of channel {}", channel),
+                        format!("Laatste meterstand van kanaal {}", channel),
+                    ),
+                ),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(info)
+}
+
+/// Tariffs are numbered from 1, the array they're in from 0.
+fn tariff_number(index: &str) -> Option<u8> {
+    index
+        .parse::<u8>()
+        .ok()
+        .filter(|index| *index < 2)
+        .map(|index| index + 1)
+}
+
+/// Unit of the readings of a device type.
+fn device_unit(device_type: Option<u64>) -> Option<&'static str> {
+    match device_type? {
+        // Gas and water.
+        3 | 7 => Some("m3"),
+        // Heat.
+        4 => Some("GJ"),
+        _ => None,
+    }
+}
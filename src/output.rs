@@ -0,0 +1,274 @@
+//! Serialization of the meter state as sent to clients over HTTP and UDP.
+
+use std::fmt::Write;
+
+use chrono::{DateTime, Datelike, Timelike};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{MissingValues, Redaction, StateFormat},
+    model::{Measurement, MeterState},
+    obis::{self, Lang},
+    reader::ReaderData,
+};
+
+/// Serialize the current state in `format`, handling missing values according to `policy`.
+pub fn render_state(
+    data: &ReaderData,
+    policy: MissingValues,
+    format: StateFormat,
+) -> serde_json::Result<Value> {
+    let mut state = serde_json::to_value(&data.dsmr_state)?;
+    if format == StateFormat::Dsmr5 {
+        to_dsmr5(&mut state);
+    }
+    match policy {
+        MissingValues::Null => {}
+        MissingValues::Omit => strip_nulls(&mut state),
+        MissingValues::LastKnown => {
+            let mut last_known = data.last_known.clone();
+            if format == StateFormat::Dsmr5 {
+                to_dsmr5(&mut last_known);
+            }
+            let mut stale_fields = Vec::new();
+            fill(&mut state, &last_known, String::new(), &mut stale_fields);
+            if let Value::Object(ref mut fields) = state {
+                fields.insert("stale".into(), Value::Bool(!stale_fields.is_empty()));
+                fields.insert("stale_fields".into(), stale_fields.into());
+            }
+        }
+    }
+    Ok(state)
+}
+
+/// Convert a serialized `MeterState` to the shape of the `dsmr5` crate. The tariff becomes
+/// its two octets and currents are rounded to whole amperes, as the meter sends them.
+/// Fields `dsmr5` doesn't have are left out.
+fn to_dsmr5(state: &mut Value) {
+    if !state.is_object() {
+        return;
+    }
+    let field = |value: &Value, key: &str| value.get(key).cloned().unwrap_or(Value::Null);
+    let item = |key: &str, i: usize| state.get(key).and_then(|v| v.get(i)).cloned();
+    let meterreadings: Vec<Value> = (0..2)
+        .map(|i| json!({ "to": item("energy_delivered", i), "by": item("energy_received", i) }))
+        .collect();
+    let lines: Vec<Value> = (0..3)
+        .map(|i| {
+            let phase = item("phases", i).unwrap_or_default();
+            json!({
+                "voltage_sags": field(&phase, "voltage_sags"),
+                "voltage_swells": field(&phase, "voltage_swells"),
+                "voltage": field(&phase, "voltage"),
+                "current": phase.get("current").and_then(Value::as_f64).map(|c| c.round() as u64),
+                "active_power_plus": field(&phase, "power_delivered"),
+                "active_power_neg": field(&phase, "power_received"),
+            })
+        })
+        .collect();
+    let slaves: Vec<Value> = (0..4)
+        .map(|i| {
+            let channel = item("channels", i).unwrap_or_default();
+            let reading = channel
+                .get("reading")
+                .filter(|reading| !reading.is_null())
+                .map(|reading| json!([tst(&field(reading, "datetime")), field(reading, "value")]));
+            json!({ "device_type": field(&channel, "device_type"), "meter_reading": reading })
+        })
+        .collect();
+    let tariff = state
+        .get("tariff")
+        .and_then(Value::as_u64)
+        .map(|tariff| [tariff >> 8, tariff & 0xff]);
+    *state = json!({
+        "datetime": tst(&field(state, "datetime")),
+        "meterreadings": meterreadings,
+        "tariff_indicator": tariff,
+        "power_delivered": field(state, "power_delivered"),
+        "power_received": field(state, "power_received"),
+        "power_failures": field(state, "power_failures"),
+        "long_power_failures": field(state, "long_power_failures"),
+        "lines": lines,
+        "slaves": slaves,
+    });
+}
+
+/// A timestamp as `dsmr5` serializes it, with a two digit year and whether daylight saving
+/// time is in effect.
+fn tst(datetime: &Value) -> Value {
+    let Some(datetime) = datetime
+        .as_str()
+        .and_then(|datetime| DateTime::parse_from_rfc3339(datetime).ok())
+    else {
+        return Value::Null;
+    };
+    json!({
+        "year": datetime.year() % 100,
+        "month": datetime.month(),
+        "day": datetime.day(),
+        "hour": datetime.hour(),
+        "minute": datetime.minute(),
+        "second": datetime.second(),
+        "dst": datetime.offset().local_minus_utc() == 7200,
+    })
+}
+
+/// Replace every value in a rendered state by an object holding the value along with its
+/// OBIS code, unit and description. Fields we know nothing about are left as they are.
+pub fn annotate(state: &mut Value, lang: Lang) {
+    let device_types: Vec<Option<u64>> = state
+        .get("channels")
+        .and_then(Value::as_array)
+        .map(|channels| {
+            channels
+                .iter()
+                .map(|channel| channel.get("device_type").and_then(Value::as_u64))
+                .collect()
+        })
+        .unwrap_or_default();
+    visit_fields(state, &mut Vec::new(), &mut |path, value| {
+        let device_type = match path {
+            ["channels", channel, ..] => channel
+                .parse::<usize>()
+                .ok()
+                .and_then(|channel| device_types.get(channel).copied().flatten()),
+            _ => None,
+        };
+        let Some(info) = obis::describe(path, device_type, lang) else {
+            return;
+        };
+        let mut annotated = Map::new();
+        annotated.insert("value".into(), value.take());
+        if let Ok(Value::Object(info)) = serde_json::to_value(info) {
+            annotated.extend(info);
+        }
+        *value = Value::Object(annotated);
+    });
+}
+
+/// Strip or hash the equipment identifiers in a rendered state.
+pub fn redact(state: &mut Value, redaction: Redaction) {
+    if redaction == Redaction::None {
+        return;
+    }
+    visit_fields(state, &mut Vec::new(), &mut |path, value| {
+        if path.last() != Some(&"equipment_id") {
+            return;
+        }
+        *value = match (redaction, value.as_str()) {
+            (Redaction::Hash, Some(id)) => {
+                let mut hash = String::with_capacity(16);
+                for byte in &Sha256::digest(id.as_bytes())[..8] {
+                    let _ = write!(hash, "{:02x}", byte);
+                }
+                Value::String(hash)
+            }
+            _ => Value::Null,
+        };
+    });
+}
+
+/// Describe every field of the state, keyed by its path as used in `stale_fields`.
+pub fn schema(lang: Lang) -> Map<String, Value> {
+    // Channels without a reading serialize as null, so give them one to list its fields.
+    let mut state = MeterState::default();
+    for channel in &mut state.channels {
+        channel.reading = Some(Measurement {
+            datetime: None,
+            value: 0.0,
+        });
+    }
+    let mut fields = Map::new();
+    if let Ok(mut state) = serde_json::to_value(&state) {
+        visit_fields(&mut state, &mut Vec::new(), &mut |path, _| {
+            if let Some(info) = obis::describe(path, None, lang) {
+                if let Ok(info) = serde_json::to_value(info) {
+                    fields.insert(path.join("."), info);
+                }
+            }
+        });
+    }
+    fields
+}
+
+/// Call `visit` with the path and value of every field of the state that isn't an object
+/// or array.
+fn visit_fields(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    visit: &mut impl FnMut(&[&str], &mut Value),
+) {
+    match value {
+        Value::Object(fields) => {
+            for (key, v) in fields.iter_mut() {
+                path.push(key.clone());
+                visit_fields(v, path, visit);
+                path.pop();
+            }
+        }
+        Value::Array(items) => {
+            for (i, v) in items.iter_mut().enumerate() {
+                path.push(i.to_string());
+                visit_fields(v, path, visit);
+                path.pop();
+            }
+        }
+        value => {
+            let path: Vec<&str> = path.iter().map(String::as_str).collect();
+            visit(&path, value);
+        }
+    }
+}
+
+/// Merge a new state into the last known values, keeping old values the new state lacks.
+pub fn remember(last_known: &mut Value, state: &MeterState) {
+    if let Ok(mut value) = serde_json::to_value(state) {
+        fill(&mut value, last_known, String::new(), &mut Vec::new());
+        *last_known = value;
+    }
+}
+
+/// Remove all fields without a value. Array elements are kept, since their position means
+/// something (the tariff, phase or channel).
+fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            fields.retain(|_, v| !v.is_null());
+            fields.values_mut().for_each(strip_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+/// Replace missing values in `value` by those in `last_known`, collecting the paths of the
+/// replaced values.
+fn fill(value: &mut Value, last_known: &Value, path: String, filled: &mut Vec<String>) {
+    let join = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    match (value, last_known) {
+        (value @ Value::Null, known) if !known.is_null() => {
+            *value = known.clone();
+            filled.push(path);
+        }
+        (Value::Object(fields), Value::Object(known)) => {
+            for (key, v) in fields.iter_mut() {
+                if let Some(k) = known.get(key) {
+                    fill(v, k, join(key), filled);
+                }
+            }
+        }
+        (Value::Array(items), Value::Array(known)) => {
+            for (i, (v, k)) in items.iter_mut().zip(known).enumerate() {
+                fill(v, k, join(&i.to_string()), filled);
+            }
+        }
+        _ => {}
+    }
+}
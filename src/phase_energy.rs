@@ -0,0 +1,64 @@
+//! Estimated energy per phase. The meter counts the energy of all phases together, but
+//! reports the power of each phase, so the power of every phase is integrated over time
+//! into totals of its own, e.g. `energy_delivered_l1`. These are stored with the rest of
+//! the samples, to show which phase carries most of the load.
+//!
+//! The totals continue from the latest sample in the history store, so they survive a
+//! restart with the file engine. The power of a telegram counts until the next one;
+//! across gaps longer than `MAX_GAP`, nothing is counted.
+
+use crate::history::{History, Metric, Sample};
+
+/// Longest time in milliseconds between telegrams to count the energy of.
+const MAX_GAP: u64 = 5 * 60 * 1000;
+
+/// The power of every phase and the total it adds up to.
+const PHASES: [(Metric, Metric); 6] = [
+    (Metric::PowerDeliveredL1, Metric::EnergyDeliveredL1),
+    (Metric::PowerDeliveredL2, Metric::EnergyDeliveredL2),
+    (Metric::PowerDeliveredL3, Metric::EnergyDeliveredL3),
+    (Metric::PowerReceivedL1, Metric::EnergyReceivedL1),
+    (Metric::PowerReceivedL2, Metric::EnergyReceivedL2),
+    (Metric::PowerReceivedL3, Metric::EnergyReceivedL3),
+];
+
+#[derive(Debug, Default)]
+pub struct PhaseEnergy {
+    /// Time and powers of the previous telegram.
+    last: Option<(u64, [Option<f64>; 6])>,
+    /// Energy per phase so far in kWh.
+    totals: [f64; 6],
+}
+
+impl PhaseEnergy {
+    /// Continue from the totals of the latest sample in `history`.
+    pub fn new(history: &History) -> Self {
+        let mut energy = Self::default();
+        if let Some(latest) = history.latest() {
+            for (total, (_, metric)) in energy.totals.iter_mut().zip(PHASES) {
+                *total = latest.get(metric).unwrap_or_default();
+            }
+        }
+        energy
+    }
+
+    /// Add the energy since the previous telegram to the totals, and set them on `sample`.
+    pub fn integrate(&mut self, sample: &mut Sample) {
+        let powers = PHASES.map(|(power, _)| sample.get(power));
+        let elapsed = self
+            .last
+            .map(|(timestamp, last)| (sample.timestamp.saturating_sub(timestamp), last))
+            .filter(|(elapsed, _)| *elapsed <= MAX_GAP);
+        for (i, (_, metric)) in PHASES.into_iter().enumerate() {
+            let power = elapsed.and_then(|(elapsed, last)| Some((elapsed, last[i]?)));
+            if let Some((elapsed, power)) = power {
+                self.totals[i] += power * elapsed as f64 / 3_600_000.0;
+            }
+            if powers[i].is_some() {
+                // Rounded to mWh, more than an estimate is worth.
+                sample.set(metric, Some((self.totals[i] * 1e6).round() / 1e6));
+            }
+        }
+        self.last = Some((sample.timestamp, powers));
+    }
+}
@@ -0,0 +1,109 @@
+//! A short fixed-width text summary, served at `/plain`, for small displays fed by curl
+//! and for status bars like polybar or waybar. Usage today is taken from the history,
+//! so after a restart it counts from the start of the daemon rather than from midnight.
+
+use std::{
+    fmt::Write,
+    sync::{Arc, RwLock},
+};
+
+use chrono::Local;
+use hyper::{
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+
+use crate::{
+    appdata::AppData,
+    history::{day_range, Metric},
+    lock::RecoverLock,
+    reader::{self, ReaderData},
+};
+
+/// Usage today of the sum of `metrics`, `None` if any of them is unknown.
+fn usage_today(appdata: &AppData, metrics: &[Metric]) -> Option<f64> {
+    let (from, to) = day_range(Local::now().date_naive());
+    let history = appdata.history.read_recover();
+    metrics
+        .iter()
+        .map(|metric| history.usage(*metric, from, to))
+        .sum()
+}
+
+/// A line with the label on the left and the value right-aligned, `-` when unknown.
+fn line(text: &mut String, label: &str, value: Option<f64>, decimals: usize, unit: &str) {
+    let value = match value {
+        Some(value) => format!("{:.*}", decimals, value),
+        None => String::from("-"),
+    };
+    let line = format!("{:<10}{:>10} {}", label, value, unit);
+    let _ = writeln!(text, "{}", line.trim_end());
+}
+
+/// Handler for `/plain`.
+pub async fn handler(
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    reader::wake(&appdata, &data).await;
+    let (power, gas, tariff, outdated) = {
+        let data = data.read_recover();
+        let state = &data.dsmr_state;
+        // Negative while feeding back more than is used.
+        let power = match (state.power_delivered, state.power_received) {
+            (Some(delivered), received) => Some(delivered - received.unwrap_or(0.0)),
+            (None, received) => received.map(|received| -received),
+        };
+        let outdated = data.is_outdated(data.stale_after(appdata.config().output.stale_after));
+        (
+            power,
+            state.gas().map(|gas| gas.value),
+            state.tariff,
+            outdated,
+        )
+    };
+
+    let mut text = String::new();
+    line(&mut text, "Power", power, 3, "kW");
+    line(
+        &mut text,
+        "Today",
+        usage_today(
+            &appdata,
+            &[
+                Metric::EnergyDeliveredTariff1,
+                Metric::EnergyDeliveredTariff2,
+            ],
+        ),
+        2,
+        "kWh",
+    );
+    line(
+        &mut text,
+        "Returned",
+        usage_today(
+            &appdata,
+            &[Metric::EnergyReceivedTariff1, Metric::EnergyReceivedTariff2],
+        ),
+        2,
+        "kWh",
+    );
+    line(
+        &mut text,
+        "Gas today",
+        usage_today(&appdata, &[Metric::GasDelivered]),
+        3,
+        "m³",
+    );
+    line(&mut text, "Gas", gas, 3, "m³");
+    line(&mut text, "Tariff", tariff.map(f64::from), 0, "");
+    if outdated {
+        text.push_str("Outdated\n");
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(CACHE_CONTROL, "no-store")
+        .body(Body::from(text))
+}
@@ -0,0 +1,529 @@
+//! Hourly day-ahead electricity prices, fetched from ENTSO-E or Tibber.
+//!
+//! The prices are served at `/prices`, `/prices/cheapest?hours=3` advises when to run
+//! flexible loads such as a dishwasher or car charger, and reports use them for the cost
+//! of electricity.
+
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Days, NaiveDateTime, Utc};
+use hyper::{header::CONTENT_TYPE, Body, Request, Response, StatusCode};
+use log::{debug, error, info};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    appdata::AppData,
+    config::{Config, DynamicPriceConfig, PriceConfig, PriceProvider},
+    dial::Dialer,
+    history::{day_range, now_millis, History, Metric, Sample},
+    http_client::HttpClient,
+    lock::RecoverLock,
+    supervisor,
+};
+
+const HOUR: u64 = 3_600_000;
+const DAY: u64 = 24 * HOUR;
+/// Prices are kept this long after their hour has passed, so reports can use them.
+const KEEP: u64 = 3 * 24 * HOUR;
+/// Upper bound on the number of hours `/prices/cheapest` can be asked for.
+const MAX_CHEAPEST: usize = 24;
+
+const ENTSOE_URL: &str = "https://web-api.tp.entsoe.eu/api";
+const TIBBER_URL: &str = "https://api.tibber.com/v1-beta/gql";
+const TIBBER_QUERY: &str = "{viewer{homes{currentSubscription{priceInfo{\
+    today{total startsAt} tomorrow{total startsAt}}}}}}";
+
+/// The price of electricity during an hour.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct HourPrice {
+    /// Start of the hour in milliseconds since the unix epoch.
+    pub start: u64,
+    /// Price per kWh.
+    pub price: f64,
+}
+
+/// A run of consecutive hours.
+#[derive(Debug, Serialize)]
+struct Block {
+    start: u64,
+    end: u64,
+    average: f64,
+}
+
+#[derive(Serialize)]
+struct Cheapest {
+    /// The cheapest upcoming hours, in order of time.
+    hours: Vec<HourPrice>,
+    /// The cheapest run of consecutive upcoming hours.
+    block: Option<Block>,
+}
+
+/// Known prices, in order of time.
+#[derive(Debug, Default)]
+pub struct PriceTable {
+    hours: Vec<HourPrice>,
+}
+
+impl PriceTable {
+    /// Add or replace the prices of the given hours, and forget the ones that are too old.
+    pub fn update(&mut self, prices: Vec<HourPrice>, now: u64) {
+        self.hours
+            .retain(|hour| !prices.iter().any(|price| price.start == hour.start));
+        self.hours.extend(prices);
+        self.hours.retain(|hour| hour.start + HOUR + KEEP > now);
+        self.hours.sort_by_key(|hour| hour.start);
+    }
+
+    /// The price per kWh at `timestamp`.
+    pub fn price_at(&self, timestamp: u64) -> Option<f64> {
+        let index = self.hours.partition_point(|hour| hour.start <= timestamp);
+        let hour = self.hours.get(index.checked_sub(1)?)?;
+        (timestamp < hour.start + HOUR).then_some(hour.price)
+    }
+
+    /// The current and future hours.
+    fn upcoming(&self, now: u64) -> &[HourPrice] {
+        let start = self.hours.partition_point(|hour| hour.start + HOUR <= now);
+        &self.hours[start..]
+    }
+}
+
+/// Cost of the electricity exchanged in `[from, to]` at the dynamic prices, with energy
+/// delivered by the client compensated at the same price. None unless there is a price
+/// for every sample.
+pub fn electricity_cost(history: &History, table: &PriceTable, from: u64, to: u64) -> Option<f64> {
+    let net = |sample: &Sample| {
+        let delivered = sample.get(Metric::EnergyDeliveredTariff1)?
+            + sample.get(Metric::EnergyDeliveredTariff2)?;
+        let received = sample.get(Metric::EnergyReceivedTariff1).unwrap_or(0.0)
+            + sample.get(Metric::EnergyReceivedTariff2).unwrap_or(0.0);
+        Some(delivered - received)
+    };
+    let mut cost = 0.0;
+    let mut previous = None;
+    for sample in history.query_range(from, to) {
+        let Some(net) = net(&sample) else {
+            continue;
+        };
+        if let Some(previous) = previous {
+            cost += (net - previous) * table.price_at(sample.timestamp)?;
+        }
+        previous = Some(net);
+    }
+    previous.map(|_| cost)
+}
+
+/// The fixed prices at `timestamp`: those of the last contract started by then, or the
+/// configured prices before the first contract.
+pub fn fixed_prices(config: &Config, timestamp: u64) -> Option<&PriceConfig> {
+    config
+        .contracts
+        .iter()
+        .filter(|contract| day_range(contract.start).0 <= timestamp)
+        .max_by_key(|contract| contract.start)
+        .map(|contract| &contract.prices)
+        .or(config.prices.as_ref())
+}
+
+/// Cost of `delivered` and `received` kWh per tariff and `gas` m³ used in `[from, to]`.
+/// Electricity is priced at the dynamic prices if they cover the whole period, and at the
+/// fixed prices otherwise. A period spanning several contracts is priced per contract,
+/// with the usage under each taken from `history`. None without prices.
+pub fn cost(
+    history: &History,
+    config: &Config,
+    dynamic_prices: Option<&PriceTable>,
+    (from, to): (u64, u64),
+    delivered: [Option<f64>; 2],
+    received: [Option<f64>; 2],
+    gas: Option<f64>,
+) -> Option<f64> {
+    let mut starts: Vec<u64> = config
+        .contracts
+        .iter()
+        .map(|contract| day_range(contract.start).0)
+        .filter(|start| *start > from && *start <= to)
+        .collect();
+    if starts.is_empty() {
+        let prices = fixed_prices(config, from);
+        return contract_cost(
+            history,
+            prices,
+            dynamic_prices,
+            (from, to),
+            delivered,
+            received,
+            gas,
+        );
+    }
+    starts.sort_unstable();
+    starts.dedup();
+
+    let mut total = None;
+    let mut start = from;
+    for end in starts.iter().map(|start| start - 1).chain([to]) {
+        let usage = |metric| history.usage(metric, start, end);
+        let cost = contract_cost(
+            history,
+            fixed_prices(config, start),
+            dynamic_prices,
+            (start, end),
+            [
+                usage(Metric::EnergyDeliveredTariff1),
+                usage(Metric::EnergyDeliveredTariff2),
+            ],
+            [
+                usage(Metric::EnergyReceivedTariff1),
+                usage(Metric::EnergyReceivedTariff2),
+            ],
+            usage(Metric::GasDelivered),
+        );
+        if let Some(cost) = cost {
+            total = Some(total.unwrap_or(0.0) + cost);
+        }
+        start = end + 1;
+    }
+    total
+}
+
+/// Cost of the usage in `[from, to]` under a single contract, including its standing
+/// charges for the time.
+fn contract_cost(
+    history: &History,
+    prices: Option<&PriceConfig>,
+    dynamic_prices: Option<&PriceTable>,
+    (from, to): (u64, u64),
+    delivered: [Option<f64>; 2],
+    received: [Option<f64>; 2],
+    gas: Option<f64>,
+) -> Option<f64> {
+    let electricity = dynamic_prices
+        .and_then(|table| electricity_cost(history, table, from, to))
+        .or_else(|| {
+            prices.map(|prices| {
+                let mut cost = 0.0;
+                for tariff in 0..2 {
+                    cost += delivered[tariff].unwrap_or(0.0) * prices.delivered[tariff];
+                    cost -= received[tariff].unwrap_or(0.0) * prices.received[tariff];
+                }
+                cost
+            })
+        });
+    let gas_cost = prices.map(|prices| {
+        gas.unwrap_or(0.0) * prices.gas
+            + prices.standing_charge * to.saturating_sub(from) as f64 / DAY as f64
+    });
+    match (electricity, gas_cost) {
+        (None, None) => None,
+        (electricity, gas_cost) => Some(electricity.unwrap_or(0.0) + gas_cost.unwrap_or(0.0)),
+    }
+}
+
+/// Spawn a thread that fetches the prices every configured interval.
+pub fn spawn_price_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("prices", appdata, |appdata| {
+        let Some(config) = appdata.config().dynamic_prices.as_ref() else {
+            return;
+        };
+        let client =
+            match HttpClient::new(Dialer::with_proxy(appdata.config().outbound.proxy.as_ref())) {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("Unable to start price job: {}", e);
+                    return;
+                }
+            };
+        info!("Price job started.");
+
+        let interval = Duration::from_secs(config.interval.max(60));
+        let mut fetched: Option<Instant> = None;
+        loop {
+            if fetched.is_none_or(|fetched| fetched.elapsed() >= interval) {
+                match fetch(&client, config) {
+                    Ok(prices) => {
+                        debug!("Fetched {} hourly prices", prices.len());
+                        appdata.prices.write_recover().update(prices, now_millis());
+                    }
+                    Err(e) => error!("Unable to fetch energy prices: {}", e),
+                }
+                fetched = Some(Instant::now());
+            }
+            #[cfg(feature = "mqtt")]
+            publish_cheapest(appdata, config.cheapest_hours);
+
+            // The advice changes with the prices and with every hour that passes.
+            let next_hour = Duration::from_millis(HOUR - now_millis() % HOUR);
+            let next_fetch = interval.saturating_sub(fetched.map_or(interval, |at| at.elapsed()));
+            thread::sleep(next_hour.min(next_fetch));
+        }
+    })
+}
+
+/// Publish the cheapest hours advice to `prices/cheapest` over MQTT, retained so an
+/// automation gets it as soon as it subscribes.
+#[cfg(feature = "mqtt")]
+fn publish_cheapest(appdata: &AppData, hours: usize) {
+    let Some(mqtt) = appdata.mqtt() else {
+        return;
+    };
+    let advice = {
+        let table = appdata.prices.read_recover();
+        serde_json::to_vec(&cheapest(
+            table.upcoming(now_millis()),
+            hours.clamp(1, MAX_CHEAPEST),
+        ))
+    };
+    let published = advice
+        .map_err(|e| e.to_string())
+        .and_then(|advice| mqtt.publish("prices/cheapest", &advice, true));
+    if let Err(e) = published {
+        log::warn!("Unable to publish the cheapest hours: {}", e);
+    }
+}
+
+fn fetch(client: &HttpClient, config: &DynamicPriceConfig) -> Result<Vec<HourPrice>, String> {
+    let slots = match &config.provider {
+        PriceProvider::Entsoe { token, area } => fetch_entsoe(client, token, area)?,
+        PriceProvider::Tibber { token } => fetch_tibber(client, token)?,
+    };
+    Ok(hourly(slots)
+        .into_iter()
+        .map(|hour| HourPrice {
+            start: hour.start,
+            price: (hour.price + config.markup) * config.multiplier,
+        })
+        .collect())
+}
+
+/// Prices from yesterday up to and including tomorrow, in EUR/MWh converted to EUR/kWh.
+fn fetch_entsoe(client: &HttpClient, token: &str, area: &str) -> Result<Vec<Slot>, String> {
+    let today = Utc::now().date_naive();
+    let day = |days: i64| {
+        let date = if days < 0 {
+            today.checked_sub_days(Days::new(days.unsigned_abs()))
+        } else {
+            today.checked_add_days(Days::new(days as u64))
+        };
+        date.map(|date| date.format("%Y%m%d0000").to_string())
+            .unwrap_or_default()
+    };
+    let url = format!(
+        "{}?securityToken={}&documentType=A44&in_Domain={}&out_Domain={}&periodStart={}&periodEnd={}",
+        ENTSOE_URL,
+        token,
+        area,
+        area,
+        day(-1),
+        day(2)
+    );
+    let body = client.get(&url, &[])?;
+    let xml = String::from_utf8_lossy(&body);
+    parse_entsoe(&xml)
+}
+
+/// A price over a period of `length` milliseconds.
+struct Slot {
+    start: u64,
+    length: u64,
+    price: f64,
+}
+
+/// Parse an ENTSO-E publication market document. Curves may leave out points with the same
+/// price as the point before them.
+fn parse_entsoe(xml: &str) -> Result<Vec<Slot>, String> {
+    let mut slots = Vec::new();
+    for period in xml.split("<Period>").skip(1) {
+        let time = |name| {
+            let time = tag(period, name).ok_or_else(|| format!("No {} in period", name))?;
+            NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%MZ")
+                .ok()
+                .and_then(|time| u64::try_from(time.and_utc().timestamp_millis()).ok())
+                .ok_or_else(|| format!("Invalid time {}", time))
+        };
+        let (start, end) = (time("start")?, time("end")?);
+        let length = match tag(period, "resolution") {
+            Some("PT15M") => HOUR / 4,
+            Some("PT30M") => HOUR / 2,
+            Some("PT60M") => HOUR,
+            resolution => return Err(format!("Unsupported resolution {:?}", resolution)),
+        };
+
+        let count = (end.saturating_sub(start) / length) as usize;
+        let mut prices = vec![None; count];
+        for point in period.split("<Point>").skip(1) {
+            let position: usize = tag(point, "position")
+                .and_then(|position| position.parse().ok())
+                .ok_or("Invalid position")?;
+            let price: f64 = tag(point, "price.amount")
+                .and_then(|price| price.parse().ok())
+                .ok_or("Invalid price")?;
+            if let Some(slot) = position.checked_sub(1).and_then(|i| prices.get_mut(i)) {
+                *slot = Some(price / 1000.0);
+            }
+        }
+        let mut last = None;
+        for (index, price) in prices.into_iter().enumerate() {
+            last = price.or(last);
+            if let Some(price) = last {
+                slots.push(Slot {
+                    start: start + index as u64 * length,
+                    length,
+                    price,
+                });
+            }
+        }
+    }
+    Ok(slots)
+}
+
+/// The text of the first `<name>` element in `xml`.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find('<')?;
+    Some(xml[start..start + end].trim())
+}
+
+/// Prices for today and tomorrow, including taxes.
+fn fetch_tibber(client: &HttpClient, token: &str) -> Result<Vec<Slot>, String> {
+    let authorization = format!("Bearer {}", token);
+    let body = serde_json::to_vec(&json!({ "query": TIBBER_QUERY }))
+        .map_err(|e| format!("Unable to build query: {}", e))?;
+    let response = client.exchange(
+        TIBBER_URL,
+        "application/json",
+        &[("Authorization", &authorization)],
+        body,
+    )?;
+    let response: Value =
+        serde_json::from_slice(&response).map_err(|e| format!("Invalid response: {}", e))?;
+    let info = response
+        .pointer("/data/viewer/homes/0/currentSubscription/priceInfo")
+        .ok_or("No price info in response")?;
+
+    let mut slots = Vec::new();
+    for price in ["today", "tomorrow"]
+        .iter()
+        .filter_map(|day| info.get(day)?.as_array())
+        .flatten()
+    {
+        let start = price
+            .get("startsAt")
+            .and_then(Value::as_str)
+            .and_then(|start| DateTime::parse_from_rfc3339(start).ok())
+            .and_then(|start| u64::try_from(start.timestamp_millis()).ok());
+        let total = price.get("total").and_then(Value::as_f64);
+        if let (Some(start), Some(price)) = (start, total) {
+            slots.push(Slot {
+                start,
+                length: HOUR,
+                price,
+            });
+        }
+    }
+    Ok(slots)
+}
+
+/// Average slots shorter than an hour into hourly prices.
+fn hourly(slots: Vec<Slot>) -> Vec<HourPrice> {
+    let mut hours: Vec<(u64, f64, u64)> = Vec::new();
+    for slot in slots {
+        let start = slot.start - slot.start % HOUR;
+        match hours.iter_mut().find(|(hour, _, _)| *hour == start) {
+            Some((_, sum, length)) => {
+                *sum += slot.price * slot.length as f64;
+                *length += slot.length;
+            }
+            None => hours.push((start, slot.price * slot.length as f64, slot.length)),
+        }
+    }
+    hours
+        .into_iter()
+        .map(|(start, sum, length)| HourPrice {
+            start,
+            price: sum / length as f64,
+        })
+        .collect()
+}
+
+/// The `count` cheapest hours in order of time, and the cheapest run of `count`
+/// consecutive hours.
+fn cheapest(hours: &[HourPrice], count: usize) -> Cheapest {
+    let mut cheapest = hours.to_vec();
+    cheapest.sort_by(|a, b| a.price.total_cmp(&b.price));
+    cheapest.truncate(count);
+    cheapest.sort_by_key(|hour| hour.start);
+
+    let block = hours
+        .windows(count)
+        .filter(|window| {
+            window
+                .windows(2)
+                .all(|pair| pair[1].start == pair[0].start + HOUR)
+        })
+        .map(|window| Block {
+            start: window[0].start,
+            end: window[count - 1].start + HOUR,
+            average: window.iter().map(|hour| hour.price).sum::<f64>() / count as f64,
+        })
+        .min_by(|a, b| a.average.total_cmp(&b.average));
+
+    Cheapest {
+        hours: cheapest,
+        block,
+    }
+}
+
+/// Handler for `/prices` and `/prices/cheapest`.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let table = appdata.prices.read_recover();
+    let now = now_millis();
+    let json = match req.uri().path().trim_end_matches('/') {
+        "/prices" => serde_json::to_string(&json!({
+            "current": table.price_at(now),
+            "prices": table.hours,
+        })),
+        "/prices/cheapest" => {
+            let hours = req
+                .uri()
+                .query()
+                .and_then(|query| {
+                    url::form_urlencoded::parse(query.as_bytes())
+                        .find(|(key, _)| key == "hours")
+                        .map(|(_, value)| value.parse::<usize>().ok())
+                })
+                .unwrap_or(Some(3));
+            let Some(hours) = hours.filter(|hours| (1..=MAX_CHEAPEST).contains(hours)) else {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(format!(
+                        "Error: hours has to be between 1 and {}.",
+                        MAX_CHEAPEST
+                    )));
+            };
+            serde_json::to_string(&cheapest(table.upcoming(now), hours))
+        }
+        _ => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Error: unknown prices endpoint."))
+        }
+    };
+    match json {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
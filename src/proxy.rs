@@ -0,0 +1,59 @@
+//! Running behind a reverse proxy. Requests passed on by a trusted proxy are attributed to
+//! the client named in `X-Forwarded-For`, and the base path the proxy serves the daemon
+//! under is stripped before routing.
+
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::{Body, Request, Uri};
+
+use crate::allowlist::{self, Cidr};
+
+/// The address of the client that made the request. Walks `X-Forwarded-For` from the
+/// right for as long as the hops are trusted proxies, so clients can't spoof their address
+/// by sending the header themselves. The port of a forwarded client is unknown and set to 0.
+pub fn client_addr(req: &Request<Body>, trusted: &[Cidr]) -> Option<SocketAddr> {
+    let peer = req.extensions().get::<SocketAddr>().copied()?;
+    let mut hops = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev();
+
+    let mut client = peer;
+    while allowlist::is_allowed(trusted, client.ip()) {
+        match hops.next().map(|hop| hop.trim().parse::<IpAddr>()) {
+            Some(Ok(ip)) => client = SocketAddr::new(ip, 0),
+            _ => break,
+        }
+    }
+    Some(client)
+}
+
+/// Strip `base` from the path of the request. Returns false if the request is outside it.
+pub fn strip_base_path(req: &mut Request<Body>, base: &str) -> bool {
+    let base = base.trim_end_matches('/');
+    if base.is_empty() {
+        return true;
+    }
+    let path = req.uri().path();
+    let rest = match path.strip_prefix(base) {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => return false,
+    };
+    let uri = match req.uri().query() {
+        Some(query) => format!("{}?{}", rest, query),
+        None => rest.to_string(),
+    };
+    match uri.parse::<Uri>() {
+        Ok(uri) => {
+            *req.uri_mut() = uri;
+            true
+        }
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,189 @@
+//! Typed query parameters. A handler describes its parameters as a struct deriving
+//! `Deserialize` and gets them parsed, or an error naming the parameter that is missing or
+//! invalid to answer 400 with.
+
+use std::{fmt, time::Duration, vec};
+
+use hyper::{Body, Request};
+use serde::{
+    de::{self, DeserializeOwned, IntoDeserializer, MapAccess, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+
+use crate::history::{parse_duration, parse_time};
+
+/// Parse the query string of `req` into `T`.
+pub fn parse<T: DeserializeOwned>(req: &Request<Body>) -> Result<T, String> {
+    let pairs: Vec<(String, String)> = req
+        .uri()
+        .query()
+        .map(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default();
+    T::deserialize(Params {
+        pairs: pairs.into_iter(),
+        current: None,
+    })
+    .map_err(|e| e.0)
+}
+
+/// An optional duration like `500ms`, `30s` or `5m`.
+pub fn duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    with(d, parse_duration, "a duration like 5m").map(|millis| millis.map(Duration::from_millis))
+}
+
+/// An optional duration like `5m`, in milliseconds.
+pub fn duration_millis<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    with(d, parse_duration, "a duration like 5m")
+}
+
+/// An optional time, in milliseconds since the unix epoch or RFC 3339.
+pub fn time<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    with(
+        d,
+        parse_time,
+        "milliseconds since the unix epoch or an RFC 3339 time",
+    )
+}
+
+/// An optional value parsed with `parse`, which describes what it takes as `expected`.
+pub fn with<'de, D: Deserializer<'de>, T>(
+    d: D,
+    parse: impl Fn(&str) -> Option<T>,
+    expected: &str,
+) -> Result<Option<T>, D::Error> {
+    let value = String::deserialize(d)?;
+    parse(&value)
+        .map(Some)
+        .ok_or_else(|| de::Error::custom(format!("expected {}", expected)))
+}
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        Error(format!("missing parameter {}", field))
+    }
+}
+
+/// The query parameters as a map.
+struct Params {
+    pairs: vec::IntoIter<(String, String)>,
+    /// The parameter whose value is up next.
+    current: Option<(String, String)>,
+}
+
+impl<'de> Deserializer<'de> for Params {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct enum
+        identifier ignored_any
+    }
+}
+
+impl<'de> MapAccess<'de> for Params {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let Some((name, value)) = self.pairs.next() else {
+            return Ok(None);
+        };
+        let key = seed.deserialize(name.clone().into_deserializer())?;
+        self.current = Some((name, value));
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (name, value) = self
+            .current
+            .take()
+            .ok_or_else(|| Error(String::from("value without a parameter")))?;
+        seed.deserialize(Value(value.clone()))
+            .map_err(|e| Error(format!("invalid value for {}: {} ({})", name, value, e)))
+    }
+}
+
+/// A single parameter value, parsed into the type asked for.
+struct Value(String);
+
+macro_rules! parse_value {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                visitor.$visit(self.0.parse().map_err(de::Error::custom)?)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_string(self.0)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0.as_str() {
+            "1" | "true" => visitor.visit_bool(true),
+            "0" | "false" => visitor.visit_bool(false),
+            _ => Err(de::Error::custom("expected true or false")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    parse_value! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
@@ -1,114 +1,458 @@
-use dsmr5::Readout;
-use log::{debug, error, info};
-use serde::Serialize;
+use dsmr5::{types::TST, Readout, OBIS};
+use log::{debug, error, info, warn};
 use serial::prelude::*;
 
-use std::io::Read;
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::rc::Rc;
 
-use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::appdata::{self, AppData};
-
-#[derive(PartialEq, Eq, Debug, Serialize)]
-pub enum ThreadStatus {
-    Running,
-    Failed,
-    Stopping,
-    Stopped,
-}
+use crate::appdata::AppData;
+use crate::config::{MeterFormat, MissingValues, ReaderConfig, RequestLine};
+#[cfg(feature = "dlms")]
+use crate::dlms;
+use crate::history::{now_millis, Sample};
+use crate::lock::RecoverLock;
+use crate::model::{meter_time, Measurement, MeterState};
+use crate::output;
+use crate::phase_energy::PhaseEnergy;
+use crate::sampling::Sampler;
+use crate::status::{LastError, ReaderStatus, TelegramInterval, ThreadStatus};
+use crate::swap;
 
 pub struct ReaderData {
-    pub dsmr_state: dsmr5::state::State,
-    pub thread_status: ThreadStatus,
+    pub dsmr_state: MeterState,
+    /// The last known value of every field, used to fill in values missing from a telegram.
+    pub last_known: serde_json::Value,
+    /// Number of telegrams received, identifies the version of the state.
+    pub sequence: u64,
+    /// Time the last telegram was received in milliseconds since the unix epoch.
+    pub received_at: Option<u64>,
+    pub interval: TelegramInterval,
+    pub status: ReaderStatus,
+    /// Why reading ended the last time it did for another reason than a stop.
+    pub last_error: Option<LastError>,
+    /// Number of times the serial port was opened again after a disconnect.
+    pub reconnects: u64,
+    /// The serial port while it is open, to control its lines.
+    pub port: Option<SharedPort>,
+    /// Commands for the reader thread, while it reads on demand.
+    pub commands: Option<SyncSender<ReaderCommand>>,
     pub thread_handle: Option<JoinHandle<()>>,
 }
 
 impl Default for ReaderData {
     fn default() -> Self {
         Self {
-            dsmr_state: dsmr5::state::State::default(),
-            thread_status: ThreadStatus::Stopped,
+            dsmr_state: MeterState::default(),
+            last_known: serde_json::Value::Null,
+            sequence: 0,
+            received_at: None,
+            interval: TelegramInterval::default(),
+            status: ReaderStatus::default(),
+            last_error: None,
+            reconnects: 0,
+            port: None,
+            commands: None,
             thread_handle: None,
         }
     }
 }
 
+impl ReaderData {
+    /// Time without a telegram after which the state is outdated, given the configured
+    /// number of seconds. `None` means it never is.
+    pub fn stale_after(&self, configured: Option<u64>) -> Option<Duration> {
+        self.interval
+            .timeout(configured, STALE_INTERVALS, Some(STALE_FALLBACK))
+    }
+
+    /// Whether no telegram was received within `stale_after`.
+    pub fn is_outdated(&self, stale_after: Option<Duration>) -> bool {
+        stale_after.is_some_and(|stale_after| {
+            self.received_at.is_none_or(|received_at| {
+                now_millis().saturating_sub(received_at) > stale_after.as_millis() as u64
+            })
+        })
+    }
+}
+
+/// Number of telegram intervals without a telegram after which the state is outdated,
+/// unless configured otherwise.
+const STALE_INTERVALS: u32 = 5;
+/// Age after which the state is outdated while the telegram interval is still unknown.
+const STALE_FALLBACK: Duration = Duration::from_secs(60);
+/// Longest a request waits for a telegram read on demand.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(20);
+/// How often to check whether a disconnected serial device is back.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of telegram intervals without a telegram after which the serial port is
+/// reopened, unless configured otherwise.
+const WATCHDOG_INTERVALS: u32 = 10;
+/// Number of telegrams in a row that may fail to parse before the reader gives up, as
+/// the meter probably sends another format than configured.
+const MAX_BAD_TELEGRAMS: u32 = 10;
+/// Smallest serial read buffer, whatever is configured.
+const MIN_BUFFER_SIZE: usize = 64;
+/// Time the meter has after the port is opened to start a telegram, before the port is
+/// reopened. Longer than the 10 second interval of DSMR 4 meters.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Commands the reader thread takes while it reads on demand.
+#[derive(Debug)]
+pub enum ReaderCommand {
+    /// Open the serial port and read a telegram.
+    Read,
+}
+
+/// Why reading from the serial port ended.
+enum ReadEnd {
+    /// A stop was requested.
+    Stopped,
+    /// A telegram was read on demand.
+    Read,
+    /// The meter sent data that can't be read.
+    Failed(String),
+    /// The serial device can't be opened or went away, e.g. because the P1 cable was
+    /// unplugged.
+    Disconnected(String),
+}
+
 /// Spawn a thread that endlessly reads the DSMR, stores its state in rwlock and notifies
-/// the udp sender.
+/// the udp sender. When the serial device goes away the reader waits for it to come back,
+/// so re-seating the P1 cable doesn't stop the reader.
 pub fn spawn_dsmr_thread(
     appdata: Arc<AppData>,
     rwlock: Arc<RwLock<ReaderData>>,
     path: String,
 ) -> Result<JoinHandle<()>, std::io::Error> {
+    // Only one reader runs at a time, so this fails unless the reader is stopped.
+    set_status(&appdata, &rwlock, ThreadStatus::Starting).map_err(io::Error::other)?;
+    let commands = if appdata.config().reader.on_demand {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        rwlock.write_recover().commands = Some(sender);
+        Some(receiver)
+    } else {
+        None
+    };
+
     // Open the reader thread and continuously update the rwlock with
     // the DSMR data. If we fail, end the thread and set threadstatus to failed.
-    thread::Builder::new().spawn(move || {
-        let data = rwlock.clone();
-
-        if let Ok(mut mx) = data.write() {
-            mx.thread_status = ThreadStatus::Running;
-        }
-        debug!("DSMR reader thread spawned.");
+    let (thread_appdata, thread_rwlock) = (appdata.clone(), rwlock.clone());
+    let spawned = thread::Builder::new()
+        .name(String::from("reader"))
+        .spawn(move || {
+            let (appdata, rwlock) = (thread_appdata, thread_rwlock);
+            debug!("DSMR reader thread spawned.");
 
-        // Initialize reader
-        let mut port = serial::open(&path).expect("Failed to set serial port.");
-        let _init = match serial_init(&mut port) {
-            Ok(res) => info!("Serial port initialized. {:?}", res),
-            Err(error) => error!("Failed to initialize serial port: {}", error),
-        };
-        let mut reader =
-            dsmr5::Reader::new(port.bytes().map(|b| b.expect("Failed to map reader.")));
-
-        // The reader is an iterator that yields data
-        loop {
-            let reader_data = reader.next().expect("No reader data present.");
-
-            match reader_convert_value(reader_data) {
-                Ok(state) => {
-                    debug!("DSMR reader value received.");
-                    if let Ok(mut mx) = data.write() {
-                        mx.dsmr_state = state;
-                        appdata.emit_event();
+            // The sampler outlives reconnects, so aligned samples continue where they left off.
+            let mut sampler = Sampler::new(&appdata.config().sampling);
+            loop {
+                // After a panic the port is opened again, like after a disconnect.
+                let end = panic::catch_unwind(AssertUnwindSafe(|| {
+                    read_port(&appdata, &rwlock, &path, &mut sampler)
+                }))
+                .unwrap_or_else(|_| {
+                    rwlock.clear_poison();
+                    appdata.clear_poison();
+                    ReadEnd::Disconnected(String::from("the reader panicked"))
+                });
+                rwlock.write_recover().port = None;
+                match end {
+                    ReadEnd::Stopped => {
+                        update_status(&appdata, &rwlock, ThreadStatus::Stopped);
+                        break;
                     }
-                }
-                Err(e) => {
-                    debug!("Unable to receive DSMR reader value: {:?}", e);
-                    if let Ok(mut mx) = data.write() {
-                        mx.thread_status = ThreadStatus::Failed;
+                    ReadEnd::Read => {
+                        update_status(&appdata, &rwlock, ThreadStatus::Sleeping);
+                        let woken = commands.as_ref().is_some_and(|commands| {
+                            sleep(&rwlock, commands, appdata.config().reader.wake_interval)
+                        });
+                        if !woken {
+                            update_status(&appdata, &rwlock, ThreadStatus::Stopped);
+                            break;
+                        }
+                        update_status(&appdata, &rwlock, ThreadStatus::Starting);
+                    }
+                    ReadEnd::Failed(e) => {
+                        debug!("Unable to receive DSMR reader value: {:?}", e);
+                        rwlock.write_recover().last_error = Some(LastError::now(e));
+                        update_status(&appdata, &rwlock, ThreadStatus::Failed);
                         break;
                     }
+                    ReadEnd::Disconnected(e) => {
+                        warn!("DSMR reader at {} disconnected: {}", path, e);
+                        rwlock.write_recover().last_error = Some(LastError::now(e));
+                        update_status(&appdata, &rwlock, ThreadStatus::Disconnected);
+                        if !wait_for_device(&rwlock, &path) {
+                            update_status(&appdata, &rwlock, ThreadStatus::Stopped);
+                            break;
+                        }
+                        info!("DSMR reader at {} is back, reconnecting.", path);
+                        rwlock.write_recover().reconnects += 1;
+                        update_status(&appdata, &rwlock, ThreadStatus::Starting);
+                    }
                 }
-            };
+            }
+            rwlock.write_recover().commands = None;
+        });
+    if spawned.is_err() {
+        update_status(&appdata, &rwlock, ThreadStatus::Failed);
+    }
+    spawned
+}
 
-            if let Ok(mx) = data.read() {
-                if mx.thread_status == ThreadStatus::Stopping {
-                    if let Ok(mut mx2) = data.write() {
-                        mx2.thread_status = ThreadStatus::Stopped;
-                        break;
-                    }
+/// Read telegrams from the serial port at `path` until reading stops.
+fn read_port(
+    appdata: &AppData,
+    data: &RwLock<ReaderData>,
+    path: &str,
+    sampler: &mut Sampler,
+) -> ReadEnd {
+    // Initialize reader
+    let config = &appdata.config().reader;
+    let missing_values = appdata.config().output.missing_values;
+    let watchdog_timeout = config.watchdog;
+    let mut port = match serial::open(path) {
+        Ok(port) => port,
+        Err(e) => return ReadEnd::Disconnected(e.to_string()),
+    };
+    match serial_init(&mut port, config) {
+        Ok(res) => info!("Serial port initialized. {:?}", res),
+        Err(e) => return ReadEnd::Disconnected(e.to_string()),
+    };
+    let start = match probe(&mut port, config.format, data) {
+        Ok(Some(start)) => start,
+        Ok(None) => return ReadEnd::Stopped,
+        Err(e) => return ReadEnd::Disconnected(e.to_string()),
+    };
+    debug!("Meter started a telegram.");
+    let port = SharedPort(Arc::new(Mutex::new(port)));
+    data.write_recover().port = Some(port.clone());
+    update_status(appdata, data, ThreadStatus::Running);
+    // The byte stream ends when the port fails, which ends the reader as well. Timeouts
+    // just mean the meter hasn't sent anything yet, unless the watchdog expired or a stop
+    // was requested: a meter that stopped sending may start again once the port is reopened.
+    let watchdog: Rc<Cell<Option<Instant>>> = Rc::default();
+    let expired = {
+        let watchdog = watchdog.clone();
+        move || {
+            watchdog
+                .get()
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        }
+    };
+    // The buffer is allocated once per connection, telegrams are read a buffer at a time.
+    let input = SerialInput {
+        reader: BufReader::with_capacity(
+            config.buffer_size.max(MIN_BUFFER_SIZE),
+            io::Cursor::new(start).chain(port),
+        ),
+        give_up: || expired() || stop_requested(data),
+    };
+    let resync = || appdata.counters.write_recover().resync();
+
+    // All readers are iterators that yield a state per telegram.
+    let mut reader: Box<dyn Iterator<Item = Result<MeterState, String>> + '_> = match config.format
+    {
+        MeterFormat::Dsmr => Box::new(
+            Telegrams::new(input, resync)
+                .map(|readout| reader_convert_value(readout).map_err(|e| format!("{:?}", e))),
+        ),
+        #[cfg(feature = "dlms")]
+        format => Box::new(dlms::Reader::new(input, format)),
+    };
+
+    let mut phase_energy = PhaseEnergy::new(&appdata.history.read_recover());
+    let mut bad_telegrams = 0;
+    loop {
+        match reader.next() {
+            Some(Ok(state)) if is_duplicate(appdata, &state) => {
+                debug!("Dropped a duplicate telegram of {:?}.", state.datetime);
+            }
+            Some(Ok(state)) => {
+                debug!("DSMR reader value received.");
+                bad_telegrams = 0;
+                let mut sample = Sample::from_state(now_millis(), &state);
+                swap::continue_totals(appdata, &state, &mut sample);
+                phase_energy.integrate(&mut sample);
+                for sample in sampler.feed(sample) {
+                    appdata.record_sample(sample);
+                }
+                appdata.events.write_recover().observe(&state);
+                let mut mx = data.write_recover();
+                if missing_values == MissingValues::LastKnown {
+                    output::remember(&mut mx.last_known, &state);
+                }
+                mx.dsmr_state = state;
+                mx.sequence += 1;
+                let now = now_millis();
+                mx.interval.observe(now);
+                mx.received_at = Some(now);
+                let timeout = mx
+                    .interval
+                    .timeout(watchdog_timeout, WATCHDOG_INTERVALS, None);
+                watchdog.set(timeout.map(|timeout| Instant::now() + timeout));
+                drop(mx);
+                appdata.emit_event();
+                if config.on_demand {
+                    return ReadEnd::Read;
                 }
             }
+            Some(Err(e)) => {
+                bad_telegrams += 1;
+                if bad_telegrams >= MAX_BAD_TELEGRAMS {
+                    return ReadEnd::Failed(e);
+                }
+                warn!("Dropped a telegram that can't be read: {}", e);
+                resync();
+            }
+            None if stop_requested(data) => return ReadEnd::Stopped,
+            None if expired() => {
+                return ReadEnd::Disconnected(String::from("no telegram within watchdog timeout"))
+            }
+            None => return ReadEnd::Disconnected(String::from("serial port closed")),
+        };
+
+        if stop_requested(data) {
+            return ReadEnd::Stopped;
         }
-    })
+    }
 }
 
-/// Convert the latest DSMR value to a dsmr state
-fn reader_convert_value(
-    // port: &mut T,
-    data: Readout,
-) -> Result<dsmr5::state::State, dsmr5::Error> {
-    // Initialize reader
-    // let mut reader = dsmr5::Reader::new(port.bytes().map(|b| b.expect("Failed to map reader.")));
-    let data = match data.to_telegram() {
-        Ok(data) => data,
-        Err(e) => {
-            error!("Failed to get data from reader");
-            return Err(e);
+/// Whether the meter sent `state` before, see `dedup`.
+fn is_duplicate(appdata: &AppData, state: &MeterState) -> bool {
+    appdata.counters.write_recover().is_duplicate(state)
+}
+
+/// Wait until the device at `path` exists again. Returns false if a stop was requested
+/// in the meantime.
+fn wait_for_device(data: &RwLock<ReaderData>, path: &str) -> bool {
+    loop {
+        thread::sleep(RECONNECT_INTERVAL);
+        if stop_requested(data) {
+            return false;
+        }
+        if Path::new(path).exists() {
+            return true;
+        }
+    }
+}
+
+/// Sleep until a read is asked for, or for `wake_interval` seconds unless that is 0.
+/// Returns false if a stop was requested in the meantime.
+fn sleep(
+    data: &RwLock<ReaderData>,
+    commands: &Receiver<ReaderCommand>,
+    wake_interval: u64,
+) -> bool {
+    // Reads asked for while reading have been served by the telegram just read.
+    while commands.try_recv().is_ok() {}
+    let deadline = (wake_interval > 0).then(|| Instant::now() + Duration::from_secs(wake_interval));
+    loop {
+        match commands.recv_timeout(RECONNECT_INTERVAL) {
+            Ok(ReaderCommand::Read) => return true,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return false,
         }
+        if stop_requested(data) {
+            return false;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return true;
+        }
+    }
+}
+
+/// Have the reader read a telegram when it reads on demand and the state is no longer
+/// fresh, and wait for the telegram.
+pub async fn wake(appdata: &AppData, rwlock: &RwLock<ReaderData>) {
+    let config = &appdata.config().reader;
+    if !config.on_demand {
+        return;
+    }
+    let listener = {
+        let data = rwlock.read_recover();
+        let fresh = data.received_at.is_some_and(|received_at| {
+            now_millis().saturating_sub(received_at) < config.fresh_for * 1000
+        });
+        let Some(commands) = data.commands.as_ref().filter(|_| !fresh) else {
+            return;
+        };
+        let listener = appdata.event_listener();
+        // A full channel means a read has been asked for already.
+        let _ = commands.try_send(ReaderCommand::Read);
+        listener
     };
-    let state = match dsmr5::Result::<dsmr5::state::State>::from(&data) {
+    if tokio::time::timeout(WAKE_TIMEOUT, listener).await.is_err() {
+        debug!("No telegram read on demand within {:?}.", WAKE_TIMEOUT);
+    }
+}
+
+fn stop_requested(data: &RwLock<ReaderData>) -> bool {
+    data.read_recover().status.current() == ThreadStatus::Stopping
+}
+
+/// Move the reader to status `to` and let listeners know, if the transition is allowed.
+fn set_status(
+    appdata: &AppData,
+    data: &RwLock<ReaderData>,
+    to: ThreadStatus,
+) -> Result<(), String> {
+    let transition = data.write_recover().status.transition(to)?;
+    info!(
+        "Reader went from {:?} to {:?}.",
+        transition.from, transition.to
+    );
+    appdata.emit_status_event();
+    Ok(())
+}
+
+/// Move the reader to status `to` from the reader thread. Refused transitions are expected
+/// here, e.g. when a stop was requested while reconnecting the reader doesn't go back to
+/// running.
+fn update_status(appdata: &AppData, data: &RwLock<ReaderData>, to: ThreadStatus) {
+    if let Err(e) = set_status(appdata, data, to) {
+        debug!("Reader status unchanged: {}", e);
+    }
+}
+
+/// Start a new reader thread on the configured port, unless there is one already.
+pub fn start_reader(appdata: Arc<AppData>, rwlock: Arc<RwLock<ReaderData>>) -> Result<(), String> {
+    if rwlock.read_recover().thread_handle.is_some() {
+        debug!("Found existing thread. Not creating new thread.");
+        return Err(String::from("existing DMSR reader thread found."));
+    }
+    // The device given on the command line is in the configuration as well.
+    let path = appdata
+        .config()
+        .reader
+        .device
+        .clone()
+        .unwrap_or_else(|| String::from("/dev/ttyUSB0"));
+    spawn_dsmr_thread(appdata, rwlock, path)
+        .map(|_| ())
+        .map_err(|e| format!("failed to start DSMR reader thread.\n{}", e))
+}
+
+/// Ask the reader thread to stop after the telegram it is reading.
+pub fn stop_reader(appdata: &AppData, rwlock: &RwLock<ReaderData>) -> Result<(), String> {
+    set_status(appdata, rwlock, ThreadStatus::Stopping)
+}
+
+/// Convert the latest DSMR value to a dsmr state
+fn reader_convert_value(data: Readout) -> Result<MeterState, dsmr5::Error> {
+    if let Err(e) = data.to_telegram() {
+        error!("Failed to get data from reader");
+        return Err(e);
+    }
+    let state = match telegram_to_state(&data) {
         Ok(state) => state,
         Err(e) => {
             error!("Failed to process state");
@@ -118,27 +462,374 @@ fn reader_convert_value(
     Ok(state)
 }
 
+/// The lines of a telegram holding COSEM objects, those between the header and the `!`.
+/// The same lines as `Telegram::objects` parses, but with their text.
+fn object_lines(readout: &Readout) -> Result<std::str::Lines<'_>, dsmr5::Error> {
+    let text = std::str::from_utf8(&readout.buffer).map_err(|_| dsmr5::Error::InvalidFormat)?;
+    let start = text.find("\r\n\r\n").ok_or(dsmr5::Error::InvalidFormat)?;
+    let end = text.find('!').ok_or(dsmr5::Error::InvalidFormat)?;
+    let objects = text
+        .get(start + 4..end)
+        .ok_or(dsmr5::Error::InvalidFormat)?;
+    Ok(objects.lines())
+}
+
+/// Convert the COSEM objects of a checked telegram to our own meter state. Objects the
+/// parser doesn't know, such as the reactive energy some meters send, are read by
+/// `apply_unknown`.
+pub fn telegram_to_state(readout: &Readout) -> Result<MeterState, dsmr5::Error> {
+    object_lines(readout)?.try_fold(MeterState::default(), |mut state, line| {
+        let o = match OBIS::parse(line) {
+            Err(dsmr5::Error::UnknownObis) => {
+                apply_unknown(&mut state, line);
+                return Ok(state);
+            }
+            o => o,
+        };
+        match o? {
+            OBIS::Version(v) => {
+                // The version is sent as plain digits, e.g. 50 for DSMR 5.0.
+                let digits = v
+                    .as_octets()
+                    .map(|b| b.map(|b| format!("{:02X}", b)))
+                    .collect::<Result<String, _>>()?;
+                state.version = Some(digits);
+            }
+            OBIS::DateTime(tst) => state.datetime = tst_to_datetime(&tst),
+            OBIS::EquipmentIdentifier(ei) => {
+                state.equipment_id = Some(octets_to_string(ei.as_octets())?)
+            }
+            OBIS::MeterReadingTo(t, mr) => {
+                state.energy_delivered[t as usize] = Some(f64::from(&mr))
+            }
+            OBIS::MeterReadingBy(t, mr) => state.energy_received[t as usize] = Some(f64::from(&mr)),
+            OBIS::TariffIndicator(ti) => {
+                let tariff = ti
+                    .as_octets()
+                    .try_fold(0u16, |tariff, b| b.map(|b| (tariff << 8) | u16::from(b)))?;
+                state.tariff = Some(tariff);
+            }
+            OBIS::PowerDelivered(p) => state.power_delivered = Some(f64::from(&p)),
+            OBIS::PowerReceived(p) => state.power_received = Some(f64::from(&p)),
+            OBIS::PowerFailures(pf) => state.power_failures = Some(pf.0),
+            OBIS::LongPowerFailures(lpf) => state.long_power_failures = Some(lpf.0),
+            OBIS::VoltageSags(l, n) => state.phases[l as usize].voltage_sags = Some(n.0),
+            OBIS::VoltageSwells(l, n) => state.phases[l as usize].voltage_swells = Some(n.0),
+            OBIS::InstantaneousVoltage(l, v) => {
+                state.phases[l as usize].voltage = Some(f64::from(&v))
+            }
+            OBIS::InstantaneousCurrent(l, a) => state.phases[l as usize].current = Some(a.0 as f64),
+            OBIS::InstantaneousActivePowerPlus(l, p) => {
+                state.phases[l as usize].power_delivered = Some(f64::from(&p))
+            }
+            OBIS::InstantaneousActivePowerNeg(l, p) => {
+                state.phases[l as usize].power_received = Some(f64::from(&p))
+            }
+            OBIS::SlaveDeviceType(s, dt) => state.channels[s as usize].device_type = Some(dt.0),
+            OBIS::SlaveEquipmentIdentifier(s, ei) => {
+                state.channels[s as usize].equipment_id = Some(octets_to_string(ei.as_octets())?)
+            }
+            OBIS::SlaveMeterReading(s, tst, mr) => {
+                state.channels[s as usize].reading = Some(Measurement {
+                    datetime: tst_to_datetime(&tst),
+                    value: f64::from(&mr),
+                })
+            }
+            _ => {} // Ignore rest.
+        }
+        Ok(state)
+    })
+}
+
+/// Read an object the parser doesn't know, e.g. `1-0:3.8.1(000123.456*kvarh)`. Only the
+/// reactive energy and power are kept, returns whether the object is one of them.
+pub fn apply_unknown(state: &mut MeterState, line: &str) -> bool {
+    let Some((reference, rest)) = line.split_once('(') else {
+        return false;
+    };
+    let Some(code) = reference.strip_prefix("1-0:") else {
+        return false;
+    };
+    let mut groups = code.split('.').map(str::parse::<u8>);
+    let (Some(Ok(c)), Some(Ok(d)), Some(Ok(e)), None) =
+        (groups.next(), groups.next(), groups.next(), groups.next())
+    else {
+        return false;
+    };
+    let Some((value, unit)) = rest.trim_end_matches(')').split_once('*') else {
+        return false;
+    };
+    let Ok(value) = value.parse::<f64>() else {
+        return false;
+    };
+    // Meters send kvar and kvarh, a few var and varh.
+    let value = if unit.starts_with('k') {
+        value
+    } else {
+        value / 1000.0
+    };
+    match (c, d, e) {
+        // Meters without tariffs only send the total, kept as the first tariff.
+        (3, 8, 0) => _ = state.reactive_energy_delivered[0].get_or_insert(value),
+        (4, 8, 0) => _ = state.reactive_energy_received[0].get_or_insert(value),
+        (3, 8, 1 | 2) => state.reactive_energy_delivered[usize::from(e - 1)] = Some(value),
+        (4, 8, 1 | 2) => state.reactive_energy_received[usize::from(e - 1)] = Some(value),
+        (5..=8, 8, 0) => state.reactive_energy_quadrants[usize::from(c - 5)] = Some(value),
+        (3, 7, 0) => state.reactive_power_delivered = Some(value),
+        (4, 7, 0) => state.reactive_power_received = Some(value),
+        (23, 7, 0) => state.phases[0].reactive_power_delivered = Some(value),
+        (43, 7, 0) => state.phases[1].reactive_power_delivered = Some(value),
+        (63, 7, 0) => state.phases[2].reactive_power_delivered = Some(value),
+        (24, 7, 0) => state.phases[0].reactive_power_received = Some(value),
+        (44, 7, 0) => state.phases[1].reactive_power_received = Some(value),
+        (64, 7, 0) => state.phases[2].reactive_power_received = Some(value),
+        _ => return false,
+    }
+    true
+}
+
+fn tst_to_datetime(tst: &TST) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    meter_time(
+        2000 + i32::from(tst.year),
+        tst.month,
+        tst.day,
+        tst.hour,
+        tst.minute,
+        tst.second,
+        tst.dst,
+    )
+}
+
+/// Octet strings hold hex encoded text, such as the equipment identifier.
+fn octets_to_string(
+    octets: impl Iterator<Item = Result<u8, dsmr5::Error>>,
+) -> Result<String, dsmr5::Error> {
+    let bytes = octets.collect::<Result<Vec<u8>, _>>()?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
 /// Initialize the serial connection to the DSMR
-fn serial_init<T: SerialPort>(port: &mut T) -> serial::Result<()> {
+fn serial_init<T: SerialPort>(port: &mut T, config: &ReaderConfig) -> serial::Result<()> {
+    // DSMR meters send at 115200 baud, the Nordic HAN port at 2400 baud.
+    // Aidon and Kaifa use even parity.
+    let (baud_rate, parity) = match config.format {
+        MeterFormat::Dsmr => (serial::Baud115200, serial::ParityNone),
+        #[cfg(feature = "dlms")]
+        MeterFormat::Dlms | MeterFormat::Kamstrup => (serial::Baud2400, serial::ParityNone),
+        #[cfg(feature = "dlms")]
+        MeterFormat::Aidon | MeterFormat::Kaifa => (serial::Baud2400, serial::ParityEven),
+    };
     port.reconfigure(&|settings| {
         settings
-            .set_baud_rate(serial::Baud115200)
+            .set_baud_rate(baud_rate)
             .expect("Failed to set baud rate.");
         settings.set_char_size(serial::Bits8);
-        settings.set_parity(serial::ParityNone);
+        settings.set_parity(parity);
         settings.set_stop_bits(serial::Stop1);
         settings.set_flow_control(serial::FlowNone);
         Ok(())
-    })
-    .expect("Failed to set configuration.");
+    })?;
 
-    port.set_timeout(Duration::from_millis(1000))
-        .expect("Failed to set timeout.");
+    port.set_timeout(Duration::from_millis(1000))?;
+    if let Some(level) = config.rts {
+        port.set_rts(level)?;
+    }
+    if let Some(level) = config.dtr {
+        port.set_dtr(level)?;
+    }
+    Ok(())
+}
 
-    let mut buf: Vec<u8> = (0..255).collect();
+/// Wait for the meter to start a telegram, which for DSMR starts with `/` and for DLMS
+/// with the HDLC flag. Returns the bytes read from the start of the telegram on, or
+/// `None` if a stop was requested in the meantime.
+fn probe<T: SerialPort>(
+    port: &mut T,
+    format: MeterFormat,
+    data: &RwLock<ReaderData>,
+) -> io::Result<Option<Vec<u8>>> {
+    let header = match format {
+        MeterFormat::Dsmr => b'/',
+        #[cfg(feature = "dlms")]
+        _ => 0x7e,
+    };
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    let mut buf = [0; 256];
+    while Instant::now() < deadline {
+        if stop_requested(data) {
+            return Ok(None);
+        }
+        let read = match port.read(&mut buf) {
+            Ok(0) => return Err(io::Error::other("serial port closed")),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(start) = buf[..read].iter().position(|b| *b == header) {
+            return Ok(Some(buf[start..read].to_vec()));
+        }
+    }
+    Err(io::Error::other(format!(
+        "no telegram within {} seconds",
+        PROBE_TIMEOUT.as_secs()
+    )))
+}
 
-    port.write(&buf[..]).expect("Port write failed.");
-    port.read(&mut buf[..]).expect("Port read failed.");
+/// Bytes from the serial port, read a buffer at a time. Timeouts just mean the meter
+/// hasn't sent anything yet, unless `give_up` says otherwise: once the watchdog expired, as
+/// a meter that stopped sending may start again once the port is reopened, or once a stop
+/// was requested. Any other error ends the input, which ends the reader as well.
+struct SerialInput<R, W> {
+    reader: BufReader<R>,
+    give_up: W,
+}
 
-    Ok(())
+impl<R: Read, W: Fn() -> bool> SerialInput<R, W> {
+    /// The buffered bytes, reading more if there are none. `None` once the input ended.
+    fn fill(&mut self) -> Option<&[u8]> {
+        loop {
+            match self.reader.fill_buf() {
+                Ok([]) => return None,
+                // Borrowing the buffer across loop iterations isn't possible yet, so
+                // it is borrowed again once known to be filled.
+                Ok(_) => return self.reader.fill_buf().ok(),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut && !(self.give_up)() => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => return None,
+                Err(e) => {
+                    warn!("Serial port read failed: {}", e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Skip everything up to and including the next `byte`. Returns false once the input
+    /// ended.
+    fn skip_past(&mut self, byte: u8) -> bool {
+        loop {
+            let Some(buf) = self.fill() else {
+                return false;
+            };
+            match buf.iter().position(|b| *b == byte) {
+                Some(i) => {
+                    self.reader.consume(i + 1);
+                    return true;
+                }
+                None => {
+                    let length = buf.len();
+                    self.reader.consume(length);
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read, W: Fn() -> bool> Iterator for SerialInput<R, W> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let b = *self.fill()?.first()?;
+        self.reader.consume(1);
+        Some(b)
+    }
+}
+
+/// Splits the serial input into DSMR telegrams, copying them straight from the read
+/// buffer. A telegram is cut short when a new one starts before its end, e.g. when bytes
+/// were lost: the partial telegram is dropped and reading resynchronizes on the header of
+/// the new one. `resync` is called for every telegram dropped this way.
+struct Telegrams<R, W, F> {
+    input: SerialInput<R, W>,
+    resync: F,
+}
+
+impl<R: Read, W: Fn() -> bool, F: FnMut()> Telegrams<R, W, F> {
+    fn new(input: SerialInput<R, W>, resync: F) -> Self {
+        Self { input, resync }
+    }
+}
+
+impl<R: Read, W: Fn() -> bool, F: FnMut()> Iterator for Telegrams<R, W, F> {
+    type Item = Readout;
+
+    fn next(&mut self) -> Option<Readout> {
+        if !self.input.skip_past(b'/') {
+            return None;
+        }
+        let mut buffer = [0; 2048];
+        buffer[0] = b'/';
+        let mut length = 1;
+        loop {
+            let buf = self.input.fill()?;
+            let end = buf.iter().position(|b| *b == b'/' || *b == b'!');
+            let take = end.map_or(buf.len(), |i| i + 1);
+            if length + take > buffer.len() {
+                // No telegram is this long, so the end was lost.
+                (self.resync)();
+                if !self.input.skip_past(b'/') {
+                    return None;
+                }
+                length = 1;
+                continue;
+            }
+            buffer[length..length + take].copy_from_slice(&buf[..take]);
+            length += take;
+            self.input.reader.consume(take);
+
+            match end.map(|_| buffer[length - 1]) {
+                Some(b'/') => {
+                    (self.resync)();
+                    length = 1;
+                }
+                Some(_) => {
+                    // The checksum follows the end of the telegram.
+                    for _ in 0..4 {
+                        let b = self.input.next()?;
+                        if let Some(slot) = buffer.get_mut(length) {
+                            *slot = b;
+                            length += 1;
+                        }
+                    }
+                    return Some(Readout { buffer });
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// The serial port, shared between the reader thread reading from it and requests
+/// controlling its lines. Reads time out after a second, so the port is never locked
+/// for long.
+#[derive(Clone)]
+pub struct SharedPort(Arc<Mutex<serial::SystemPort>>);
+
+impl std::fmt::Debug for SharedPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedPort")
+    }
+}
+
+impl Read for SharedPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .map_err(|_| io::Error::other("serial port poisoned"))?
+            .read(buf)
+    }
+}
+
+impl SharedPort {
+    /// Set the data request `line` to `level`.
+    pub fn set_request_line(&self, line: RequestLine, level: bool) -> Result<(), String> {
+        let mut port = self
+            .0
+            .lock()
+            .map_err(|_| String::from("serial port poisoned"))?;
+        match line {
+            RequestLine::Rts => port.set_rts(level),
+            RequestLine::Dtr => port.set_dtr(level),
+        }
+        .map_err(|e| format!("unable to set {:?}: {}", line, e))
+    }
 }
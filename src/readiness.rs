@@ -0,0 +1,105 @@
+//! The daemon is ready once the first valid telegram has been parsed, rather than as soon
+//! as it listens. Until then `/readyz` answers 503 and the MQTT availability is `offline`,
+//! and systemd is told through `sd_notify` once it is. A meter that sends nothing within the startup timeout is a
+//! fatal error, which beats silently serving an empty state.
+
+use std::{
+    env, io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    sync::{Arc, RwLock},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use event_listener::Listener;
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use log::{debug, error, info};
+
+use crate::{appdata::AppData, lock::RecoverLock, reader::ReaderData};
+
+/// Whether the first telegram has been received. An aggregator reads no meter, so it is
+/// ready right away.
+fn is_ready(appdata: &AppData, data: &RwLock<ReaderData>) -> bool {
+    appdata.config().aggregator.is_some() || data.read_recover().received_at.is_some()
+}
+
+/// Spawn a thread that waits for the first telegram, and exits the daemon if it doesn't
+/// arrive within the configured startup timeout.
+pub fn spawn_readiness_job(
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<JoinHandle<()>, io::Error> {
+    thread::Builder::new().spawn(move || {
+        let timeout = appdata.config().reader.startup_timeout;
+        let deadline = (timeout > 0).then(|| Instant::now() + Duration::from_secs(timeout));
+        loop {
+            // Listen before checking, so a telegram arriving in between isn't missed.
+            let listener = appdata.event_listener();
+            if is_ready(&appdata, &data) {
+                break;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if listener.wait_deadline(deadline).is_none() && !is_ready(&appdata, &data) {
+                        let message = format!(
+                            "No telegram received within {} s, check the P1 cable and \
+                             whether the meter gets its data request (RTS) signal.",
+                            timeout
+                        );
+                        error!("{}", message);
+                        sd_notify(&format!("STATUS={}", message));
+                        std::process::exit(1);
+                    }
+                }
+                None => listener.wait(),
+            }
+        }
+        info!("First telegram received, ready.");
+        sd_notify("READY=1\nSTATUS=Receiving telegrams");
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = appdata.mqtt() {
+            if let Err(e) = mqtt.publish(crate::mqtt::AVAILABILITY, b"online", true) {
+                error!("Unable to publish availability: {}", e);
+            }
+        }
+    })
+}
+
+/// Send `state` to the service manager, if we were started by one that asked for it.
+fn sd_notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let path = path.to_string_lossy();
+        match path.strip_prefix('@') {
+            // Abstract socket names start with @, which stands for a leading zero byte.
+            Some(name) => {
+                socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)
+            }
+            None => socket.send_to(state.as_bytes(), path.as_ref()),
+        }
+    });
+    match result {
+        Ok(_) => debug!("Notified service manager: {}", state),
+        Err(e) => error!("Unable to notify service manager: {}", e),
+    }
+}
+
+/// Handler for `/readyz`: 200 once the first telegram has been received, 503 before.
+pub async fn handler(
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let (status, body) = match is_ready(&appdata, &data) {
+        true => (StatusCode::OK, r#"{"ready":true}"#),
+        false => (StatusCode::SERVICE_UNAVAILABLE, r#"{"ready":false}"#),
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+}
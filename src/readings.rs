@@ -0,0 +1,264 @@
+//! The meter readings ("meterstanden") to submit to the energy supplier: the totals of
+//! both tariffs in each direction and of gas, as the meter showed them when
+//! `readings.schedule` fired, by default at midnight on the first of the month. They are
+//! kept in `readings.file`, served at `/readings`, and sent by email, posted to a webhook
+//! or published over MQTT when configured.
+
+use std::{fs, sync::Arc, thread::JoinHandle};
+
+use chrono::{Local, NaiveDate, TimeZone};
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    allowlist,
+    appdata::AppData,
+    config::ReadingsConfig,
+    dial::Dialer,
+    history::{History, Metric, Sample},
+    http_client::HttpClient,
+    lock::RecoverLock,
+    report, supervisor,
+};
+
+/// Longest a telegram may be older than the time the readings are taken for.
+const MAX_AGE: u64 = 3_600_000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reading {
+    /// Day the readings are taken on.
+    pub date: NaiveDate,
+    /// Time of the telegram they're from in milliseconds since the unix epoch.
+    pub timestamp: u64,
+    /// Meter totals of energy delivered to the client per tariff in kWh. In the
+    /// Netherlands tariff 1 is the low (dal) tariff and tariff 2 the normal tariff.
+    pub delivered: [Option<f64>; 2],
+    /// Meter totals of energy delivered by the client per tariff in kWh.
+    pub received: [Option<f64>; 2],
+    /// Meter total of gas delivered in m³.
+    pub gas: Option<f64>,
+}
+
+impl Reading {
+    fn from_sample(date: NaiveDate, sample: &Sample) -> Self {
+        Self {
+            date,
+            timestamp: sample.timestamp,
+            delivered: [
+                sample.get(Metric::EnergyDeliveredTariff1),
+                sample.get(Metric::EnergyDeliveredTariff2),
+            ],
+            received: [
+                sample.get(Metric::EnergyReceivedTariff1),
+                sample.get(Metric::EnergyReceivedTariff2),
+            ],
+            gas: sample.get(Metric::GasDelivered),
+        }
+    }
+
+    /// The readings at `time`, from the first sample since then, or the last one before if
+    /// there is none yet.
+    fn take(history: &History, time: u64) -> Option<Self> {
+        let sample = history
+            .query_range(time, time.saturating_add(MAX_AGE))
+            .next()
+            .or_else(|| history.latest())
+            .filter(|sample| sample.timestamp.abs_diff(time) <= MAX_AGE)?;
+        let date = Local
+            .timestamp_millis_opt(time as i64)
+            .single()?
+            .date_naive();
+        Some(Self::from_sample(date, &sample))
+    }
+
+    pub fn to_text(&self) -> String {
+        let value = |v: Option<f64>, unit: &str| match v {
+            Some(v) => format!("{:.3} {}", v, unit),
+            None => String::from("-"),
+        };
+        let time = Local
+            .timestamp_millis_opt(self.timestamp as i64)
+            .single()
+            .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        [
+            format!("Meter readings on {}, read at {}", self.date, time),
+            String::new(),
+            format!(
+                "Delivered, tariff 1 (low):    {}",
+                value(self.delivered[0], "kWh")
+            ),
+            format!(
+                "Delivered, tariff 2 (normal): {}",
+                value(self.delivered[1], "kWh")
+            ),
+            format!(
+                "Received, tariff 1 (low):     {}",
+                value(self.received[0], "kWh")
+            ),
+            format!(
+                "Received, tariff 2 (normal):  {}",
+                value(self.received[1], "kWh")
+            ),
+            format!("Gas:                          {}", value(self.gas, "m³")),
+        ]
+        .join("\n")
+    }
+}
+
+/// The readings taken, oldest first, kept in the configured file.
+#[derive(Debug)]
+pub struct Readings {
+    file: Option<String>,
+    max: usize,
+    readings: Vec<Reading>,
+}
+
+impl Readings {
+    pub fn new(config: &ReadingsConfig) -> Self {
+        let readings = config
+            .file
+            .as_ref()
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(readings) => serde_json::from_str(&readings)
+                    .map_err(|e| error!("Unable to parse readings {}: {}", path, e))
+                    .ok(),
+                Err(e) => {
+                    info!("No readings read from {}: {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            file: config.file.clone(),
+            max: config.max.max(1),
+            readings,
+        }
+    }
+
+    /// Add the readings of a day, replacing those taken on the same day before.
+    fn insert(&mut self, reading: Reading) {
+        self.readings.retain(|taken| taken.date != reading.date);
+        self.readings.push(reading);
+        if self.readings.len() > self.max {
+            self.readings.remove(0);
+        }
+        self.save();
+    }
+
+    pub fn save(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let result = serde_json::to_string(&self.readings)
+            .map_err(|e| e.to_string())
+            .and_then(|readings| fs::write(path, readings).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Unable to write readings {}: {}", path, e);
+        }
+    }
+}
+
+/// Spawn a thread that takes the readings every time the schedule fires.
+pub fn spawn_readings_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("readings", appdata, |appdata| {
+        let config = &appdata.config().readings;
+        loop {
+            let time = config.schedule.wait();
+            // The schedule fires at the start of the minute.
+            let time = (time.timestamp() as u64 - time.timestamp() as u64 % 60) * 1000;
+            let Some(reading) = Reading::take(&appdata.history.read_recover(), time) else {
+                error!("Unable to take meter readings: no recent telegram.");
+                continue;
+            };
+            info!("Took the meter readings of {}.", reading.date);
+            appdata.readings.write_recover().insert(reading.clone());
+            send(appdata, &reading);
+        }
+    })
+}
+
+/// Deliver the readings through every configured channel.
+fn send(appdata: &AppData, reading: &Reading) {
+    let config = &appdata.config().readings;
+    let outbound = &appdata.config().outbound;
+    if let Some(smtp) = &config.smtp {
+        let subject = format!("Meter readings on {}", reading.date);
+        match report::send_email(smtp, subject, reading.to_text(), None) {
+            Ok(_) => info!("Sent meter readings of {} by email.", reading.date),
+            Err(e) => error!("Failed to send meter readings by email: {}", e),
+        }
+    }
+    if let Some(webhook) = &config.webhook {
+        let result = serde_json::to_vec(reading)
+            .map_err(|e| e.to_string())
+            .and_then(|body| {
+                allowlist::check_url(&outbound.allow, &webhook.url)?;
+                let dialer = Dialer::with_proxy(outbound.proxy.as_ref()).allowing(&outbound.allow);
+                let client = HttpClient::new(dialer)?;
+                client.post(&webhook.url, "application/json", &[], body)
+            });
+        match result {
+            Ok(_) => info!("Posted meter readings of {} to webhook.", reading.date),
+            Err(e) => error!("Failed to post meter readings to webhook: {}", e),
+        }
+    }
+    if let Some(topic) = &config.topic {
+        #[cfg(feature = "mqtt")]
+        let result = appdata
+            .mqtt()
+            .ok_or_else(|| String::from("no MQTT broker is configured"))
+            .and_then(|mqtt| {
+                let payload = serde_json::to_vec(reading).map_err(|e| e.to_string())?;
+                mqtt.publish(topic, &payload, true)
+            });
+        #[cfg(not(feature = "mqtt"))]
+        let result: Result<u64, String> =
+            Err(String::from("dsmrd was built without the mqtt feature"));
+        match result {
+            Ok(_) => info!("Published meter readings of {} to {}.", reading.date, topic),
+            Err(e) => error!("Failed to publish meter readings: {}", e),
+        }
+    }
+}
+
+/// The response of `/readings`.
+#[derive(Serialize)]
+struct ReadingsResponse<'a> {
+    /// The readings as the meter shows them now.
+    current: Option<Reading>,
+    readings: &'a [Reading],
+}
+
+/// Handler for `GET /readings`, listing the readings taken, latest first.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    if req.method() != Method::GET {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::from("Error: method not allowed."));
+    }
+    let current = appdata
+        .history
+        .read_recover()
+        .latest()
+        .map(|sample| Reading::from_sample(Local::now().date_naive(), &sample));
+    let mut readings = appdata.readings.read_recover().readings.clone();
+    readings.reverse();
+    let response = ReadingsResponse {
+        current,
+        readings: &readings,
+    };
+    match serde_json::to_string(&response) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
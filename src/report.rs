@@ -0,0 +1,284 @@
+//! Daily usage reports, sent by email or posted to a webhook on a schedule.
+
+use std::{fmt::Write, sync::Arc, thread::JoinHandle};
+
+use chrono::{DateTime, Days, Local, NaiveDate, TimeZone};
+#[cfg(feature = "email")]
+use lettre::{
+    message::{header::ContentType, Attachment, MultiPart, SinglePart},
+    transport::smtp::authentication::Credentials,
+    Message, SmtpTransport, Transport,
+};
+use log::{debug, error, info};
+
+#[cfg(feature = "email")]
+use crate::config::SmtpSecurity;
+use crate::{
+    allowlist,
+    appdata::AppData,
+    config::{Config, OutboundConfig, ReportConfig, ReportFormat, SmtpConfig},
+    dial::Dialer,
+    history::{day_range, History, Metric},
+    http_client::HttpClient,
+    lock::RecoverLock,
+    prices::{self, PriceTable},
+    supervisor,
+};
+
+/// Usage over a single day.
+#[derive(Debug)]
+pub struct DailySummary {
+    pub date: NaiveDate,
+    /// Energy delivered to the client per tariff in kWh.
+    pub delivered: [Option<f64>; 2],
+    /// Energy delivered by the client per tariff in kWh.
+    pub received: [Option<f64>; 2],
+    /// Gas delivered in m³.
+    pub gas: Option<f64>,
+    /// Highest power delivered to the client in kW, and when it occurred.
+    pub peak: Option<(DateTime<Local>, f64)>,
+    pub cost: Option<f64>,
+}
+
+impl DailySummary {
+    /// Summarize the samples of `date` (in local time) stored in `history`. Electricity
+    /// is priced at the dynamic prices if they cover the whole day, and at the fixed
+    /// prices otherwise.
+    pub fn compute(
+        date: NaiveDate,
+        history: &History,
+        config: &Config,
+        dynamic_prices: Option<&PriceTable>,
+    ) -> Self {
+        let (from, to) = day_range(date);
+        let usage = |metric| history.usage(metric, from, to);
+
+        let delivered = [
+            usage(Metric::EnergyDeliveredTariff1),
+            usage(Metric::EnergyDeliveredTariff2),
+        ];
+        let received = [
+            usage(Metric::EnergyReceivedTariff1),
+            usage(Metric::EnergyReceivedTariff2),
+        ];
+        let gas = usage(Metric::GasDelivered);
+        let peak = history
+            .query_range(from, to)
+            .filter_map(|s| Some((s.timestamp, s.get(Metric::PowerDelivered)?)))
+            .fold(
+                None,
+                |peak: Option<(u64, f64)>, (timestamp, power)| match peak {
+                    Some((_, max)) if max >= power => peak,
+                    _ => Some((timestamp, power)),
+                },
+            )
+            .and_then(|(timestamp, power)| {
+                let time = Local.timestamp_millis_opt(timestamp as i64).single()?;
+                Some((time, power))
+            });
+
+        let cost = prices::cost(
+            history,
+            config,
+            dynamic_prices,
+            (from, to),
+            delivered,
+            received,
+            gas,
+        );
+
+        Self {
+            date,
+            delivered,
+            received,
+            gas,
+            peak,
+            cost,
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        let value = |v: Option<f64>| v.map(|v| format!("{:.3}", v)).unwrap_or_default();
+        let mut csv = String::from(
+            "date,delivered_tariff1_kwh,delivered_tariff2_kwh,received_tariff1_kwh,\
+             received_tariff2_kwh,gas_m3,peak_kw,peak_time,cost\n",
+        );
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{},{},{},{}",
+            self.date,
+            value(self.delivered[0]),
+            value(self.delivered[1]),
+            value(self.received[0]),
+            value(self.received[1]),
+            value(self.gas),
+            value(self.peak.map(|(_, power)| power)),
+            self.peak
+                .map(|(time, _)| time.format("%H:%M:%S").to_string())
+                .unwrap_or_default(),
+            self.cost.map(|c| format!("{:.2}", c)).unwrap_or_default(),
+        );
+        csv
+    }
+
+    pub fn to_text(&self) -> String {
+        let value = |v: Option<f64>, unit: &str| match v {
+            Some(v) => format!("{:.3} {}", v, unit),
+            None => String::from("unknown"),
+        };
+        let mut text = format!("Energy usage on {}\n\n", self.date);
+        let _ = writeln!(
+            text,
+            "Delivered (tariff 1): {}",
+            value(self.delivered[0], "kWh")
+        );
+        let _ = writeln!(
+            text,
+            "Delivered (tariff 2): {}",
+            value(self.delivered[1], "kWh")
+        );
+        let _ = writeln!(
+            text,
+            "Received (tariff 1):  {}",
+            value(self.received[0], "kWh")
+        );
+        let _ = writeln!(
+            text,
+            "Received (tariff 2):  {}",
+            value(self.received[1], "kWh")
+        );
+        let _ = writeln!(text, "Gas:                  {}", value(self.gas, "m³"));
+        if let Some((time, power)) = self.peak {
+            let _ = writeln!(
+                text,
+                "Peak:                 {:.3} kW at {}",
+                power,
+                time.format("%H:%M:%S")
+            );
+        }
+        if let Some(cost) = self.cost {
+            let _ = writeln!(text, "Cost:                 {:.2}", cost);
+        }
+        text
+    }
+}
+
+/// Spawn a thread that sends the report of the previous day every time the schedule fires.
+pub fn spawn_report_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("report", appdata, |appdata| {
+        let Some(config) = appdata.config().report.as_ref() else {
+            return;
+        };
+        info!("Report job started.");
+
+        loop {
+            let now = config.schedule.wait();
+            let Some(yesterday) = now.date_naive().checked_sub_days(Days::new(1)) else {
+                continue;
+            };
+            let summary = DailySummary::compute(
+                yesterday,
+                &appdata.history.read_recover(),
+                appdata.config(),
+                Some(&appdata.prices.read_recover()),
+            );
+            debug!("Sending report for {}", yesterday);
+            send_report(config, &appdata.config().outbound, &summary);
+        }
+    })
+}
+
+/// Deliver a report through every configured channel.
+fn send_report(config: &ReportConfig, outbound: &OutboundConfig, summary: &DailySummary) {
+    if let Some(smtp) = &config.smtp {
+        let subject = format!("Energy usage on {}", summary.date);
+        let attachment = match config.format {
+            ReportFormat::Csv => Some((format!("usage-{}.csv", summary.date), summary.to_csv())),
+            ReportFormat::Text => None,
+        };
+        match send_email(smtp, subject, summary.to_text(), attachment) {
+            Ok(_) => info!("Sent report for {} by email.", summary.date),
+            Err(e) => error!("Failed to send report by email: {}", e),
+        }
+    }
+    if let Some(webhook) = &config.webhook {
+        let (content_type, body) = match config.format {
+            ReportFormat::Csv => ("text/csv", summary.to_csv()),
+            ReportFormat::Text => ("text/plain; charset=utf-8", summary.to_text()),
+        };
+        let result = allowlist::check_url(&outbound.allow, &webhook.url)
+            .and_then(|_| {
+                HttpClient::new(
+                    Dialer::with_proxy(outbound.proxy.as_ref()).allowing(&outbound.allow),
+                )
+            })
+            .and_then(|client| client.post(&webhook.url, content_type, &[], body.into_bytes()));
+        match result {
+            Ok(_) => info!("Posted report for {} to webhook.", summary.date),
+            Err(e) => error!("Failed to post report to webhook: {}", e),
+        }
+    }
+}
+
+/// Send an email with `text` as its body, and a CSV file given by its name and contents
+/// attached if there is one.
+#[cfg(feature = "email")]
+pub fn send_email(
+    smtp: &SmtpConfig,
+    subject: String,
+    text: String,
+    attachment: Option<(String, String)>,
+) -> Result<(), String> {
+    let mut builder = Message::builder()
+        .from(
+            smtp.from
+                .parse()
+                .map_err(|e| format!("Invalid sender: {}", e))?,
+        )
+        .subject(subject);
+    for to in &smtp.to {
+        builder = builder.to(to
+            .parse()
+            .map_err(|e| format!("Invalid recipient: {}", e))?);
+    }
+
+    let csv_type = ContentType::parse("text/csv").map_err(|e| e.to_string())?;
+    let message = match attachment {
+        None => builder.body(text),
+        Some((name, csv)) => builder.multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain(text))
+                .singlepart(Attachment::new(name).body(csv, csv_type)),
+        ),
+    }
+    .map_err(|e| format!("Unable to build email: {}", e))?;
+
+    let mut transport = match smtp.security {
+        SmtpSecurity::Tls => SmtpTransport::relay(&smtp.server),
+        SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&smtp.server),
+        SmtpSecurity::None => Ok(SmtpTransport::builder_dangerous(&smtp.server)),
+    }
+    .map_err(|e| format!("Unable to connect to {}: {}", smtp.server, e))?;
+    if let Some(port) = smtp.port {
+        transport = transport.port(port);
+    }
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    transport
+        .build()
+        .send(&message)
+        .map(|_| ())
+        .map_err(|e| format!("Unable to send email: {}", e))
+}
+
+#[cfg(not(feature = "email"))]
+pub fn send_email(
+    _smtp: &SmtpConfig,
+    _subject: String,
+    _text: String,
+    _attachment: Option<(String, String)>,
+) -> Result<(), String> {
+    Err(String::from("dsmrd was built without the email feature"))
+}
@@ -0,0 +1,223 @@
+//! JSON-RPC 2.0 over a WebSocket at `/rpc`, giving plugins a single connection for both
+//! control and data.
+//!
+//! Methods are `get_state`, `subscribe`, `unsubscribe`, `start_reader`, `stop_reader` and
+//! `list_clients`. After `subscribe`, the state is pushed as a `state` notification every
+//! time a telegram arrives, and the reader status as a `status` notification every time it
+//! changes.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
+
+use futures::{SinkExt, StreamExt};
+use hyper::{Body, Request, Response, StatusCode};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    appdata::AppData,
+    config::StateFormat,
+    lock::RecoverLock,
+    output,
+    reader::{start_reader, stop_reader, ReaderData},
+    websocket::{self, Socket},
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+/// Reserved for implementation defined server errors.
+const SERVER_ERROR: i64 = -32000;
+
+/// Subscription ids are unique over all connections, which makes logs easier to follow.
+static NEXT_SUBSCRIPTION: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    /// None of our methods take parameters, so `params` is ignored.
+    method: String,
+    /// Requests without an id are notifications, which get no response.
+    id: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Build a response to the request with the given id.
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
+
+/// Handler for `/rpc`.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    if !websocket::is_upgrade(&req) {
+        return Response::builder()
+            .status(StatusCode::UPGRADE_REQUIRED)
+            .body(Body::from(
+                "Error: JSON-RPC is only offered over a WebSocket.",
+            ));
+    }
+    let session = Session {
+        appdata,
+        data,
+        subscription: None,
+    };
+    websocket::upgrade(req, None, move |socket| session.run(socket))
+}
+
+/// A single WebSocket connection.
+struct Session {
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+    subscription: Option<u64>,
+}
+
+impl Session {
+    async fn run(mut self, socket: Socket) {
+        let (mut sink, mut stream) = socket.split();
+        loop {
+            let listener = self.appdata.event_listener();
+            let status_listener = self.appdata.status_listener();
+            let reply = tokio::select! {
+                message = stream.next() => match message {
+                    Some(Ok(Message::Text(text))) => self.handle(&text),
+                    Some(Ok(Message::Binary(data))) => {
+                        self.handle(&String::from_utf8_lossy(&data))
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => None,
+                },
+                _ = listener, if self.subscription.is_some() => self.notification(),
+                _ = status_listener, if self.subscription.is_some() => {
+                    self.status_notification()
+                }
+            };
+            if let Some(reply) = reply {
+                if sink.send(Message::Text(reply.to_string())).await.is_err() {
+                    break;
+                }
+            }
+        }
+        debug!("JSON-RPC connection closed");
+    }
+
+    /// Handle a message holding a single request or a batch of them, returning the reply
+    /// if there is one.
+    fn handle(&mut self, text: &str) -> Option<Value> {
+        let message: Value = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, e.to_string());
+                return Some(response(Value::Null, Err(error)));
+            }
+        };
+        match message {
+            Value::Array(batch) if !batch.is_empty() => {
+                let replies: Vec<Value> = batch
+                    .into_iter()
+                    .filter_map(|request| self.handle_request(request))
+                    .collect();
+                (!replies.is_empty()).then_some(Value::Array(replies))
+            }
+            request => self.handle_request(request),
+        }
+    }
+
+    fn handle_request(&mut self, request: Value) -> Option<Value> {
+        let request: RpcRequest = match serde_json::from_value(request) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError::new(INVALID_REQUEST, e.to_string());
+                return Some(response(Value::Null, Err(error)));
+            }
+        };
+        if request.jsonrpc != "2.0" {
+            let error = RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported");
+            return Some(response(request.id.unwrap_or_default(), Err(error)));
+        }
+        let result = self.call(&request.method);
+        request.id.map(|id| response(id, result))
+    }
+
+    fn call(&mut self, method: &str) -> Result<Value, RpcError> {
+        match method {
+            "get_state" => self.state(),
+            "subscribe" => {
+                let id = *self
+                    .subscription
+                    .get_or_insert_with(|| NEXT_SUBSCRIPTION.fetch_add(1, Ordering::Relaxed));
+                Ok(id.into())
+            }
+            "unsubscribe" => Ok(self.subscription.take().is_some().into()),
+            "start_reader" => start_reader(self.appdata.clone(), self.data.clone())
+                .map(|_| Value::Bool(true))
+                .map_err(|e| RpcError::new(SERVER_ERROR, e)),
+            "stop_reader" => stop_reader(&self.appdata, &self.data)
+                .map(|_| Value::Bool(true))
+                .map_err(|e| RpcError::new(SERVER_ERROR, e)),
+            "list_clients" => self
+                .appdata
+                .list_clients()
+                .map(Value::from)
+                .map_err(|e| RpcError::new(INTERNAL_ERROR, e)),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {}", method),
+            )),
+        }
+    }
+
+    fn state(&self) -> Result<Value, RpcError> {
+        output::render_state(
+            &self.data.read_recover(),
+            self.appdata.config().output.missing_values,
+            StateFormat::Native,
+        )
+        .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+    }
+
+    /// The notification sent to subscribers when the reader status changes.
+    fn status_notification(&self) -> Option<Value> {
+        let status = self.data.read_recover().status.clone();
+        Some(json!({
+            "jsonrpc": "2.0",
+            "method": "status",
+            "params": { "subscription": self.subscription, "status": status },
+        }))
+    }
+
+    /// The notification sent to subscribers when a telegram arrives.
+    fn notification(&self) -> Option<Value> {
+        let state = self.state().ok()?;
+        Some(json!({
+            "jsonrpc": "2.0",
+            "method": "state",
+            "params": { "subscription": self.subscription, "state": state },
+        }))
+    }
+}
@@ -0,0 +1,150 @@
+//! Alerts on thresholds, configured in `alerts.rules`. A rule fires its alert once one of
+//! its metrics has been above `above` or below `below` for `duration` seconds, and resolves
+//! it once all of them have been back for as long. Together with `actions` this makes for
+//! simple load shedding, e.g. switching off the boiler while a phase draws over 25 A.
+
+use std::{sync::Arc, thread, thread::JoinHandle, time::Duration};
+
+use log::error;
+
+use crate::{
+    alerts,
+    appdata::AppData,
+    config::AlertRule,
+    history::{Metric, Sample},
+    lock::RecoverLock,
+    supervisor,
+};
+
+/// Time between checks of the new samples.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Rule<'a> {
+    config: &'a AlertRule,
+    metrics: Vec<Metric>,
+    firing: bool,
+    /// Time the threshold was first crossed while not firing, or clear while firing.
+    changing_since: Option<u64>,
+}
+
+impl<'a> Rule<'a> {
+    fn new(config: &'a AlertRule, firing: bool) -> Result<Self, String> {
+        let metrics = config
+            .metrics
+            .iter()
+            .map(|name| {
+                Metric::from_name(name)
+                    .ok_or_else(|| format!("Unknown metric {} in rule {}", name, config.name))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            config,
+            metrics,
+            firing,
+            changing_since: None,
+        })
+    }
+
+    /// The metric crossing the threshold in `sample` and its value, if one does.
+    fn crossing(&self, sample: &Sample) -> Option<(Metric, f64)> {
+        self.metrics.iter().find_map(|metric| {
+            let value = sample.get(*metric)?;
+            let crossed = self.config.above.is_some_and(|above| value > above)
+                || self.config.below.is_some_and(|below| value < below);
+            crossed.then_some((*metric, value))
+        })
+    }
+
+    /// Follow the rule through a sample. Returns whether the alert fires or resolves, when
+    /// it does either.
+    fn update(&mut self, sample: &Sample) -> Option<bool> {
+        if self
+            .metrics
+            .iter()
+            .all(|metric| sample.get(*metric).is_none())
+        {
+            return None;
+        }
+        if self.crossing(sample).is_some() == self.firing {
+            self.changing_since = None;
+            return None;
+        }
+        let since = *self.changing_since.get_or_insert(sample.timestamp);
+        if sample.timestamp.saturating_sub(since) < self.config.duration * 1000 {
+            return None;
+        }
+        self.firing = !self.firing;
+        self.changing_since = None;
+        Some(self.firing)
+    }
+
+    fn threshold(&self) -> String {
+        match (self.config.above, self.config.below) {
+            (Some(above), Some(below)) => format!("above {} or below {}", above, below),
+            (Some(above), None) => format!("above {}", above),
+            (None, Some(below)) => format!("below {}", below),
+            (None, None) => String::from("past no threshold"),
+        }
+    }
+}
+
+/// Spawn a thread that follows the rules through the new samples, firing and resolving
+/// their alerts.
+pub fn spawn_rules_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("rules", appdata, |appdata| {
+        let alerts = appdata.alerts.read_recover();
+        let rules: Result<Vec<Rule>, String> = appdata
+            .config()
+            .alerts
+            .rules
+            .iter()
+            .map(|config| Rule::new(config, alerts.is_firing(&config.name)))
+            .collect();
+        drop(alerts);
+        let mut rules = match rules {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("Unable to start alert rules: {}", e);
+                return;
+            }
+        };
+        // Only samples from now on count.
+        let mut after = appdata.history.read_recover().latest().map_or(0, |s| s.id);
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+            let samples: Vec<Sample> = appdata.history.read_recover().since(after).collect();
+            for sample in samples {
+                after = sample.id;
+                for rule in &mut rules {
+                    match rule.update(&sample) {
+                        Some(true) => fire(appdata, rule, &sample),
+                        Some(false) => alerts::resolve(
+                            appdata,
+                            &rule.config.name,
+                            format!("Back within the threshold of {}.", rule.config.name),
+                        ),
+                        None => {}
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn fire(appdata: &AppData, rule: &Rule, sample: &Sample) {
+    let Some((metric, value)) = rule.crossing(sample) else {
+        return;
+    };
+    alerts::fire(
+        appdata,
+        &rule.config.name,
+        format!("{} {}", metric.name(), rule.threshold()),
+        format!(
+            "{} is {}, {} for {} seconds.",
+            metric.name(),
+            value,
+            rule.threshold(),
+            rule.config.duration
+        ),
+    );
+}
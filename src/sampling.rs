@@ -0,0 +1,69 @@
+//! Turns the samples of incoming telegrams into the samples that are recorded: either as
+//! they are, or interpolated to wall-clock boundaries so series of several sources line up.
+
+use crate::{
+    config::{SamplingConfig, SamplingMode},
+    history::{Metric, Sample, METRIC_COUNT},
+};
+
+/// Telegrams further apart than this are not interpolated between, since we can't tell
+/// what happened in the meantime.
+const MAX_GAP: u64 = 5 * 60 * 1000;
+
+pub struct Sampler {
+    /// Interval between aligned samples in milliseconds, `None` to pass samples through.
+    interval: Option<u64>,
+    previous: Option<Sample>,
+}
+
+impl Sampler {
+    pub fn new(config: &SamplingConfig) -> Self {
+        let interval = match config.mode {
+            SamplingMode::Telegram => None,
+            SamplingMode::Aligned => Some(config.interval.max(1) * 1000),
+        };
+        Self {
+            interval,
+            previous: None,
+        }
+    }
+
+    /// Feed the sample of a new telegram, returning the samples to record.
+    pub fn feed(&mut self, sample: Sample) -> Vec<Sample> {
+        let Some(interval) = self.interval else {
+            return vec![sample];
+        };
+        let Some(previous) = self.previous.replace(sample.clone()) else {
+            return Vec::new();
+        };
+        if sample.timestamp <= previous.timestamp || sample.timestamp - previous.timestamp > MAX_GAP
+        {
+            return Vec::new();
+        }
+
+        // Every boundary in (previous, current] gets a sample.
+        let mut boundary = (previous.timestamp / interval + 1) * interval;
+        let mut samples = Vec::new();
+        while boundary <= sample.timestamp {
+            samples.push(interpolate(&previous, &sample, boundary));
+            boundary += interval;
+        }
+        samples
+    }
+}
+
+/// Linearly interpolate between two samples. Metrics missing from either sample are taken
+/// from the sample closest to `timestamp`.
+fn interpolate(before: &Sample, after: &Sample, timestamp: u64) -> Sample {
+    let fraction =
+        (timestamp - before.timestamp) as f64 / (after.timestamp - before.timestamp) as f64;
+    let mut values = [None; METRIC_COUNT];
+    for metric in Metric::ALL {
+        values[metric as usize] = match (before.get(metric), after.get(metric)) {
+            (Some(a), Some(b)) => Some(a + (b - a) * fraction),
+            (a, b) if fraction < 0.5 => a.or(b),
+            (a, b) => b.or(a),
+        };
+    }
+    Sample::from_values(timestamp, values)
+}
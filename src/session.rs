@@ -0,0 +1,226 @@
+//! Measurement sessions, for auditing what a single appliance uses. `POST /sessions` with
+//! `{"name": "dishwasher"}` notes the meter totals, `GET /sessions/<id>` reports what was
+//! used since, and `DELETE /sessions/<id>` ends the session with the final figures.
+//! Everything else in the house counts as well, so sessions are best kept short and run
+//! while the rest of the load is steady. Sessions are lost when the daemon restarts.
+
+use std::sync::Arc;
+
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    appdata::AppData,
+    history::{Metric, Sample},
+    lock::RecoverLock,
+    prices,
+};
+
+/// Largest request body accepted.
+const MAX_BODY: usize = 1024;
+/// Upper bound on the number of open sessions.
+const MAX_SESSIONS: usize = 32;
+
+#[derive(Debug)]
+struct Session {
+    id: u64,
+    name: String,
+    /// The sample the session started at.
+    start: Sample,
+}
+
+/// The open sessions.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    next_id: u64,
+    open: Vec<Session>,
+}
+
+#[derive(Deserialize)]
+struct NewSession {
+    name: String,
+}
+
+/// What was used during a session, as served by the API.
+#[derive(Serialize)]
+struct Figures {
+    id: u64,
+    name: String,
+    /// Start and end of the session in milliseconds since the unix epoch. Sessions still
+    /// open end at the latest sample.
+    from: u64,
+    to: u64,
+    /// Whether the session is over.
+    ended: bool,
+    /// Electricity delivered to the client in kWh, per tariff and in total.
+    delivered: [Option<f64>; 2],
+    delivered_total: Option<f64>,
+    /// Electricity fed back by the client in kWh.
+    received: [Option<f64>; 2],
+    /// Gas delivered in m³.
+    gas: Option<f64>,
+    /// Average power delivered to the client in kW.
+    average_power: Option<f64>,
+    cost: Option<f64>,
+}
+
+impl Figures {
+    fn compute(appdata: &AppData, session: &Session, end: &Sample, ended: bool) -> Self {
+        let used = |metric| Some(end.get(metric)? - session.start.get(metric)?);
+        let delivered = [
+            used(Metric::EnergyDeliveredTariff1),
+            used(Metric::EnergyDeliveredTariff2),
+        ];
+        let received = [
+            used(Metric::EnergyReceivedTariff1),
+            used(Metric::EnergyReceivedTariff2),
+        ];
+        let gas = used(Metric::GasDelivered);
+        let delivered_total = delivered.iter().copied().sum::<Option<f64>>();
+        let (from, to) = (session.start.timestamp, end.timestamp);
+        let hours = to.saturating_sub(from) as f64 / 3_600_000.0;
+        let cost = prices::cost(
+            &appdata.history.read_recover(),
+            appdata.config(),
+            Some(&appdata.prices.read_recover()),
+            (from, to),
+            delivered,
+            received,
+            gas,
+        );
+        Self {
+            id: session.id,
+            name: session.name.clone(),
+            from,
+            to,
+            ended,
+            delivered,
+            delivered_total,
+            received,
+            gas,
+            average_power: delivered_total
+                .filter(|_| hours > 0.0)
+                .map(|total| total / hours),
+            cost,
+        }
+    }
+}
+
+fn respond(status: StatusCode, message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+}
+
+fn json(value: &impl Serialize, status: StatusCode) -> Result<Response<Body>, hyper::http::Error> {
+    match serde_json::to_string(value) {
+        Ok(json) => Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, &format!("Error: {}", e)),
+    }
+}
+
+/// Handler for `/sessions` and `/sessions/<id>`.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let Some(latest) = appdata.history.read_recover().latest() else {
+        return respond(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Error: no telegram received yet.",
+        );
+    };
+    let path = req.uri().path().trim_end_matches('/').to_string();
+
+    let Some(id) = path.strip_prefix("/sessions/") else {
+        return match *req.method() {
+            Method::GET => {
+                let sessions = appdata.sessions.read_recover();
+                let figures: Vec<Figures> = sessions
+                    .open
+                    .iter()
+                    .map(|session| Figures::compute(&appdata, session, &latest, false))
+                    .collect();
+                json(&figures, StatusCode::OK)
+            }
+            Method::POST => start(req, &appdata, latest).await,
+            _ => respond(StatusCode::METHOD_NOT_ALLOWED, "Error: method not allowed."),
+        };
+    };
+    let Ok(id) = id.parse::<u64>() else {
+        return respond(StatusCode::NOT_FOUND, "Error: unknown session.");
+    };
+
+    match *req.method() {
+        Method::GET => {
+            let sessions = appdata.sessions.read_recover();
+            match sessions.open.iter().find(|session| session.id == id) {
+                Some(session) => json(
+                    &Figures::compute(&appdata, session, &latest, false),
+                    StatusCode::OK,
+                ),
+                None => respond(StatusCode::NOT_FOUND, "Error: unknown session."),
+            }
+        }
+        Method::DELETE => {
+            let mut sessions = appdata.sessions.write_recover();
+            let Some(index) = sessions.open.iter().position(|session| session.id == id) else {
+                return respond(StatusCode::NOT_FOUND, "Error: unknown session.");
+            };
+            let session = sessions.open.remove(index);
+            drop(sessions);
+            info!("Ended session {} ({})", session.id, session.name);
+            json(
+                &Figures::compute(&appdata, &session, &latest, true),
+                StatusCode::OK,
+            )
+        }
+        _ => respond(StatusCode::METHOD_NOT_ALLOWED, "Error: method not allowed."),
+    }
+}
+
+/// Start a session at `latest`.
+async fn start(
+    req: Request<Body>,
+    appdata: &AppData,
+    latest: Sample,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) if body.len() > MAX_BODY => {
+            return respond(StatusCode::PAYLOAD_TOO_LARGE, "Error: request too large.")
+        }
+        Ok(body) => body,
+        Err(e) => {
+            return respond(
+                StatusCode::BAD_REQUEST,
+                &format!("Error: unable to read request body: {}", e),
+            )
+        }
+    };
+    let new: NewSession = match serde_json::from_slice(&body) {
+        Ok(new) => new,
+        Err(e) => return respond(StatusCode::BAD_REQUEST, &format!("Error: {}", e)),
+    };
+
+    let mut sessions = appdata.sessions.write_recover();
+    if sessions.open.len() >= MAX_SESSIONS {
+        return respond(
+            StatusCode::TOO_MANY_REQUESTS,
+            &format!("Error: at most {} sessions can be open.", MAX_SESSIONS),
+        );
+    }
+    sessions.next_id += 1;
+    let session = Session {
+        id: sessions.next_id,
+        name: new.name,
+        start: latest.clone(),
+    };
+    info!("Started session {} ({})", session.id, session.name);
+    let figures = Figures::compute(appdata, &session, &latest, false);
+    sessions.open.push(session);
+    json(&figures, StatusCode::CREATED)
+}
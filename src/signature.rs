@@ -0,0 +1,98 @@
+//! HMAC-SHA256 signatures for outbound packets, so consumers on an untrusted network can
+//! tell they came from us. A signed packet is wrapped in an envelope holding the original
+//! packet as a string and the signature over its bytes in lowercase hex:
+//!
+//! ```json
+//! {"payload":"{\"electricity\":...}","hmac":"5d2c..."}
+//! ```
+//!
+//! The payload is kept as a string rather than nested, so consumers verify the exact bytes
+//! that were signed instead of a re-serialization of them.
+
+use std::fmt::Write;
+
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+
+/// HMAC-SHA256 (RFC 2104) of `message` with `key`.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    // HMAC takes keys of any length, longer ones are hashed first.
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// `payload` wrapped in an envelope signed with `key`.
+pub fn sign(key: &str, payload: &[u8]) -> Result<Vec<u8>, String> {
+    let payload = std::str::from_utf8(payload).map_err(|e| e.to_string())?;
+    let mut hmac = String::with_capacity(64);
+    for byte in hmac_sha256(key.as_bytes(), payload.as_bytes()) {
+        let _ = write!(hmac, "{:02x}", byte);
+    }
+    serde_json::to_vec(&json!({ "payload": payload, "hmac": hmac })).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn hmac_sha256_rfc_4231() {
+        let cases: [(&[u8], &[u8], &str); 6] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                &[0xaa; 20],
+                &[0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                &[
+                    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+                    0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19,
+                ],
+                &[0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                &[0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than \
+                  block-size data. The key needs to be hashed before being used by the HMAC \
+                  algorithm.",
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, message, mac) in cases {
+            assert_eq!(hex(&hmac_sha256(key, message)), mac);
+        }
+    }
+
+    #[test]
+    fn sign_envelope() {
+        let signed = sign("Jefe", b"what do ya want for nothing?").unwrap();
+        let envelope: serde_json::Value = serde_json::from_slice(&signed).unwrap();
+        assert_eq!(envelope["payload"], "what do ya want for nothing?");
+        assert_eq!(
+            envelope["hmac"],
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
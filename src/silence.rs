@@ -0,0 +1,209 @@
+//! Silences for alerting, so planned work like a meter swap or work on the breakers doesn't
+//! page anyone. While a silence covers an event, it is still recorded in `events` but no
+//! notification or alert webhook goes out for it.
+//!
+//! Maintenance windows are configured up front, silences for work at short notice are
+//! added with `POST /alerts/silence?for=2h&events=power_failure&reason=meter%20swap`.
+//! `GET /alerts/silence` lists them and `DELETE /alerts/silence/<id>` lifts one.
+
+use std::sync::Arc;
+
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use log::info;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    appdata::AppData, config::MaintenanceWindow, events::EventKind, history::now_millis,
+    lock::RecoverLock, query,
+};
+
+/// Upper bound on the number of silences.
+const MAX_SILENCES: usize = 64;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Silence {
+    pub id: u64,
+    /// Start and end in milliseconds since the unix epoch, open when unset.
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// Kinds of events silenced, all of them when empty.
+    pub events: Vec<EventKind>,
+    pub reason: Option<String>,
+    /// Whether the silence is a configured maintenance window.
+    pub configured: bool,
+}
+
+impl Silence {
+    fn covers(&self, kind: EventKind, at: u64) -> bool {
+        // Silencing alerts silences their reminders and recoveries as well.
+        let kind = match kind {
+            EventKind::Reminder | EventKind::Recovery => EventKind::Alert,
+            kind => kind,
+        };
+        self.from.is_none_or(|from| from <= at)
+            && self.to.is_none_or(|to| at < to)
+            && (self.events.is_empty() || self.events.contains(&kind))
+    }
+}
+
+/// The configured maintenance windows and the silences added since.
+#[derive(Debug)]
+pub struct Silences {
+    next_id: u64,
+    silences: Vec<Silence>,
+}
+
+impl Silences {
+    pub fn new(windows: &[MaintenanceWindow]) -> Self {
+        let silences: Vec<Silence> = windows
+            .iter()
+            .zip(1..)
+            .map(|(window, id)| Silence {
+                id,
+                from: window.from,
+                to: window.to,
+                events: window.events.clone(),
+                reason: window.reason.clone(),
+                configured: true,
+            })
+            .collect();
+        Self {
+            next_id: silences.len() as u64,
+            silences,
+        }
+    }
+
+    /// Whether an event of `kind` at `at` is silenced.
+    pub fn is_silenced(&self, kind: EventKind, at: u64) -> bool {
+        self.silences.iter().any(|silence| silence.covers(kind, at))
+    }
+
+    /// Forget the silences that are over.
+    fn prune(&mut self, now: u64) {
+        self.silences
+            .retain(|silence| silence.to.is_none_or(|to| to > now));
+    }
+}
+
+/// Query parameters of `POST /alerts/silence`.
+#[derive(Deserialize)]
+struct SilenceParams {
+    /// Defaults to now.
+    #[serde(default, deserialize_with = "query::time")]
+    from: Option<u64>,
+    /// Either an end or a duration from the start is required.
+    #[serde(default, deserialize_with = "query::time")]
+    until: Option<u64>,
+    #[serde(default, rename = "for", deserialize_with = "query::duration_millis")]
+    duration: Option<u64>,
+    /// Comma separated kinds of events, all of them by default.
+    #[serde(default, deserialize_with = "kinds")]
+    events: Vec<EventKind>,
+    reason: Option<String>,
+}
+
+fn kinds<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<EventKind>, D::Error> {
+    String::deserialize(d)?
+        .split(',')
+        .filter(|kind| !kind.is_empty())
+        .map(|kind| {
+            serde_json::from_value(serde_json::Value::String(kind.trim().to_string()))
+                .map_err(|_| serde::de::Error::custom(format!("unknown event kind {}", kind)))
+        })
+        .collect()
+}
+
+fn respond(status: StatusCode, message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+}
+
+fn json(value: &impl Serialize, status: StatusCode) -> Result<Response<Body>, hyper::http::Error> {
+    match serde_json::to_string(value) {
+        Ok(json) => Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, &format!("Error: {}", e)),
+    }
+}
+
+/// Handler for `/alerts/silence` and `/alerts/silence/<id>`.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let now = now_millis();
+    let path = req.uri().path().trim_end_matches('/');
+    if let Some(id) = path.strip_prefix("/alerts/silence/") {
+        if req.method() != Method::DELETE {
+            return respond(StatusCode::METHOD_NOT_ALLOWED, "Error: method not allowed.");
+        }
+        let mut silences = appdata.silences.write_recover();
+        let count = silences.silences.len();
+        silences
+            .silences
+            .retain(|silence| Some(silence.id) != id.parse().ok());
+        if silences.silences.len() == count {
+            return respond(StatusCode::NOT_FOUND, "Error: unknown silence.");
+        }
+        info!("Lifted silence {}", id);
+        return respond(StatusCode::OK, &format!("Silence {} lifted.", id));
+    }
+    if path != "/alerts/silence" {
+        return respond(StatusCode::NOT_FOUND, "Error: not found.");
+    }
+
+    match *req.method() {
+        Method::GET => {
+            let mut silences = appdata.silences.write_recover();
+            silences.prune(now);
+            json(&silences.silences, StatusCode::OK)
+        }
+        Method::POST => {
+            let params: SilenceParams = match query::parse(&req) {
+                Ok(params) => params,
+                Err(e) => return respond(StatusCode::BAD_REQUEST, &format!("Error: {}", e)),
+            };
+            let from = params.from.unwrap_or(now);
+            let to = match (params.until, params.duration) {
+                (Some(until), None) => until,
+                (None, Some(duration)) => from.saturating_add(duration),
+                _ => return respond(StatusCode::BAD_REQUEST, "Error: give either until or for."),
+            };
+            if to <= from.max(now) {
+                return respond(
+                    StatusCode::BAD_REQUEST,
+                    "Error: the silence is over already.",
+                );
+            }
+
+            let mut silences = appdata.silences.write_recover();
+            silences.prune(now);
+            if silences.silences.len() >= MAX_SILENCES {
+                return respond(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &format!("Error: at most {} silences can be set.", MAX_SILENCES),
+                );
+            }
+            silences.next_id += 1;
+            let silence = Silence {
+                id: silences.next_id,
+                from: Some(from),
+                to: Some(to),
+                events: params.events,
+                reason: params.reason,
+                configured: false,
+            };
+            info!(
+                "Silenced alerts until {}: {}",
+                to,
+                silence.reason.as_deref().unwrap_or("no reason given")
+            );
+            silences.silences.push(silence.clone());
+            json(&silence, StatusCode::CREATED)
+        }
+        _ => respond(StatusCode::METHOD_NOT_ALLOWED, "Error: method not allowed."),
+    }
+}
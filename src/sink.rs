@@ -0,0 +1,563 @@
+//! Sinks push every new sample to an external system, each from its own thread so a slow
+//! or unreachable destination doesn't hold up the others.
+
+mod batch;
+mod breaker;
+mod collector;
+mod knx;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod nats;
+mod pushgateway;
+mod redis;
+#[cfg(feature = "remote-write")]
+mod remote_write;
+mod signalk;
+mod victoria_metrics;
+mod webhook;
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    thread::JoinHandle,
+};
+
+use chrono::Local;
+use event_listener::Listener;
+use log::{debug, error, info, warn};
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use serde_json::Value;
+
+pub use self::breaker::BreakerState;
+use self::breaker::CircuitBreaker;
+use crate::{
+    appdata::AppData,
+    config::{ResolveConfig, SinkConfig, SinkKind, StateFormat},
+    dial::Dialer,
+    history::{now_millis, Sample},
+    lock::RecoverLock,
+    metrics::metric_name,
+    model::MeterState,
+    output,
+    reader::ReaderData,
+    supervisor,
+    traffic::Traffic,
+};
+
+pub trait Sink: Send {
+    /// Handle a new sample. `state` is the meter state as served to clients. Returns the
+    /// number of bytes sent, see `Traffic`.
+    fn send(&mut self, sample: &Sample, state: &Value) -> Result<u64, String>;
+
+    /// Keep a sample the sink can't be given right now, for sinks that can deliver it later.
+    fn hold(&mut self, _sample: &Sample, _state: &Value) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Whether the sink holds a connection to its destination, for sinks that keep one.
+    fn connected(&self) -> Option<bool> {
+        None
+    }
+}
+
+impl SinkKind {
+    /// The name of the kind of sink, as used in the configuration.
+    pub fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "remote-write")]
+            SinkKind::RemoteWrite(_) => "remote_write",
+            SinkKind::Pushgateway(_) => "pushgateway",
+            SinkKind::VictoriaMetrics(_) => "victoria_metrics",
+            SinkKind::Redis(_) => "redis",
+            SinkKind::Nats(_) => "nats",
+            #[cfg(feature = "mqtt")]
+            SinkKind::Mqtt(_) => "mqtt",
+            SinkKind::SignalK(_) => "signalk",
+            SinkKind::Knx(_) => "knx",
+            SinkKind::Webhook(_) => "webhook",
+            SinkKind::Collector(_) => "collector",
+        }
+    }
+
+    fn build(
+        &self,
+        appdata: &Arc<AppData>,
+        resolve: &ResolveConfig,
+    ) -> Result<Box<dyn Sink>, String> {
+        let dialer = Dialer::new(appdata.config().outbound.proxy.as_ref(), resolve);
+        match self {
+            #[cfg(feature = "remote-write")]
+            SinkKind::RemoteWrite(config) => {
+                Ok(Box::new(remote_write::RemoteWrite::new(config, dialer)?))
+            }
+            SinkKind::Pushgateway(config) => {
+                Ok(Box::new(pushgateway::Pushgateway::new(config, dialer)?))
+            }
+            SinkKind::VictoriaMetrics(config) => Ok(Box::new(
+                victoria_metrics::VictoriaMetrics::new(config, dialer)?,
+            )),
+            SinkKind::Redis(config) => Ok(Box::new(redis::Redis::new(config, dialer)?)),
+            SinkKind::Nats(config) => Ok(Box::new(nats::Nats::new(config, dialer)?)),
+            #[cfg(feature = "mqtt")]
+            SinkKind::Mqtt(config) => Ok(Box::new(mqtt::Mqtt::new(config, appdata.clone())?)),
+            SinkKind::SignalK(config) => Ok(Box::new(signalk::SignalK::new(config, dialer)?)),
+            SinkKind::Knx(config) => Ok(Box::new(knx::Knx::new(config, appdata.clone())?)),
+            SinkKind::Webhook(config) => Ok(Box::new(webhook::Webhook::new(
+                config,
+                dialer,
+                &appdata.config().outbound.allow,
+            )?)),
+            SinkKind::Collector(config) => Ok(Box::new(collector::Collector::new(config, dialer)?)),
+        }
+    }
+}
+
+/// What a sink has been up to, as reported by the API.
+#[derive(Clone, Debug, Default, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct SinkStatus {
+    /// `null` for sinks that don't keep a connection.
+    pub connected: Option<bool>,
+    /// Time of the last successful publish in milliseconds since the unix epoch.
+    pub last_publish: Option<u64>,
+    pub published: u64,
+    pub errors: u64,
+    pub last_error: Option<String>,
+    /// Time of the last error in milliseconds since the unix epoch.
+    pub last_error_at: Option<u64>,
+    pub breaker: BreakerState,
+    pub consecutive_errors: u32,
+    /// Samples not handed to the sink because its breaker was open.
+    pub skipped: u64,
+    /// Whether the sink is in a quiet period.
+    pub quiet: bool,
+    #[cfg_attr(feature = "graphql", graphql(skip))]
+    pub traffic: Traffic,
+}
+
+/// A configured sink, shared between the thread running it and the API.
+#[derive(Debug)]
+pub struct SinkHandle {
+    pub id: String,
+    pub kind: &'static str,
+    enabled: AtomicBool,
+    restart: AtomicBool,
+    /// Whether to publish the latest sample on the next wake up, even if it was already.
+    publish: AtomicBool,
+    status: Mutex<SinkStatus>,
+}
+
+impl SinkHandle {
+    fn new(id: String, kind: &'static str, enabled: bool) -> Self {
+        Self {
+            id,
+            kind,
+            enabled: AtomicBool::new(enabled),
+            restart: AtomicBool::new(false),
+            publish: AtomicBool::new(false),
+            status: Mutex::new(SinkStatus::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Have the sink set up again before it handles the next sample.
+    pub fn restart(&self) {
+        self.restart.store(true, Ordering::Relaxed);
+    }
+
+    /// Have the sink publish the latest sample when it's woken up next, new or not.
+    pub fn publish(&self) {
+        self.publish.store(true, Ordering::Relaxed);
+    }
+
+    pub fn status(&self) -> SinkStatus {
+        self.status
+            .lock()
+            .map(|status| status.clone())
+            .unwrap_or_default()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SinkStatus)) {
+        if let Ok(mut status) = self.status.lock() {
+            f(&mut status);
+        }
+    }
+
+    fn record_error(&self, error: String, breaker: &mut CircuitBreaker) {
+        error!("Sink {} failed: {}", self.id, error);
+        if let Some(cooldown) = breaker.failure() {
+            warn!(
+                "Sink {} failed {} times in a row, pausing it for {}s.",
+                self.id,
+                breaker.failures(),
+                cooldown.as_secs()
+            );
+        }
+        self.update(|status| {
+            status.errors += 1;
+            status.last_error = Some(error);
+            status.last_error_at = Some(now_millis());
+            status.breaker = breaker.state();
+            status.consecutive_errors = breaker.failures();
+        });
+    }
+
+    fn record_success(&self, bytes: u64, breaker: &mut CircuitBreaker) {
+        if breaker.success() {
+            info!("Sink {} recovered.", self.id);
+        }
+        self.update(|status| {
+            status.published += 1;
+            status.traffic.add(bytes);
+            status.last_publish = Some(now_millis());
+            status.breaker = breaker.state();
+            status.consecutive_errors = 0;
+        });
+    }
+}
+
+/// Maximum number of samples handed to a sink at once when it has fallen behind.
+const MAX_CATCH_UP: usize = 100;
+
+/// Convert configured headers to the form taken by the HTTP client.
+pub fn header_refs(headers: &BTreeMap<String, String>) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect()
+}
+
+/// Spawn a thread for every configured sink, and register the sinks in `appdata` so they
+/// can be managed through the API.
+pub fn spawn_sinks(
+    appdata: Arc<AppData>,
+    reader_data: Arc<RwLock<ReaderData>>,
+) -> Result<Vec<JoinHandle<()>>, std::io::Error> {
+    let mut threads = Vec::new();
+    for (index, config) in appdata.config().sinks.iter().enumerate() {
+        let kind = config.kind.name();
+        let id = config
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", kind, index + 1));
+        let handle = Arc::new(SinkHandle::new(id, kind, config.enabled));
+        appdata.sinks.write_recover().push(handle.clone());
+
+        let reader_data = reader_data.clone();
+        let name = format!("sink-{}", handle.id);
+        let thread = supervisor::spawn(&name, appdata.clone(), move |appdata| {
+            run(index, handle.clone(), appdata.clone(), reader_data.clone())
+        })?;
+        threads.push(thread);
+    }
+    Ok(threads)
+}
+
+/// Wait for new data and hand it to the sink. The sink is set up on the first sample it
+/// handles, and dropped when it is disabled or restarted.
+fn run(
+    index: usize,
+    handle: Arc<SinkHandle>,
+    appdata: Arc<AppData>,
+    reader_data: Arc<RwLock<ReaderData>>,
+) {
+    let config = &appdata.config().sinks[index];
+    info!("Sink {} started.", handle.id);
+    let mut sink: Option<Box<dyn Sink>> = None;
+    let mut breaker = CircuitBreaker::new(&config.breaker);
+    let mut last_id = 0;
+    // The id of the last sample handled before the current quiet period started.
+    let mut quiet_since: Option<u64> = None;
+    loop {
+        let listener = appdata.event_listener();
+        listener.wait();
+
+        if handle.restart.swap(false, Ordering::Relaxed) {
+            info!("Restarting sink {}.", handle.id);
+            sink = None;
+            breaker.reset();
+            handle.update(|status| status.breaker = breaker.state());
+        }
+        if !handle.is_enabled() {
+            sink = None;
+            handle.update(|status| status.connected = None);
+            continue;
+        }
+        // The active daemon of a pair publishes, a standby one starts from the latest
+        // sample when it takes over.
+        if !appdata.ha.read_recover().active {
+            last_id = 0;
+            continue;
+        }
+
+        // Samples are held back while quiet, and summarized in one once it's over.
+        let now = Local::now();
+        let quiet = config.quiet.iter().any(|schedule| schedule.matches(&now));
+        if quiet != quiet_since.is_some() {
+            handle.update(|status| status.quiet = quiet);
+        }
+        if quiet {
+            if quiet_since.is_none() {
+                info!("Sink {} is quiet.", handle.id);
+                quiet_since = Some(last_id);
+            }
+            continue;
+        }
+        if let Some(since) = quiet_since.take() {
+            info!("Sink {} is no longer quiet.", handle.id);
+            let Some(summary) = appdata.history.read_recover().summary(since) else {
+                continue;
+            };
+            last_id = summary.id;
+            if let Some(state) = render(&handle, &appdata, config, &reader_data) {
+                deliver(
+                    &handle,
+                    &appdata,
+                    config,
+                    &mut sink,
+                    &mut breaker,
+                    &summary,
+                    &state,
+                );
+            }
+            continue;
+        }
+
+        // Usually there is a single new sample, but with clock-aligned sampling a telegram
+        // may complete several or none.
+        let publish = handle.publish.swap(false, Ordering::Relaxed);
+        let samples: Vec<Sample> = {
+            let history = appdata.history.read_recover();
+            if last_id == 0 || publish {
+                history.latest().into_iter().collect()
+            } else {
+                history.page(0, u64::MAX, last_id, MAX_CATCH_UP).0
+            }
+        };
+        let Some(last) = samples.last() else {
+            continue;
+        };
+        last_id = last.id;
+
+        let Some(state) = render(&handle, &appdata, config, &reader_data) else {
+            continue;
+        };
+
+        for sample in &samples {
+            deliver(
+                &handle,
+                &appdata,
+                config,
+                &mut sink,
+                &mut breaker,
+                sample,
+                &state,
+            );
+        }
+    }
+}
+
+/// The meter state as served to clients, with identifiers redacted as configured.
+fn render(
+    handle: &SinkHandle,
+    appdata: &AppData,
+    config: &SinkConfig,
+    reader_data: &RwLock<ReaderData>,
+) -> Option<Value> {
+    let missing_values = appdata.config().output.missing_values;
+    let state = output::render_state(
+        &reader_data.read_recover(),
+        missing_values,
+        StateFormat::Native,
+    );
+    let mut state = state
+        .map_err(|e| error!("Unable to serialize state for sink {}: {}", handle.id, e))
+        .ok()?;
+    output::redact(&mut state, config.redact);
+    Some(state)
+}
+
+/// Hand a sample of the current state to a fresh instance of the sink `id`, whether it is
+/// enabled, quiet or paused by its breaker or not. Returns the number of bytes sent.
+pub fn test(
+    appdata: &Arc<AppData>,
+    reader_data: &RwLock<ReaderData>,
+    id: &str,
+) -> Result<u64, String> {
+    // The sinks are registered in the order they are configured in.
+    let (index, handle) = appdata
+        .sinks
+        .read_recover()
+        .iter()
+        .enumerate()
+        .find(|(_, sink)| sink.id == id)
+        .map(|(index, sink)| (index, sink.clone()))
+        .ok_or("unknown sink")?;
+    let config = &appdata.config().sinks[index];
+    let state = render(&handle, appdata, config, reader_data).ok_or("no state to send")?;
+    let sample = Sample::from_state(now_millis(), &reader_data.read_recover().dsmr_state);
+    let mut sink = config.kind.build(appdata, &config.resolve)?;
+    let bytes = sink.send(&sample, &state)?;
+    info!("Sink {} delivered a test sample.", handle.id);
+    Ok(bytes)
+}
+
+/// Hand a single sample to the sink, setting it up first if needed.
+fn deliver(
+    handle: &SinkHandle,
+    appdata: &Arc<AppData>,
+    config: &SinkConfig,
+    sink: &mut Option<Box<dyn Sink>>,
+    breaker: &mut CircuitBreaker,
+    sample: &Sample,
+    state: &Value,
+) {
+    if !breaker.allow() {
+        handle.update(|status| status.skipped += 1);
+        if let Some(Err(e)) = sink.as_mut().map(|sink| sink.hold(sample, state)) {
+            error!("Sink {} could not keep sample: {}", handle.id, e);
+        }
+        return;
+    }
+
+    let active = match sink.as_mut() {
+        Some(active) => active,
+        None => match config.kind.build(appdata, &config.resolve) {
+            Ok(built) => sink.insert(built),
+            Err(e) => {
+                handle.record_error(format!("unable to set up sink: {}", e), breaker);
+                return;
+            }
+        },
+    };
+    let result = active.send(sample, state);
+    let connected = active.connected();
+    handle.update(|status| status.connected = connected);
+    match result {
+        Ok(bytes) => {
+            debug!("Sink {} handled sample {}", handle.id, sample.id);
+            handle.record_success(bytes, breaker);
+        }
+        Err(e) => handle.record_error(e, breaker),
+    }
+}
+
+/// Number of pushed states that may wait for the sinks of a meter of an aggregator.
+const PIPELINE_QUEUE: usize = 16;
+
+/// The sinks of a meter of an aggregator, fed the states pushed for it from a thread of
+/// their own. Serializes to the status of every sink by id.
+#[derive(Clone, Debug)]
+pub struct Pipeline {
+    sender: SyncSender<Value>,
+    sinks: Vec<Arc<SinkHandle>>,
+}
+
+impl Pipeline {
+    /// Hand a pushed state to the sinks. It is dropped when they are too far behind.
+    pub fn feed(&self, state: Value) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(state) {
+            warn!(
+                "Dropped a state, sinks {} are behind.",
+                self.sinks
+                    .iter()
+                    .map(|sink| sink.id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+}
+
+impl Serialize for Pipeline {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut map = s.serialize_map(Some(self.sinks.len()))?;
+        for sink in &self.sinks {
+            map.serialize_entry(&sink.id, &sink.status())?;
+        }
+        map.end()
+    }
+}
+
+/// Spawn a thread feeding the sinks of the aggregator to the states pushed for `meter`.
+/// The sinks are named after the meter, e.g. `<meter>/webhook-1`.
+pub fn spawn_pipeline(appdata: &Arc<AppData>, meter: &str) -> Result<Pipeline, String> {
+    let templates = appdata
+        .config()
+        .aggregator
+        .as_ref()
+        .map(|config| config.sinks.as_slice())
+        .unwrap_or_default();
+    let mut configs = Vec::new();
+    let mut sinks = Vec::new();
+    for (index, template) in templates.iter().enumerate() {
+        let config = template.for_meter(meter)?;
+        let kind = config.kind.name();
+        let name = config
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", kind, index + 1));
+        let id = format!("{}/{}", meter, name);
+        sinks.push(Arc::new(SinkHandle::new(id, kind, config.enabled)));
+        configs.push(config);
+    }
+
+    let (sender, receiver) = mpsc::sync_channel(PIPELINE_QUEUE);
+    let receiver = Mutex::new(receiver);
+    let handles = sinks.clone();
+    let name = format!("pipeline-{}", meter);
+    supervisor::spawn(&name, appdata.clone(), move |appdata| {
+        // A panic in the pipeline leaves the receiver poisoned, but it is still usable.
+        let receiver = receiver.lock().unwrap_or_else(PoisonError::into_inner);
+        run_pipeline(appdata, &configs, &handles, &receiver);
+    })
+    .map_err(|e| e.to_string())?;
+    info!("Pipeline of meter {} started.", meter);
+    Ok(Pipeline { sender, sinks })
+}
+
+/// Hand every state pushed for a meter to its sinks. States pushed while a sink is quiet
+/// are dropped rather than summarized, the aggregator keeps no history of its meters.
+fn run_pipeline(
+    appdata: &Arc<AppData>,
+    configs: &[SinkConfig],
+    handles: &[Arc<SinkHandle>],
+    receiver: &Receiver<Value>,
+) {
+    let mut sinks: Vec<Option<Box<dyn Sink>>> = configs.iter().map(|_| None).collect();
+    let mut breakers: Vec<CircuitBreaker> = configs
+        .iter()
+        .map(|config| CircuitBreaker::new(&config.breaker))
+        .collect();
+    for (id, state) in (1..).zip(receiver.iter()) {
+        if !appdata.ha.read_recover().active {
+            continue;
+        }
+        let meter_state: MeterState = serde_json::from_value(state.clone())
+            .map_err(|e| debug!("Pushed state has no values to sample: {}", e))
+            .unwrap_or_default();
+        let mut sample = Sample::from_state(now_millis(), &meter_state);
+        sample.id = id;
+        let now = Local::now();
+        let sinks = sinks.iter_mut().zip(breakers.iter_mut());
+        for ((config, handle), (sink, breaker)) in configs.iter().zip(handles).zip(sinks) {
+            if !handle.is_enabled() || config.quiet.iter().any(|quiet| quiet.matches(&now)) {
+                continue;
+            }
+            let mut state = state.clone();
+            output::redact(&mut state, config.redact);
+            deliver(handle, appdata, config, sink, breaker, &sample, &state);
+        }
+    }
+}
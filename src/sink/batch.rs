@@ -0,0 +1,407 @@
+//! Batching of samples for the time series sinks, with an optional on-disk spool that keeps
+//! samples which couldn't be delivered until the destination is reachable again.
+//!
+//! The spool starts with a header holding the number of metrics of its records, as that
+//! grows when metrics are added. A spool with records of another size, or one written
+//! before there was a header, is converted when it is opened.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    mem,
+    path::PathBuf,
+};
+
+use log::{info, warn};
+
+use crate::{
+    config::SpoolConfig,
+    history::{now_millis, Metric, Sample, METRIC_COUNT},
+};
+
+/// Size of a spooled sample: the timestamp followed by every metric, NaN when missing.
+const RECORD_SIZE: usize = 8 + 8 * METRIC_COUNT;
+/// Start of the header, followed by the number of metrics as a little endian u32.
+const MAGIC: &[u8; 8] = b"DSMRDSPL";
+const HEADER_SIZE: usize = MAGIC.len() + 4;
+/// Numbers of metrics of the spools written before the header, oldest first: without the
+/// per-phase energy, with it, and with the reactive metrics.
+const LEGACY_METRICS: [usize; 3] = [19, 25, 29];
+/// 2000-01-01, no spooled sample is older.
+const EPOCH_2000: u64 = 946_684_800_000;
+
+pub struct Batch {
+    size: usize,
+    pending: Vec<Sample>,
+    spool: Option<Spool>,
+}
+
+impl Batch {
+    pub fn new(size: usize, spool: Option<&SpoolConfig>) -> Result<Self, String> {
+        Ok(Self {
+            size: size.max(1),
+            pending: Vec::new(),
+            spool: spool.map(Spool::open).transpose()?,
+        })
+    }
+
+    /// Add a sample, delivering the batch with `send` once it is full. Spooled samples are
+    /// delivered first, so the destination receives everything in order. Returns the
+    /// number of bytes `send` sent.
+    pub fn push(
+        &mut self,
+        sample: &Sample,
+        mut send: impl FnMut(&[Sample]) -> Result<u64, String>,
+    ) -> Result<u64, String> {
+        self.pending.push(sample.clone());
+        if self.pending.len() < self.size {
+            return Ok(0);
+        }
+        let batch = mem::take(&mut self.pending);
+
+        let Some(spool) = self.spool.as_mut() else {
+            // Without a spool a failed batch is lost.
+            return send(&batch);
+        };
+        let result = spool
+            .replay(self.size, &mut send)
+            .and_then(|replayed| Ok(replayed + send(&batch)?));
+        if result.is_err() {
+            spool.append(&batch)?;
+        }
+        result
+    }
+
+    /// Keep a sample that can't be delivered right now. It is spooled along with the
+    /// pending samples if there is a spool, and dropped otherwise.
+    pub fn hold(&mut self, sample: &Sample) -> Result<(), String> {
+        let Some(spool) = self.spool.as_mut() else {
+            return Ok(());
+        };
+        self.pending.push(sample.clone());
+        spool.append(&mem::take(&mut self.pending))
+    }
+}
+
+/// A bounded queue of samples in a file. When full, the oldest samples are dropped.
+struct Spool {
+    path: PathBuf,
+    max_samples: usize,
+    len: usize,
+}
+
+impl Spool {
+    fn open(config: &SpoolConfig) -> Result<Self, String> {
+        let path = PathBuf::from(&config.path);
+        let mut data = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => file
+                .read_to_end(&mut data)
+                .map(|_| ())
+                .map_err(|e| format!("Unable to read spool {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Unable to open spool {}: {}", path.display(), e)),
+        }
+        let mut spool = Self {
+            path,
+            max_samples: config.max_samples.max(1),
+            len: 0,
+        };
+
+        let (metrics, records, current) = match data.strip_prefix(MAGIC) {
+            Some(rest) if rest.len() >= 4 => {
+                let metrics = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+                (metrics, &rest[4..], metrics == METRIC_COUNT)
+            }
+            // A torn header, nothing was spooled yet. It is written again in full.
+            Some(_) => (METRIC_COUNT, &[][..], false),
+            None => (legacy_metrics(&data), &data[..], false),
+        };
+        // A partial record at the end is left over from a crash, and dropped.
+        if current && records.len().is_multiple_of(RECORD_SIZE) {
+            spool.len = records.len() / RECORD_SIZE;
+        } else {
+            let samples = decode(records, metrics);
+            if !records.is_empty() && !current {
+                info!(
+                    "Converting spool {} from {} to {} metrics.",
+                    spool.path.display(),
+                    metrics,
+                    METRIC_COUNT
+                );
+            }
+            spool.rewrite(&samples)?;
+        }
+        if spool.len > 0 {
+            info!(
+                "Spool {} holds {} samples.",
+                spool.path.display(),
+                spool.len
+            );
+        }
+        Ok(spool)
+    }
+
+    fn append(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let mut data = Vec::with_capacity(samples.len() * RECORD_SIZE);
+        for sample in samples {
+            data.extend_from_slice(&sample.timestamp.to_le_bytes());
+            for metric in Metric::ALL {
+                let value = sample.get(metric).unwrap_or(f64::NAN);
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&data))
+            .map_err(|e| format!("Unable to write spool {}: {}", self.path.display(), e))?;
+        self.len += samples.len();
+
+        if self.len > self.max_samples {
+            // Drop a bit more than needed, so we don't rewrite the file on every append.
+            let keep = self.max_samples - self.max_samples / 10;
+            warn!(
+                "Spool {} is full, dropping the {} oldest samples.",
+                self.path.display(),
+                self.len - keep
+            );
+            let samples = self.read()?;
+            self.rewrite(&samples[samples.len().saturating_sub(keep)..])?;
+        }
+        Ok(())
+    }
+
+    /// Deliver the spooled samples in batches. Whatever isn't delivered stays spooled.
+    fn replay(
+        &mut self,
+        batch_size: usize,
+        send: &mut impl FnMut(&[Sample]) -> Result<u64, String>,
+    ) -> Result<u64, String> {
+        if self.len == 0 {
+            return Ok(0);
+        }
+        let samples = self.read()?;
+        info!(
+            "Replaying {} samples from spool {}.",
+            samples.len(),
+            self.path.display()
+        );
+        let mut bytes = 0;
+        for (index, batch) in samples.chunks(batch_size).enumerate() {
+            match send(batch) {
+                Ok(sent) => bytes += sent,
+                Err(e) => {
+                    self.rewrite(&samples[index * batch_size..])?;
+                    return Err(e);
+                }
+            }
+        }
+        self.rewrite(&[]).map(|_| bytes)
+    }
+
+    fn read(&self) -> Result<Vec<Sample>, String> {
+        let mut data = Vec::new();
+        File::open(&self.path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| format!("Unable to read spool {}: {}", self.path.display(), e))?;
+        Ok(decode(
+            data.get(HEADER_SIZE..).unwrap_or_default(),
+            METRIC_COUNT,
+        ))
+    }
+
+    /// Replace the contents of the spool by `samples`.
+    fn rewrite(&mut self, samples: &[Sample]) -> Result<(), String> {
+        // Write to a new file first, so a crash doesn't lose the spool.
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&(METRIC_COUNT as u32).to_le_bytes());
+        fs::write(&temporary, header)
+            .map_err(|e| format!("Unable to write spool {}: {}", self.path.display(), e))?;
+        let mut spool = Spool {
+            path: PathBuf::from(&temporary),
+            max_samples: self.max_samples,
+            len: 0,
+        };
+        if !samples.is_empty() {
+            spool.append(samples)?;
+        }
+        fs::rename(&temporary, &self.path)
+            .map_err(|e| format!("Unable to write spool {}: {}", self.path.display(), e))?;
+        self.len = samples.len();
+        Ok(())
+    }
+}
+
+/// Decode records of `metrics` metrics each. Metrics added since are missing, metrics the
+/// record has beyond the known ones are dropped.
+fn decode(records: &[u8], metrics: usize) -> Vec<Sample> {
+    records
+        .chunks_exact(8 + 8 * metrics)
+        .map(|record| {
+            let (timestamp, values) = record.split_at(8);
+            let mut decoded = [None; METRIC_COUNT];
+            for (value, bytes) in decoded.iter_mut().zip(values.chunks_exact(8)) {
+                let number = f64::from_le_bytes(bytes.try_into().unwrap_or_default());
+                *value = (!number.is_nan()).then_some(number);
+            }
+            let timestamp = u64::from_le_bytes(timestamp.try_into().unwrap_or_default());
+            Sample::from_values(timestamp, decoded)
+        })
+        .collect()
+}
+
+/// The number of metrics of a spool written before the header. Read with the wrong record
+/// size, the timestamps are made of the bytes of values and aren't plausible times.
+fn legacy_metrics(data: &[u8]) -> usize {
+    let latest = now_millis().saturating_add(86_400_000);
+    LEGACY_METRICS
+        .into_iter()
+        .filter(|metrics| {
+            data.chunks_exact(8 + 8 * metrics).all(|record| {
+                let timestamp = u64::from_le_bytes(record[..8].try_into().unwrap_or_default());
+                (EPOCH_2000..=latest).contains(&timestamp)
+            })
+        })
+        // Without a crash, the records fill the file exactly.
+        .min_by_key(|metrics| data.len() % (8 + 8 * metrics))
+        .unwrap_or(LEGACY_METRICS[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2023-11-14, a plausible time for a spooled sample.
+    const TIME: u64 = 1_700_000_000_000;
+
+    /// A spool in the temporary directory, removed when dropped.
+    struct TestSpool(PathBuf);
+
+    impl TestSpool {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("dsmrd-spool-{}-{}", name, std::process::id()));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+
+        fn open(&self) -> Spool {
+            Spool::open(&SpoolConfig {
+                path: self.0.to_string_lossy().into_owned(),
+                max_samples: 100,
+            })
+            .unwrap()
+        }
+    }
+
+    impl Drop for TestSpool {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// A sample with its first `metrics` metrics set, and the one in between missing.
+    fn sample(index: u64, metrics: usize) -> Sample {
+        let mut values = [None; METRIC_COUNT];
+        for (i, value) in values.iter_mut().enumerate().take(metrics) {
+            if i != 1 {
+                *value = Some(index as f64 + i as f64 / 8.0);
+            }
+        }
+        Sample::from_values(TIME + index * 1000, values)
+    }
+
+    fn assert_samples(read: &[Sample], expected: &[Sample]) {
+        assert_eq!(read.len(), expected.len());
+        for (read, expected) in read.iter().zip(expected) {
+            assert_eq!(read.timestamp, expected.timestamp);
+            for metric in Metric::ALL {
+                assert_eq!(read.get(metric), expected.get(metric), "{:?}", metric);
+            }
+        }
+    }
+
+    fn header() -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&(METRIC_COUNT as u32).to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn round_trip() {
+        let test = TestSpool::new("round-trip");
+        let samples: Vec<_> = (0..3).map(|i| sample(i, METRIC_COUNT)).collect();
+
+        let mut spool = test.open();
+        assert_eq!(spool.len, 0);
+        spool.append(&samples).unwrap();
+
+        let data = fs::read(&test.0).unwrap();
+        assert!(data.starts_with(&header()));
+        assert_eq!(data.len(), HEADER_SIZE + 3 * RECORD_SIZE);
+
+        let spool = test.open();
+        assert_eq!(spool.len, 3);
+        assert_samples(&spool.read().unwrap(), &samples);
+    }
+
+    #[test]
+    fn partial_record() {
+        let test = TestSpool::new("partial-record");
+        let samples: Vec<_> = (0..2).map(|i| sample(i, METRIC_COUNT)).collect();
+        test.open().append(&samples).unwrap();
+
+        let mut data = fs::read(&test.0).unwrap();
+        data.truncate(data.len() - RECORD_SIZE / 2);
+        fs::write(&test.0, data).unwrap();
+
+        let spool = test.open();
+        assert_eq!(spool.len, 1);
+        assert_samples(&spool.read().unwrap(), &samples[..1]);
+        assert_eq!(
+            fs::metadata(&test.0).unwrap().len() as usize,
+            HEADER_SIZE + RECORD_SIZE
+        );
+    }
+
+    #[test]
+    fn legacy_spools() {
+        for metrics in LEGACY_METRICS {
+            let test = TestSpool::new(&format!("legacy-{}", metrics));
+            let samples: Vec<_> = (0..3).map(|i| sample(i, metrics)).collect();
+            let mut data = Vec::new();
+            for sample in &samples {
+                data.extend_from_slice(&sample.timestamp.to_le_bytes());
+                for metric in &Metric::ALL[..metrics] {
+                    let value = sample.get(*metric).unwrap_or(f64::NAN);
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            fs::write(&test.0, data).unwrap();
+
+            let spool = test.open();
+            assert_eq!(spool.len, 3, "{} metrics", metrics);
+            assert_samples(&spool.read().unwrap(), &samples);
+            let data = fs::read(&test.0).unwrap();
+            assert!(data.starts_with(&header()));
+            assert_eq!(data.len(), HEADER_SIZE + 3 * RECORD_SIZE);
+        }
+    }
+
+    #[test]
+    fn torn_header() {
+        let test = TestSpool::new("torn-header");
+        fs::write(&test.0, &MAGIC[..]).unwrap();
+
+        let mut spool = test.open();
+        assert_eq!(spool.len, 0);
+        assert_eq!(fs::read(&test.0).unwrap(), header());
+
+        let samples = [sample(0, METRIC_COUNT)];
+        spool.append(&samples).unwrap();
+        assert_samples(&test.open().read().unwrap(), &samples);
+    }
+}
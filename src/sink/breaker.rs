@@ -0,0 +1,190 @@
+//! Circuit breaker keeping a failing sink from being hammered with requests.
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::BreakerConfig;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Everything is passed on to the sink.
+    #[default]
+    Closed,
+    /// The sink failed too often and is left alone for a while.
+    Open,
+    /// The cooldown has passed and the next sample is used to probe the sink.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    max_cooldown: Duration,
+    state: BreakerState,
+    failures: u32,
+    /// Number of times in a row the breaker opened, used to back off further.
+    trips: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &BreakerConfig) -> Self {
+        Self {
+            threshold: config.failures.max(1),
+            cooldown: Duration::from_secs(config.cooldown),
+            max_cooldown: Duration::from_secs(config.max_cooldown.max(config.cooldown)),
+            state: BreakerState::Closed,
+            failures: 0,
+            trips: 0,
+            open_until: None,
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    /// Number of failures since the last success.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Whether the sink should be tried. Moves an open breaker whose cooldown has passed
+    /// to half-open.
+    pub fn allow(&mut self) -> bool {
+        match self.state {
+            BreakerState::Closed | BreakerState::HalfOpen => true,
+            BreakerState::Open => {
+                if self.open_until.is_some_and(|until| Instant::now() < until) {
+                    return false;
+                }
+                self.state = BreakerState::HalfOpen;
+                true
+            }
+        }
+    }
+
+    /// Record a success, returning whether the breaker closed because of it.
+    pub fn success(&mut self) -> bool {
+        let recovered = self.state != BreakerState::Closed;
+        self.reset();
+        recovered
+    }
+
+    /// Record a failure. Returns the time the breaker opens for if it opened because of it.
+    pub fn failure(&mut self) -> Option<Duration> {
+        self.failures += 1;
+        let trip = match self.state {
+            BreakerState::Closed => self.failures >= self.threshold,
+            // A failed probe opens the breaker again right away.
+            BreakerState::HalfOpen => true,
+            BreakerState::Open => false,
+        };
+        if !trip {
+            return None;
+        }
+        // Double the cooldown for every trip in a row.
+        let cooldown = self
+            .cooldown
+            .saturating_mul(1 << self.trips.min(16))
+            .min(self.max_cooldown);
+        self.trips += 1;
+        self.state = BreakerState::Open;
+        self.open_until = Some(Instant::now() + cooldown);
+        Some(cooldown)
+    }
+
+    pub fn reset(&mut self) {
+        self.state = BreakerState::Closed;
+        self.failures = 0;
+        self.trips = 0;
+        self.open_until = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(cooldown: u64) -> CircuitBreaker {
+        CircuitBreaker::new(&BreakerConfig {
+            failures: 3,
+            cooldown,
+            max_cooldown: 4 * cooldown,
+        })
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let mut breaker = breaker(30);
+        assert_eq!(breaker.failure(), None);
+        assert_eq!(breaker.failure(), None);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+
+        assert_eq!(breaker.failure(), Some(Duration::from_secs(30)));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.failures(), 3);
+        assert!(!breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let mut breaker = breaker(30);
+        breaker.failure();
+        breaker.failure();
+        assert!(!breaker.success());
+        assert_eq!(breaker.failures(), 0);
+        assert_eq!(breaker.failure(), None);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn half_open_after_cooldown() {
+        let mut breaker = breaker(0);
+        for _ in 0..3 {
+            breaker.failure();
+        }
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        // A successful probe closes the breaker.
+        assert!(breaker.success());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.failures(), 0);
+    }
+
+    #[test]
+    fn failed_probe_backs_off() {
+        let mut breaker = breaker(30);
+        for _ in 0..3 {
+            breaker.failure();
+        }
+        // Pretend the cooldown passed for every probe.
+        let probe = |breaker: &mut CircuitBreaker| {
+            breaker.open_until = Some(Instant::now());
+            assert!(breaker.allow());
+            assert_eq!(breaker.state(), BreakerState::HalfOpen);
+            breaker.failure()
+        };
+        assert_eq!(probe(&mut breaker), Some(Duration::from_secs(60)));
+        assert_eq!(probe(&mut breaker), Some(Duration::from_secs(120)));
+        assert_eq!(probe(&mut breaker), Some(Duration::from_secs(120)));
+
+        // Closing starts over at the first cooldown.
+        probe(&mut breaker);
+        breaker.open_until = Some(Instant::now());
+        assert!(breaker.allow());
+        assert!(breaker.success());
+        for _ in 0..2 {
+            breaker.failure();
+        }
+        assert_eq!(breaker.failure(), Some(Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,85 @@
+//! Collector sink, pushing every state to a dsmrd aggregator so a satellite needs no
+//! storage or sinks of its own. States that can't be pushed are kept in memory and pushed
+//! in order once the aggregator is reachable again.
+
+use std::collections::VecDeque;
+
+use log::warn;
+use serde_json::Value;
+
+use super::Sink;
+use crate::{config::CollectorConfig, dial::Dialer, history::Sample, http_client::HttpClient};
+
+pub struct Collector {
+    client: HttpClient,
+    url: String,
+    authorization: String,
+    /// States waiting to be pushed, oldest first.
+    buffer: VecDeque<Vec<u8>>,
+    capacity: usize,
+    /// Whether states are being dropped, to warn about it once.
+    dropping: bool,
+}
+
+impl Collector {
+    pub fn new(config: &CollectorConfig, dialer: Dialer) -> Result<Self, String> {
+        Ok(Self {
+            client: HttpClient::new(dialer)?,
+            url: format!(
+                "{}/push/{}",
+                config.url.trim_end_matches('/'),
+                percent_encoding::utf8_percent_encode(
+                    &config.meter,
+                    percent_encoding::NON_ALPHANUMERIC
+                )
+            ),
+            authorization: format!("Bearer {}", config.token),
+            buffer: VecDeque::new(),
+            // The state being pushed takes a place as well.
+            capacity: config.buffer.max(1),
+            dropping: false,
+        })
+    }
+
+    /// Queue a state, dropping the oldest when the buffer is full.
+    fn keep(&mut self, state: &Value) {
+        if self.buffer.len() >= self.capacity {
+            if !self.dropping && self.capacity > 1 {
+                warn!(
+                    "Buffer for {} is full, dropping the oldest states.",
+                    self.url
+                );
+            }
+            self.dropping = true;
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(state.to_string().into_bytes());
+    }
+
+    /// Push the buffered states in order, stopping at the first that fails.
+    fn flush(&mut self) -> Result<u64, String> {
+        let headers = [("Authorization", self.authorization.as_str())];
+        let mut sent = 0;
+        while let Some(body) = self.buffer.front() {
+            let length = body.len() as u64;
+            self.client
+                .post(&self.url, "application/json", &headers, body.clone())?;
+            self.buffer.pop_front();
+            sent += length;
+        }
+        self.dropping = false;
+        Ok(sent)
+    }
+}
+
+impl Sink for Collector {
+    fn send(&mut self, _sample: &Sample, state: &Value) -> Result<u64, String> {
+        self.keep(state);
+        self.flush()
+    }
+
+    fn hold(&mut self, _sample: &Sample, state: &Value) -> Result<(), String> {
+        self.keep(state);
+        Ok(())
+    }
+}
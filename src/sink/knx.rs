@@ -0,0 +1,334 @@
+//! KNX sink, writing values to group addresses through a KNXnet/IP tunnelling connection.
+//! Only the parts of the protocol needed to send group value writes are implemented.
+
+use std::{
+    net::UdpSocket,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Local;
+use log::{debug, info};
+use serde_json::Value;
+
+use super::Sink;
+use crate::{
+    appdata::AppData,
+    config::{KnxConfig, KnxValueConfig},
+    history::{day_range, Metric, Sample},
+    lock::RecoverLock,
+};
+
+const CONNECT_REQUEST: u16 = 0x0205;
+const CONNECT_RESPONSE: u16 = 0x0206;
+const CONNECTIONSTATE_REQUEST: u16 = 0x0207;
+const CONNECTIONSTATE_RESPONSE: u16 = 0x0208;
+const DISCONNECT_REQUEST: u16 = 0x0209;
+const TUNNELLING_REQUEST: u16 = 0x0420;
+const TUNNELLING_ACK: u16 = 0x0421;
+
+/// cEMI message code of a data request.
+const L_DATA_REQ: u8 = 0x11;
+/// APCI of a group value write.
+const GROUP_VALUE_WRITE: u8 = 0x80;
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Gateways drop tunnels that haven't been checked for two minutes.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where a written value comes from.
+#[derive(Clone, Copy, Debug)]
+enum Source {
+    Current(Metric),
+    /// Increase since midnight.
+    Today(Metric),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Dpt {
+    /// DPT 9, 2 byte float.
+    Float16,
+    /// DPT 14, 4 byte float.
+    Float32,
+}
+
+#[derive(Debug)]
+struct Target {
+    address: u16,
+    source: Source,
+    dpt: Dpt,
+    scale: f64,
+}
+
+impl Target {
+    fn parse(config: &KnxValueConfig) -> Result<Self, String> {
+        let source = match config.source.strip_suffix("_today") {
+            Some(name) => Metric::from_name(name).map(Source::Today),
+            None => Metric::from_name(&config.source).map(Source::Current),
+        }
+        .ok_or_else(|| format!("Unknown KNX source {}", config.source))?;
+        let dpt = match config.dpt.split('.').next() {
+            Some("9") => Dpt::Float16,
+            Some("14") => Dpt::Float32,
+            _ => return Err(format!("Unsupported KNX datapoint type {}", config.dpt)),
+        };
+        Ok(Self {
+            address: parse_group_address(&config.address)?,
+            source,
+            dpt,
+            scale: config.scale,
+        })
+    }
+}
+
+pub struct Knx {
+    gateway: String,
+    interval: Duration,
+    targets: Vec<Target>,
+    appdata: Arc<AppData>,
+    tunnel: Option<Tunnel>,
+    last_write: Option<Instant>,
+}
+
+impl Knx {
+    pub fn new(config: &KnxConfig, appdata: Arc<AppData>) -> Result<Self, String> {
+        let targets = config
+            .values
+            .iter()
+            .map(Target::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            gateway: config.gateway.clone(),
+            interval: Duration::from_secs(config.interval),
+            targets,
+            appdata,
+            tunnel: None,
+            last_write: None,
+        })
+    }
+
+    fn value(&self, source: Source, sample: &Sample) -> Option<f64> {
+        match source {
+            Source::Current(metric) => sample.get(metric),
+            Source::Today(metric) => {
+                let (from, to) = day_range(Local::now().date_naive());
+                self.appdata.history.read_recover().usage(metric, from, to)
+            }
+        }
+    }
+
+    fn write(&mut self, sample: &Sample) -> Result<u64, String> {
+        let writes: Vec<(u16, Vec<u8>)> = self
+            .targets
+            .iter()
+            .filter_map(|target| {
+                let value = self.value(target.source, sample)? * target.scale;
+                Some((target.address, encode(value, target.dpt)))
+            })
+            .collect();
+
+        let tunnel = match self.tunnel.as_mut() {
+            Some(tunnel) => tunnel,
+            None => self.tunnel.insert(Tunnel::open(&self.gateway)?),
+        };
+        tunnel.heartbeat()?;
+        let mut bytes = 0;
+        for (address, data) in writes {
+            tunnel.group_write(address, &data)?;
+            bytes += data.len() as u64;
+        }
+        Ok(bytes)
+    }
+}
+
+impl Sink for Knx {
+    fn connected(&self) -> Option<bool> {
+        Some(self.tunnel.is_some())
+    }
+
+    fn send(&mut self, sample: &Sample, _state: &Value) -> Result<u64, String> {
+        if self
+            .last_write
+            .is_some_and(|last| last.elapsed() < self.interval)
+        {
+            return Ok(0);
+        }
+        self.last_write = Some(Instant::now());
+
+        let result = self.write(sample);
+        // Set up a new tunnel on the next write, whatever went wrong.
+        if result.is_err() {
+            self.tunnel = None;
+        }
+        result
+    }
+}
+
+/// A tunnelling connection to a KNXnet/IP gateway.
+struct Tunnel {
+    socket: UdpSocket,
+    channel: u8,
+    sequence: u8,
+    last_heartbeat: Instant,
+}
+
+impl Tunnel {
+    fn open(gateway: &str) -> Result<Self, String> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| {
+                socket.connect(gateway)?;
+                socket.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+                Ok(socket)
+            })
+            .map_err(|e| format!("Unable to reach KNX gateway {}: {}", gateway, e))?;
+        let mut tunnel = Self {
+            socket,
+            channel: 0,
+            sequence: 0,
+            last_heartbeat: Instant::now(),
+        };
+
+        // Both endpoints are left empty (0.0.0.0:0), which tells the gateway to answer
+        // to the address the request came from. This works through NAT as well.
+        let mut body = Vec::new();
+        body.extend_from_slice(&EMPTY_HPAI);
+        body.extend_from_slice(&EMPTY_HPAI);
+        // Connection request information: tunnel connection on the link layer.
+        body.extend_from_slice(&[0x04, 0x04, 0x02, 0x00]);
+        tunnel.send(CONNECT_REQUEST, &body)?;
+
+        let response = tunnel.receive(CONNECT_RESPONSE)?;
+        match response[..] {
+            [channel, 0, ..] => tunnel.channel = channel,
+            [_, status, ..] => {
+                return Err(format!("KNX gateway refused connection: {:#04x}", status))
+            }
+            _ => return Err(String::from("Invalid connect response from KNX gateway")),
+        }
+        info!("Connected to KNX gateway {}", gateway);
+        Ok(tunnel)
+    }
+
+    /// Keep the tunnel alive by checking its state every once in a while.
+    fn heartbeat(&mut self) -> Result<(), String> {
+        if self.last_heartbeat.elapsed() < HEARTBEAT_INTERVAL {
+            return Ok(());
+        }
+        let mut body = vec![self.channel, 0];
+        body.extend_from_slice(&EMPTY_HPAI);
+        self.send(CONNECTIONSTATE_REQUEST, &body)?;
+        match self.receive(CONNECTIONSTATE_RESPONSE)?[..] {
+            [_, 0, ..] => {
+                self.last_heartbeat = Instant::now();
+                Ok(())
+            }
+            _ => Err(String::from("KNX tunnel was closed by the gateway")),
+        }
+    }
+
+    fn group_write(&mut self, address: u16, data: &[u8]) -> Result<(), String> {
+        let sequence = self.sequence;
+        let mut body = vec![0x04, self.channel, sequence, 0x00];
+        // cEMI L_Data.req without additional info, from the gateway's own address.
+        body.extend_from_slice(&[L_DATA_REQ, 0x00, 0xbc, 0xe0, 0x00, 0x00]);
+        body.extend_from_slice(&address.to_be_bytes());
+        body.push(data.len() as u8 + 1);
+        body.extend_from_slice(&[0x00, GROUP_VALUE_WRITE]);
+        body.extend_from_slice(data);
+        self.send(TUNNELLING_REQUEST, &body)?;
+
+        match self.receive(TUNNELLING_ACK)?[..] {
+            [_, _, ack_sequence, 0] if ack_sequence == sequence => {
+                self.sequence = self.sequence.wrapping_add(1);
+                Ok(())
+            }
+            _ => Err(String::from("KNX gateway did not acknowledge write")),
+        }
+    }
+
+    fn send(&self, service: u16, body: &[u8]) -> Result<(), String> {
+        let mut frame = vec![0x06, 0x10];
+        frame.extend_from_slice(&service.to_be_bytes());
+        frame.extend_from_slice(&(body.len() as u16 + 6).to_be_bytes());
+        frame.extend_from_slice(body);
+        self.socket
+            .send(&frame)
+            .map(|_| ())
+            .map_err(|e| format!("Unable to send to KNX gateway: {}", e))
+    }
+
+    /// Wait for a frame of the given service type and return its body. Confirmations the
+    /// gateway tunnels back to us are acknowledged and otherwise ignored.
+    fn receive(&self, service: u16) -> Result<Vec<u8>, String> {
+        let mut buffer = [0; 512];
+        loop {
+            let length = self
+                .socket
+                .recv(&mut buffer)
+                .map_err(|e| format!("No response from KNX gateway: {}", e))?;
+            let frame = &buffer[..length];
+            let [0x06, 0x10, high, low, _, _, body @ ..] = frame else {
+                continue;
+            };
+            match u16::from_be_bytes([*high, *low]) {
+                received if received == service => return Ok(body.to_vec()),
+                TUNNELLING_REQUEST => {
+                    if let [_, channel, sequence, ..] = body {
+                        self.send(TUNNELLING_ACK, &[0x04, *channel, *sequence, 0x00])?;
+                    }
+                }
+                DISCONNECT_REQUEST => {
+                    return Err(String::from("KNX tunnel was closed by the gateway"))
+                }
+                other => debug!("Ignoring KNX frame of type {:#06x}", other),
+            }
+        }
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let mut body = vec![self.channel, 0];
+        body.extend_from_slice(&EMPTY_HPAI);
+        let _ = self.send(DISCONNECT_REQUEST, &body);
+    }
+}
+
+/// Host protocol address information for UDP without address or port.
+const EMPTY_HPAI: [u8; 8] = [0x08, 0x01, 0, 0, 0, 0, 0, 0];
+
+/// Parse a group address in three level, two level or raw notation.
+fn parse_group_address(address: &str) -> Result<u16, String> {
+    let invalid = || format!("Invalid KNX group address {}", address);
+    let parts: Vec<u16> = address
+        .split('/')
+        .map(|part| part.parse().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    match parts[..] {
+        [main, middle, sub] if main < 32 && middle < 8 && sub < 256 => {
+            Ok(main << 11 | middle << 8 | sub)
+        }
+        [main, sub] if main < 32 && sub < 2048 => Ok(main << 11 | sub),
+        [raw] => Ok(raw),
+        _ => Err(invalid()),
+    }
+}
+
+/// Encode a value for a datapoint type.
+fn encode(value: f64, dpt: Dpt) -> Vec<u8> {
+    match dpt {
+        Dpt::Float32 => (value as f32).to_be_bytes().to_vec(),
+        Dpt::Float16 => {
+            // 0.01 * mantissa * 2^exponent, with a 12 bit signed mantissa.
+            let mut mantissa = (value * 100.0).round();
+            let mut exponent = 0;
+            while !(-2048.0..=2047.0).contains(&mantissa) && exponent < 15 {
+                mantissa = (mantissa / 2.0).round();
+                exponent += 1;
+            }
+            let mantissa = (mantissa.clamp(-2048.0, 2047.0) as i16) as u16;
+            let high = (mantissa >> 8) as u8 & 0x87 | exponent << 3;
+            vec![high, mantissa as u8]
+        }
+    }
+}
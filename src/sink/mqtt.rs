@@ -0,0 +1,44 @@
+//! MQTT sink, publishing the state to a topic through the connection to the broker in
+//! `mqtt`, so the sink's redaction applies to what goes out over MQTT.
+
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use super::Sink;
+use crate::{appdata::AppData, config::MqttSinkConfig, history::Sample};
+
+pub struct Mqtt {
+    appdata: Arc<AppData>,
+    topic: String,
+    retain: bool,
+}
+
+impl Mqtt {
+    pub fn new(config: &MqttSinkConfig, appdata: Arc<AppData>) -> Result<Self, String> {
+        if appdata.mqtt().is_none() {
+            return Err(String::from("no MQTT broker is configured"));
+        }
+        Ok(Self {
+            appdata,
+            topic: config.topic.clone(),
+            retain: config.retain,
+        })
+    }
+}
+
+impl Sink for Mqtt {
+    fn connected(&self) -> Option<bool> {
+        self.appdata.mqtt().map(|mqtt| mqtt.connected())
+    }
+
+    fn send(&mut self, _sample: &Sample, state: &Value) -> Result<u64, String> {
+        let mqtt = self.appdata.mqtt().ok_or("no MQTT broker is configured")?;
+        // A retained state would be kept for the next connection, but the next telegram
+        // is more recent by then.
+        if !mqtt.connected() {
+            return Err(String::from("Not connected to the MQTT broker"));
+        }
+        mqtt.publish(&self.topic, state.to_string().as_bytes(), self.retain)
+    }
+}
@@ -0,0 +1,256 @@
+//! NATS sink, publishing the state to a subject using the NATS text protocol. With
+//! JetStream enabled every message is published as a request, and the acknowledgement of
+//! the stream is awaited.
+
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use log::info;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use url::Url;
+
+use super::Sink;
+use crate::{config::NatsConfig, dial::Dialer, history::Sample};
+
+const DEFAULT_PORT: u16 = 4222;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Subscription id of the inbox receiving JetStream acknowledgements.
+const INBOX_SID: &str = "1";
+
+/// Reply of JetStream to a published message.
+#[derive(Deserialize)]
+struct PubAck {
+    error: Option<Value>,
+}
+
+pub struct Nats {
+    url: Url,
+    subject: String,
+    token: Option<String>,
+    jetstream: bool,
+    dialer: Dialer,
+    connection: Option<Connection>,
+}
+
+impl Nats {
+    pub fn new(config: &NatsConfig, dialer: Dialer) -> Result<Self, String> {
+        let url = Url::parse(&config.url)
+            .map_err(|e| format!("Invalid NATS url {}: {}", config.url, e))?;
+        if url.scheme() != "nats" {
+            return Err(format!("Unsupported NATS url {}", config.url));
+        }
+        Ok(Self {
+            url,
+            subject: config.subject.clone(),
+            token: config.token.clone(),
+            jetstream: config.jetstream,
+            dialer,
+            connection: None,
+        })
+    }
+
+    fn publish(&mut self, state: &Value) -> Result<u64, String> {
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => self.connection.insert(Connection::open(
+                &self.url,
+                self.token.as_deref(),
+                self.jetstream,
+                &self.dialer,
+            )?),
+        };
+        let payload = state.to_string();
+        let bytes = payload.len() as u64;
+        if !self.jetstream {
+            // Nothing else reads the socket, so answer the pings of the server here lest it
+            // drops the connection.
+            connection.drain()?;
+            return connection
+                .write(&format!(
+                    "PUB {} {}\r\n{}\r\n",
+                    self.subject,
+                    payload.len(),
+                    payload
+                ))
+                .map(|_| bytes);
+        }
+
+        connection.write(&format!(
+            "PUB {} {} {}\r\n{}\r\n",
+            self.subject,
+            connection.inbox,
+            payload.len(),
+            payload
+        ))?;
+        let ack = connection.read_message()?;
+        match serde_json::from_slice::<PubAck>(&ack) {
+            Ok(PubAck { error: None }) => Ok(bytes),
+            Ok(PubAck { error: Some(e) }) => Err(format!("JetStream error: {}", e)),
+            Err(_) => Err(String::from("Invalid JetStream acknowledgement")),
+        }
+    }
+}
+
+impl Sink for Nats {
+    fn connected(&self) -> Option<bool> {
+        Some(self.connection.is_some())
+    }
+
+    fn send(&mut self, _sample: &Sample, state: &Value) -> Result<u64, String> {
+        let result = self.publish(state);
+        // Reconnect on the next sample, whatever went wrong.
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    /// Subject acknowledgements are sent to.
+    inbox: String,
+}
+
+impl Connection {
+    fn open(
+        url: &Url,
+        token: Option<&str>,
+        jetstream: bool,
+        dialer: &Dialer,
+    ) -> Result<Self, String> {
+        let host = url.host_str().unwrap_or("localhost");
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let stream = dialer
+            .connect(host, port)
+            .map_err(|e| format!("Unable to connect to NATS at {}:{}: {}", host, port, e))?;
+        let reader = stream
+            .try_clone()
+            .and_then(|s| {
+                s.set_read_timeout(Some(TIMEOUT))?;
+                s.set_write_timeout(Some(TIMEOUT))?;
+                Ok(BufReader::new(s))
+            })
+            .map_err(|e| format!("Unable to set up NATS connection: {}", e))?;
+        let mut connection = Self {
+            stream,
+            reader,
+            inbox: format!("_INBOX.dsmrd.{}", std::process::id()),
+        };
+
+        // The server introduces itself first.
+        let info = connection.read_line()?;
+        if !info.starts_with("INFO ") {
+            return Err(format!("Unexpected greeting from NATS: {}", info));
+        }
+
+        let mut options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "dsmrd",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+        });
+        if !url.username().is_empty() {
+            options["user"] = url.username().into();
+            options["pass"] = url.password().unwrap_or_default().into();
+        }
+        if let Some(token) = token {
+            options["auth_token"] = token.into();
+        }
+        connection.write(&format!("CONNECT {}\r\n", options))?;
+        if jetstream {
+            let subscribe = format!("SUB {} {}\r\n", connection.inbox, INBOX_SID);
+            connection.write(&subscribe)?;
+        }
+        // A PING makes the server report authentication errors before we carry on.
+        connection.write("PING\r\n")?;
+        loop {
+            match connection.read_line()?.as_str() {
+                "PONG" => break,
+                line if line.starts_with("-ERR") => {
+                    return Err(format!("NATS error: {}", line));
+                }
+                _ => {}
+            }
+        }
+        info!("Connected to NATS at {}:{}", host, port);
+        Ok(connection)
+    }
+
+    fn write(&mut self, data: &str) -> Result<(), String> {
+        self.stream
+            .write_all(data.as_bytes())
+            .map_err(|e| format!("Unable to send to NATS: {}", e))
+    }
+
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err(String::from("NATS closed the connection")),
+            Ok(_) => Ok(line.trim_end().to_string()),
+            Err(e) => Err(format!("Unable to read from NATS: {}", e)),
+        }
+    }
+
+    /// Handle what the server sent since the last call, without waiting for more.
+    fn drain(&mut self) -> Result<(), String> {
+        while !self.reader.buffer().is_empty() || self.pending()? {
+            let line = self.read_line()?;
+            if line == "PING" {
+                self.write("PONG\r\n")?;
+            } else if line.starts_with("-ERR") {
+                return Err(format!("NATS error: {}", line));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the server sent anything that wasn't read yet.
+    fn pending(&mut self) -> Result<bool, String> {
+        let stream = self.reader.get_ref();
+        let mut byte = [0; 1];
+        let result = stream
+            .set_nonblocking(true)
+            .and_then(|_| stream.peek(&mut byte));
+        stream
+            .set_nonblocking(false)
+            .map_err(|e| format!("Unable to read from NATS: {}", e))?;
+        match result {
+            Ok(0) => Err(String::from("NATS closed the connection")),
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(format!("Unable to read from NATS: {}", e)),
+        }
+    }
+
+    /// Wait for the next message on the inbox, answering pings in the meantime.
+    fn read_message(&mut self) -> Result<Vec<u8>, String> {
+        loop {
+            let line = self.read_line()?;
+            if line == "PING" {
+                self.write("PONG\r\n")?;
+            } else if line.starts_with("-ERR") {
+                return Err(format!("NATS error: {}", line));
+            } else if let Some(args) = line.strip_prefix("MSG ") {
+                // MSG <subject> <sid> [reply-to] <#bytes>
+                let length: usize = args
+                    .split_whitespace()
+                    .last()
+                    .and_then(|l| l.parse().ok())
+                    .ok_or_else(|| format!("Invalid message from NATS: {}", line))?;
+                let mut payload = vec![0; length + 2];
+                self.reader
+                    .read_exact(&mut payload)
+                    .map_err(|e| format!("Unable to read from NATS: {}", e))?;
+                payload.truncate(length);
+                return Ok(payload);
+            }
+        }
+    }
+}
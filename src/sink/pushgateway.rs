@@ -0,0 +1,93 @@
+//! Prometheus Pushgateway sink. The latest values are pushed in the text exposition format,
+//! replacing the previous push of the same group.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use serde_json::Value;
+
+use super::{header_refs, metric_name, Sink};
+use crate::{
+    config::PushgatewayConfig,
+    dial::Dialer,
+    history::{Metric, Sample},
+    http_client::HttpClient,
+    metrics::metric_type,
+};
+
+pub struct Pushgateway {
+    client: HttpClient,
+    url: String,
+    headers: BTreeMap<String, String>,
+    interval: Duration,
+    last_push: Option<Instant>,
+}
+
+impl Pushgateway {
+    pub fn new(config: &PushgatewayConfig, dialer: Dialer) -> Result<Self, String> {
+        // The job and grouping labels are part of the url, see
+        // https://github.com/prometheus/pushgateway#url
+        let mut url = format!("{}/metrics", config.url.trim_end_matches('/'));
+        write_label(&mut url, "job", &config.job);
+        for (name, value) in &config.labels {
+            write_label(&mut url, name, value);
+        }
+        Ok(Self {
+            client: HttpClient::new(dialer)?,
+            url,
+            headers: config.headers.clone(),
+            interval: Duration::from_secs(config.interval),
+            last_push: None,
+        })
+    }
+}
+
+impl Sink for Pushgateway {
+    fn send(&mut self, sample: &Sample, _state: &Value) -> Result<u64, String> {
+        if self
+            .last_push
+            .is_some_and(|last| last.elapsed() < self.interval)
+        {
+            return Ok(0);
+        }
+        self.last_push = Some(Instant::now());
+
+        // The pushgateway doesn't accept timestamps, values get the time of the scrape.
+        let mut body = String::new();
+        for metric in Metric::ALL {
+            if let Some(value) = sample.get(metric) {
+                let name = metric_name(metric);
+                let _ = writeln!(body, "# TYPE {} {}", name, metric_type(metric));
+                let _ = writeln!(body, "{} {}", name, value);
+            }
+        }
+        let bytes = body.len() as u64;
+        self.client
+            .put(
+                &self.url,
+                "text/plain; version=0.0.4",
+                &header_refs(&self.headers),
+                body.into_bytes(),
+            )
+            .map(|_| bytes)
+    }
+}
+
+/// Add a grouping label to the url. Values that can't be used as a path segment as they are
+/// get base64 encoded.
+fn write_label(url: &mut String, name: &str, value: &str) {
+    if value.is_empty() {
+        let _ = write!(url, "/{}@base64/=", name);
+    } else if value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    {
+        let _ = write!(url, "/{}/{}", name, value);
+    } else {
+        let _ = write!(url, "/{}@base64/{}", name, URL_SAFE.encode(value));
+    }
+}
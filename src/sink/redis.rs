@@ -0,0 +1,169 @@
+//! Redis sink, publishing the state to a channel and/or appending it to a stream. Speaks
+//! just enough of the RESP protocol to send commands and check their replies.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use log::info;
+use serde_json::Value;
+use url::Url;
+
+use super::Sink;
+use crate::{config::RedisConfig, dial::Dialer, history::Sample};
+
+const DEFAULT_PORT: u16 = 6379;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Redis {
+    url: Url,
+    channel: Option<String>,
+    stream: Option<String>,
+    maxlen: u64,
+    dialer: Dialer,
+    connection: Option<Connection>,
+}
+
+impl Redis {
+    pub fn new(config: &RedisConfig, dialer: Dialer) -> Result<Self, String> {
+        let url = Url::parse(&config.url)
+            .map_err(|e| format!("Invalid redis url {}: {}", config.url, e))?;
+        if url.scheme() != "redis" {
+            return Err(format!("Unsupported redis url {}", config.url));
+        }
+        if config.channel.is_none() && config.stream.is_none() {
+            return Err(String::from("Redis sink needs a channel or a stream"));
+        }
+        Ok(Self {
+            url,
+            channel: config.channel.clone(),
+            stream: config.stream.clone(),
+            maxlen: config.maxlen,
+            dialer,
+            connection: None,
+        })
+    }
+
+    fn publish(&mut self, state: &Value) -> Result<u64, String> {
+        let state = state.to_string();
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => self
+                .connection
+                .insert(Connection::open(&self.url, &self.dialer)?),
+        };
+        let mut bytes = 0;
+        if let Some(channel) = &self.channel {
+            connection.command(&["PUBLISH", channel, &state])?;
+            bytes += state.len() as u64;
+        }
+        if let Some(stream) = &self.stream {
+            let maxlen = self.maxlen.to_string();
+            connection.command(&["XADD", stream, "MAXLEN", "~", &maxlen, "*", "state", &state])?;
+            bytes += state.len() as u64;
+        }
+        Ok(bytes)
+    }
+}
+
+impl Sink for Redis {
+    fn connected(&self) -> Option<bool> {
+        Some(self.connection.is_some())
+    }
+
+    fn send(&mut self, _sample: &Sample, state: &Value) -> Result<u64, String> {
+        let result = self.publish(state);
+        // Reconnect on the next sample, whatever went wrong.
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    /// Connect, log in and select the database given in the url.
+    fn open(url: &Url, dialer: &Dialer) -> Result<Self, String> {
+        let host = url.host_str().unwrap_or("localhost");
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let stream = dialer
+            .connect(host, port)
+            .map_err(|e| format!("Unable to connect to redis at {}:{}: {}", host, port, e))?;
+        let reader = stream
+            .try_clone()
+            .and_then(|s| {
+                s.set_read_timeout(Some(TIMEOUT))?;
+                s.set_write_timeout(Some(TIMEOUT))?;
+                Ok(BufReader::new(s))
+            })
+            .map_err(|e| format!("Unable to set up redis connection: {}", e))?;
+        let mut connection = Self { stream, reader };
+
+        if let Some(password) = url.password() {
+            match url.username() {
+                "" => connection.command(&["AUTH", password])?,
+                username => connection.command(&["AUTH", username, password])?,
+            }
+        }
+        let database = url.path().trim_start_matches('/');
+        if !database.is_empty() {
+            connection.command(&["SELECT", database])?;
+        }
+        info!("Connected to redis at {}:{}", host, port);
+        Ok(connection)
+    }
+
+    /// Send a command and wait for its reply, failing on an error reply.
+    fn command(&mut self, args: &[&str]) -> Result<(), String> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg.as_bytes());
+            request.extend_from_slice(b"\r\n");
+        }
+        self.stream
+            .write_all(&request)
+            .map_err(|e| format!("Unable to send to redis: {}", e))?;
+        self.read_reply()
+    }
+
+    /// Read a single reply. We don't need the contents, only whether it is an error.
+    fn read_reply(&mut self) -> Result<(), String> {
+        let mut line = String::new();
+        self.reader
+            .read_line(&mut line)
+            .map_err(|e| format!("Unable to read from redis: {}", e))?;
+        let line = line.trim_end();
+        let invalid = || format!("Invalid reply from redis: {}", line);
+        match line.split_at_checked(1) {
+            Some(("+" | ":", _)) => Ok(()),
+            Some(("-", error)) => Err(format!("Redis error: {}", error)),
+            Some(("$", length)) => {
+                // Null bulk strings have length -1 and no data.
+                let length: i64 = length.parse().map_err(|_| invalid())?;
+                if length >= 0 {
+                    let mut data = vec![0; length as usize + 2];
+                    self.reader
+                        .read_exact(&mut data)
+                        .map_err(|e| format!("Unable to read from redis: {}", e))?;
+                }
+                Ok(())
+            }
+            Some(("*", count)) => {
+                let count: i64 = count.parse().map_err(|_| invalid())?;
+                for _ in 0..count.max(0) {
+                    self.read_reply()?;
+                }
+                Ok(())
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Prometheus remote write sink. Samples are sent as a snappy compressed protobuf
+//! `WriteRequest`, which is small enough to encode by hand.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use super::{batch::Batch, header_refs, metric_name, Sink};
+use crate::{
+    config::RemoteWriteConfig,
+    dial::Dialer,
+    history::{Metric, Sample},
+    http_client::HttpClient,
+};
+
+pub struct RemoteWrite {
+    writer: Writer,
+    batch: Batch,
+}
+
+impl RemoteWrite {
+    pub fn new(config: &RemoteWriteConfig, dialer: Dialer) -> Result<Self, String> {
+        Ok(Self {
+            writer: Writer {
+                client: HttpClient::new(dialer)?,
+                url: config.url.clone(),
+                headers: config.headers.clone(),
+                labels: config.labels.clone(),
+            },
+            batch: Batch::new(config.batch_size, config.spool.as_ref())?,
+        })
+    }
+}
+
+impl Sink for RemoteWrite {
+    fn send(&mut self, sample: &Sample, _state: &Value) -> Result<u64, String> {
+        self.batch
+            .push(sample, |samples| self.writer.write(samples))
+    }
+
+    fn hold(&mut self, sample: &Sample, _state: &Value) -> Result<(), String> {
+        self.batch.hold(sample)
+    }
+}
+
+struct Writer {
+    client: HttpClient,
+    url: String,
+    headers: BTreeMap<String, String>,
+    labels: BTreeMap<String, String>,
+}
+
+impl Writer {
+    /// Encode samples as a `WriteRequest`, one time series per metric.
+    fn encode(&self, samples: &[Sample]) -> Vec<u8> {
+        let mut request = Vec::new();
+        for metric in Metric::ALL {
+            let mut labels = self.labels.clone();
+            labels.insert(String::from("__name__"), metric_name(metric));
+
+            let mut series = Vec::new();
+            // Receivers expect the labels sorted by name, which the BTreeMap takes care of.
+            for (name, value) in &labels {
+                let mut label = Vec::new();
+                write_bytes(&mut label, 1, name.as_bytes());
+                write_bytes(&mut label, 2, value.as_bytes());
+                write_bytes(&mut series, 1, &label);
+            }
+            let mut has_samples = false;
+            for sample in samples {
+                let Some(value) = sample.get(metric) else {
+                    continue;
+                };
+                let mut encoded = Vec::new();
+                write_key(&mut encoded, 1, 1);
+                encoded.extend_from_slice(&value.to_le_bytes());
+                write_key(&mut encoded, 2, 0);
+                write_varint(&mut encoded, sample.timestamp);
+                write_bytes(&mut series, 2, &encoded);
+                has_samples = true;
+            }
+            if has_samples {
+                write_bytes(&mut request, 1, &series);
+            }
+        }
+        request
+    }
+
+    fn write(&self, samples: &[Sample]) -> Result<u64, String> {
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&self.encode(samples))
+            .map_err(|e| format!("Unable to compress request: {}", e))?;
+        let mut headers = vec![
+            ("Content-Encoding", "snappy"),
+            ("X-Prometheus-Remote-Write-Version", "0.1.0"),
+        ];
+        headers.extend(header_refs(&self.headers));
+        let bytes = body.len() as u64;
+        self.client
+            .post(&self.url, "application/x-protobuf", &headers, body)
+            .map(|_| bytes)
+    }
+}
+
+/// Write a protobuf field key.
+fn write_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(buf, field << 3 | wire_type);
+}
+
+/// Write a length delimited protobuf field.
+fn write_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_key(buf, field, 2);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(value: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, value);
+        buf
+    }
+
+    #[test]
+    fn varint_boundaries() {
+        assert_eq!(varint(0), [0x00]);
+        assert_eq!(varint(127), [0x7f]);
+        assert_eq!(varint(128), [0x80, 0x01]);
+        assert_eq!(varint(300), [0xac, 0x02]);
+        assert_eq!(varint(16_383), [0xff, 0x7f]);
+        assert_eq!(varint(16_384), [0x80, 0x80, 0x01]);
+        assert_eq!(
+            varint(u64::MAX),
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]
+        );
+    }
+
+    #[test]
+    fn write_request() {
+        let writer = Writer {
+            client: HttpClient::new(Dialer::with_proxy(None)).unwrap(),
+            url: String::new(),
+            headers: BTreeMap::new(),
+            labels: BTreeMap::from([(String::from("job"), String::from("dsmrd"))]),
+        };
+        let mut sample = Sample::from_values(1_700_000_000_000, Default::default());
+        sample.set(Metric::PowerDelivered, Some(1.5));
+
+        // timeseries, with labels { name: "__name__", value: "dsmr_power_delivered" }
+        let mut expected = vec![0x0a, 0x42, 0x0a, 0x20, 0x0a, 0x08];
+        expected.extend_from_slice(b"__name__");
+        expected.extend_from_slice(&[0x12, 0x14]);
+        expected.extend_from_slice(b"dsmr_power_delivered");
+        // labels { name: "job", value: "dsmrd" }
+        expected.extend_from_slice(&[0x0a, 0x0c, 0x0a, 0x03]);
+        expected.extend_from_slice(b"job");
+        expected.extend_from_slice(&[0x12, 0x05]);
+        expected.extend_from_slice(b"dsmrd");
+        // samples { value: 1.5, timestamp: 1700000000000 }
+        expected.extend_from_slice(&[0x12, 0x10, 0x09]);
+        expected.extend_from_slice(&1.5f64.to_le_bytes());
+        expected.extend_from_slice(&[0x10, 0x80, 0xd0, 0x95, 0xff, 0xbc, 0x31]);
+
+        assert_eq!(writer.encode(&[sample]), expected);
+        // Metrics without samples are left out.
+        assert!(writer.encode(&[]).is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+//! Signal K sink. Every sample is sent as a delta on the `electrical.ac.<id>` paths, in
+//! the SI units Signal K uses: W, V, A and, for the meter readings, J.
+//!
+//! Signal K has no standard paths for energy counters, so those are sent as
+//! `total.energyImported` and `total.energyExported`.
+
+use std::net::{TcpStream, UdpSocket};
+
+use chrono::{SecondsFormat, TimeZone, Utc};
+use log::info;
+use serde_json::{json, Value};
+use tungstenite::{client::IntoClientRequest, http::HeaderValue, Message, WebSocket};
+use url::Url;
+
+use super::Sink;
+use crate::{
+    config::SignalKConfig,
+    dial::Dialer,
+    history::{Metric, Sample},
+};
+
+/// Joules per kWh.
+const JOULES_PER_KWH: f64 = 3_600_000.0;
+
+enum Transport {
+    Udp(UdpSocket),
+    WebSocket(Option<Box<WebSocket<TcpStream>>>),
+}
+
+pub struct SignalK {
+    url: Url,
+    id: String,
+    token: Option<String>,
+    /// Opens WebSocket connections. UDP deltas are always sent directly.
+    dialer: Dialer,
+    transport: Transport,
+}
+
+impl SignalK {
+    pub fn new(config: &SignalKConfig, dialer: Dialer) -> Result<Self, String> {
+        let mut url = Url::parse(&config.url)
+            .map_err(|e| format!("Invalid Signal K url {}: {}", config.url, e))?;
+        // We only send, so ask the server not to send us its own deltas.
+        if url.scheme() == "ws" && !url.query_pairs().any(|(name, _)| name == "subscribe") {
+            url.query_pairs_mut().append_pair("subscribe", "none");
+        }
+        let transport = match url.scheme() {
+            "udp" => {
+                let socket = UdpSocket::bind("0.0.0.0:0")
+                    .map_err(|e| format!("Unable to bind UDP socket: {}", e))?;
+                Transport::Udp(socket)
+            }
+            "ws" => Transport::WebSocket(None),
+            _ => return Err(format!("Unsupported Signal K url {}", config.url)),
+        };
+        Ok(Self {
+            url,
+            id: config.id.clone(),
+            token: config.token.clone(),
+            dialer,
+            transport,
+        })
+    }
+
+    /// Build the delta message for a sample.
+    fn delta(&self, sample: &Sample) -> Value {
+        let base = format!("electrical.ac.{}", self.id);
+        let mut values = Vec::new();
+        let mut add = |path: String, value: Option<f64>| {
+            if let Some(value) = value {
+                values.push(json!({ "path": path, "value": value }));
+            }
+        };
+        let net_power = |delivered: Metric, received: Metric| {
+            let delivered = sample.get(delivered)?;
+            Some((delivered - sample.get(received).unwrap_or(0.0)) * 1000.0)
+        };
+        let energy = |tariffs: [Metric; 2]| {
+            let [low, normal] = tariffs.map(|m| sample.get(m));
+            Some((low? + normal.unwrap_or(0.0)) * JOULES_PER_KWH)
+        };
+
+        add(
+            format!("{}.total.realPower", base),
+            net_power(Metric::PowerDelivered, Metric::PowerReceived),
+        );
+        add(
+            format!("{}.total.energyImported", base),
+            energy([
+                Metric::EnergyDeliveredTariff1,
+                Metric::EnergyDeliveredTariff2,
+            ]),
+        );
+        add(
+            format!("{}.total.energyExported", base),
+            energy([Metric::EnergyReceivedTariff1, Metric::EnergyReceivedTariff2]),
+        );
+        let phases = [
+            (
+                "A",
+                Metric::VoltageL1,
+                Metric::CurrentL1,
+                Metric::PowerDeliveredL1,
+                Metric::PowerReceivedL1,
+            ),
+            (
+                "B",
+                Metric::VoltageL2,
+                Metric::CurrentL2,
+                Metric::PowerDeliveredL2,
+                Metric::PowerReceivedL2,
+            ),
+            (
+                "C",
+                Metric::VoltageL3,
+                Metric::CurrentL3,
+                Metric::PowerDeliveredL3,
+                Metric::PowerReceivedL3,
+            ),
+        ];
+        for (phase, voltage, current, delivered, received) in phases {
+            let path = |name: &str| format!("{}.phase.{}.{}", base, phase, name);
+            add(path("lineNeutralVoltage"), sample.get(voltage));
+            add(path("current"), sample.get(current));
+            add(path("realPower"), net_power(delivered, received));
+        }
+
+        let timestamp = Utc
+            .timestamp_millis_opt(sample.timestamp as i64)
+            .single()
+            .unwrap_or_else(Utc::now)
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        json!({
+            "context": "vessels.self",
+            "updates": [{
+                "source": { "label": "dsmrd" },
+                "timestamp": timestamp,
+                "values": values,
+            }],
+        })
+    }
+}
+
+/// Open a WebSocket connection to the Signal K server.
+fn connect(
+    url: &Url,
+    token: Option<&str>,
+    dialer: &Dialer,
+) -> Result<Box<WebSocket<TcpStream>>, String> {
+    let host = url.host_str().unwrap_or("localhost");
+    let port = url.port_or_known_default().unwrap_or(80);
+    let stream = dialer
+        .connect(host, port)
+        .map_err(|e| format!("Unable to connect to {}:{}: {}", host, port, e))?;
+
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .map_err(|e| format!("Invalid Signal K url: {}", e))?;
+    if let Some(token) = token {
+        let value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|e| format!("Invalid Signal K token: {}", e))?;
+        request.headers_mut().insert("Authorization", value);
+    }
+    let (socket, _) = tungstenite::client(request, stream)
+        .map_err(|e| format!("WebSocket handshake with {} failed: {}", url, e))?;
+    info!("Connected to Signal K at {}", url);
+    Ok(Box::new(socket))
+}
+
+impl Sink for SignalK {
+    fn connected(&self) -> Option<bool> {
+        match &self.transport {
+            Transport::Udp(_) => None,
+            Transport::WebSocket(connection) => Some(connection.is_some()),
+        }
+    }
+
+    fn send(&mut self, sample: &Sample, _state: &Value) -> Result<u64, String> {
+        let delta = self.delta(sample).to_string();
+        let bytes = delta.len() as u64;
+        match &mut self.transport {
+            Transport::Udp(socket) => {
+                let host = self.url.host_str().unwrap_or("localhost");
+                let port = self.url.port().ok_or("Signal K url needs a port")?;
+                socket
+                    .send_to(delta.as_bytes(), (host, port))
+                    .map(|_| bytes)
+                    .map_err(|e| format!("Unable to send to {}:{}: {}", host, port, e))
+            }
+            Transport::WebSocket(connection) => {
+                let socket = match connection {
+                    Some(socket) => socket,
+                    None => {
+                        connection.insert(connect(&self.url, self.token.as_deref(), &self.dialer)?)
+                    }
+                };
+                let result = socket
+                    .send(Message::text(delta))
+                    .map(|_| bytes)
+                    .map_err(|e| format!("Unable to send to {}: {}", self.url, e));
+                if result.is_err() {
+                    *connection = None;
+                }
+                result
+            }
+        }
+    }
+}
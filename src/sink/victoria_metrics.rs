@@ -0,0 +1,98 @@
+//! VictoriaMetrics sink, using the JSON line format of `/api/v1/import`.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use super::{batch::Batch, header_refs, metric_name, Sink};
+use crate::{
+    config::VictoriaMetricsConfig,
+    dial::Dialer,
+    history::{Metric, Sample},
+    http_client::HttpClient,
+};
+
+/// A single line of the import format: one series with its values.
+#[derive(Serialize)]
+struct Series<'a> {
+    metric: &'a BTreeMap<String, String>,
+    values: Vec<f64>,
+    timestamps: Vec<u64>,
+}
+
+pub struct VictoriaMetrics {
+    importer: Importer,
+    batch: Batch,
+}
+
+impl VictoriaMetrics {
+    pub fn new(config: &VictoriaMetricsConfig, dialer: Dialer) -> Result<Self, String> {
+        Ok(Self {
+            importer: Importer {
+                client: HttpClient::new(dialer)?,
+                url: format!("{}/api/v1/import", config.url.trim_end_matches('/')),
+                headers: config.headers.clone(),
+                labels: config.labels.clone(),
+            },
+            batch: Batch::new(config.batch_size, config.spool.as_ref())?,
+        })
+    }
+}
+
+impl Sink for VictoriaMetrics {
+    fn send(&mut self, sample: &Sample, _state: &Value) -> Result<u64, String> {
+        self.batch
+            .push(sample, |samples| self.importer.import(samples))
+    }
+
+    fn hold(&mut self, sample: &Sample, _state: &Value) -> Result<(), String> {
+        self.batch.hold(sample)
+    }
+}
+
+struct Importer {
+    client: HttpClient,
+    url: String,
+    headers: BTreeMap<String, String>,
+    labels: BTreeMap<String, String>,
+}
+
+impl Importer {
+    fn encode(&self, samples: &[Sample]) -> Result<Vec<u8>, String> {
+        let mut body = Vec::new();
+        for metric in Metric::ALL {
+            let mut labels = self.labels.clone();
+            labels.insert(String::from("__name__"), metric_name(metric));
+            let (timestamps, values) = samples
+                .iter()
+                .filter_map(|s| Some((s.timestamp, s.get(metric)?)))
+                .unzip();
+            let series = Series {
+                metric: &labels,
+                values,
+                timestamps,
+            };
+            if series.values.is_empty() {
+                continue;
+            }
+            serde_json::to_writer(&mut body, &series)
+                .map_err(|e| format!("Unable to serialize series: {}", e))?;
+            body.push(b'\n');
+        }
+        Ok(body)
+    }
+
+    fn import(&self, samples: &[Sample]) -> Result<u64, String> {
+        let body = self.encode(samples)?;
+        let bytes = body.len() as u64;
+        self.client
+            .post(
+                &self.url,
+                "application/json",
+                &header_refs(&self.headers),
+                body,
+            )
+            .map(|_| bytes)
+    }
+}
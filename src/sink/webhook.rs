@@ -0,0 +1,110 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use futures::{stream, StreamExt};
+use hyper::Method;
+use log::error;
+use serde_json::Value;
+
+use super::{header_refs, Sink};
+use crate::{
+    allowlist::{self, Cidr},
+    config::WebhookSinkConfig,
+    dial::Dialer,
+    history::Sample,
+    http_client::{upload_request, HttpClient},
+};
+
+/// Wait before the first retry of a failed post. Doubles with every retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub struct Webhook {
+    client: HttpClient,
+    targets: Vec<Target>,
+    concurrency: usize,
+}
+
+struct Target {
+    url: String,
+    headers: BTreeMap<String, String>,
+    timeout: Duration,
+    retries: u32,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookSinkConfig, dialer: Dialer, allow: &[Cidr]) -> Result<Self, String> {
+        if config.targets.is_empty() {
+            return Err(String::from("Webhook sink needs at least one target"));
+        }
+        let targets = config
+            .targets
+            .iter()
+            .map(|target| {
+                allowlist::check_url(allow, &target.url)?;
+                Ok(Target {
+                    url: target.url.clone(),
+                    headers: target.headers.clone(),
+                    timeout: Duration::from_secs(target.timeout.max(1)),
+                    retries: target.retries,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            client: HttpClient::new(dialer.allowing(allow))?,
+            targets,
+            concurrency: config.concurrency.max(1),
+        })
+    }
+
+    /// Post to a single target, retrying as configured.
+    async fn post(&self, target: &Target, body: &[u8]) -> Result<(), String> {
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let request = upload_request(
+                Method::POST,
+                &target.url,
+                "application/json",
+                &header_refs(&target.headers),
+                body.to_vec(),
+            )?;
+            match self
+                .client
+                .fetch(&target.url, request, target.timeout)
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt >= target.retries => return Err(e),
+                Err(_) => {
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Sink for Webhook {
+    /// Post the state to every target, at most `concurrency` at a time. Fails only when
+    /// no target could be reached, so a single broken target doesn't trip the breaker of
+    /// the others.
+    fn send(&mut self, _sample: &Sample, state: &Value) -> Result<u64, String> {
+        let body = state.to_string().into_bytes();
+        let results: Vec<Result<(), String>> = self.client.block_on(
+            stream::iter(&self.targets)
+                .map(|target| self.post(target, &body))
+                .buffer_unordered(self.concurrency)
+                .collect(),
+        );
+
+        let errors: Vec<String> = results.into_iter().filter_map(Result::err).collect();
+        if errors.len() == self.targets.len() {
+            return Err(errors.join("; "));
+        }
+        let posted = (self.targets.len() - errors.len()) as u64;
+        for e in errors {
+            error!("Webhook failed: {}", e);
+        }
+        Ok(posted * body.len() as u64)
+    }
+}
@@ -0,0 +1,237 @@
+//! Named snapshots of the meter state, for bracketing events like a holiday or a new
+//! appliance. `POST /snapshot?name=before-holiday` stores the current state and has every
+//! sink publish it right away, `/snapshots` lists the snapshots and `/snapshots/<name>`
+//! serves one. Taking a snapshot with a name already in use replaces it.
+
+use std::{
+    fs,
+    sync::{Arc, RwLock},
+};
+
+use chrono::Local;
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    appdata::AppData,
+    config::{SnapshotConfig, StateFormat},
+    history::now_millis,
+    lock::RecoverLock,
+    output, query,
+    reader::ReaderData,
+};
+
+/// Longest snapshot name.
+const MAX_NAME: usize = 64;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// Time the snapshot was taken in milliseconds since the unix epoch.
+    pub taken_at: u64,
+    /// Time the telegram the state is from was received.
+    pub received_at: u64,
+    pub state: Value,
+}
+
+/// A snapshot as listed by `/snapshots`.
+#[derive(Serialize)]
+struct Entry<'a> {
+    name: &'a str,
+    taken_at: u64,
+}
+
+/// The snapshots taken, oldest first, kept in the configured file.
+#[derive(Debug)]
+pub struct Snapshots {
+    file: Option<String>,
+    max: usize,
+    snapshots: Vec<Snapshot>,
+}
+
+impl Snapshots {
+    pub fn new(config: &SnapshotConfig) -> Self {
+        let snapshots = config
+            .file
+            .as_ref()
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(snapshots) => serde_json::from_str(&snapshots)
+                    .map_err(|e| error!("Unable to parse snapshots {}: {}", path, e))
+                    .ok(),
+                Err(e) => {
+                    info!("No snapshots read from {}: {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            file: config.file.clone(),
+            max: config.max.max(1),
+            snapshots,
+        }
+    }
+
+    fn insert(&mut self, snapshot: Snapshot) {
+        self.snapshots.retain(|taken| taken.name != snapshot.name);
+        self.snapshots.push(snapshot);
+        if self.snapshots.len() > self.max {
+            self.snapshots.remove(0);
+        }
+        self.save();
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        let count = self.snapshots.len();
+        self.snapshots.retain(|taken| taken.name != name);
+        let removed = self.snapshots.len() < count;
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    fn get(&self, name: &str) -> Option<&Snapshot> {
+        self.snapshots.iter().find(|taken| taken.name == name)
+    }
+
+    pub fn save(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let result = serde_json::to_string(&self.snapshots)
+            .map_err(|e| e.to_string())
+            .and_then(|snapshots| fs::write(path, snapshots).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Unable to write snapshots {}: {}", path, e);
+        }
+    }
+}
+
+/// Query parameters of `/snapshot`.
+#[derive(Deserialize)]
+struct SnapshotParams {
+    /// Defaults to the current time.
+    name: Option<String>,
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+fn respond(status: StatusCode, message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+}
+
+fn json(value: &impl Serialize, status: StatusCode) -> Result<Response<Body>, hyper::http::Error> {
+    match serde_json::to_string(value) {
+        Ok(json) => Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, &format!("Error: {}", e)),
+    }
+}
+
+/// Handler for `POST /snapshot`.
+pub async fn take_handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+    data: Arc<RwLock<ReaderData>>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    if req.method() != Method::POST {
+        return respond(StatusCode::METHOD_NOT_ALLOWED, "Error: method not allowed.");
+    }
+    let params: SnapshotParams = match query::parse(&req) {
+        Ok(params) => params,
+        Err(e) => return respond(StatusCode::BAD_REQUEST, &format!("Error: {}", e)),
+    };
+    let name = params
+        .name
+        .unwrap_or_else(|| Local::now().format("%Y-%m-%dT%H:%M:%S").to_string());
+    if !valid_name(&name) {
+        return respond(
+            StatusCode::BAD_REQUEST,
+            &format!(
+                "Error: invalid snapshot name {}, use at most {} letters, digits and -_.:",
+                name, MAX_NAME
+            ),
+        );
+    }
+
+    let (received_at, state) = {
+        let data = data.read_recover();
+        let state = output::render_state(
+            &data,
+            appdata.config().output.missing_values,
+            StateFormat::Native,
+        );
+        (data.received_at, state)
+    };
+    let Some(received_at) = received_at else {
+        return respond(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Error: no telegram received yet.",
+        );
+    };
+    let state = match state {
+        Ok(state) => state,
+        Err(e) => return respond(StatusCode::INTERNAL_SERVER_ERROR, &format!("Error: {}", e)),
+    };
+    let snapshot = Snapshot {
+        name,
+        taken_at: now_millis(),
+        received_at,
+        state,
+    };
+    info!("Taking snapshot {}", snapshot.name);
+    appdata.snapshots.write_recover().insert(snapshot.clone());
+
+    for sink in appdata.sinks.read_recover().iter() {
+        sink.publish();
+    }
+    appdata.emit_event();
+    json(&snapshot, StatusCode::CREATED)
+}
+
+/// Handler for `/snapshots` and `/snapshots/<name>`, which can be deleted as well.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let path = req.uri().path().trim_end_matches('/');
+    let Some(name) = path.strip_prefix("/snapshots/") else {
+        if req.method() != Method::GET {
+            return respond(StatusCode::METHOD_NOT_ALLOWED, "Error: method not allowed.");
+        }
+        let snapshots = appdata.snapshots.read_recover();
+        let entries: Vec<Entry> = snapshots
+            .snapshots
+            .iter()
+            .map(|snapshot| Entry {
+                name: &snapshot.name,
+                taken_at: snapshot.taken_at,
+            })
+            .collect();
+        return json(&entries, StatusCode::OK);
+    };
+
+    match *req.method() {
+        Method::GET => match appdata.snapshots.read_recover().get(name) {
+            Some(snapshot) => json(snapshot, StatusCode::OK),
+            None => respond(StatusCode::NOT_FOUND, "Error: unknown snapshot."),
+        },
+        Method::DELETE => match appdata.snapshots.write_recover().remove(name) {
+            true => respond(StatusCode::OK, &format!("Snapshot {} deleted.", name)),
+            false => respond(StatusCode::NOT_FOUND, "Error: unknown snapshot."),
+        },
+        _ => respond(StatusCode::METHOD_NOT_ALLOWED, "Error: method not allowed."),
+    }
+}
@@ -0,0 +1,376 @@
+//! The SQLite and Postgres engines of the history store. Samples are kept in a `samples`
+//! table with a column per metric, named like the metric, and missing values stored as
+//! NULL. Columns of metrics added since the table was created are added when it's opened.
+//!
+//! Like the file engine, samples are written behind: they wait in memory until
+//! `history.flush_size` of them are waiting or the oldest has waited
+//! `history.flush_interval` seconds, and are then inserted in a single transaction.
+//! Queries see the waiting samples as well. Once the table holds more than
+//! `history.capacity` samples the oldest are deleted.
+
+#[cfg(feature = "postgres")]
+mod pg;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std::{
+    fmt::Debug,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use log::error;
+
+use crate::{
+    config::HistoryConfig,
+    history::{now_millis, FlushStats, HistoryStore, Metric, Sample, METRIC_COUNT},
+};
+
+#[cfg(feature = "postgres")]
+pub use self::pg::Postgres;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::Sqlite;
+
+/// Samples to select.
+#[derive(Clone, Copy, Debug)]
+pub enum Filter {
+    /// With a timestamp in `[from, to]`.
+    Range(u64, u64),
+    /// Stored after the sample with the given id.
+    Since(u64),
+    Latest,
+    Oldest,
+}
+
+/// What an engine needs from its database.
+pub trait Database: Debug + Send {
+    /// Insert samples, all or none of them.
+    fn insert(&mut self, samples: &[Sample]) -> Result<(), String>;
+
+    /// The selected samples, in the order they were stored.
+    fn select(&mut self, filter: Filter) -> Result<Vec<Sample>, String>;
+
+    fn count(&mut self) -> Result<usize, String>;
+
+    /// The highest id stored, 0 without samples.
+    fn last_id(&mut self) -> Result<u64, String>;
+
+    /// Delete the samples with a timestamp before `before`. Returns the number deleted.
+    fn delete_before(&mut self, before: u64) -> Result<usize, String>;
+
+    /// Delete the samples with an id up to `id`.
+    fn delete_up_to(&mut self, id: u64) -> Result<usize, String>;
+
+    /// Space taken by the samples in bytes.
+    fn bytes(&mut self) -> Result<usize, String>;
+
+    /// Give back the space of deleted samples.
+    fn vacuum(&mut self) -> Result<(), String>;
+}
+
+/// The column names of the metrics, in the order of `Metric::ALL`.
+fn columns() -> String {
+    Metric::ALL.map(|metric| metric.name()).join(", ")
+}
+
+/// The condition selecting `filter`, with the parameters it takes. `parameter` gives the
+/// placeholder of the nth parameter.
+fn condition(filter: Filter, parameter: fn(usize) -> String) -> (String, Vec<i64>) {
+    // Times and ids are stored as signed integers, open ended ranges end at the largest.
+    let integer = |n: u64| i64::try_from(n).unwrap_or(i64::MAX);
+    match filter {
+        Filter::Range(from, to) => (
+            format!(
+                "WHERE timestamp >= {} AND timestamp <= {} ORDER BY id",
+                parameter(1),
+                parameter(2)
+            ),
+            vec![integer(from), integer(to)],
+        ),
+        Filter::Since(after) => (
+            format!("WHERE id > {} ORDER BY id", parameter(1)),
+            vec![integer(after)],
+        ),
+        Filter::Latest => (String::from("ORDER BY id DESC LIMIT 1"), vec![]),
+        Filter::Oldest => (String::from("ORDER BY id LIMIT 1"), vec![]),
+    }
+}
+
+/// A sample from the id, timestamp and metric values of a row.
+fn sample(id: i64, timestamp: i64, values: [Option<f64>; METRIC_COUNT]) -> Sample {
+    let mut sample = Sample::from_values(timestamp as u64, values);
+    sample.id = id as u64;
+    sample
+}
+
+/// A history store in a database, writing behind.
+#[derive(Debug)]
+pub struct SqlStore<D: Database> {
+    database: Mutex<D>,
+    /// Samples waiting to be inserted.
+    pending: Vec<Sample>,
+    capacity: usize,
+    next_id: u64,
+    flush_interval: u64,
+    flush_size: usize,
+    stats: FlushStats,
+}
+
+impl<D: Database> SqlStore<D> {
+    pub fn new(config: &HistoryConfig, mut database: D) -> Result<Self, String> {
+        let next_id = database.last_id()? + 1;
+        Ok(Self {
+            database: Mutex::new(database),
+            pending: Vec::new(),
+            capacity: config.capacity,
+            next_id,
+            flush_interval: config.flush_interval,
+            flush_size: config.flush_size.max(1),
+            stats: FlushStats::default(),
+        })
+    }
+
+    fn database(&self) -> MutexGuard<'_, D> {
+        self.database.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The stored samples selected by `filter`, followed by the waiting ones matching it.
+    fn select(&self, filter: Filter) -> Vec<Sample> {
+        let mut samples = match self.database().select(filter) {
+            Ok(samples) => samples,
+            Err(e) => {
+                error!("Unable to read history: {}", e);
+                Vec::new()
+            }
+        };
+        let pending = self.pending.iter().filter(|sample| match filter {
+            Filter::Range(from, to) => from <= sample.timestamp && sample.timestamp <= to,
+            Filter::Since(after) => sample.id > after,
+            Filter::Latest | Filter::Oldest => true,
+        });
+        samples.extend(pending.cloned());
+        samples
+    }
+
+    fn due(&self) -> bool {
+        let Some(oldest) = self.pending.first() else {
+            return false;
+        };
+        self.pending.len() >= self.flush_size
+            || now_millis().saturating_sub(oldest.timestamp) >= self.flush_interval * 1000
+    }
+
+    /// Insert the waiting samples and delete the ones beyond the capacity.
+    fn insert_pending(&mut self) -> Result<(), String> {
+        let database = self
+            .database
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        database.insert(&self.pending)?;
+        self.pending.clear();
+        let keep_from = self.next_id.saturating_sub(self.capacity as u64);
+        if keep_from > 1 {
+            database.delete_up_to(keep_from - 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: Database> HistoryStore for SqlStore<D> {
+    fn append(&mut self, mut sample: Sample) {
+        if self.capacity == 0 {
+            return;
+        }
+        sample.id = self.next_id;
+        self.next_id += 1;
+        self.pending.push(sample);
+        // While the database can't be written, the samples beyond the capacity are given up.
+        if self.pending.len() > self.capacity {
+            self.pending.remove(0);
+        }
+        if self.due() {
+            let _ = self.flush();
+        }
+    }
+
+    fn query_range(&self, from: u64, to: u64) -> Box<dyn Iterator<Item = Sample> + '_> {
+        Box::new(self.select(Filter::Range(from, to)).into_iter())
+    }
+
+    fn since(&self, after: u64) -> Box<dyn Iterator<Item = Sample> + '_> {
+        Box::new(self.select(Filter::Since(after)).into_iter())
+    }
+
+    fn latest(&self) -> Option<Sample> {
+        match self.pending.last() {
+            Some(sample) => Some(sample.clone()),
+            None => self.select(Filter::Latest).pop(),
+        }
+    }
+
+    fn oldest(&self) -> Option<Sample> {
+        self.select(Filter::Oldest).into_iter().next()
+    }
+
+    fn count(&self) -> usize {
+        let stored = self.database().count().unwrap_or_else(|e| {
+            error!("Unable to count history: {}", e);
+            0
+        });
+        stored + self.pending.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn bytes(&self) -> usize {
+        let stored = self.database().bytes().unwrap_or_else(|e| {
+            error!("Unable to read the size of the history: {}", e);
+            0
+        });
+        stored + self.pending.len() * std::mem::size_of::<Sample>()
+    }
+
+    fn prune(&mut self, before: u64) -> usize {
+        let waiting = self.pending.len();
+        self.pending.retain(|sample| sample.timestamp >= before);
+        let database = self
+            .database
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let deleted = database.delete_before(before).unwrap_or_else(|e| {
+            error!("Unable to prune history: {}", e);
+            0
+        });
+        deleted + waiting - self.pending.len()
+    }
+
+    fn vacuum(&mut self) {
+        let _ = self.flush();
+        let database = self
+            .database
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = database.vacuum() {
+            error!("Unable to vacuum history: {}", e);
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let result = self.insert_pending();
+        match &result {
+            Ok(_) => {
+                self.stats.flushes += 1;
+                self.stats.last_flush = Some(now_millis());
+            }
+            Err(e) => {
+                self.stats.errors += 1;
+                error!("Unable to write samples to the history database: {}", e);
+            }
+        }
+        result
+    }
+
+    fn flush_stats(&self) -> Option<FlushStats> {
+        let lag = self
+            .pending
+            .first()
+            .map_or(0, |oldest| now_millis().saturating_sub(oldest.timestamp));
+        Some(FlushStats {
+            pending: self.pending.len(),
+            lag,
+            ..self.stats
+        })
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    fn config() -> HistoryConfig {
+        HistoryConfig {
+            capacity: 3,
+            flush_size: 2,
+            flush_interval: u64::MAX / 1000,
+            ..HistoryConfig::default()
+        }
+    }
+
+    fn sample(timestamp: u64, power: f64) -> Sample {
+        let mut sample = Sample::from_values(timestamp, [None; METRIC_COUNT]);
+        sample.set(Metric::PowerDelivered, Some(power));
+        sample
+    }
+
+    fn ids(samples: impl Iterator<Item = Sample>) -> Vec<u64> {
+        samples.map(|sample| sample.id).collect()
+    }
+
+    #[test]
+    fn write_behind() {
+        let mut store = SqlStore::new(&config(), Sqlite::open(":memory:").unwrap()).unwrap();
+        store.append(sample(1000, 0.5));
+        // Waiting samples are queried along with the stored ones.
+        assert_eq!(store.database().count(), Ok(0));
+        assert_eq!(store.count(), 1);
+        assert_eq!(
+            store.latest().map(|s| s.get(Metric::PowerDelivered)),
+            Some(Some(0.5))
+        );
+
+        store.append(sample(2000, 1.0));
+        assert_eq!(store.database().count(), Ok(2));
+        assert_eq!(store.flush_stats().map(|stats| stats.flushes), Some(1));
+        store.append(sample(3000, 1.5));
+        assert_eq!(ids(store.query_range(1500, 3000)), [2, 3]);
+        assert_eq!(ids(store.since(1)), [2, 3]);
+        assert_eq!(store.usage(Metric::PowerDelivered, 0, 3000), Some(1.0));
+
+        // The oldest samples are deleted beyond the capacity.
+        store.append(sample(4000, 2.0));
+        assert_eq!(store.database().count(), Ok(3));
+        assert_eq!(store.oldest().map(|s| s.id), Some(2));
+        assert_eq!(ids(store.query_range(0, u64::MAX)), [2, 3, 4]);
+
+        assert_eq!(store.prune(3500), 2);
+        assert_eq!(ids(store.since(0)), [4]);
+        store.vacuum();
+        assert_eq!(store.count(), 1);
+    }
+
+    #[test]
+    fn reopen() {
+        let path = env::temp_dir().join(format!("dsmrd-history-{}.db", process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = fs::remove_file(&path);
+        {
+            // A table of an older version, without most metrics.
+            let connection = rusqlite::Connection::open(&path).unwrap();
+            connection
+                .execute_batch(
+                    "CREATE TABLE samples (id INTEGER PRIMARY KEY, timestamp INTEGER NOT NULL,
+                        power_delivered REAL);
+                    INSERT INTO samples VALUES (7, 1000, 0.25);",
+                )
+                .unwrap();
+        }
+
+        let mut store = SqlStore::new(&config(), Sqlite::open(&path).unwrap()).unwrap();
+        let old = store.latest().unwrap();
+        assert_eq!((old.id, old.get(Metric::PowerDelivered)), (7, Some(0.25)));
+        assert_eq!(old.get(Metric::PowerReceived), None);
+        store.append(sample(2000, 1.0));
+        store.flush().unwrap();
+        drop(store);
+
+        let store = SqlStore::new(&config(), Sqlite::open(&path).unwrap()).unwrap();
+        assert_eq!(ids(store.since(0)), [7, 8]);
+        let _ = fs::remove_file(&path);
+    }
+}
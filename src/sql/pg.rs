@@ -0,0 +1,201 @@
+//! The history in a Postgres database at `history.url`.
+//!
+//! The client runs a runtime of its own, which can't be started on a thread that is already
+//! running async code such as the HTTP handlers. So the connection lives on a thread of its
+//! own, and the store hands it the queries to run. The connection is made again when it
+//! was lost.
+
+use std::{
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use log::{error, info};
+use postgres::{types::ToSql, Client, NoTls, Row};
+
+use super::{columns, condition, sample, Database, Filter};
+use crate::history::{Metric, Sample, METRIC_COUNT};
+
+type Job = Box<dyn FnOnce(&mut Client) + Send>;
+
+#[derive(Debug)]
+pub struct Postgres {
+    jobs: Sender<Job>,
+}
+
+impl Postgres {
+    /// Connect to the database at `url`, creating the table or adding missing columns as
+    /// needed.
+    pub fn open(url: &str) -> Result<Self, String> {
+        let (jobs, received) = mpsc::channel::<Job>();
+        let (connected, connection) = mpsc::channel();
+        let url = url.to_string();
+        thread::Builder::new()
+            .name(String::from("history-postgres"))
+            .spawn(move || {
+                let mut client = match connect(&url) {
+                    Ok(client) => client,
+                    Err(e) => {
+                        let _ = connected.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = connected.send(Ok(()));
+                for job in received {
+                    if client.is_closed() {
+                        match connect(&url) {
+                            Ok(reconnected) => {
+                                info!("Reconnected to the history database.");
+                                client = reconnected;
+                            }
+                            // Dropping the job tells the store it failed.
+                            Err(e) => {
+                                error!("{}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    job(&mut client);
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        connection
+            .recv()
+            .map_err(|_| String::from("The history database thread stopped"))??;
+        Ok(Self { jobs })
+    }
+
+    /// Run `query` on the connection and wait for its result.
+    fn call<R: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Client) -> Result<R, postgres::Error> + Send + 'static,
+    ) -> Result<R, String> {
+        let (sender, result) = mpsc::channel();
+        self.jobs
+            .send(Box::new(move |client| {
+                let _ = sender.send(query(client));
+            }))
+            .map_err(|_| String::from("The history database thread stopped"))?;
+        result
+            .recv()
+            .map_err(|_| String::from("Not connected to the history database"))?
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn connect(url: &str) -> Result<Client, String> {
+    let error = |e: postgres::Error| format!("Unable to connect to the history database: {}", e);
+    let mut client = Client::connect(url, NoTls).map_err(error)?;
+    let mut schema = String::from(
+        "CREATE TABLE IF NOT EXISTS samples (
+            id BIGINT PRIMARY KEY,
+            timestamp BIGINT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS samples_timestamp ON samples (timestamp);",
+    );
+    for metric in Metric::ALL {
+        schema.push_str(&format!(
+            "ALTER TABLE samples ADD COLUMN IF NOT EXISTS {} DOUBLE PRECISION;",
+            metric.name()
+        ));
+    }
+    client.batch_execute(&schema).map_err(error)?;
+    Ok(client)
+}
+
+fn read_row(row: &Row) -> Sample {
+    let mut values = [None; METRIC_COUNT];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = row.get(i + 2);
+    }
+    sample(row.get(0), row.get(1), values)
+}
+
+impl Database for Postgres {
+    fn insert(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let placeholders: Vec<String> = (1..=METRIC_COUNT + 2).map(|n| format!("${}", n)).collect();
+        let sql = format!(
+            "INSERT INTO samples (id, timestamp, {}) VALUES ({})",
+            columns(),
+            placeholders.join(", ")
+        );
+        let samples = samples.to_vec();
+        self.call(move |client| {
+            let mut transaction = client.transaction()?;
+            let statement = transaction.prepare(&sql)?;
+            for sample in samples {
+                let id = sample.id as i64;
+                let timestamp = sample.timestamp as i64;
+                let values = Metric::ALL.map(|metric| sample.get(metric));
+                let mut parameters: Vec<&(dyn ToSql + Sync)> = vec![&id, &timestamp];
+                parameters.extend(values.iter().map(|v| v as &(dyn ToSql + Sync)));
+                transaction.execute(&statement, &parameters)?;
+            }
+            transaction.commit()
+        })
+    }
+
+    fn select(&mut self, filter: Filter) -> Result<Vec<Sample>, String> {
+        let (condition, parameters) = condition(filter, |n| format!("${}", n));
+        let sql = format!(
+            "SELECT id, timestamp, {} FROM samples {}",
+            columns(),
+            condition
+        );
+        self.call(move |client| {
+            let parameters: Vec<&(dyn ToSql + Sync)> = parameters
+                .iter()
+                .map(|p| p as &(dyn ToSql + Sync))
+                .collect();
+            let rows = client.query(&sql, &parameters)?;
+            Ok(rows.iter().map(read_row).collect())
+        })
+    }
+
+    fn count(&mut self) -> Result<usize, String> {
+        self.call(|client| {
+            let count: i64 = client
+                .query_one("SELECT COUNT(*) FROM samples", &[])?
+                .get(0);
+            Ok(count as usize)
+        })
+    }
+
+    fn last_id(&mut self) -> Result<u64, String> {
+        self.call(|client| {
+            let id: i64 = client
+                .query_one("SELECT COALESCE(MAX(id), 0) FROM samples", &[])?
+                .get(0);
+            Ok(id as u64)
+        })
+    }
+
+    fn delete_before(&mut self, before: u64) -> Result<usize, String> {
+        let before = before as i64;
+        self.call(move |client| {
+            let deleted = client.execute("DELETE FROM samples WHERE timestamp < $1", &[&before])?;
+            Ok(deleted as usize)
+        })
+    }
+
+    fn delete_up_to(&mut self, id: u64) -> Result<usize, String> {
+        let id = id as i64;
+        self.call(move |client| {
+            let deleted = client.execute("DELETE FROM samples WHERE id <= $1", &[&id])?;
+            Ok(deleted as usize)
+        })
+    }
+
+    fn bytes(&mut self) -> Result<usize, String> {
+        self.call(|client| {
+            let bytes: i64 = client
+                .query_one("SELECT pg_total_relation_size('samples')", &[])?
+                .get(0);
+            Ok(bytes as usize)
+        })
+    }
+
+    fn vacuum(&mut self) -> Result<(), String> {
+        self.call(|client| client.batch_execute("VACUUM samples"))
+    }
+}
@@ -0,0 +1,144 @@
+//! The history in an SQLite database at `history.file`.
+
+use rusqlite::{params_from_iter, Connection, OptionalExtension, Row};
+
+use super::{columns, condition, sample, Database, Filter};
+use crate::history::{Metric, Sample, METRIC_COUNT};
+
+#[derive(Debug)]
+pub struct Sqlite {
+    connection: Connection,
+}
+
+impl Sqlite {
+    /// Open the database at `path`, creating it or adding missing columns as needed.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let error = |e: rusqlite::Error| format!("Unable to open {}: {}", path, e);
+        let connection = Connection::open(path).map_err(error)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS samples (
+                    id INTEGER PRIMARY KEY,
+                    timestamp INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS samples_timestamp ON samples (timestamp);",
+            )
+            .map_err(error)?;
+        let existing = connection
+            .prepare("SELECT name FROM pragma_table_info('samples')")
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(error)?;
+        for metric in Metric::ALL {
+            if !existing.iter().any(|name| name == metric.name()) {
+                connection
+                    .execute(
+                        &format!("ALTER TABLE samples ADD COLUMN {} REAL", metric.name()),
+                        [],
+                    )
+                    .map_err(error)?;
+            }
+        }
+        Ok(Self { connection })
+    }
+
+    fn query_usize(&self, sql: &str) -> Result<usize, String> {
+        let value: i64 = self
+            .connection
+            .query_row(sql, [], |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        Ok(value as usize)
+    }
+}
+
+fn read_row(row: &Row) -> rusqlite::Result<Sample> {
+    let mut values = [None; METRIC_COUNT];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = row.get(i + 2)?;
+    }
+    Ok(sample(row.get(0)?, row.get(1)?, values))
+}
+
+impl Database for Sqlite {
+    fn insert(&mut self, samples: &[Sample]) -> Result<(), String> {
+        let placeholders = vec!["?"; METRIC_COUNT + 2].join(", ");
+        let sql = format!(
+            "INSERT INTO samples (id, timestamp, {}) VALUES ({})",
+            columns(),
+            placeholders
+        );
+        let transaction = self.connection.transaction().map_err(|e| e.to_string())?;
+        {
+            let mut statement = transaction.prepare(&sql).map_err(|e| e.to_string())?;
+            for sample in samples {
+                let mut values = vec![
+                    rusqlite::types::Value::Integer(sample.id as i64),
+                    rusqlite::types::Value::Integer(sample.timestamp as i64),
+                ];
+                values.extend(Metric::ALL.map(|metric| match sample.get(metric) {
+                    Some(value) => rusqlite::types::Value::Real(value),
+                    None => rusqlite::types::Value::Null,
+                }));
+                statement
+                    .execute(params_from_iter(values))
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    fn select(&mut self, filter: Filter) -> Result<Vec<Sample>, String> {
+        let (condition, parameters) = condition(filter, |n| format!("?{}", n));
+        let sql = format!(
+            "SELECT id, timestamp, {} FROM samples {}",
+            columns(),
+            condition
+        );
+        let mut statement = self.connection.prepare(&sql).map_err(|e| e.to_string())?;
+        let samples = statement
+            .query_map(params_from_iter(parameters), read_row)
+            .and_then(|rows| rows.collect());
+        samples.map_err(|e| e.to_string())
+    }
+
+    fn count(&mut self) -> Result<usize, String> {
+        self.query_usize("SELECT COUNT(*) FROM samples")
+    }
+
+    fn last_id(&mut self) -> Result<u64, String> {
+        let id: Option<i64> = self
+            .connection
+            .query_row("SELECT MAX(id) FROM samples", [], |row| row.get(0))
+            .optional()
+            .map_err(|e| e.to_string())?
+            .flatten();
+        Ok(id.unwrap_or(0) as u64)
+    }
+
+    fn delete_before(&mut self, before: u64) -> Result<usize, String> {
+        self.connection
+            .execute("DELETE FROM samples WHERE timestamp < ?1", [before as i64])
+            .map_err(|e| e.to_string())
+    }
+
+    fn delete_up_to(&mut self, id: u64) -> Result<usize, String> {
+        self.connection
+            .execute("DELETE FROM samples WHERE id <= ?1", [id as i64])
+            .map_err(|e| e.to_string())
+    }
+
+    fn bytes(&mut self) -> Result<usize, String> {
+        self.query_usize(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        )
+    }
+
+    fn vacuum(&mut self) -> Result<(), String> {
+        self.connection
+            .execute_batch("VACUUM")
+            .map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,180 @@
+//! The status of the reader thread as a state machine. The reader goes from `Stopped`
+//! through `Starting` to `Running`, and ends up `Stopped` again through `Stopping`, or
+//! `Failed`. Reading on demand, it goes back and forth between `Running` and `Sleeping`.
+//! Only the transitions in `ThreadStatus::can_become` are allowed, and every transition is
+//! kept along with its time. The interval between telegrams is measured here as well, as
+//! it differs per meter: DSMR 5 meters send a telegram every second, DSMR 4 meters every
+//! 10 seconds.
+
+use std::{collections::VecDeque, time::Duration};
+
+use serde::Serialize;
+
+use crate::history::now_millis;
+
+/// Number of transitions kept.
+const MAX_TRANSITIONS: usize = 20;
+/// Number of gaps between telegrams the interval is measured over.
+const MAX_GAPS: usize = 9;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+pub enum ThreadStatus {
+    Stopped,
+    /// Opening the serial device.
+    Starting,
+    Running,
+    /// The serial port is closed until data is asked for, in on-demand mode.
+    Sleeping,
+    /// The serial device went away, the reader starts again when it comes back.
+    Disconnected,
+    Stopping,
+    Failed,
+}
+
+impl ThreadStatus {
+    /// Whether the reader may go from this status to `next`.
+    pub fn can_become(self, next: ThreadStatus) -> bool {
+        use ThreadStatus::*;
+        matches!(
+            (self, next),
+            (Stopped | Failed, Starting)
+                | (Starting, Running | Disconnected | Stopping | Failed)
+                | (Running, Sleeping | Disconnected | Stopping | Failed)
+                | (Sleeping, Starting | Stopping)
+                | (Disconnected, Starting | Stopping)
+                | (Stopping, Stopped | Failed)
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Transition {
+    pub from: ThreadStatus,
+    pub to: ThreadStatus,
+    /// Time of the transition in milliseconds since the unix epoch.
+    pub at: u64,
+}
+
+/// The current status of the reader and how it got there, as served by `/status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReaderStatus {
+    status: ThreadStatus,
+    /// Time of the last transition, or of startup.
+    since: u64,
+    /// The most recent transitions, oldest first.
+    transitions: VecDeque<Transition>,
+}
+
+impl Default for ReaderStatus {
+    fn default() -> Self {
+        Self {
+            status: ThreadStatus::Stopped,
+            since: now_millis(),
+            transitions: VecDeque::new(),
+        }
+    }
+}
+
+impl ReaderStatus {
+    pub fn current(&self) -> ThreadStatus {
+        self.status
+    }
+
+    /// Go to status `to`, if that's allowed from the current status.
+    pub fn transition(&mut self, to: ThreadStatus) -> Result<Transition, String> {
+        if !self.status.can_become(to) {
+            return Err(format!(
+                "the reader can't go from {:?} to {:?}",
+                self.status, to
+            ));
+        }
+        let transition = Transition {
+            from: self.status,
+            to,
+            at: now_millis(),
+        };
+        if self.transitions.len() >= MAX_TRANSITIONS {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(transition);
+        self.status = to;
+        self.since = transition.at;
+        Ok(transition)
+    }
+}
+
+/// The most recent error of a part of the daemon.
+#[derive(Clone, Debug, Serialize)]
+pub struct LastError {
+    /// Time of the error in milliseconds since the unix epoch.
+    pub at: u64,
+    pub message: String,
+}
+
+impl LastError {
+    pub fn now(message: impl Into<String>) -> Self {
+        Self {
+            at: now_millis(),
+            message: message.into(),
+        }
+    }
+}
+
+/// The interval between telegrams the meter actually sends.
+#[derive(Clone, Debug, Default)]
+pub struct TelegramInterval {
+    last: Option<u64>,
+    /// The most recent gaps between telegrams in milliseconds.
+    gaps: VecDeque<u64>,
+}
+
+impl TelegramInterval {
+    /// Note a telegram received at `at`, in milliseconds since the unix epoch.
+    pub fn observe(&mut self, at: u64) {
+        if let Some(last) = self.last {
+            if self.gaps.len() >= MAX_GAPS {
+                self.gaps.pop_front();
+            }
+            self.gaps.push_back(at.saturating_sub(last));
+        }
+        self.last = Some(at);
+    }
+
+    /// The median gap, so a reconnect or a missed telegram doesn't throw it off. `None`
+    /// until two telegrams have been received.
+    pub fn current(&self) -> Option<Duration> {
+        let mut gaps: Vec<u64> = self.gaps.iter().copied().collect();
+        gaps.sort_unstable();
+        gaps.get(gaps.len() / 2)
+            .map(|gap| Duration::from_millis(*gap))
+    }
+
+    /// A timeout of `configured` seconds, or else of `intervals` telegram intervals, or
+    /// `fallback` while the interval is unknown. `None` means no timeout, which is what a
+    /// configured 0 asks for.
+    pub fn timeout(
+        &self,
+        configured: Option<u64>,
+        intervals: u32,
+        fallback: Option<Duration>,
+    ) -> Option<Duration> {
+        match configured {
+            Some(0) => None,
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None => self
+                .current()
+                .map(|interval| interval * intervals)
+                .or(fallback),
+        }
+    }
+}
+
+impl Serialize for TelegramInterval {
+    /// The interval in milliseconds, or null while unknown.
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.current()
+            .map(|interval| interval.as_millis() as u64)
+            .serialize(serializer)
+    }
+}
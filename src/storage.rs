@@ -0,0 +1,222 @@
+//! Upkeep of what the daemon stores: the history store and the state files of the longer
+//! term figures. `GET /admin/db/stats` reports the size of every store, the number of
+//! records in it and whether its file still parses. For the sqlite and postgres engines
+//! the samples are counted in the database. `POST /admin/db/vacuum` drops the samples older
+//! than `history.retention`, gives back the space the history store holds beyond what it
+//! needs, which is a `VACUUM` of the database engines, and writes the state files again
+//! from memory, repairing a file damaged on disk. The stores are vacuumed every
+//! `storage.vacuum_interval` hours as well.
+
+use std::{
+    fs,
+    sync::Arc,
+    thread,
+    thread::JoinHandle,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use log::info;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    appdata::AppData,
+    config::{Config, HistoryEngine},
+    history::{now_millis, FlushStats},
+    lock::RecoverLock,
+    supervisor,
+};
+
+#[derive(Serialize)]
+struct Stats {
+    history: HistoryStats,
+    files: Vec<FileStats>,
+}
+
+#[derive(Serialize)]
+struct HistoryStats {
+    engine: HistoryEngine,
+    samples: usize,
+    capacity: usize,
+    /// Space taken by the samples in bytes, in memory or in the database.
+    bytes: usize,
+    /// Times of the oldest and newest sample in milliseconds since the unix epoch.
+    oldest: Option<u64>,
+    newest: Option<u64>,
+    /// How far the writes are behind, for engines that write behind.
+    flush: Option<FlushStats>,
+}
+
+#[derive(Serialize)]
+struct FileStats {
+    /// The setting naming the file, e.g. `netting.file`.
+    setting: &'static str,
+    path: String,
+    /// Size in bytes, `null` when the file wasn't written yet.
+    size: Option<u64>,
+    /// Time the file was last written in milliseconds since the unix epoch.
+    modified: Option<u64>,
+    /// Number of entries in the file, such as months or events.
+    records: Option<usize>,
+    /// Why the file can't be read, if it can't.
+    error: Option<String>,
+}
+
+/// The state files of `config` by the setting naming them.
+pub fn state_files(config: &Config) -> Vec<(&'static str, &str)> {
+    [
+        ("annual.state_file", &config.annual.state_file),
+        ("baseload.file", &config.baseload.file),
+        ("netting.file", &config.netting.file),
+        ("events.file", &config.events.file),
+        ("alerts.state_file", &config.alerts.state_file),
+        ("snapshots.file", &config.snapshots.file),
+        ("readings.file", &config.readings.file),
+        ("swap.file", &config.swap.file),
+        ("udp.register_file", &config.udp.register_file),
+    ]
+    .into_iter()
+    .filter_map(|(setting, path)| Some((setting, path.as_deref()?)))
+    .collect()
+}
+
+fn file_stats(setting: &'static str, path: &str) -> FileStats {
+    let mut stats = FileStats {
+        setting,
+        path: path.to_string(),
+        size: None,
+        modified: None,
+        records: None,
+        error: None,
+    };
+    let Ok(metadata) = fs::metadata(path) else {
+        return stats;
+    };
+    stats.size = Some(metadata.len());
+    stats.modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_millis() as u64);
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string());
+    match contents.and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string())) {
+        Ok(Value::Array(records)) => stats.records = Some(records.len()),
+        Ok(Value::Object(records)) => stats.records = Some(records.len()),
+        Ok(_) => stats.records = Some(1),
+        Err(e) => stats.error = Some(e),
+    }
+    stats
+}
+
+fn stats(appdata: &AppData) -> Stats {
+    let history = appdata.history.read_recover();
+    let history = HistoryStats {
+        engine: history.engine(),
+        samples: history.count(),
+        capacity: history.capacity(),
+        bytes: history.bytes(),
+        oldest: history.oldest().map(|sample| sample.timestamp),
+        newest: history.latest().map(|sample| sample.timestamp),
+        flush: history.flush_stats(),
+    };
+    let files = state_files(appdata.config())
+        .into_iter()
+        .map(|(setting, path)| file_stats(setting, path))
+        .collect();
+    Stats { history, files }
+}
+
+/// Write the state files again, drop the samples past their retention and shrink the
+/// history store.
+fn vacuum(appdata: &AppData) {
+    let retention = appdata.config().history.retention;
+    let mut history = appdata.history.write_recover();
+    if retention > 0 {
+        let before = now_millis().saturating_sub(retention.saturating_mul(3_600_000));
+        let pruned = history.prune(before);
+        if pruned > 0 {
+            info!("Dropped {} samples past their retention.", pruned);
+        }
+    }
+    history.vacuum();
+    drop(history);
+    appdata.annual.write_recover().save();
+    appdata.baseloads.read_recover().save();
+    appdata.months.read_recover().save();
+    appdata.events.read_recover().save();
+    appdata.alerts.read_recover().save();
+    appdata.snapshots.read_recover().save();
+    appdata.readings.read_recover().save();
+    appdata.swaps.write_recover().save();
+    appdata.save_clients();
+}
+
+/// Spawn a thread vacuuming the stores every configured interval.
+pub fn spawn_vacuum_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("vacuum", appdata, |appdata| {
+        let hours = appdata.config().storage.vacuum_interval;
+        if hours == 0 {
+            return;
+        }
+        loop {
+            thread::sleep(Duration::from_secs(hours.saturating_mul(3600)));
+            vacuum(appdata);
+            info!("Vacuumed the stores.");
+        }
+    })
+}
+
+/// Spawn a thread writing the samples waiting for the history journal, so they're written
+/// in time when no telegrams come in.
+pub fn spawn_flush_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("history_flush", appdata, |appdata| {
+        let config = &appdata.config().history;
+        if config.engine == HistoryEngine::Memory {
+            return;
+        }
+        loop {
+            thread::sleep(Duration::from_secs(config.flush_interval.max(1)));
+            let _ = appdata.history.write_recover().flush();
+        }
+    })
+}
+
+fn respond(status: StatusCode, message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+}
+
+/// Handler for `GET /admin/db/stats` and `POST /admin/db/vacuum`.
+pub async fn handler(
+    req: Request<Body>,
+    appdata: Arc<AppData>,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let vacuuming = match (req.uri().path().trim_end_matches('/'), req.method()) {
+        ("/admin/db/stats", &Method::GET) => false,
+        ("/admin/db/vacuum", &Method::POST) => true,
+        ("/admin/db/stats" | "/admin/db/vacuum", _) => {
+            return respond(StatusCode::METHOD_NOT_ALLOWED, "Error: method not allowed.")
+        }
+        _ => return respond(StatusCode::NOT_FOUND, "Error: not found."),
+    };
+    let stats = tokio::task::spawn_blocking(move || {
+        if vacuuming {
+            vacuum(&appdata);
+            info!("Vacuumed the stores on request.");
+        }
+        stats(&appdata)
+    })
+    .await;
+    let json = stats
+        .map_err(|e| e.to_string())
+        .and_then(|stats| serde_json::to_string(&stats).map_err(|e| e.to_string()));
+    match json {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, &format!("Error: {}", e)),
+    }
+}
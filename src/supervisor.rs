@@ -0,0 +1,106 @@
+//! Panics in background jobs. A panic is logged with its backtrace and counted in
+//! `/metrics`, and the job it happened in is started again, rather than leaving the
+//! daemon answering HTTP requests without reading the meter or feeding its sinks.
+
+use std::{
+    backtrace::Backtrace,
+    collections::BTreeMap,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+use crate::appdata::AppData;
+
+/// Wait before restarting a job after its first panic. Doubles with every panic in a row.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Longest wait before restarting a job. A job that ran this long before it panicked is
+/// restarted after `RESTART_DELAY` again.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+static PANICS: AtomicU64 = AtomicU64::new(0);
+/// Number of restarts of every job that was restarted, by name.
+static RESTARTS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// Log panics along with a backtrace, and count them.
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        PANICS.fetch_add(1, Ordering::Relaxed);
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        let location = info
+            .location()
+            .map(|location| location.to_string())
+            .unwrap_or_default();
+        error!(
+            "Thread {} panicked at {}: {}\n{}",
+            thread::current().name().unwrap_or("unnamed"),
+            location,
+            payload,
+            Backtrace::force_capture()
+        );
+    }));
+}
+
+/// Number of panics since startup.
+pub fn panics() -> u64 {
+    PANICS.load(Ordering::Relaxed)
+}
+
+/// Number of times the job named `name` was restarted after a panic.
+pub fn restarts(name: &str) -> u64 {
+    RESTARTS
+        .lock()
+        .ok()
+        .and_then(|restarts| restarts.get(name).copied())
+        .unwrap_or_default()
+}
+
+/// Run `job` in the current thread until it returns, running it again whenever it panics.
+pub fn run(name: &str, appdata: &Arc<AppData>, job: impl Fn(&Arc<AppData>)) {
+    let mut delay = RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        if panic::catch_unwind(AssertUnwindSafe(|| job(appdata))).is_ok() {
+            return;
+        }
+        // The job may have held a lock when it panicked.
+        appdata.clear_poison();
+        if let Ok(mut restarts) = RESTARTS.lock() {
+            *restarts.entry(name.to_string()).or_default() += 1;
+        }
+        if started.elapsed() >= MAX_RESTART_DELAY {
+            delay = RESTART_DELAY;
+        }
+        error!(
+            "{} stopped on a panic, restarting in {} seconds.",
+            name,
+            delay.as_secs()
+        );
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+/// Spawn a thread running `job`, running it again whenever it panics.
+pub fn spawn(
+    name: &str,
+    appdata: Arc<AppData>,
+    job: impl Fn(&Arc<AppData>) + Send + 'static,
+) -> Result<JoinHandle<()>, io::Error> {
+    let name = name.to_string();
+    thread::Builder::new()
+        .name(name.clone())
+        .spawn(move || run(&name, &appdata, job))
+}
@@ -0,0 +1,240 @@
+//! Replaced meters. When the equipment id of the meter changes, or a meter total goes
+//! down, the meter was swapped or reset, which is recorded as an event. With `swap.auto`,
+//! the totals of the new meter are continued from where the old one left off, so the
+//! monthly and annual totals and the costs made from them don't jump. Offsets configured
+//! in `swap.offsets` for the equipment id of a meter are used instead of detected ones,
+//! e.g. for a meter replaced while dsmrd wasn't reading it.
+//!
+//! The offsets only apply to the samples, the state served at `/` shows what the meter
+//! does. The swaps and offsets are kept in `swap.file` and served at `/swaps`.
+
+use std::{fs, sync::Arc};
+
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    appdata::AppData,
+    config::SwapConfig,
+    events::EventKind,
+    history::{now_millis, Metric, Sample},
+    lock::RecoverLock,
+    model::MeterState,
+};
+
+/// The meter totals, in the order they are kept in.
+const TOTALS: [Metric; 5] = [
+    Metric::EnergyDeliveredTariff1,
+    Metric::EnergyDeliveredTariff2,
+    Metric::EnergyReceivedTariff1,
+    Metric::EnergyReceivedTariff2,
+    Metric::GasDelivered,
+];
+
+/// Milliseconds between writes of the last totals to the file.
+const SAVE_INTERVAL: u64 = 3_600_000;
+
+/// A value for each of the meter totals.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Totals {
+    /// Energy delivered to the client per tariff in kWh.
+    pub delivered: [f64; 2],
+    /// Energy delivered by the client per tariff in kWh.
+    pub received: [f64; 2],
+    /// Gas delivered in m³.
+    pub gas: f64,
+}
+
+impl Totals {
+    fn get(&self, index: usize) -> f64 {
+        match index {
+            0 | 1 => self.delivered[index],
+            2 | 3 => self.received[index - 2],
+            _ => self.gas,
+        }
+    }
+
+    fn set(&mut self, index: usize, value: f64) {
+        match index {
+            0 | 1 => self.delivered[index] = value,
+            2 | 3 => self.received[index - 2] = value,
+            _ => self.gas = value,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Swap {
+    /// Time the swap was noticed in milliseconds since the unix epoch.
+    pub timestamp: u64,
+    /// Equipment ids of the old and the new meter, the same when a total went down.
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// The offsets continuing the totals from the swap on, used with `swap.auto`.
+    pub offsets: Totals,
+}
+
+/// What is kept in the configured file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// Equipment id of the meter read last.
+    meter: Option<String>,
+    /// Totals of the last telegram as the meter showed them.
+    last: [Option<f64>; 5],
+    /// Offsets added to the totals of the current meter.
+    offsets: Totals,
+    swaps: Vec<Swap>,
+}
+
+#[derive(Debug)]
+pub struct Swaps {
+    file: Option<String>,
+    state: State,
+    /// Time the file was last written.
+    saved_at: u64,
+}
+
+impl Swaps {
+    pub fn new(config: &SwapConfig) -> Self {
+        let state = config
+            .file
+            .as_ref()
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(state) => serde_json::from_str(&state)
+                    .map_err(|e| error!("Unable to parse meter swaps {}: {}", path, e))
+                    .ok(),
+                Err(e) => {
+                    info!("No meter swaps read from {}: {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            file: config.file.clone(),
+            state,
+            saved_at: 0,
+        }
+    }
+
+    /// Look for a swap between the previous state and `state`, and add the offsets to the
+    /// totals of its `sample`. Returns the swap if there was one.
+    fn observe(
+        &mut self,
+        config: &SwapConfig,
+        state: &MeterState,
+        sample: &mut Sample,
+    ) -> Option<Swap> {
+        let previous = self.state.meter.clone();
+        let meter = state.equipment_id.clone().or(previous.clone());
+        let replaced = previous.is_some() && meter != previous;
+        let mut swapped = replaced;
+        let mut offsets = self.state.offsets;
+        for (index, metric) in TOTALS.into_iter().enumerate() {
+            let Some(value) = sample.get(metric) else {
+                continue;
+            };
+            // The gas meter has an id of its own, it stays when the electricity meter is
+            // replaced.
+            let rebase = replaced && metric != Metric::GasDelivered;
+            if let Some(last) = self.state.last[index].filter(|last| value < *last || rebase) {
+                swapped = true;
+                // Meters count in thousandths, rounding keeps the offsets readable.
+                let offset = offsets.get(index) + last - value;
+                offsets.set(index, (offset * 1000.0).round() / 1000.0);
+            }
+            self.state.last[index] = Some(value);
+        }
+        self.state.meter = meter.clone();
+        if config.auto {
+            self.state.offsets = offsets;
+        }
+
+        let applied = meter
+            .as_ref()
+            .and_then(|meter| config.offsets.get(meter))
+            .unwrap_or(&self.state.offsets);
+        for (index, metric) in TOTALS.into_iter().enumerate() {
+            if let Some(value) = sample.get(metric) {
+                sample.set(metric, Some(value + applied.get(index)));
+            }
+        }
+
+        let swap = swapped.then(|| Swap {
+            timestamp: now_millis(),
+            from: previous,
+            to: meter,
+            offsets,
+        });
+        if let Some(swap) = &swap {
+            self.state.swaps.push(swap.clone());
+        }
+        swap
+    }
+
+    pub fn save(&mut self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        self.saved_at = now_millis();
+        let result = serde_json::to_string(&self.state)
+            .map_err(|e| e.to_string())
+            .and_then(|state| fs::write(path, state).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Unable to write meter swaps {}: {}", path, e);
+        }
+    }
+}
+
+/// Continue the totals of `sample`, made from `state`, across meter swaps, recording a
+/// swap as an event.
+pub fn continue_totals(appdata: &AppData, state: &MeterState, sample: &mut Sample) {
+    let config = &appdata.config().swap;
+    let mut swaps = appdata.swaps.write_recover();
+    let swap = swaps.observe(config, state, sample);
+    // Besides on a swap, the file is written once in a while. The totals in it only need
+    // to be lower than those of the next telegram to notice a swap while dsmrd was down.
+    if swap.is_some() || now_millis().saturating_sub(swaps.saved_at) >= SAVE_INTERVAL {
+        swaps.save();
+    }
+    drop(swaps);
+    let Some(swap) = swap else {
+        return;
+    };
+
+    let description = match (&swap.from, &swap.to) {
+        (Some(from), Some(to)) if from != to => {
+            format!("Meter {} was replaced by meter {}.", from, to)
+        }
+        _ => String::from("A meter total went down, the meter was reset or replaced."),
+    };
+    let description = if config.auto {
+        format!(
+            "{} Its totals are continued from the last ones.",
+            description
+        )
+    } else {
+        description
+    };
+    warn!("{}", description);
+    appdata.events.write_recover().record(
+        EventKind::MeterSwap,
+        String::from("Meter swap"),
+        description,
+    );
+}
+
+/// Handler for `/swaps`, listing the meter swaps and the offsets in use.
+pub async fn handler(appdata: Arc<AppData>) -> Result<Response<Body>, hyper::http::Error> {
+    let json = serde_json::to_string(&appdata.swaps.read_recover().state);
+    match json {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
@@ -0,0 +1,169 @@
+//! Resource usage of the daemon itself, served at `/system` and exported in `/metrics`, so
+//! it's easy to tell whether dsmrd is what keeps a small board busy. Read from `/proc`.
+
+use std::{
+    fmt::Write,
+    fs, io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use serde::Serialize;
+
+/// Clock ticks per second in `/proc`. Fixed at 100 for user space on every Linux platform.
+const TICKS_PER_SECOND: f64 = 100.0;
+
+/// CPU time at the previous `/system` request, to report CPU usage since then.
+static PREVIOUS: Mutex<Option<(Instant, f64)>> = Mutex::new(None);
+
+#[derive(Debug, Serialize)]
+pub struct Usage {
+    pub rss_bytes: u64,
+    /// CPU time spent in user and kernel mode.
+    pub cpu_seconds: f64,
+    pub open_fds: usize,
+    pub threads: u64,
+    pub uptime_seconds: f64,
+}
+
+/// Read the current resource usage.
+pub fn usage() -> io::Result<Usage> {
+    let stat = fs::read_to_string("/proc/self/stat")?;
+    // The command name may hold spaces, so the fields are counted from the end of it.
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .map(|(_, fields)| fields.split_whitespace().collect())
+        .unwrap_or_default();
+    let field = |index: usize| -> io::Result<f64> {
+        fields
+            .get(index)
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(|| io::Error::other("unexpected /proc/self/stat format"))
+    };
+    let cpu_seconds = (field(11)? + field(12)?) / TICKS_PER_SECOND;
+    let started = field(19)? / TICKS_PER_SECOND;
+
+    let boot = fs::read_to_string("/proc/uptime")?;
+    let since_boot: f64 = boot
+        .split_whitespace()
+        .next()
+        .and_then(|uptime| uptime.parse().ok())
+        .ok_or_else(|| io::Error::other("unexpected /proc/uptime format"))?;
+
+    let status = fs::read_to_string("/proc/self/status")?;
+    let status_field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or_default()
+    };
+
+    Ok(Usage {
+        rss_bytes: status_field("VmRSS:") * 1024,
+        cpu_seconds,
+        open_fds: fs::read_dir("/proc/self/fd")?.count(),
+        threads: status_field("Threads:"),
+        // Both have a resolution of 10 ms.
+        uptime_seconds: ((since_boot - started).max(0.0) * 100.0).round() / 100.0,
+    })
+}
+
+/// Render the usage in the Prometheus text format, under the names Prometheus client
+/// libraries use for process metrics.
+pub fn render(usage: &Usage) -> String {
+    let mut body = String::new();
+    let metrics = [
+        (
+            "process_resident_memory_bytes",
+            "gauge",
+            "Resident memory size in bytes.",
+            usage.rss_bytes as f64,
+        ),
+        (
+            "process_cpu_seconds_total",
+            "counter",
+            "Total user and system CPU time spent in seconds.",
+            usage.cpu_seconds,
+        ),
+        (
+            "process_open_fds",
+            "gauge",
+            "Number of open file descriptors.",
+            usage.open_fds as f64,
+        ),
+        (
+            "process_threads",
+            "gauge",
+            "Number of OS threads in the process.",
+            usage.threads as f64,
+        ),
+        (
+            "process_uptime_seconds",
+            "gauge",
+            "Time since the process started in seconds.",
+            usage.uptime_seconds,
+        ),
+    ];
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} {}", name, kind);
+        let _ = writeln!(body, "{} {}", name, value);
+    }
+    body
+}
+
+#[derive(Serialize)]
+struct System {
+    #[serde(flatten)]
+    usage: Usage,
+    /// CPU usage since the previous request, or since startup for the first one. 100 is
+    /// a single core kept busy.
+    cpu_percent: f64,
+}
+
+/// Handler for `/system`.
+pub async fn handler() -> Result<Response<Body>, hyper::http::Error> {
+    let usage = match usage() {
+        Ok(usage) => usage,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(format!("Error: unable to read usage: {}", e)))
+        }
+    };
+    let now = Instant::now();
+    let (elapsed, cpu_seconds) = match PREVIOUS.lock() {
+        Ok(mut previous) => {
+            let (elapsed, cpu_seconds) = match *previous {
+                Some((at, cpu_seconds)) => (now - at, usage.cpu_seconds - cpu_seconds),
+                None => (
+                    Duration::from_secs_f64(usage.uptime_seconds),
+                    usage.cpu_seconds,
+                ),
+            };
+            *previous = Some((now, usage.cpu_seconds));
+            (elapsed, cpu_seconds)
+        }
+        Err(_) => (
+            Duration::from_secs_f64(usage.uptime_seconds),
+            usage.cpu_seconds,
+        ),
+    };
+    let cpu_percent = match elapsed.as_secs_f64() {
+        0.0 => 0.0,
+        elapsed => (cpu_seconds / elapsed * 1000.0).round() / 10.0,
+    };
+
+    match serde_json::to_string(&System { usage, cpu_percent }) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
@@ -0,0 +1,52 @@
+//! Bytes sent to a destination in total and today, for keeping an eye on metered uplinks.
+//! Only the payload is counted, not the overhead of the protocols it's sent over.
+
+use chrono::{Local, NaiveDate};
+use serde::{Serialize, Serializer};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Traffic {
+    total: u64,
+    today: u64,
+    /// The local day `today` counts bytes of.
+    day: Option<NaiveDate>,
+}
+
+impl Traffic {
+    pub fn add(&mut self, bytes: u64) {
+        let day = Local::now().date_naive();
+        if self.day != Some(day) {
+            self.day = Some(day);
+            self.today = 0;
+        }
+        self.total += bytes;
+        self.today += bytes;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Bytes sent since midnight.
+    pub fn today(&self) -> u64 {
+        match self.day {
+            Some(day) if day == Local::now().date_naive() => self.today,
+            _ => 0,
+        }
+    }
+}
+
+impl Serialize for Traffic {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Totals {
+            total: u64,
+            today: u64,
+        }
+        Totals {
+            total: self.total,
+            today: self.today(),
+        }
+        .serialize(serializer)
+    }
+}
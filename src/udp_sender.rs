@@ -1,14 +1,88 @@
 use event_listener::Listener;
 
 use std::{
-    net::UdpSocket,
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
     sync::{Arc, RwLock},
     thread::{self, JoinHandle},
+    time::Instant,
 };
 
-use log::debug;
+use log::{debug, info, warn};
+use serde_json::{json, Map, Value};
 
-use crate::{appdata::AppData, reader::ReaderData};
+use crate::{
+    appdata::AppData,
+    encryption::{self, Key},
+    lock::RecoverLock,
+    output,
+    reader::ReaderData,
+    signature, supervisor,
+};
+
+/// What has been sent to a client.
+struct Stream {
+    last_sent: Instant,
+    /// Sequence number and content of the last full state sent, deltas are relative to it.
+    keyframe: Option<(u64, Value)>,
+    /// Packets sent since the keyframe.
+    deltas: u32,
+    /// Whether packets are skipped because the client reached its daily quota.
+    over_quota: bool,
+}
+
+impl Stream {
+    /// The packet to send for `state`. With keyframes enabled, every packet carries its
+    /// type and the sequence number of the state. Deltas hold the changes since the last
+    /// keyframe, rather than since the last delta, so a lost delta doesn't matter. A client
+    /// that lost a keyframe ignores deltas until the next one.
+    fn packet(&mut self, seq: u64, state: &Value, keyframe_interval: u32) -> Value {
+        if keyframe_interval == 0 {
+            return state.clone();
+        }
+        match &self.keyframe {
+            Some((keyframe, previous)) if self.deltas + 1 < keyframe_interval => {
+                self.deltas += 1;
+                json!({
+                    "type": "delta",
+                    "seq": seq,
+                    "keyframe": keyframe,
+                    "changes": diff(previous, state).unwrap_or_else(|| json!({})),
+                })
+            }
+            _ => {
+                self.keyframe = Some((seq, state.clone()));
+                self.deltas = 0;
+                json!({ "type": "keyframe", "seq": seq, "state": state })
+            }
+        }
+    }
+}
+
+/// The changes from `old` to `new` as a JSON merge patch (RFC 7386): changed fields get
+/// their new value, removed fields are set to null. None if nothing changed.
+fn diff(old: &Value, new: &Value) -> Option<Value> {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut changes = Map::new();
+            for (key, value) in new {
+                let change = match old.get(key) {
+                    Some(previous) => diff(previous, value),
+                    None => Some(value.clone()),
+                };
+                if let Some(change) = change {
+                    changes.insert(key.clone(), change);
+                }
+            }
+            for key in old.keys().filter(|key| !new.contains_key(*key)) {
+                changes.insert(key.clone(), Value::Null);
+            }
+            (!changes.is_empty()).then_some(Value::Object(changes))
+        }
+        (old, new) if old == new => None,
+        (_, new) => Some(new.clone()),
+    }
+}
 
 /// Spawns a thread that sends new dsmr_data to registered clients using UDP packets.
 /// Waits for an EventListener to signal new data, then reads data from the RwLock and
@@ -26,27 +100,142 @@ pub fn spawn_udp_sender(
             .expect("Failed to get local address")
             .port();
         println!("UDP service started on port: {}", assigned_port);
+        match sock.try_clone() {
+            Ok(sock) => appdata.set_udp_socket(sock),
+            Err(e) => warn!("Unable to share the UDP socket: {}", e),
+        }
 
-        // inner loop
-        loop {
-            let listener = appdata.event_listener();
-            listener.wait();
+        match sock.try_clone() {
+            Ok(sock) => {
+                let appdata = appdata.clone();
+                if let Err(e) = thread::Builder::new().spawn(move || receive_hellos(sock, appdata))
+                {
+                    warn!("Unable to spawn UDP hello thread: {}", e);
+                }
+            }
+            Err(e) => warn!("Unable to receive UDP hello datagrams: {}", e),
+        }
 
-            debug!("Received data");
-            let Ok(dsmr_data) = reader_data.read() else {
-                continue;
-            };
-            let Ok(addresses) = appdata.client_register.as_ref().read() else {
-                continue;
-            };
+        // A panic while sending restarts sending on the same socket, so clients keep
+        // the port they registered with.
+        supervisor::run("udp-sender", &appdata, |appdata| {
+            // What each client was sent, to keep to their intervals and to send deltas.
+            let mut streams: HashMap<SocketAddr, Stream> = HashMap::new();
+            let keyframe_interval = appdata.config().udp.keyframe_interval;
+            let hmac_key = appdata.config().udp.hmac_key.clone();
+
+            // inner loop
+            loop {
+                let listener = appdata.event_listener();
+                listener.wait();
+
+                debug!("Received data");
+                let dsmr_data = reader_data.read_recover();
+                let clients = appdata.client_register.read_recover();
+                streams.retain(|addr, _| clients.iter().any(|client| client.addr == *addr));
 
-            if let Ok(ser_data) = serde_json::to_vec(&dsmr_data.dsmr_state) {
-                for addr in addresses.iter() {
-                    if let Ok(length) = sock.send_to(&ser_data, addr) {
-                        debug!("Sent {} bytes to {}", length, addr)
+                let output = &appdata.config().output;
+                let Ok(state) =
+                    output::render_state(&dsmr_data, output.missing_values, output.format)
+                else {
+                    continue;
+                };
+                for client in clients.iter() {
+                    if streams
+                        .get(&client.addr)
+                        .is_some_and(|stream| stream.last_sent.elapsed() < client.interval)
+                    {
+                        continue;
+                    }
+                    let stream = streams.entry(client.addr).or_insert_with(|| Stream {
+                        last_sent: Instant::now(),
+                        keyframe: None,
+                        deltas: 0,
+                        over_quota: false,
+                    });
+                    let packet = stream.packet(dsmr_data.sequence, &state, keyframe_interval);
+                    let ser_data = match encode(&packet, hmac_key.as_deref(), client.key) {
+                        Ok(ser_data) => ser_data,
+                        Err(e) => {
+                            warn!("Unable to encode packet for {}: {}", client.addr, e);
+                            continue;
+                        }
+                    };
+                    if !appdata.count_udp_traffic(client.addr, ser_data.len() as u64) {
+                        if !stream.over_quota {
+                            warn!("Client {} reached its daily quota.", client.addr);
+                            stream.over_quota = true;
+                        }
+                        // Deltas would refer to a keyframe the client never got.
+                        stream.keyframe = None;
+                        continue;
+                    }
+                    stream.over_quota = false;
+                    if let Ok(length) = sock.send_to(&ser_data, client.addr) {
+                        debug!("Sent {} bytes to {}", length, client.addr);
+                        stream.last_sent = Instant::now();
                     };
                 }
             }
-        }
+        });
     })
 }
+
+/// `packet` as sent: signed when an HMAC key is configured, then encrypted when the
+/// client has a key.
+fn encode(packet: &Value, hmac_key: Option<&str>, key: Option<Key>) -> Result<Vec<u8>, String> {
+    let mut ser_data = serde_json::to_vec(packet).map_err(|e| e.to_string())?;
+    if let Some(hmac_key) = hmac_key {
+        ser_data = signature::sign(hmac_key, &ser_data)?;
+    }
+    match key {
+        Some(key) => encryption::encrypt(&key, &ser_data),
+        None => Ok(ser_data),
+    }
+}
+
+/// Send a `{"hello": "<address>"}` datagram to a newly registered client, from the port its
+/// packets will come from, so it can tell right away whether they get through.
+pub fn send_hello(appdata: &AppData, client_addr: SocketAddr) -> Result<(), String> {
+    let sock = appdata.udp_socket().ok_or("UDP service is not available")?;
+    let hello = json!({ "hello": client_addr.to_string() });
+    let config = &appdata.config().udp;
+    let key = encryption::key_for(&config.keys, client_addr.ip());
+    let ser_data = encode(&hello, config.hmac_key.as_deref(), key)?;
+    if !appdata.count_udp_traffic(client_addr, ser_data.len() as u64) {
+        return Err(String::from("the client reached its daily quota"));
+    }
+    sock.send_to(&ser_data, client_addr)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Complete registrations started with `/subscribe`. Clients send `hello <token>` from the
+/// address they want to receive on. Only successful registrations get an answer, so the
+/// socket can't be used to reflect traffic to someone else.
+fn receive_hellos(sock: UdpSocket, appdata: Arc<AppData>) {
+    let mut buffer = [0; 128];
+    loop {
+        let (length, client_addr) = match sock.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Unable to receive UDP datagram: {}", e);
+                continue;
+            }
+        };
+        let Some(token) = std::str::from_utf8(&buffer[..length])
+            .ok()
+            .and_then(|hello| hello.trim().strip_prefix("hello "))
+        else {
+            debug!("Ignoring UDP datagram from {}", client_addr);
+            continue;
+        };
+        match appdata.redeem_token(token.trim(), client_addr) {
+            Ok(_) => {
+                info!("Registered client {} using a token", client_addr);
+                let _ = sock.send_to(b"registered", client_addr);
+            }
+            Err(e) => debug!("Refused hello from {}: {}", client_addr, e),
+        }
+    }
+}
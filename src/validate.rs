@@ -0,0 +1,265 @@
+//! Troubleshooting of DSMR telegrams at `POST /validate`. The body is a raw telegram, as
+//! captured from the P1 port, and the response reports the checksum and how every line
+//! parses, to help track down flaky cables and meters sending odd data.
+
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+
+use crate::{
+    model::MeterState,
+    reader::{apply_unknown, telegram_to_state},
+};
+
+/// Telegrams are read into a buffer of this size, longer ones can never be read.
+pub const READOUT_SIZE: usize = 2048;
+/// Largest body accepted, leaving room for a telegram with some noise around it.
+const MAX_BODY: usize = 4 * READOUT_SIZE;
+
+#[derive(Serialize)]
+struct Report {
+    /// Whether the reader would accept the telegram.
+    valid: bool,
+    /// The identification line, e.g. `/ISK5\2M550E-1012`.
+    header: Option<String>,
+    checksum: Checksum,
+    lines: Vec<LineReport>,
+    /// The state the telegram converts to, if it is valid.
+    state: Option<MeterState>,
+    problems: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Checksum {
+    /// The checksum sent after the `!`, in hex.
+    expected: Option<String>,
+    /// The CRC16 of everything from the `/` up to and including the `!`, in hex.
+    computed: Option<String>,
+    matches: bool,
+}
+
+#[derive(Serialize)]
+struct LineReport {
+    line: usize,
+    text: String,
+    obis: Option<String>,
+    result: LineResult,
+    /// The parsed value, or why the line doesn't parse.
+    detail: String,
+}
+
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LineResult {
+    Ok,
+    /// A well formed line with an OBIS code the reader doesn't know.
+    Unknown,
+    Invalid,
+}
+
+/// Handler for `/validate`.
+pub async fn handler(req: Request<Body>) -> Result<Response<Body>, hyper::http::Error> {
+    if req.method() != Method::POST {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::from("Error: method not allowed."));
+    }
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) if body.len() > MAX_BODY => {
+            return Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Body::from(format!(
+                    "Error: telegrams can be at most {} bytes.",
+                    READOUT_SIZE
+                )))
+        }
+        Ok(body) => body,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!(
+                    "Error: unable to read request body: {}",
+                    e
+                )))
+        }
+    };
+    match serde_json::to_string(&validate(&body)) {
+        Ok(json) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Error: {}", e))),
+    }
+}
+
+fn validate(body: &[u8]) -> Report {
+    let mut problems = Vec::new();
+    let text = match std::str::from_utf8(body) {
+        Ok(text) => text,
+        Err(e) => {
+            problems.push(format!("Telegram is not valid text: {}", e));
+            return Report {
+                valid: false,
+                header: None,
+                checksum: Checksum {
+                    expected: None,
+                    computed: None,
+                    matches: false,
+                },
+                lines: Vec::new(),
+                state: None,
+                problems,
+            };
+        }
+    };
+
+    // Anything before the `/` is noise from a previous telegram or the line settling.
+    let telegram = match text.find('/') {
+        Some(0) => text,
+        Some(start) => {
+            problems.push(format!("Skipped {} bytes before the '/'", start));
+            &text[start..]
+        }
+        None => {
+            problems.push(String::from("No '/' marking the start of the telegram"));
+            text
+        }
+    };
+    if telegram.len() > READOUT_SIZE {
+        problems.push(format!(
+            "Telegram is {} bytes, the reader only reads {}",
+            telegram.len(),
+            READOUT_SIZE
+        ));
+    }
+    if telegram.contains('\n') && !telegram.contains("\r\n") {
+        problems.push(String::from(
+            "Lines end in LF instead of CRLF, which changes the checksum",
+        ));
+    }
+
+    let checksum = check_crc(telegram, &mut problems);
+    let lines = check_lines(telegram);
+    let header = telegram.lines().next().map(str::to_string);
+    let invalid = lines
+        .iter()
+        .filter(|line| line.result != LineResult::Ok)
+        .count();
+    if invalid > 0 {
+        problems.push(format!("{} lines don't parse", invalid));
+    }
+
+    let state = if telegram.len() <= READOUT_SIZE {
+        match convert(telegram) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                // The problems found so far already explain why.
+                if problems.is_empty() {
+                    problems.push(e);
+                }
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    Report {
+        valid: state.is_some(),
+        header,
+        checksum,
+        lines,
+        state,
+        problems,
+    }
+}
+
+fn check_crc(telegram: &str, problems: &mut Vec<String>) -> Checksum {
+    let Some(end) = telegram.find('!') else {
+        problems.push(String::from("No '!' marking the end of the telegram"));
+        return Checksum {
+            expected: None,
+            computed: None,
+            matches: false,
+        };
+    };
+    let (data, postfix) = telegram.split_at(end + 1);
+    let computed = crc16_arc(data.as_bytes());
+    let expected = postfix
+        .get(..4)
+        .and_then(|crc| u16::from_str_radix(crc, 16).ok());
+    match expected {
+        Some(expected) if expected != computed => problems.push(String::from(
+            "Checksum mismatch, the telegram was damaged on its way",
+        )),
+        Some(_) => {}
+        None => problems.push(String::from("No checksum after the '!'")),
+    }
+    Checksum {
+        expected: expected.map(|crc| format!("{:04X}", crc)),
+        computed: Some(format!("{:04X}", computed)),
+        matches: expected == Some(computed),
+    }
+}
+
+/// Parse every line holding a COSEM object, the lines between the header and the `!`.
+fn check_lines(telegram: &str) -> Vec<LineReport> {
+    telegram
+        .lines()
+        .enumerate()
+        .skip(1)
+        .take_while(|(_, text)| !text.starts_with('!'))
+        .filter(|(_, text)| !text.is_empty())
+        .map(|(index, text)| {
+            let obis = text.find('(').map(|end| text[..end].to_string());
+            let (result, detail) = match dsmr5::OBIS::parse(text) {
+                Ok(object) => (LineResult::Ok, format!("{:?}", object)),
+                Err(dsmr5::Error::UnknownObis)
+                    if apply_unknown(&mut MeterState::default(), text) =>
+                {
+                    (LineResult::Ok, String::from("Reactive energy or power"))
+                }
+                Err(dsmr5::Error::UnknownObis) => {
+                    (LineResult::Unknown, String::from("Unknown OBIS code"))
+                }
+                Err(e) => (LineResult::Invalid, format!("{:?}", e)),
+            };
+            LineReport {
+                line: index + 1,
+                text: text.to_string(),
+                obis,
+                result,
+                detail,
+            }
+        })
+        .collect()
+}
+
+/// Convert the telegram the way the reader does.
+pub fn convert(telegram: &str) -> Result<MeterState, String> {
+    let mut readout = dsmr5::Readout {
+        buffer: [0; READOUT_SIZE],
+    };
+    readout.buffer[..telegram.len()].copy_from_slice(telegram.as_bytes());
+    readout
+        .to_telegram()
+        .and_then(|_| telegram_to_state(&readout))
+        .map_err(|e| format!("Reader refuses the telegram: {:?}", e))
+}
+
+/// CRC16/ARC, the checksum used by DSMR telegrams.
+fn crc16_arc(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
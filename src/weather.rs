@@ -0,0 +1,131 @@
+//! Outdoor temperature, polled from a weather API or followed on an MQTT topic, so gas
+//! usage can be compared between cold and mild weeks.
+
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{debug, error, info};
+use serde_json::Value;
+
+use crate::{
+    appdata::AppData, dial::Dialer, history::now_millis, http_client::HttpClient,
+    lock::RecoverLock, sink::header_refs, supervisor,
+};
+
+/// Number of readings kept, over two months at the default interval.
+const CAPACITY: usize = 6_000;
+
+/// Temperature readings in °C, oldest first.
+#[derive(Debug, Default)]
+pub struct Temperatures {
+    readings: VecDeque<(u64, f64)>,
+}
+
+impl Temperatures {
+    pub fn push(&mut self, timestamp: u64, celsius: f64) {
+        if self.readings.len() >= CAPACITY {
+            self.readings.pop_front();
+        }
+        self.readings.push_back((timestamp, celsius));
+    }
+
+    /// Mean of the readings with a timestamp in `[from, to]`.
+    pub fn mean(&self, from: u64, to: u64) -> Option<f64> {
+        let (sum, count) = self
+            .readings
+            .iter()
+            .filter(|(timestamp, _)| (from..=to).contains(timestamp))
+            .fold((0.0, 0), |(sum, count), (_, celsius)| {
+                (sum + celsius, count + 1)
+            });
+        (count > 0).then(|| sum / f64::from(count))
+    }
+}
+
+/// Spawn a thread that fetches the outdoor temperature every configured interval, or
+/// follows the configured topic.
+pub fn spawn_weather_job(appdata: Arc<AppData>) -> Result<JoinHandle<()>, std::io::Error> {
+    supervisor::spawn("weather", appdata, |appdata| {
+        let Some(config) = appdata.config().weather.as_ref() else {
+            return;
+        };
+        if let Some(topic) = &config.topic {
+            #[cfg(feature = "mqtt")]
+            follow(appdata, config, topic);
+            #[cfg(not(feature = "mqtt"))]
+            error!(
+                "Unable to follow {}: dsmrd was built without the mqtt feature.",
+                topic
+            );
+            return;
+        }
+        let Some(url) = &config.url else {
+            error!("Unable to start weather job: neither a url nor a topic is configured.");
+            return;
+        };
+        let client =
+            match HttpClient::new(Dialer::with_proxy(appdata.config().outbound.proxy.as_ref())) {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("Unable to start weather job: {}", e);
+                    return;
+                }
+            };
+        info!("Weather job started.");
+
+        loop {
+            let fetched = client
+                .get(url, &header_refs(&config.headers))
+                .and_then(|body| temperature(&body, &config.pointer));
+            match fetched {
+                Ok(celsius) => {
+                    debug!("Outdoor temperature is {} °C", celsius);
+                    appdata
+                        .temperatures
+                        .write_recover()
+                        .push(now_millis(), celsius);
+                }
+                Err(e) => error!("Unable to fetch the outdoor temperature: {}", e),
+            }
+            thread::sleep(Duration::from_secs(config.interval.max(1)));
+        }
+    })
+}
+
+/// Keep the temperatures published to `topic`, at most one every interval.
+#[cfg(feature = "mqtt")]
+fn follow(appdata: &AppData, config: &crate::config::WeatherConfig, topic: &str) {
+    let Some(mqtt) = appdata.mqtt() else {
+        error!("Unable to follow {}: no MQTT broker is configured.", topic);
+        return;
+    };
+    info!("Following the outdoor temperature on {}.", topic);
+    let mut kept: Option<u64> = None;
+    for message in mqtt.subscribe(topic) {
+        match temperature(&message, &config.pointer) {
+            Ok(celsius) => {
+                debug!("Outdoor temperature is {} °C", celsius);
+                let now = now_millis();
+                if kept.is_some_and(|kept| now.saturating_sub(kept) < config.interval * 1000) {
+                    continue;
+                }
+                kept = Some(now);
+                appdata.temperatures.write_recover().push(now, celsius);
+            }
+            Err(e) => error!("Unable to read the outdoor temperature on {}: {}", topic, e),
+        }
+    }
+}
+
+/// The temperature at `pointer` in a JSON response or message.
+fn temperature(body: &[u8], pointer: &str) -> Result<f64, String> {
+    let value: Value = serde_json::from_slice(body).map_err(|e| format!("Invalid JSON: {}", e))?;
+    value
+        .pointer(pointer)
+        .and_then(Value::as_f64)
+        .ok_or_else(|| format!("No temperature at {:?}", pointer))
+}
@@ -0,0 +1,61 @@
+//! Upgrading HTTP requests to WebSocket connections, for the endpoints that offer one.
+
+use std::future::Future;
+
+use hyper::{
+    header::{
+        CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, UPGRADE,
+    },
+    upgrade::Upgraded,
+    Body, Request, Response, StatusCode,
+};
+use log::warn;
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role},
+    WebSocketStream,
+};
+
+pub type Socket = WebSocketStream<Upgraded>;
+
+/// Whether the client asks to upgrade the connection to a WebSocket.
+pub fn is_upgrade(req: &Request<Body>) -> bool {
+    req.headers().contains_key(SEC_WEBSOCKET_KEY)
+}
+
+/// Accept the WebSocket handshake of `req`, with the given subprotocol if any. Once the
+/// connection is upgraded, `serve` is run on a task of its own.
+pub fn upgrade<F, Fut>(
+    req: Request<Body>,
+    protocol: Option<&'static str>,
+    serve: F,
+) -> Result<Response<Body>, hyper::http::Error>
+where
+    F: FnOnce(Socket) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let Some(key) = req.headers().get(SEC_WEBSOCKET_KEY) else {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from("Error: expected a WebSocket handshake."));
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                serve(WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await).await
+            }
+            Err(e) => warn!("Unable to upgrade connection to WebSocket: {}", e),
+        }
+    });
+
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(CONNECTION, "upgrade")
+        .header(UPGRADE, "websocket")
+        .header(SEC_WEBSOCKET_ACCEPT, accept);
+    if let Some(protocol) = protocol {
+        response = response.header(SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    response.body(Body::empty())
+}